@@ -0,0 +1,249 @@
+//! Local directory backend.
+//!
+//! Stores objects as plain files using the on-disk repository layout:
+//!
+//! ```text
+//! config.json
+//! index.json
+//! keys/<id>.json
+//! snapshots/<id>.json
+//! data/packs/<prefix>/<pack-id>
+//! data/index/<index-id>
+//! data/chunks/<prefix>/<hash>
+//! locks/<id>
+//! ```
+
+use super::{check_range, is_sharded, kind_dir, object_name, object_path, Backend, ObjectKind};
+use crate::error::{Result, SnapVaultError};
+use log::debug;
+use std::fs;
+use crate::utils::write_atomic;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Backend storing a repository in a local directory
+#[derive(Debug, Clone)]
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    /// Create a backend for the repository directory at `root`
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Root directory of the repository
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Directory holding objects of a kind (the root for singletons)
+    fn dir(&self, kind: ObjectKind) -> PathBuf {
+        self.root.join(kind_dir(kind))
+    }
+
+    /// Get the path of an object
+    pub fn path(&self, kind: ObjectKind, name: &str) -> Result<PathBuf> {
+        Ok(self.root.join(object_path(kind, name)?))
+    }
+}
+
+/// Convert a missing-file error into `ObjectNotFound`
+fn not_found(kind: ObjectKind, name: &str) -> impl FnOnce(std::io::Error) -> SnapVaultError {
+    let name = name.to_string();
+    move |e| match e.kind() {
+        ErrorKind::NotFound => SnapVaultError::ObjectNotFound { kind, name },
+        _ => SnapVaultError::Io(e),
+    }
+}
+
+impl Backend for LocalBackend {
+    fn location(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn create(&self) -> Result<()> {
+        let existed = self.root.exists();
+        fs::create_dir_all(&self.root).map_err(|e| {
+            SnapVaultError::Io(std::io::Error::new(
+                e.kind(),
+                format!("Failed to create repository directory: {}", e),
+            ))
+        })?;
+
+        // Set permissions on Unix (owner only)
+        #[cfg(unix)]
+        if !existed {
+            use std::os::unix::fs::PermissionsExt;
+            let mut perms = fs::metadata(&self.root)?.permissions();
+            perms.set_mode(0o700);
+            fs::set_permissions(&self.root, perms)?;
+        }
+
+        for kind in [ObjectKind::Snapshot, ObjectKind::Pack, ObjectKind::PackIndex] {
+            fs::create_dir_all(self.dir(kind))?;
+        }
+        debug!("Created repository layout at {}", self.root.display());
+        Ok(())
+    }
+
+    fn put(&self, kind: ObjectKind, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(kind, name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&path, data)
+    }
+
+    fn get(&self, kind: ObjectKind, name: &str) -> Result<Vec<u8>> {
+        fs::read(self.path(kind, name)?).map_err(not_found(kind, name))
+    }
+
+    fn get_range(&self, kind: ObjectKind, name: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut file = fs::File::open(self.path(kind, name)?).map_err(not_found(kind, name))?;
+        check_range(kind, name, offset, length, file.metadata()?.len())?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; length as usize];
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn size(&self, kind: ObjectKind, name: &str) -> Result<u64> {
+        let metadata = fs::metadata(self.path(kind, name)?).map_err(not_found(kind, name))?;
+        Ok(metadata.len())
+    }
+
+    fn exists(&self, kind: ObjectKind, name: &str) -> Result<bool> {
+        Ok(self.path(kind, name)?.is_file())
+    }
+
+    fn list(&self, kind: ObjectKind) -> Result<Vec<String>> {
+        if kind.is_singleton() {
+            let present = self.exists(kind, "")?;
+            return Ok(if present { vec![String::new()] } else { Vec::new() });
+        }
+
+        let dir = self.dir(kind);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        // Sharded kinds keep their objects one directory level down
+        let dirs = if is_sharded(kind) {
+            let mut dirs = Vec::new();
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+            }
+            dirs
+        } else {
+            vec![dir]
+        };
+
+        let mut names = Vec::new();
+        for dir in dirs {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() {
+                    continue;
+                }
+                if let Some(name) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|f| object_name(kind, f))
+                {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, kind: ObjectKind, name: &str) -> Result<()> {
+        let path = self.path(kind, name)?;
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            // Already deleted or never existed
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+
+        // Try to remove an empty prefix directory (cleanup)
+        if is_sharded(kind)
+            && let Some(parent) = path.parent()
+            && let Ok(mut entries) = fs::read_dir(parent)
+            && entries.next().is_none()
+        {
+            let _ = fs::remove_dir(parent);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::check_backend_contract;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_local_backend_contract() {
+        let temp = TempDir::new().unwrap();
+        check_backend_contract(&LocalBackend::new(temp.path().join("repo")));
+    }
+
+    #[test]
+    fn test_local_layout() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("repo");
+        let backend = LocalBackend::new(&root);
+        backend.create().unwrap();
+
+        assert!(root.join("snapshots").is_dir());
+        assert!(root.join("data").join("packs").is_dir());
+        assert!(root.join("data").join("index").is_dir());
+
+        backend.put(ObjectKind::Snapshot, "snap", b"{}").unwrap();
+        backend.put(ObjectKind::Key, "k1", b"{}").unwrap();
+        backend.put(ObjectKind::Index, "", b"{}").unwrap();
+        let hash = "abcdef1234567890abcdef1234567890abcdef1234567890abcdef1234567890";
+        backend.put(ObjectKind::Chunk, hash, b"chunk").unwrap();
+
+        assert!(root.join("snapshots").join("snap.json").is_file());
+        assert!(root.join("keys").join("k1.json").is_file());
+        assert!(root.join("index.json").is_file());
+        assert!(root.join("data").join("chunks").join("ab").join(hash).is_file());
+
+        // Objects are renamed into place, leaving no temporary files
+        backend.put(ObjectKind::Snapshot, "snap", b"{\"v\":2}").unwrap();
+        assert_eq!(fs::read_dir(root.join("snapshots")).unwrap().count(), 1);
+
+        // Stray files are not mistaken for objects, nor are temporary files
+        // left behind by a crash
+        fs::write(root.join("snapshots").join("notes.txt"), b"").unwrap();
+        fs::write(root.join("snapshots").join(".snap2.json.tmp-1234abcd"), b"{").unwrap();
+        assert_eq!(backend.list(ObjectKind::Snapshot).unwrap(), ["snap"]);
+
+        // Deleting the last object of a prefix removes its directory
+        backend.delete(ObjectKind::Chunk, hash).unwrap();
+        assert!(!root.join("data").join("chunks").join("ab").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_restricts_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("repo");
+        LocalBackend::new(&root).create().unwrap();
+
+        let mode = fs::metadata(&root).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}
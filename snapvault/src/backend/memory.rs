@@ -0,0 +1,123 @@
+//! In-memory backend, for tests and experiments.
+
+use super::{slice_range, validate_name, Backend, ObjectKind};
+use crate::error::{Result, SnapVaultError};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type Objects = BTreeMap<(ObjectKind, String), Vec<u8>>;
+
+/// Backend keeping every object in memory.
+/// Clones share the same objects, so a test can keep a handle to inspect
+/// what a repository wrote.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    objects: Arc<Mutex<Objects>>,
+}
+
+impl MemoryBackend {
+    /// Create an empty backend
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn objects(&self) -> MutexGuard<'_, Objects> {
+        self.objects.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Key of an object; singletons are stored under an empty name
+    fn key(kind: ObjectKind, name: &str) -> Result<(ObjectKind, String)> {
+        validate_name(kind, name)?;
+        let name = if kind.is_singleton() { "" } else { name };
+        Ok((kind, name.to_string()))
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn location(&self) -> String {
+        "memory:".to_string()
+    }
+
+    fn create(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn put(&self, kind: ObjectKind, name: &str, data: &[u8]) -> Result<()> {
+        let key = Self::key(kind, name)?;
+        self.objects().insert(key, data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, kind: ObjectKind, name: &str) -> Result<Vec<u8>> {
+        let key = Self::key(kind, name)?;
+        self.objects()
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| SnapVaultError::ObjectNotFound {
+                kind,
+                name: name.to_string(),
+            })
+    }
+
+    fn get_range(&self, kind: ObjectKind, name: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let key = Self::key(kind, name)?;
+        match self.objects().get(&key) {
+            Some(data) => slice_range(data, kind, name, offset, length),
+            None => Err(SnapVaultError::ObjectNotFound {
+                kind,
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    fn size(&self, kind: ObjectKind, name: &str) -> Result<u64> {
+        let key = Self::key(kind, name)?;
+        match self.objects().get(&key) {
+            Some(data) => Ok(data.len() as u64),
+            None => Err(SnapVaultError::ObjectNotFound {
+                kind,
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    fn exists(&self, kind: ObjectKind, name: &str) -> Result<bool> {
+        let key = Self::key(kind, name)?;
+        Ok(self.objects().contains_key(&key))
+    }
+
+    fn list(&self, kind: ObjectKind) -> Result<Vec<String>> {
+        Ok(self
+            .objects()
+            .keys()
+            .filter(|(k, _)| *k == kind)
+            .map(|(_, name)| name.clone())
+            .collect())
+    }
+
+    fn delete(&self, kind: ObjectKind, name: &str) -> Result<()> {
+        let key = Self::key(kind, name)?;
+        self.objects().remove(&key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::check_backend_contract;
+
+    #[test]
+    fn test_memory_backend_contract() {
+        check_backend_contract(&MemoryBackend::new());
+    }
+
+    #[test]
+    fn test_clones_share_objects() {
+        let backend = MemoryBackend::new();
+        let clone = backend.clone();
+
+        clone.put(ObjectKind::Lock, "l1", b"lock").unwrap();
+        assert_eq!(backend.get(ObjectKind::Lock, "l1").unwrap(), b"lock");
+    }
+}
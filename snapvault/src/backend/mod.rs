@@ -0,0 +1,289 @@
+//! Storage backends.
+//!
+//! A backend stores named objects of a few kinds and knows nothing about
+//! their contents: `Repository` and `ChunkStore` decide what goes into each
+//! object (and encrypt it), the backend only moves bytes. Every repository
+//! operation goes through the `Backend` trait, so new kinds of storage can be
+//! added without touching the commands.
+
+pub mod local;
+pub mod memory;
+pub mod s3;
+pub mod sftp;
+
+use crate::error::{Result, SnapVaultError};
+use std::fmt;
+
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+pub use s3::{S3Backend, S3Config};
+pub use sftp::{SftpBackend, SftpConfig};
+
+/// Kinds of objects stored in a repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ObjectKind {
+    /// Repository configuration (a single object; the name is ignored)
+    Config,
+    /// Passphrase-wrapped master key slot
+    Key,
+    /// Snapshot manifest
+    Snapshot,
+    /// Chunk reference index (a single object; the name is ignored)
+    Index,
+    /// Pack file holding chunk data
+    Pack,
+    /// Pack index file mapping chunks to packs
+    PackIndex,
+    /// Chunk stored in its own object, as written before pack files
+    Chunk,
+    /// Repository lock
+    Lock,
+}
+
+impl ObjectKind {
+    /// Whether the repository holds at most one object of this kind
+    pub fn is_singleton(&self) -> bool {
+        matches!(self, ObjectKind::Config | ObjectKind::Index)
+    }
+
+    /// Short lowercase name of the kind
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectKind::Config => "config",
+            ObjectKind::Key => "key",
+            ObjectKind::Snapshot => "snapshot",
+            ObjectKind::Index => "index",
+            ObjectKind::Pack => "pack",
+            ObjectKind::PackIndex => "pack index",
+            ObjectKind::Chunk => "chunk",
+            ObjectKind::Lock => "lock",
+        }
+    }
+}
+
+impl fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Object storage underneath a repository.
+///
+/// Objects are addressed by kind and name. Names are generated by snapvault
+/// (hex IDs, snapshot IDs) and never contain path separators; backends must
+/// reject any that do. Singleton kinds ignore the name.
+pub trait Backend: Send + Sync {
+    /// Human-readable location of the repository, for messages
+    fn location(&self) -> String;
+
+    /// Prepare storage for a new repository
+    fn create(&self) -> Result<()>;
+
+    /// Store an object, replacing any existing object with the same name.
+    /// Readers never observe a partially written object: after a crash the
+    /// object either has its old contents (or is missing) or its new ones.
+    fn put(&self, kind: ObjectKind, name: &str, data: &[u8]) -> Result<()>;
+
+    /// Read a whole object.
+    /// Returns `ObjectNotFound` if it does not exist.
+    fn get(&self, kind: ObjectKind, name: &str) -> Result<Vec<u8>>;
+
+    /// Read `length` bytes of an object starting at `offset`
+    fn get_range(&self, kind: ObjectKind, name: &str, offset: u64, length: u64) -> Result<Vec<u8>>;
+
+    /// Size of an object in bytes
+    fn size(&self, kind: ObjectKind, name: &str) -> Result<u64>;
+
+    /// Whether an object exists
+    fn exists(&self, kind: ObjectKind, name: &str) -> Result<bool>;
+
+    /// Names of all objects of a kind (unordered)
+    fn list(&self, kind: ObjectKind) -> Result<Vec<String>>;
+
+    /// Delete an object. Deleting a missing object is not an error.
+    fn delete(&self, kind: ObjectKind, name: &str) -> Result<()>;
+}
+
+/// Directory holding objects of a kind, relative to the repository root
+/// ("" for singletons, which live in the root)
+pub(crate) fn kind_dir(kind: ObjectKind) -> &'static str {
+    match kind {
+        ObjectKind::Config | ObjectKind::Index => "",
+        ObjectKind::Key => "keys",
+        ObjectKind::Snapshot => "snapshots",
+        ObjectKind::Pack => "data/packs",
+        ObjectKind::PackIndex => "data/index",
+        ObjectKind::Chunk => "data/chunks",
+        ObjectKind::Lock => "locks",
+    }
+}
+
+/// Whether objects of a kind are spread over two-character prefix directories
+pub(crate) fn is_sharded(kind: ObjectKind) -> bool {
+    matches!(kind, ObjectKind::Pack | ObjectKind::Chunk)
+}
+
+/// Path of an object relative to the repository root, `/`-separated.
+/// Every backend that stores files uses this layout, so a repository can be
+/// copied between them as-is.
+pub(crate) fn object_path(kind: ObjectKind, name: &str) -> Result<String> {
+    validate_name(kind, name)?;
+    let dir = kind_dir(kind);
+    Ok(match kind {
+        ObjectKind::Config => "config.json".to_string(),
+        ObjectKind::Index => "index.json".to_string(),
+        ObjectKind::Key | ObjectKind::Snapshot => format!("{}/{}.json", dir, name),
+        // Two-level directory structure keeps directories small:
+        // data/packs/<prefix>/<name>, e.g. data/packs/ab/ab123456...
+        ObjectKind::Pack | ObjectKind::Chunk => {
+            let prefix = name.get(..2).unwrap_or(name);
+            format!("{}/{}/{}", dir, prefix, name)
+        }
+        ObjectKind::PackIndex | ObjectKind::Lock => format!("{}/{}", dir, name),
+    })
+}
+
+/// Map a file name found in a kind's directory back to an object name,
+/// skipping unrelated files
+pub(crate) fn object_name(kind: ObjectKind, file_name: &str) -> Option<String> {
+    let name = match kind {
+        ObjectKind::Key | ObjectKind::Snapshot => file_name.strip_suffix(".json")?,
+        _ => file_name,
+    };
+    validate_name(kind, name).ok()?;
+    Some(name.to_string())
+}
+
+/// Reject object names that could escape the object's namespace
+pub(crate) fn validate_name(kind: ObjectKind, name: &str) -> Result<()> {
+    if kind.is_singleton() {
+        return Ok(());
+    }
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(['/', '\\', '\0'])
+    {
+        return Err(SnapVaultError::Other(format!(
+            "Invalid {} name: {:?}",
+            kind, name
+        )));
+    }
+    Ok(())
+}
+
+/// Fail unless `offset..offset + length` lies within an object of `size` bytes
+pub(crate) fn check_range(
+    kind: ObjectKind,
+    name: &str,
+    offset: u64,
+    length: u64,
+    size: u64,
+) -> Result<()> {
+    if offset.checked_add(length).is_none_or(|end| end > size) {
+        return Err(SnapVaultError::Other(format!(
+            "Read past end of {} {}: {}+{} > {}",
+            kind, name, offset, length, size
+        )));
+    }
+    Ok(())
+}
+
+/// Slice `data[offset..offset + length]`, failing if it is out of bounds
+pub(crate) fn slice_range(
+    data: &[u8],
+    kind: ObjectKind,
+    name: &str,
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>> {
+    check_range(kind, name, offset, length, data.len() as u64)?;
+    Ok(data[offset as usize..(offset + length) as usize].to_vec())
+}
+
+/// Checks every backend implementation must pass
+#[cfg(test)]
+pub(crate) fn check_backend_contract(backend: &dyn Backend) {
+    backend.create().unwrap();
+
+    // Singletons ignore the name
+    backend.put(ObjectKind::Config, "", b"{}").unwrap();
+    assert_eq!(backend.get(ObjectKind::Config, "anything").unwrap(), b"{}");
+    assert_eq!(backend.list(ObjectKind::Config).unwrap().len(), 1);
+
+    // Missing objects
+    assert!(!backend.exists(ObjectKind::Snapshot, "missing").unwrap());
+    assert!(matches!(
+        backend.get(ObjectKind::Snapshot, "missing"),
+        Err(SnapVaultError::ObjectNotFound { .. })
+    ));
+    backend.delete(ObjectKind::Snapshot, "missing").unwrap();
+
+    // Round trip, overwrite and ranges
+    let pack = "ab".repeat(32);
+    backend.put(ObjectKind::Pack, &pack, b"first").unwrap();
+    backend.put(ObjectKind::Pack, &pack, b"0123456789").unwrap();
+    assert!(backend.exists(ObjectKind::Pack, &pack).unwrap());
+    assert_eq!(backend.get(ObjectKind::Pack, &pack).unwrap(), b"0123456789");
+    assert_eq!(backend.get_range(ObjectKind::Pack, &pack, 3, 4).unwrap(), b"3456");
+    assert!(backend.get_range(ObjectKind::Pack, &pack, 8, 4).is_err());
+    assert_eq!(backend.size(ObjectKind::Pack, &pack).unwrap(), 10);
+
+    // Kinds are separate namespaces
+    backend.put(ObjectKind::Snapshot, "snap-1", b"one").unwrap();
+    backend.put(ObjectKind::Snapshot, "snap-2", b"two").unwrap();
+    backend.put(ObjectKind::Key, "snap-1", b"key").unwrap();
+    let mut snapshots = backend.list(ObjectKind::Snapshot).unwrap();
+    snapshots.sort();
+    assert_eq!(snapshots, ["snap-1", "snap-2"]);
+    assert_eq!(backend.list(ObjectKind::Pack).unwrap(), vec![pack.clone()]);
+    assert!(backend.list(ObjectKind::Lock).unwrap().is_empty());
+
+    backend.delete(ObjectKind::Snapshot, "snap-1").unwrap();
+    assert!(!backend.exists(ObjectKind::Snapshot, "snap-1").unwrap());
+    assert!(backend.exists(ObjectKind::Key, "snap-1").unwrap());
+    backend.delete(ObjectKind::Pack, &pack).unwrap();
+    assert!(backend.list(ObjectKind::Pack).unwrap().is_empty());
+
+    // Names that could escape their namespace are rejected
+    assert!(backend.put(ObjectKind::Snapshot, "../config", b"x").is_err());
+    assert!(backend.get(ObjectKind::Key, "a/b").is_err());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name(ObjectKind::Snapshot, "20240101T000000Z-abcd1234").is_ok());
+        assert!(validate_name(ObjectKind::Config, "").is_ok());
+
+        for bad in ["", "..", ".hidden", "a/b", "a\\b", "nul\0"] {
+            assert!(validate_name(ObjectKind::Pack, bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_object_path() {
+        let hash = "ab".repeat(32);
+        assert_eq!(object_path(ObjectKind::Config, "").unwrap(), "config.json");
+        assert_eq!(object_path(ObjectKind::Snapshot, "s1").unwrap(), "snapshots/s1.json");
+        assert_eq!(
+            object_path(ObjectKind::Pack, &hash).unwrap(),
+            format!("data/packs/ab/{}", hash)
+        );
+        assert_eq!(object_path(ObjectKind::Lock, "l1").unwrap(), "locks/l1");
+        assert!(object_path(ObjectKind::Key, "../x").is_err());
+
+        assert_eq!(object_name(ObjectKind::Key, "k1.json").as_deref(), Some("k1"));
+        assert_eq!(object_name(ObjectKind::Key, "notes.txt"), None);
+    }
+
+    #[test]
+    fn test_slice_range() {
+        let data = b"0123456789";
+        assert_eq!(slice_range(data, ObjectKind::Pack, "p", 2, 3).unwrap(), b"234");
+        assert!(slice_range(data, ObjectKind::Pack, "p", 8, 3).is_err());
+        assert!(slice_range(data, ObjectKind::Pack, "p", u64::MAX, 2).is_err());
+    }
+}
@@ -0,0 +1,574 @@
+//! Minimal SFTP version 3 client.
+//!
+//! Speaks the protocol of draft-ietf-secsh-filexfer-02 over any byte stream,
+//! normally the stdin/stdout of `ssh host -s sftp`. Only the requests
+//! snapvault needs are implemented. Reads and writes of large files are
+//! pipelined so throughput does not suffer from the round-trip time.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process::Child;
+
+pub(crate) const SSH_FXP_INIT: u8 = 1;
+pub(crate) const SSH_FXP_VERSION: u8 = 2;
+pub(crate) const SSH_FXP_OPEN: u8 = 3;
+pub(crate) const SSH_FXP_CLOSE: u8 = 4;
+pub(crate) const SSH_FXP_READ: u8 = 5;
+pub(crate) const SSH_FXP_WRITE: u8 = 6;
+pub(crate) const SSH_FXP_OPENDIR: u8 = 11;
+pub(crate) const SSH_FXP_READDIR: u8 = 12;
+pub(crate) const SSH_FXP_REMOVE: u8 = 13;
+pub(crate) const SSH_FXP_MKDIR: u8 = 14;
+pub(crate) const SSH_FXP_RMDIR: u8 = 15;
+pub(crate) const SSH_FXP_STAT: u8 = 17;
+pub(crate) const SSH_FXP_RENAME: u8 = 18;
+pub(crate) const SSH_FXP_STATUS: u8 = 101;
+pub(crate) const SSH_FXP_HANDLE: u8 = 102;
+pub(crate) const SSH_FXP_DATA: u8 = 103;
+pub(crate) const SSH_FXP_NAME: u8 = 104;
+pub(crate) const SSH_FXP_ATTRS: u8 = 105;
+pub(crate) const SSH_FXP_EXTENDED: u8 = 200;
+
+pub(crate) const SSH_FX_OK: u32 = 0;
+pub(crate) const SSH_FX_EOF: u32 = 1;
+pub(crate) const SSH_FX_NO_SUCH_FILE: u32 = 2;
+pub(crate) const SSH_FX_FAILURE: u32 = 4;
+
+pub(crate) const SSH_FXF_READ: u32 = 0x01;
+pub(crate) const SSH_FXF_WRITE: u32 = 0x02;
+pub(crate) const SSH_FXF_CREAT: u32 = 0x08;
+pub(crate) const SSH_FXF_TRUNC: u32 = 0x10;
+
+pub(crate) const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+pub(crate) const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+pub(crate) const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+pub(crate) const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+pub(crate) const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+/// OpenSSH extension renaming over an existing file, as rename(2) does
+pub(crate) const POSIX_RENAME_EXTENSION: &str = "posix-rename@openssh.com";
+
+/// OpenSSH extension flushing an open file to disk
+pub(crate) const FSYNC_EXTENSION: &str = "fsync@openssh.com";
+
+/// Protocol version spoken by the client
+const SFTP_VERSION: u32 = 3;
+
+/// Largest read or write request; every server accepts at least this much
+const MAX_IO_SIZE: usize = 32 * 1024;
+
+/// Number of read or write requests kept in flight at once
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Largest packet accepted from the server
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// File type bits of `permissions`
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+
+/// Errors returned by the client
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// The server refused a request
+    Status { code: u32, message: String },
+    /// The connection failed or the server broke the protocol; the client
+    /// can no longer be used
+    Io(io::Error),
+}
+
+impl Error {
+    pub(crate) fn is_not_found(&self) -> bool {
+        matches!(self, Error::Status { code: SSH_FX_NO_SUCH_FILE, .. })
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Status { code, message } if message.is_empty() => {
+                write!(f, "server returned status {}", code)
+            }
+            Error::Status { message, .. } => f.write_str(message),
+            Error::Io(e) => write!(f, "connection failed: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+fn protocol_error(message: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, message.to_string()))
+}
+
+/// File attributes; only the fields snapvault uses are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Attrs {
+    pub size: Option<u64>,
+    pub permissions: Option<u32>,
+}
+
+impl Attrs {
+    /// Whether the attributes describe a regular file.
+    /// Servers that omit permissions are trusted to list only files.
+    pub(crate) fn is_file(&self) -> bool {
+        self.permissions.is_none_or(|p| p & S_IFMT == S_IFREG)
+    }
+
+    pub(crate) fn is_dir(&self) -> bool {
+        self.permissions.is_some_and(|p| p & S_IFMT == S_IFDIR)
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Encoder) {
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        if self.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        buf.u32(flags);
+        if let Some(size) = self.size {
+            buf.u64(size);
+        }
+        if let Some(permissions) = self.permissions {
+            buf.u32(permissions);
+        }
+    }
+
+    pub(crate) fn decode(data: &mut Decoder) -> Result<Self> {
+        let flags = data.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(data.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            data.u32()?;
+            data.u32()?;
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(data.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            data.u32()?;
+            data.u32()?;
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..data.u32()? {
+                data.string()?;
+                data.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// Builds the body of a packet
+#[derive(Default)]
+pub(crate) struct Encoder(pub Vec<u8>);
+
+impl Encoder {
+    pub(crate) fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub(crate) fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub(crate) fn string(&mut self, s: &[u8]) -> &mut Self {
+        self.u32(s.len() as u32);
+        self.0.extend_from_slice(s);
+        self
+    }
+}
+
+/// Reads fields from the body of a packet
+pub(crate) struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(protocol_error("truncated SFTP packet"));
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Read one packet as (type, body)
+pub(crate) fn read_packet(reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PACKET_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid SFTP packet length"));
+    }
+    let mut packet = vec![0u8; len];
+    reader.read_exact(&mut packet)?;
+    let body = packet.split_off(1);
+    Ok((packet[0], body))
+}
+
+/// Write one packet of the given type
+pub(crate) fn write_packet(
+    writer: &mut impl Write,
+    packet_type: u8,
+    body: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&(body.len() as u32 + 1).to_be_bytes())?;
+    writer.write_all(&[packet_type])?;
+    writer.write_all(body)
+}
+
+/// Byte streams to and from an SFTP server
+pub(crate) struct Transport {
+    pub reader: Box<dyn Read + Send>,
+    pub writer: Box<dyn Write + Send>,
+    /// Process providing the streams, waited for when the client is dropped
+    pub child: Option<Child>,
+}
+
+/// An open file or directory on the server
+struct Handle(Vec<u8>);
+
+/// A connected SFTP session
+pub(crate) struct Client {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    child: Option<Child>,
+    next_id: u32,
+    /// Responses that arrived while waiting for another request
+    pending: HashMap<u32, (u8, Vec<u8>)>,
+    /// Protocol extensions announced by the server
+    extensions: HashSet<String>,
+}
+
+impl Client {
+    /// Perform the version handshake over `transport`
+    pub(crate) fn connect(transport: Transport) -> Result<Self> {
+        let mut client = Self {
+            reader: BufReader::new(transport.reader),
+            writer: BufWriter::new(transport.writer),
+            child: transport.child,
+            next_id: 0,
+            pending: HashMap::new(),
+            extensions: HashSet::new(),
+        };
+
+        write_packet(&mut client.writer, SSH_FXP_INIT, &SFTP_VERSION.to_be_bytes())?;
+        client.writer.flush()?;
+        let (packet_type, body) = read_packet(&mut client.reader)?;
+        if packet_type != SSH_FXP_VERSION {
+            return Err(protocol_error("server did not answer the SFTP handshake"));
+        }
+        let mut data = Decoder::new(&body);
+        let version = data.u32()?;
+        if version < SFTP_VERSION {
+            return Err(protocol_error(&format!("unsupported SFTP version {}", version)));
+        }
+        // The version is followed by (name, data) pairs naming extensions
+        while !data.data.is_empty() {
+            let name = String::from_utf8_lossy(data.string()?).into_owned();
+            data.string()?;
+            client.extensions.insert(name);
+        }
+        Ok(client)
+    }
+
+    /// Queue a request; returns its ID. Call `flush` before waiting on it.
+    fn send(&mut self, packet_type: u8, body: &Encoder) -> Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = Vec::with_capacity(4 + body.0.len());
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&body.0);
+        write_packet(&mut self.writer, packet_type, &packet)?;
+        Ok(id)
+    }
+
+    /// Wait for the response to request `id`, as (type, body after the ID)
+    fn receive(&mut self, id: u32) -> Result<(u8, Vec<u8>)> {
+        self.writer.flush()?;
+        if let Some(response) = self.pending.remove(&id) {
+            return Ok(response);
+        }
+        loop {
+            let (packet_type, body) = read_packet(&mut self.reader)?;
+            let mut data = Decoder::new(&body);
+            let response_id = data.u32()?;
+            let response = (packet_type, data.data.to_vec());
+            if response_id == id {
+                return Ok(response);
+            }
+            self.pending.insert(response_id, response);
+        }
+    }
+
+    fn request(&mut self, packet_type: u8, body: &Encoder) -> Result<(u8, Vec<u8>)> {
+        let id = self.send(packet_type, body)?;
+        self.receive(id)
+    }
+
+    /// Turn a response into an error unless it is of the expected type
+    fn expect(response: (u8, Vec<u8>), expected: u8) -> Result<Vec<u8>> {
+        match response {
+            (packet_type, body) if packet_type == expected => Ok(body),
+            (SSH_FXP_STATUS, body) => Err(Self::status(&body)?),
+            _ => Err(protocol_error("unexpected SFTP response")),
+        }
+    }
+
+    /// Expect an `SSH_FX_OK` status
+    fn expect_ok(response: (u8, Vec<u8>)) -> Result<()> {
+        match response {
+            (SSH_FXP_STATUS, body) => match Self::status(&body)? {
+                Error::Status { code: SSH_FX_OK, .. } => Ok(()),
+                e => Err(e),
+            },
+            _ => Err(protocol_error("unexpected SFTP response")),
+        }
+    }
+
+    /// Decode the body of a status response
+    fn status(body: &[u8]) -> Result<Error> {
+        let mut data = Decoder::new(body);
+        let code = data.u32()?;
+        let message = data.string().unwrap_or_default();
+        let message = String::from_utf8_lossy(message).into_owned();
+        Ok(Error::Status { code, message })
+    }
+
+    /// Get the attributes of a path, following symlinks
+    pub(crate) fn stat(&mut self, path: &str) -> Result<Attrs> {
+        let response = self.request(SSH_FXP_STAT, Encoder::default().string(path.as_bytes()))?;
+        let body = Self::expect(response, SSH_FXP_ATTRS)?;
+        Attrs::decode(&mut Decoder::new(&body))
+    }
+
+    fn open(&mut self, path: &str, flags: u32, attrs: Attrs) -> Result<Handle> {
+        let mut body = Encoder::default();
+        body.string(path.as_bytes()).u32(flags);
+        attrs.encode(&mut body);
+        let response = self.request(SSH_FXP_OPEN, &body)?;
+        let body = Self::expect(response, SSH_FXP_HANDLE)?;
+        Ok(Handle(Decoder::new(&body).string()?.to_vec()))
+    }
+
+    fn close(&mut self, handle: Handle) -> Result<()> {
+        let response = self.request(SSH_FXP_CLOSE, Encoder::default().string(&handle.0))?;
+        Self::expect_ok(response)
+    }
+
+    /// Send a request of an extension, named at the start of its body
+    fn extended(&mut self, extension: &str, fields: &[&[u8]]) -> Result<()> {
+        let mut body = Encoder::default();
+        body.string(extension.as_bytes());
+        for field in fields {
+            body.string(field);
+        }
+        let response = self.request(SSH_FXP_EXTENDED, &body)?;
+        Self::expect_ok(response)
+    }
+
+    /// Create or truncate a file and write `data` to it. The data is flushed
+    /// to disk before returning if the server supports `fsync@openssh.com`.
+    pub(crate) fn write_file(&mut self, path: &str, data: &[u8], mode: u32) -> Result<()> {
+        let attrs = Attrs {
+            size: None,
+            permissions: Some(mode),
+        };
+        let handle = self.open(path, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC, attrs)?;
+        let mut written = self.write_all(&handle, data);
+        if written.is_ok() && self.extensions.contains(FSYNC_EXTENSION) {
+            written = self.extended(FSYNC_EXTENSION, &[&handle.0]);
+        }
+        // Close even after a failed write so the handle is not leaked
+        let closed = self.close(handle);
+        written.and(closed)
+    }
+
+    fn write_all(&mut self, handle: &Handle, data: &[u8]) -> Result<()> {
+        let mut in_flight = Vec::new();
+        let mut result = Ok(());
+        for (i, part) in data.chunks(MAX_IO_SIZE).enumerate() {
+            let mut body = Encoder::default();
+            body.string(&handle.0).u64((i * MAX_IO_SIZE) as u64).string(part);
+            in_flight.push(self.send(SSH_FXP_WRITE, &body)?);
+            if in_flight.len() >= MAX_IN_FLIGHT {
+                let response = self.receive(in_flight.remove(0))?;
+                result = result.and(Self::expect_ok(response));
+            }
+        }
+        // Collect every response, even after a failure, to keep the stream in sync
+        for id in in_flight {
+            let response = self.receive(id)?;
+            result = result.and(Self::expect_ok(response));
+        }
+        result
+    }
+
+    /// Read `length` bytes of a file starting at `offset`
+    pub(crate) fn read_file(&mut self, path: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let handle = self.open(path, SSH_FXF_READ, Attrs::default())?;
+        let read = self.read_exact_at(&handle, offset, length);
+        let closed = self.close(handle);
+        let data = read?;
+        closed?;
+        Ok(data)
+    }
+
+    fn read_exact_at(&mut self, handle: &Handle, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(length as usize);
+        while (data.len() as u64) < length {
+            // Issue a window of reads, then collect them in order
+            let start = offset + data.len() as u64;
+            let remaining = length - data.len() as u64;
+            let mut requests = Vec::new();
+            let mut position = 0u64;
+            while position < remaining && requests.len() < MAX_IN_FLIGHT {
+                let size = (remaining - position).min(MAX_IO_SIZE as u64);
+                let mut body = Encoder::default();
+                body.string(&handle.0).u64(start + position).u32(size as u32);
+                requests.push((self.send(SSH_FXP_READ, &body)?, size));
+                position += size;
+            }
+
+            // Every response is collected, even after a failure, to keep the
+            // stream in sync. After a short read the rest of the window is
+            // discarded and re-requested.
+            let mut result = Ok(());
+            let mut short = false;
+            for (id, size) in requests {
+                let response = self.receive(id)?;
+                if short || result.is_err() {
+                    continue;
+                }
+                match Self::expect(response, SSH_FXP_DATA) {
+                    Ok(body) => {
+                        let chunk = Decoder::new(&body).string()?;
+                        data.extend_from_slice(chunk);
+                        short = (chunk.len() as u64) < size;
+                    }
+                    Err(Error::Status { code: SSH_FX_EOF, .. }) => {
+                        result = Err(Error::Status {
+                            code: SSH_FX_EOF,
+                            message: "unexpected end of file".to_string(),
+                        });
+                    }
+                    Err(e) => result = Err(e),
+                }
+            }
+            result?;
+        }
+        Ok(data)
+    }
+
+    /// List a directory as (file name, attributes), without `.` and `..`
+    pub(crate) fn read_dir(&mut self, path: &str) -> Result<Vec<(String, Attrs)>> {
+        let response = self.request(SSH_FXP_OPENDIR, Encoder::default().string(path.as_bytes()))?;
+        let body = Self::expect(response, SSH_FXP_HANDLE)?;
+        let handle = Handle(Decoder::new(&body).string()?.to_vec());
+
+        let mut entries = Vec::new();
+        let result = loop {
+            let response = self.request(SSH_FXP_READDIR, Encoder::default().string(&handle.0))?;
+            let body = match Self::expect(response, SSH_FXP_NAME) {
+                Ok(body) => body,
+                Err(Error::Status { code: SSH_FX_EOF, .. }) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            let mut data = Decoder::new(&body);
+            for _ in 0..data.u32()? {
+                let name = String::from_utf8_lossy(data.string()?).into_owned();
+                data.string()?; // long name
+                let attrs = Attrs::decode(&mut data)?;
+                if name != "." && name != ".." {
+                    entries.push((name, attrs));
+                }
+            }
+        };
+        self.close(handle)?;
+        result.map(|()| entries)
+    }
+
+    pub(crate) fn mkdir(&mut self, path: &str, mode: u32) -> Result<()> {
+        let mut body = Encoder::default();
+        body.string(path.as_bytes());
+        Attrs {
+            size: None,
+            permissions: Some(mode),
+        }
+        .encode(&mut body);
+        let response = self.request(SSH_FXP_MKDIR, &body)?;
+        Self::expect_ok(response)
+    }
+
+    /// Rename `from` to `to`, replacing `to` if it exists. Servers without
+    /// `posix-rename@openssh.com` refuse to rename over a file, so `to` is
+    /// removed first and briefly missing.
+    pub(crate) fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.extensions.contains(POSIX_RENAME_EXTENSION) {
+            return self.extended(POSIX_RENAME_EXTENSION, &[from.as_bytes(), to.as_bytes()]);
+        }
+        let mut body = Encoder::default();
+        body.string(from.as_bytes()).string(to.as_bytes());
+        match Self::expect_ok(self.request(SSH_FXP_RENAME, &body)?) {
+            Err(Error::Status { .. }) if self.stat(to).is_ok() => {
+                self.remove(to)?;
+                Self::expect_ok(self.request(SSH_FXP_RENAME, &body)?)
+            }
+            result => result,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, path: &str) -> Result<()> {
+        let response = self.request(SSH_FXP_REMOVE, Encoder::default().string(path.as_bytes()))?;
+        Self::expect_ok(response)
+    }
+
+    pub(crate) fn rmdir(&mut self, path: &str) -> Result<()> {
+        let response = self.request(SSH_FXP_RMDIR, Encoder::default().string(path.as_bytes()))?;
+        Self::expect_ok(response)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+        if let Some(mut child) = self.child.take() {
+            // Closing stdin ends the session; don't leave a zombie behind
+            drop(std::mem::replace(self.writer.get_mut(), Box::new(io::sink())));
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,539 @@
+//! SFTP backend for repositories on remote servers.
+//!
+//! Runs `ssh [user@]host -s sftp` and speaks SFTP over its stdin/stdout, so
+//! authentication, host keys and `~/.ssh/config` work exactly as for a plain
+//! `ssh` login. The repository uses the same layout as a local one. A single
+//! session is opened lazily and reused for every request; if the connection
+//! drops it is re-established on the next request.
+
+mod client;
+#[cfg(test)]
+mod server;
+
+use super::{check_range, is_sharded, kind_dir, object_name, object_path, Backend, ObjectKind};
+use crate::error::{Result, SnapVaultError};
+use crate::utils::temp_file_name;
+use client::{Client, Transport};
+use log::{debug, warn};
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+
+/// URL scheme of SFTP repositories
+pub const SFTP_SCHEME: &str = "sftp://";
+
+/// Where an SFTP repository lives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpConfig {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Repository directory on the server
+    pub path: String,
+}
+
+impl SftpConfig {
+    /// Parse `sftp://[user@]host[:port]/path`.
+    /// `sftp://user@host:/path`, with an empty port, is accepted as well.
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = |reason: &str| {
+            SnapVaultError::Backend(format!("Invalid SFTP URL {}: {}", url, reason))
+        };
+        let rest = url
+            .strip_prefix(SFTP_SCHEME)
+            .ok_or_else(|| invalid("expected sftp://"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => return Err(invalid("missing repository path")),
+        };
+        if path.len() <= 1 {
+            return Err(invalid("missing repository path"));
+        }
+
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user.to_string()), host_port),
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (host, Some(port.parse().map_err(|_| invalid("invalid port"))?)),
+            None => (host_port, None),
+        };
+        if host.is_empty() || user.as_deref() == Some("") {
+            return Err(invalid("missing host"));
+        }
+
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Arguments for `ssh` that start the SFTP subsystem on the server
+    fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(port) = self.port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        args.push(match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        });
+        args.push("-s".to_string());
+        args.push("sftp".to_string());
+        args
+    }
+}
+
+impl fmt::Display for SftpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(SFTP_SCHEME)?;
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        f.write_str(&self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        f.write_str(&self.path)
+    }
+}
+
+/// Opens a new connection to the server
+type Connector = Box<dyn Fn() -> std::io::Result<Transport> + Send + Sync>;
+
+/// Backend storing a repository on a server reachable over SSH
+pub struct SftpBackend {
+    config: SftpConfig,
+    connect: Connector,
+    session: Mutex<Option<Client>>,
+}
+
+impl fmt::Debug for SftpBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpBackend")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SftpBackend {
+    /// Create a backend connecting with the system `ssh` client
+    pub fn new(config: SftpConfig) -> Self {
+        let args = config.ssh_args();
+        Self::with_connector(
+            config,
+            Box::new(move || {
+                let mut child = Command::new("ssh")
+                    .args(&args)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .spawn()?;
+                Ok(Transport {
+                    reader: Box::new(child.stdout.take().unwrap()),
+                    writer: Box::new(child.stdin.take().unwrap()),
+                    child: Some(child),
+                })
+            }),
+        )
+    }
+
+    fn with_connector(config: SftpConfig, connect: Connector) -> Self {
+        Self {
+            config,
+            connect,
+            session: Mutex::new(None),
+        }
+    }
+
+    /// Path of an object on the server
+    fn path(&self, kind: ObjectKind, name: &str) -> Result<String> {
+        Ok(format!("{}/{}", self.config.path, object_path(kind, name)?))
+    }
+
+    /// Directory of a kind on the server
+    fn dir(&self, kind: ObjectKind) -> String {
+        format!("{}/{}", self.config.path, kind_dir(kind))
+    }
+
+    fn session(&self) -> MutexGuard<'_, Option<Client>> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `op` on the shared session, connecting first if needed.
+    /// A request that fails because the connection broke is retried once on
+    /// a fresh connection; all requests snapvault sends are idempotent.
+    fn with_client<T>(
+        &self,
+        what: &str,
+        op: impl Fn(&mut Client) -> client::Result<T>,
+    ) -> Result<client::Result<T>> {
+        let mut session = self.session();
+        let mut retried = false;
+        loop {
+            if session.is_none() {
+                debug!("Connecting to {}", self.config);
+                let transport = (self.connect)().map_err(|e| {
+                    SnapVaultError::Backend(format!("Failed to run ssh for {}: {}", self.config, e))
+                })?;
+                let client = Client::connect(transport).map_err(|e| {
+                    SnapVaultError::Backend(format!(
+                        "SFTP connection to {} failed: {}",
+                        self.config, e
+                    ))
+                })?;
+                *session = Some(client);
+            }
+
+            match op(session.as_mut().unwrap()) {
+                Err(client::Error::Io(e)) => {
+                    // The session is unusable after a transport error
+                    *session = None;
+                    if retried {
+                        return Err(SnapVaultError::Backend(format!(
+                            "SFTP {} on {} failed: {}",
+                            what, self.config, e
+                        )));
+                    }
+                    warn!("SFTP connection to {} lost ({}); reconnecting", self.config, e);
+                    retried = true;
+                }
+                result => return Ok(result),
+            }
+        }
+    }
+
+    /// Like `with_client`, turning every server error into a backend error
+    fn run<T>(
+        &self,
+        what: &str,
+        path: &str,
+        op: impl Fn(&mut Client) -> client::Result<T>,
+    ) -> Result<T> {
+        self.with_client(what, op)?
+            .map_err(|e| self.error(what, path, e))
+    }
+
+    /// Run an operation on an object, mapping "no such file" to `ObjectNotFound`
+    fn run_object<T>(
+        &self,
+        kind: ObjectKind,
+        name: &str,
+        op: impl Fn(&mut Client, &str) -> client::Result<T>,
+    ) -> Result<T> {
+        let path = self.path(kind, name)?;
+        match self.with_client("request", |c| op(c, &path))? {
+            Ok(value) => Ok(value),
+            Err(e) if e.is_not_found() => Err(SnapVaultError::ObjectNotFound {
+                kind,
+                name: name.to_string(),
+            }),
+            Err(e) => Err(self.error("request", &path, e)),
+        }
+    }
+
+    fn error(&self, what: &str, path: &str, e: client::Error) -> SnapVaultError {
+        SnapVaultError::Backend(format!(
+            "SFTP {} {} on {} failed: {}",
+            what, path, self.config.host, e
+        ))
+    }
+
+    /// Create a directory and its missing parents
+    fn mkdir_all(client: &mut Client, path: &str, mode: u32) -> client::Result<()> {
+        match client.stat(path) {
+            Ok(attrs) if attrs.is_dir() => return Ok(()),
+            Ok(_) => {
+                return Err(client::Error::Status {
+                    code: client::SSH_FX_FAILURE,
+                    message: format!("{} is not a directory", path),
+                });
+            }
+            Err(e) if e.is_not_found() => {}
+            Err(e) => return Err(e),
+        }
+        if let Some((parent, _)) = path.rsplit_once('/')
+            && !parent.is_empty()
+        {
+            Self::mkdir_all(client, parent, mode)?;
+        }
+        client.mkdir(path, mode)
+    }
+}
+
+/// Parent directory of a server path
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+impl Backend for SftpBackend {
+    fn location(&self) -> String {
+        self.config.to_string()
+    }
+
+    fn create(&self) -> Result<()> {
+        let root = self.config.path.clone();
+        let dirs: Vec<String> = [ObjectKind::Snapshot, ObjectKind::Pack, ObjectKind::PackIndex]
+            .into_iter()
+            .map(|kind| self.dir(kind))
+            .collect();
+        self.run("create", &root, |client| {
+            // Owner-only permissions, as for local repositories
+            Self::mkdir_all(client, &root, 0o700)?;
+            for dir in &dirs {
+                Self::mkdir_all(client, dir, 0o755)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn put(&self, kind: ObjectKind, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(kind, name)?;
+        // Written next to its final path, then renamed over it
+        let temp = match path.rsplit_once('/') {
+            Some((dir, file)) => format!("{}/{}", dir, temp_file_name(file)),
+            None => temp_file_name(&path),
+        };
+        self.run("write", &path, |client| {
+            let written = match client.write_file(&temp, data, 0o644) {
+                // Prefix and key directories are created on first use
+                Err(e) if e.is_not_found() => {
+                    Self::mkdir_all(client, parent(&path), 0o755)?;
+                    client.write_file(&temp, data, 0o644)
+                }
+                result => result,
+            };
+            let renamed = written.and_then(|()| client.rename(&temp, &path));
+            if renamed.is_err() {
+                let _ = client.remove(&temp);
+            }
+            renamed
+        })
+    }
+
+    fn get(&self, kind: ObjectKind, name: &str) -> Result<Vec<u8>> {
+        self.run_object(kind, name, |client, path| {
+            let size = client.stat(path)?.size.unwrap_or(0);
+            client.read_file(path, 0, size)
+        })
+    }
+
+    fn get_range(&self, kind: ObjectKind, name: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let size = self.size(kind, name)?;
+        check_range(kind, name, offset, length, size)?;
+        self.run_object(kind, name, |client, path| client.read_file(path, offset, length))
+    }
+
+    fn size(&self, kind: ObjectKind, name: &str) -> Result<u64> {
+        let attrs = self.run_object(kind, name, |client, path| client.stat(path))?;
+        attrs.size.ok_or_else(|| {
+            SnapVaultError::Backend(format!(
+                "SFTP server did not report the size of {} {}",
+                kind, name
+            ))
+        })
+    }
+
+    fn exists(&self, kind: ObjectKind, name: &str) -> Result<bool> {
+        match self.run_object(kind, name, |client, path| client.stat(path)) {
+            Ok(attrs) => Ok(attrs.is_file()),
+            Err(SnapVaultError::ObjectNotFound { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list(&self, kind: ObjectKind) -> Result<Vec<String>> {
+        if kind.is_singleton() {
+            let present = self.exists(kind, "")?;
+            return Ok(if present { vec![String::new()] } else { Vec::new() });
+        }
+
+        let dir = self.dir(kind);
+        let sharded = is_sharded(kind);
+        self.run("list", &dir, |client| {
+            let entries = match client.read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.is_not_found() => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+
+            // Sharded kinds keep their objects one directory level down
+            let files = if sharded {
+                let mut files = Vec::new();
+                for (shard, attrs) in entries {
+                    if attrs.is_dir() {
+                        files.extend(client.read_dir(&format!("{}/{}", dir, shard))?);
+                    }
+                }
+                files
+            } else {
+                entries
+            };
+
+            Ok(files
+                .into_iter()
+                .filter(|(_, attrs)| attrs.is_file())
+                .filter_map(|(file_name, _)| object_name(kind, &file_name))
+                .collect())
+        })
+    }
+
+    fn delete(&self, kind: ObjectKind, name: &str) -> Result<()> {
+        let path = self.path(kind, name)?;
+        self.run("delete", &path, |client| {
+            match client.remove(&path) {
+                Ok(()) => {}
+                // Already deleted or never existed
+                Err(e) if e.is_not_found() => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            // Remove the prefix directory once empty; fails harmlessly otherwise
+            if is_sharded(kind) {
+                let _ = client.rmdir(parent(&path));
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::check_backend_contract;
+    use server::StandIn;
+    use tempfile::TempDir;
+
+    fn backend(stand_in: &StandIn, path: &str) -> SftpBackend {
+        let config = SftpConfig::parse(&format!("sftp://backup@server:{}", path)).unwrap();
+        let stand_in = stand_in.clone();
+        SftpBackend::with_connector(config, Box::new(move || stand_in.connect()))
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let config = SftpConfig::parse("sftp://backup@host.example:/srv/repo").unwrap();
+        assert_eq!(config.user.as_deref(), Some("backup"));
+        assert_eq!(config.host, "host.example");
+        assert_eq!(config.port, None);
+        assert_eq!(config.path, "/srv/repo");
+        assert_eq!(config.to_string(), "sftp://backup@host.example/srv/repo");
+
+        let config = SftpConfig::parse("sftp://host:2222/repo/").unwrap();
+        assert_eq!(config.user, None);
+        assert_eq!(config.port, Some(2222));
+        assert_eq!(config.path, "/repo");
+        assert_eq!(config.ssh_args(), ["-p", "2222", "host", "-s", "sftp"]);
+
+        let bad_urls = [
+            "sftp://host",
+            "sftp://host/",
+            "sftp://@host/x",
+            "sftp://:22/x",
+            "sftp://h:x/p",
+            "ftp://h/p",
+        ];
+        for bad in bad_urls {
+            assert!(SftpConfig::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_sftp_backend_contract() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("repo");
+        let stand_in = StandIn::new();
+        check_backend_contract(&backend(&stand_in, root.to_str().unwrap()));
+
+        // Same layout as a local repository
+        assert!(root.join("config.json").is_file());
+        assert!(root.join("snapshots").join("snap-2.json").is_file());
+        assert!(root.join("data").join("index").is_dir());
+    }
+
+    #[test]
+    fn test_put_replaces_without_leftovers() {
+        for stand_in in [StandIn::new(), StandIn::without_extensions()] {
+            let temp = TempDir::new().unwrap();
+            let backend = backend(&stand_in, temp.path().to_str().unwrap());
+            backend.create().unwrap();
+
+            backend.put(ObjectKind::Index, "", b"old").unwrap();
+            backend.put(ObjectKind::Index, "", b"new").unwrap();
+            assert_eq!(backend.get(ObjectKind::Index, "").unwrap(), b"new");
+
+            // Only the renamed file remains; no temporary files are left
+            let stray: Vec<_> = std::fs::read_dir(temp.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.starts_with('.'))
+                .collect();
+            assert!(stray.is_empty(), "{:?}", stray);
+        }
+    }
+
+    #[test]
+    fn test_session_is_reused() {
+        let temp = TempDir::new().unwrap();
+        let stand_in = StandIn::new();
+        let backend = backend(&stand_in, temp.path().to_str().unwrap());
+        backend.create().unwrap();
+
+        // Large enough to need many pipelined reads and writes
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        for i in 0..20u8 {
+            let name = format!("{:02x}", i).repeat(32);
+            backend.put(ObjectKind::Pack, &name, &data).unwrap();
+        }
+        let name = "05".repeat(32);
+        assert_eq!(backend.get(ObjectKind::Pack, &name).unwrap(), data);
+        let range = backend.get_range(ObjectKind::Pack, &name, 70_000, 100_000).unwrap();
+        assert_eq!(range, &data[70_000..170_000]);
+        assert_eq!(backend.list(ObjectKind::Pack).unwrap().len(), 20);
+
+        assert_eq!(stand_in.connections(), 1);
+    }
+
+    #[test]
+    fn test_reconnects_after_disconnect() {
+        let temp = TempDir::new().unwrap();
+        let stand_in = StandIn::new();
+        let backend = backend(&stand_in, temp.path().to_str().unwrap());
+        backend.create().unwrap();
+        backend.put(ObjectKind::Snapshot, "snap", b"data").unwrap();
+
+        stand_in.disconnect_all();
+        assert_eq!(backend.get(ObjectKind::Snapshot, "snap").unwrap(), b"data");
+        assert_eq!(stand_in.connections(), 2);
+    }
+
+    #[test]
+    fn test_repository_over_sftp() {
+        use crate::repository::config::RepoConfig;
+        use crate::repository::snapshot::SnapshotManifest;
+        use crate::repository::Repository;
+        use std::sync::Arc;
+
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("repo");
+        let stand_in = StandIn::new();
+        let sftp = Arc::new(backend(&stand_in, root.to_str().unwrap()));
+
+        let repo = Repository::init_backend(sftp.clone(), RepoConfig::new(), None).unwrap();
+        let manifest = SnapshotManifest::new("snap-1".to_string(), "/src".to_string());
+        repo.save_manifest(&manifest).unwrap();
+        let store = repo.chunk_store();
+        let hash = repo.chunk_hasher().hash(b"remote chunk");
+        store.store(&hash, b"remote chunk").unwrap();
+        store.flush().unwrap();
+
+        // What was written over SFTP is a valid local repository
+        let local = Repository::open(&root).unwrap();
+        assert_eq!(local.snapshot_ids().unwrap(), ["snap-1"]);
+        assert_eq!(local.chunk_store().read(&hash).unwrap(), b"remote chunk");
+        assert_eq!(stand_in.connections(), 1);
+    }
+}
@@ -0,0 +1,287 @@
+//! In-process SFTP server standing in for `ssh host -s sftp` in tests.
+//!
+//! Serves the local filesystem over pipes, one thread per connection. It
+//! deliberately returns short reads and small directory batches so the
+//! client's handling of both is exercised.
+
+use super::client::*;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Largest read served at once, below what the client asks for
+const MAX_READ: usize = 20_000;
+
+/// Directory entries returned per READDIR
+const DIR_BATCH: usize = 3;
+
+const SSH_FX_OP_UNSUPPORTED: u32 = 8;
+
+#[derive(Default)]
+struct Shared {
+    connections: AtomicUsize,
+    /// Connections opened before the current generation are dropped
+    generation: AtomicU64,
+    /// Serve only plain SFTP v3, like servers other than OpenSSH
+    no_extensions: bool,
+}
+
+/// Handle to the stand-in; clones share connection statistics
+#[derive(Clone, Default)]
+pub(crate) struct StandIn {
+    shared: Arc<Shared>,
+}
+
+enum Open {
+    File(File),
+    Dir(Vec<(String, Attrs)>),
+}
+
+impl StandIn {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// A stand-in announcing no protocol extensions
+    pub(crate) fn without_extensions() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                no_extensions: true,
+                ..Shared::default()
+            }),
+        }
+    }
+
+    /// Number of connections opened so far
+    pub(crate) fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+
+    /// Drop every open connection at its next request
+    pub(crate) fn disconnect_all(&self) {
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Open a connection served by a new thread
+    pub(crate) fn connect(&self) -> io::Result<Transport> {
+        let (server_reader, client_writer) = io::pipe()?;
+        let (client_reader, server_writer) = io::pipe()?;
+        self.shared.connections.fetch_add(1, Ordering::SeqCst);
+
+        let shared = self.shared.clone();
+        let generation = shared.generation.load(Ordering::SeqCst);
+        thread::spawn(move || serve(server_reader, server_writer, &shared, generation));
+
+        Ok(Transport {
+            reader: Box::new(client_reader),
+            writer: Box::new(client_writer),
+            child: None,
+        })
+    }
+}
+
+fn serve(mut reader: impl Read, mut writer: impl Write, shared: &Shared, generation: u64) {
+    match read_packet(&mut reader) {
+        Ok((SSH_FXP_INIT, _)) => {}
+        _ => return,
+    }
+    let mut version = Encoder::default();
+    version.u32(3);
+    if !shared.no_extensions {
+        for extension in [POSIX_RENAME_EXTENSION, FSYNC_EXTENSION] {
+            version.string(extension.as_bytes()).string(b"1");
+        }
+    }
+    if write_packet(&mut writer, SSH_FXP_VERSION, &version.0).is_err() {
+        return;
+    }
+
+    let mut handles = HashMap::new();
+    let mut next_handle = 0u32;
+    while let Ok((packet_type, body)) = read_packet(&mut reader) {
+        if shared.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        let mut data = Decoder::new(&body);
+        let Ok(id) = data.u32() else { return };
+        let (response_type, response) =
+            handle(packet_type, &mut data, &mut handles, &mut next_handle)
+                .unwrap_or_else(|e| status(io_status(&e), &e.to_string()));
+
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&response.0);
+        if write_packet(&mut writer, response_type, &packet).is_err() {
+            return;
+        }
+    }
+}
+
+fn io_status(e: &io::Error) -> u32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => SSH_FX_NO_SUCH_FILE,
+        _ => SSH_FX_FAILURE,
+    }
+}
+
+fn status(code: u32, message: &str) -> (u8, Encoder) {
+    let mut body = Encoder::default();
+    body.u32(code).string(message.as_bytes()).string(b"en");
+    (SSH_FXP_STATUS, body)
+}
+
+fn ok() -> io::Result<(u8, Encoder)> {
+    Ok(status(SSH_FX_OK, ""))
+}
+
+fn attrs(metadata: &fs::Metadata) -> Attrs {
+    Attrs {
+        size: Some(metadata.len()),
+        permissions: Some(if metadata.is_dir() { 0o040755 } else { 0o100644 }),
+    }
+}
+
+fn bad_packet() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed request")
+}
+
+fn path(data: &mut Decoder) -> io::Result<String> {
+    let raw = data.string().map_err(|_| bad_packet())?;
+    String::from_utf8(raw.to_vec()).map_err(|_| bad_packet())
+}
+
+fn handle(
+    packet_type: u8,
+    data: &mut Decoder,
+    handles: &mut HashMap<Vec<u8>, Open>,
+    next_handle: &mut u32,
+) -> io::Result<(u8, Encoder)> {
+    let u32 = |data: &mut Decoder| data.u32().map_err(|_| bad_packet());
+    let mut new_handle = |handles: &mut HashMap<Vec<u8>, Open>, open: Open| {
+        *next_handle += 1;
+        let handle = format!("h{}", next_handle).into_bytes();
+        let mut body = Encoder::default();
+        body.string(&handle);
+        handles.insert(handle, open);
+        (SSH_FXP_HANDLE, body)
+    };
+    let open_handle = |data: &mut Decoder| {
+        data.string().map(<[u8]>::to_vec).map_err(|_| bad_packet())
+    };
+
+    match packet_type {
+        SSH_FXP_OPEN => {
+            let path = path(data)?;
+            let flags = u32(data)?;
+            let file = OpenOptions::new()
+                .read(flags & SSH_FXF_READ != 0)
+                .write(flags & SSH_FXF_WRITE != 0)
+                .create(flags & SSH_FXF_CREAT != 0)
+                .truncate(flags & SSH_FXF_TRUNC != 0)
+                .open(path)?;
+            Ok(new_handle(handles, Open::File(file)))
+        }
+        SSH_FXP_CLOSE => {
+            handles.remove(&open_handle(data)?);
+            ok()
+        }
+        SSH_FXP_READ => {
+            let handle = open_handle(data)?;
+            let offset = data.u64().map_err(|_| bad_packet())?;
+            let length = (u32(data)? as usize).min(MAX_READ);
+            let Some(Open::File(file)) = handles.get_mut(&handle) else {
+                return Err(bad_packet());
+            };
+            file.seek(SeekFrom::Start(offset))?;
+            let mut buf = vec![0u8; length];
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Ok(status(SSH_FX_EOF, "end of file"));
+            }
+            let mut body = Encoder::default();
+            body.string(&buf[..n]);
+            Ok((SSH_FXP_DATA, body))
+        }
+        SSH_FXP_WRITE => {
+            let handle = open_handle(data)?;
+            let offset = data.u64().map_err(|_| bad_packet())?;
+            let bytes = data.string().map_err(|_| bad_packet())?;
+            let Some(Open::File(file)) = handles.get_mut(&handle) else {
+                return Err(bad_packet());
+            };
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(bytes)?;
+            ok()
+        }
+        SSH_FXP_OPENDIR => {
+            let mut entries = vec![(".".to_string(), Attrs::default())];
+            for entry in fs::read_dir(path(data)?)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                entries.push((name, attrs(&entry.metadata()?)));
+            }
+            Ok(new_handle(handles, Open::Dir(entries)))
+        }
+        SSH_FXP_READDIR => {
+            let Some(Open::Dir(entries)) = handles.get_mut(&open_handle(data)?) else {
+                return Err(bad_packet());
+            };
+            if entries.is_empty() {
+                return Ok(status(SSH_FX_EOF, ""));
+            }
+            let batch: Vec<_> = entries.drain(..entries.len().min(DIR_BATCH)).collect();
+            let mut body = Encoder::default();
+            body.u32(batch.len() as u32);
+            for (name, attrs) in batch {
+                body.string(name.as_bytes()).string(name.as_bytes());
+                attrs.encode(&mut body);
+            }
+            Ok((SSH_FXP_NAME, body))
+        }
+        SSH_FXP_STAT => {
+            let metadata = fs::metadata(path(data)?)?;
+            let mut body = Encoder::default();
+            attrs(&metadata).encode(&mut body);
+            Ok((SSH_FXP_ATTRS, body))
+        }
+        SSH_FXP_MKDIR => {
+            fs::create_dir(path(data)?)?;
+            ok()
+        }
+        SSH_FXP_REMOVE => {
+            fs::remove_file(path(data)?)?;
+            ok()
+        }
+        SSH_FXP_RMDIR => {
+            fs::remove_dir(path(data)?)?;
+            ok()
+        }
+        SSH_FXP_RENAME => {
+            let (from, to) = (path(data)?, path(data)?);
+            // Plain SFTP renames refuse to replace a file
+            if fs::symlink_metadata(&to).is_ok() {
+                return Ok(status(SSH_FX_FAILURE, "target exists"));
+            }
+            fs::rename(from, to)?;
+            ok()
+        }
+        SSH_FXP_EXTENDED => match path(data)?.as_str() {
+            POSIX_RENAME_EXTENSION => {
+                let (from, to) = (path(data)?, path(data)?);
+                fs::rename(from, to)?;
+                ok()
+            }
+            FSYNC_EXTENSION => {
+                let Some(Open::File(file)) = handles.get_mut(&open_handle(data)?) else {
+                    return Err(bad_packet());
+                };
+                file.sync_all()?;
+                ok()
+            }
+            _ => Ok(status(SSH_FX_OP_UNSUPPORTED, "unsupported extension")),
+        },
+        _ => Ok(status(SSH_FX_OP_UNSUPPORTED, "unsupported request")),
+    }
+}
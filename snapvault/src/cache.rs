@@ -0,0 +1,167 @@
+//! Bounded least-recently-used cache of chunk contents.
+//!
+//! The cache holds decoded chunks up to a total number of bytes. Inserting
+//! beyond that evicts the chunks used least recently; a chunk larger than the
+//! whole cache is not kept at all.
+
+use crate::chunking::ChunkHash;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// LRU cache of chunk contents, bounded by their total size in bytes
+#[derive(Debug, Default)]
+pub struct ChunkCache {
+    /// Most bytes held at once
+    capacity: u64,
+    /// Bytes currently held
+    size: u64,
+    /// Increases with every use, ordering entries by recency
+    clock: u64,
+    entries: HashMap<ChunkHash, CacheEntry>,
+    /// Entries by the time they were last used, oldest first
+    recency: BTreeMap<u64, ChunkHash>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+impl ChunkCache {
+    /// Create an empty cache holding at most `capacity` bytes
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    /// Get a chunk, marking it as the most recently used
+    pub fn get(&mut self, hash: &ChunkHash) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(hash)?;
+        self.recency.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.recency.insert(self.clock, hash.clone());
+        Some(entry.data.clone())
+    }
+
+    /// Add a chunk, evicting the least recently used ones to make room.
+    /// Returns whether the chunk is now cached.
+    pub fn insert(&mut self, hash: ChunkHash, data: Arc<[u8]>) -> bool {
+        self.remove(&hash);
+        let len = data.len() as u64;
+        if len > self.capacity {
+            return false;
+        }
+        while self.size + len > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.data.len() as u64;
+            }
+        }
+
+        self.clock += 1;
+        self.recency.insert(self.clock, hash.clone());
+        self.entries.insert(
+            hash,
+            CacheEntry {
+                data,
+                last_used: self.clock,
+            },
+        );
+        self.size += len;
+        true
+    }
+
+    /// Drop a chunk from the cache
+    pub fn remove(&mut self, hash: &ChunkHash) -> Option<Arc<[u8]>> {
+        let entry = self.entries.remove(hash)?;
+        self.recency.remove(&entry.last_used);
+        self.size -= entry.data.len() as u64;
+        Some(entry.data)
+    }
+
+    /// Whether a chunk is cached, without marking it as used
+    pub fn contains(&self, hash: &ChunkHash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Number of cached chunks
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no chunk is cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size of the cached chunks in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::ChunkHasher;
+
+    fn chunk(fill: u8, len: usize) -> (ChunkHash, Arc<[u8]>) {
+        let data = vec![fill; len];
+        (ChunkHasher::Plain.hash(&data), data.into())
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = ChunkCache::new(30);
+        let (a, a_data) = chunk(1, 10);
+        let (b, b_data) = chunk(2, 10);
+        let (c, c_data) = chunk(3, 10);
+        let (d, d_data) = chunk(4, 10);
+
+        assert!(cache.insert(a.clone(), a_data));
+        assert!(cache.insert(b.clone(), b_data));
+        assert!(cache.insert(c.clone(), c_data));
+        // Using `a` makes `b` the least recently used
+        assert!(cache.get(&a).is_some());
+        assert!(cache.insert(d.clone(), d_data));
+
+        assert!(cache.contains(&a));
+        assert!(!cache.contains(&b));
+        assert!(cache.contains(&c));
+        assert!(cache.contains(&d));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.size(), 30);
+    }
+
+    #[test]
+    fn test_chunk_larger_than_cache_is_not_kept() {
+        let mut cache = ChunkCache::new(10);
+        let (small, small_data) = chunk(1, 5);
+        let (large, large_data) = chunk(2, 11);
+
+        assert!(cache.insert(small.clone(), small_data));
+        assert!(!cache.insert(large.clone(), large_data));
+        assert!(cache.contains(&small));
+        assert!(cache.get(&large).is_none());
+
+        assert_eq!(cache.remove(&small).map(|data| data.len()), Some(5));
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_reinsert_replaces_entry() {
+        let mut cache = ChunkCache::new(20);
+        let (a, a_data) = chunk(1, 10);
+        assert!(cache.insert(a.clone(), a_data.clone()));
+        assert!(cache.insert(a.clone(), a_data));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.size(), 10);
+    }
+}
@@ -1,12 +1,13 @@
 //! File chunking module for content-addressed storage and deduplication.
 //!
-//! This module provides functionality to split files into fixed-size chunks,
-//! compute their content hashes, and enable deduplication across snapshots.
+//! This module provides functionality to split files into fixed-size or
+//! content-defined chunks, compute their content hashes, and enable
+//! deduplication across snapshots.
 
 use crate::error::{Result, SnapVaultError};
 use blake3::Hasher;
 use std::fs::File;
-use std::io::{Read, BufReader};
+use std::io::Read;
 use std::path::Path;
 
 /// Default chunk size: 1 MiB
@@ -77,7 +78,7 @@ impl ChunkHash {
 
     /// Convert the hash to a hexadecimal string
     pub fn to_hex(&self) -> String {
-        hex::encode(&self.0)
+        hex::encode(self.0)
     }
 
     /// Parse a hash from a hexadecimal string
@@ -110,9 +111,153 @@ impl std::fmt::Display for ChunkHash {
     }
 }
 
-/// Fixed-size chunker that splits files into equal-sized chunks
+/// Computes chunk IDs from chunk contents
+///
+/// Unencrypted repositories address chunks by their plain BLAKE3 hash.
+/// Encrypted repositories use keyed BLAKE3 so chunk names don't reveal
+/// plaintext content hashes.
+#[derive(Clone, Default)]
+pub enum ChunkHasher {
+    #[default]
+    Plain,
+    Keyed([u8; 32]),
+}
+
+impl std::fmt::Debug for ChunkHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkHasher::Plain => f.write_str("Plain"),
+            ChunkHasher::Keyed(_) => f.write_str("Keyed(..)"),
+        }
+    }
+}
+
+impl ChunkHasher {
+    /// Hash chunk contents into a chunk ID
+    pub fn hash(&self, data: &[u8]) -> ChunkHash {
+        match self {
+            ChunkHasher::Plain => hash_bytes(data),
+            ChunkHasher::Keyed(key) => ChunkHash(blake3::keyed_hash(key, data).into()),
+        }
+    }
+}
+
+/// Chunking algorithm and parameters used by a repository.
+///
+/// This is recorded in the repository config so that every backup into the
+/// same repository cuts chunk boundaries the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum ChunkerConfig {
+    /// Fixed-size blocks of `chunk_size` bytes
+    Fixed { chunk_size: usize },
+    /// Content-defined chunking using a FastCDC gear hash
+    FastCdc {
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    },
+}
+
+impl ChunkerConfig {
+    /// Fixed-size chunking with the default chunk size
+    pub fn fixed() -> Self {
+        ChunkerConfig::Fixed {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Content-defined chunking with the default min/avg/max sizes
+    pub fn fastcdc() -> Self {
+        ChunkerConfig::FastCdc {
+            min_size: MIN_CHUNK_SIZE,
+            avg_size: DEFAULT_CHUNK_SIZE,
+            max_size: MAX_CHUNK_SIZE,
+        }
+    }
+
+    /// Validate the chunk size parameters
+    pub fn validate(&self) -> Result<()> {
+        let in_range = |size: usize| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size);
+        match *self {
+            ChunkerConfig::Fixed { chunk_size } => {
+                if !in_range(chunk_size) {
+                    return Err(SnapVaultError::InvalidChunkerConfig(format!(
+                        "chunk size {} outside {}..={}",
+                        chunk_size, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+                    )));
+                }
+            }
+            ChunkerConfig::FastCdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => {
+                if !(in_range(min_size) && in_range(avg_size) && in_range(max_size)) {
+                    return Err(SnapVaultError::InvalidChunkerConfig(format!(
+                        "chunk sizes must be within {}..={}",
+                        MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+                    )));
+                }
+                if !(min_size <= avg_size && avg_size <= max_size) {
+                    return Err(SnapVaultError::InvalidChunkerConfig(format!(
+                        "expected min <= avg <= max, got {}/{}/{}",
+                        min_size, avg_size, max_size
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Short human-readable name of the algorithm
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChunkerConfig::Fixed { .. } => "fixed",
+            ChunkerConfig::FastCdc { .. } => "fastcdc",
+        }
+    }
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self::fixed()
+    }
+}
+
+/// Gear table for the FastCDC rolling hash.
+///
+/// Generated with splitmix64 from a fixed seed. This table is part of the
+/// on-disk format: changing it moves every content-defined chunk boundary.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5356_4348_554e_4b31; // "SVCHUNK1"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Build a mask selecting the top `bits` bits of the gear hash
+fn gear_mask(bits: u32) -> u64 {
+    let bits = bits.clamp(1, 63);
+    !0u64 << (64 - bits)
+}
+
+/// Splits files into chunks, either fixed-size or content-defined
 pub struct Chunker {
-    chunk_size: usize,
+    config: ChunkerConfig,
+    hasher: ChunkHasher,
+    /// Stricter mask used before the average size is reached (FastCDC only)
+    mask_small: u64,
+    /// Looser mask used after the average size is reached (FastCDC only)
+    mask_large: u64,
 }
 
 impl Chunker {
@@ -121,78 +266,215 @@ impl Chunker {
         Self::with_size(DEFAULT_CHUNK_SIZE)
     }
 
-    /// Create a new chunker with a custom chunk size
+    /// Create a new fixed-size chunker with a custom chunk size
     pub fn with_size(chunk_size: usize) -> Self {
         // Validate chunk size
         let chunk_size = chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
-        Self { chunk_size }
+        Self::from_config(&ChunkerConfig::Fixed { chunk_size })
+    }
+
+    /// Create a chunker from a repository's chunker configuration
+    pub fn from_config(config: &ChunkerConfig) -> Self {
+        let (mask_small, mask_large) = match *config {
+            ChunkerConfig::Fixed { .. } => (0, 0),
+            ChunkerConfig::FastCdc { avg_size, .. } => {
+                // Normalized chunking: one bit harder before the average
+                // size, one bit easier after it
+                let bits = avg_size.max(2).ilog2();
+                (gear_mask(bits + 1), gear_mask(bits - 1))
+            }
+        };
+        Self {
+            config: *config,
+            hasher: ChunkHasher::Plain,
+            mask_small,
+            mask_large,
+        }
+    }
+
+    /// Set the hasher used to compute chunk IDs
+    pub fn with_hasher(mut self, hasher: ChunkHasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Get the chunker configuration
+    pub fn config(&self) -> &ChunkerConfig {
+        &self.config
     }
 
-    /// Get the chunk size
+    /// Get the target chunk size (the average size for content-defined chunking)
     pub fn chunk_size(&self) -> usize {
-        self.chunk_size
+        match self.config {
+            ChunkerConfig::Fixed { chunk_size } => chunk_size,
+            ChunkerConfig::FastCdc { avg_size, .. } => avg_size,
+        }
     }
 
-    /// Chunk a file and return a list of chunks with their hashes
-    pub fn chunk_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<Chunk>> {
-        let path = path.as_ref();
-        let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
-        
-        let mut chunks = Vec::new();
-        let mut buffer = vec![0u8; self.chunk_size];
-        let mut offset = 0u64;
-
-        loop {
-            // Read a chunk
-            let mut total_read = 0;
-            while total_read < self.chunk_size {
-                match reader.read(&mut buffer[total_read..]) {
-                    Ok(0) => break, // EOF
-                    Ok(n) => total_read += n,
-                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(e.into()),
-                }
-            }
+    /// Largest chunk this chunker can produce
+    fn max_chunk_size(&self) -> usize {
+        match self.config {
+            ChunkerConfig::Fixed { chunk_size } => chunk_size,
+            ChunkerConfig::FastCdc { max_size, .. } => max_size,
+        }
+    }
 
-            if total_read == 0 {
-                break; // EOF
+    /// Find the length of the first chunk in `data`.
+    ///
+    /// `data` must either hold at least `max_chunk_size()` bytes or be the
+    /// final bytes of the input.
+    fn cut_point(&self, data: &[u8]) -> usize {
+        match self.config {
+            ChunkerConfig::Fixed { chunk_size } => data.len().min(chunk_size),
+            ChunkerConfig::FastCdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => {
+                if data.len() <= min_size {
+                    return data.len();
+                }
+                let end = data.len().min(max_size);
+                let normal = end.min(avg_size);
+
+                let mut hash = 0u64;
+                let mut i = min_size;
+                while i < normal {
+                    hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+                    if hash & self.mask_small == 0 {
+                        return i + 1;
+                    }
+                    i += 1;
+                }
+                while i < end {
+                    hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+                    if hash & self.mask_large == 0 {
+                        return i + 1;
+                    }
+                    i += 1;
+                }
+                end
             }
+        }
+    }
 
-            // Hash the chunk
-            let hash = hash_bytes(&buffer[..total_read]);
-            
-            chunks.push(Chunk {
-                hash,
-                size: total_read,
-                offset,
-            });
+    /// Chunk a file and return a list of chunks with their hashes
+    pub fn chunk_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<Chunk>> {
+        let file = File::open(path.as_ref())?;
+        self.stream(file)
+            .map(|item| item.map(|(chunk, _)| chunk))
+            .collect()
+    }
 
-            offset += total_read as u64;
+    /// Chunk a stream, yielding each chunk with its data as it is cut.
+    /// The stream is read once and at most one window of it is buffered.
+    pub fn stream<R: Read>(&self, reader: R) -> ChunkStream<'_, R> {
+        ChunkStream {
+            chunker: self,
+            reader,
+            buffer: Vec::with_capacity(self.max_chunk_size()),
+            offset: 0,
+            eof: false,
+            content_hasher: Hasher::new(),
         }
-
-        Ok(chunks)
     }
 
     /// Chunk data from a byte slice (useful for testing)
     pub fn chunk_bytes(&self, data: &[u8]) -> Vec<Chunk> {
         let mut chunks = Vec::new();
-        let mut offset = 0u64;
+        let mut offset = 0usize;
 
-        for chunk_data in data.chunks(self.chunk_size) {
-            let hash = hash_bytes(chunk_data);
+        while offset < data.len() {
+            let len = self.cut_point(&data[offset..]);
+            let chunk_data = &data[offset..offset + len];
             chunks.push(Chunk {
-                hash,
-                size: chunk_data.len(),
-                offset,
+                hash: self.hasher.hash(chunk_data),
+                size: len,
+                offset: offset as u64,
             });
-            offset += chunk_data.len() as u64;
+            offset += len;
         }
 
         chunks
     }
 }
 
+/// Chunks of a stream with their data, produced by `Chunker::stream`.
+///
+/// The whole stream is hashed while it is read, so the content hash of a
+/// file is available once its chunks have been consumed without reading the
+/// file a second time.
+pub struct ChunkStream<'a, R> {
+    chunker: &'a Chunker,
+    reader: R,
+    buffer: Vec<u8>,
+    offset: u64,
+    eof: bool,
+    content_hasher: Hasher,
+}
+
+impl<R: Read> ChunkStream<'_, R> {
+    /// Top the buffer up to a full window so cut points never depend on how
+    /// the underlying reads happened to be split
+    fn fill(&mut self) -> std::io::Result<()> {
+        let window = self.chunker.max_chunk_size();
+        while !self.eof && self.buffer.len() < window {
+            let filled = self.buffer.len();
+            self.buffer.resize(window, 0);
+            match self.reader.read(&mut self.buffer[filled..]) {
+                Ok(0) => {
+                    self.buffer.truncate(filled);
+                    self.eof = true;
+                }
+                Ok(n) => {
+                    self.content_hasher.update(&self.buffer[filled..filled + n]);
+                    self.buffer.truncate(filled + n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    self.buffer.truncate(filled);
+                }
+                Err(e) => {
+                    self.buffer.truncate(filled);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Blake3 hash of everything read so far, the same as `hash_file` once
+    /// every chunk has been consumed
+    pub fn content_hash(&self) -> ChunkHash {
+        ChunkHash(self.content_hasher.finalize().into())
+    }
+}
+
+impl<R: Read> Iterator for ChunkStream<'_, R> {
+    type Item = Result<(Chunk, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            // Nothing more is yielded after a read error
+            self.eof = true;
+            self.buffer.clear();
+            return Some(Err(e.into()));
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        let len = self.chunker.cut_point(&self.buffer);
+        let data: Vec<u8> = self.buffer.drain(..len).collect();
+        let chunk = Chunk {
+            hash: self.chunker.hasher.hash(&data),
+            size: len,
+            offset: self.offset,
+        };
+        self.offset += len as u64;
+        Some(Ok((chunk, data)))
+    }
+}
+
 impl Default for Chunker {
     fn default() -> Self {
         Self::new()
@@ -225,6 +507,7 @@ pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<ChunkHash> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
 
     #[test]
     fn test_chunk_hash_hex_roundtrip() {
@@ -282,7 +565,7 @@ mod tests {
 
     #[test]
     fn test_chunk_bytes_single_chunk() {
-        let chunker = Chunker::with_size(1024);
+        let chunker = Chunker::with_size(MIN_CHUNK_SIZE);
         let data = vec![42u8; 512]; // Smaller than chunk size
         let chunks = chunker.chunk_bytes(&data);
         
@@ -293,27 +576,28 @@ mod tests {
 
     #[test]
     fn test_chunk_bytes_multiple_chunks() {
-        let chunker = Chunker::with_size(1024);
-        let data = vec![42u8; 2500]; // Will create 3 chunks: 1024 + 1024 + 452
+        let chunker = Chunker::with_size(MIN_CHUNK_SIZE);
+        // Will create 3 chunks: MIN + MIN + 452
+        let data = vec![42u8; 2 * MIN_CHUNK_SIZE + 452];
         let chunks = chunker.chunk_bytes(&data);
         
         assert_eq!(chunks.len(), 3);
-        assert_eq!(chunks[0].size, 1024);
+        assert_eq!(chunks[0].size, MIN_CHUNK_SIZE);
         assert_eq!(chunks[0].offset, 0);
-        assert_eq!(chunks[1].size, 1024);
-        assert_eq!(chunks[1].offset, 1024);
+        assert_eq!(chunks[1].size, MIN_CHUNK_SIZE);
+        assert_eq!(chunks[1].offset, MIN_CHUNK_SIZE as u64);
         assert_eq!(chunks[2].size, 452);
-        assert_eq!(chunks[2].offset, 2048);
+        assert_eq!(chunks[2].offset, 2 * MIN_CHUNK_SIZE as u64);
     }
 
     #[test]
     fn test_chunk_bytes_deduplication() {
-        let chunker = Chunker::with_size(1024);
+        let chunker = Chunker::with_size(MIN_CHUNK_SIZE);
         
         // Create data with repeating pattern
         let mut data = Vec::new();
-        data.extend_from_slice(&[1u8; 1024]); // First chunk
-        data.extend_from_slice(&[1u8; 1024]); // Identical second chunk
+        data.extend_from_slice(&[1u8; MIN_CHUNK_SIZE]); // First chunk
+        data.extend_from_slice(&[1u8; MIN_CHUNK_SIZE]); // Identical second chunk
         
         let chunks = chunker.chunk_bytes(&data);
         
@@ -324,11 +608,11 @@ mod tests {
 
     #[test]
     fn test_chunk_bytes_different_content() {
-        let chunker = Chunker::with_size(1024);
+        let chunker = Chunker::with_size(MIN_CHUNK_SIZE);
         
         let mut data = Vec::new();
-        data.extend_from_slice(&[1u8; 1024]); // First chunk
-        data.extend_from_slice(&[2u8; 1024]); // Different second chunk
+        data.extend_from_slice(&[1u8; MIN_CHUNK_SIZE]); // First chunk
+        data.extend_from_slice(&[2u8; MIN_CHUNK_SIZE]); // Different second chunk
         
         let chunks = chunker.chunk_bytes(&data);
         
@@ -344,18 +628,18 @@ mod tests {
 
         // Create a temporary file
         let mut temp_file = NamedTempFile::new()?;
-        let data = vec![42u8; 2500];
+        let data = vec![42u8; 2 * MIN_CHUNK_SIZE + 452];
         temp_file.write_all(&data)?;
         temp_file.flush()?;
 
         // Chunk the file
-        let chunker = Chunker::with_size(1024);
+        let chunker = Chunker::with_size(MIN_CHUNK_SIZE);
         let chunks = chunker.chunk_file(temp_file.path())?;
 
         // Verify chunks
         assert_eq!(chunks.len(), 3);
-        assert_eq!(chunks[0].size, 1024);
-        assert_eq!(chunks[1].size, 1024);
+        assert_eq!(chunks[0].size, MIN_CHUNK_SIZE);
+        assert_eq!(chunks[1].size, MIN_CHUNK_SIZE);
         assert_eq!(chunks[2].size, 452);
 
         Ok(())
@@ -373,6 +657,163 @@ mod tests {
         Ok(())
     }
 
+    /// Deterministic pseudo-random test data
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn small_cdc() -> Chunker {
+        Chunker::from_config(&ChunkerConfig::FastCdc {
+            min_size: MIN_CHUNK_SIZE,
+            avg_size: 2 * MIN_CHUNK_SIZE,
+            max_size: 8 * MIN_CHUNK_SIZE,
+        })
+    }
+
+    #[test]
+    fn test_fastcdc_respects_size_bounds() {
+        let chunker = small_cdc();
+        let data = pseudo_random(4 * 1024 * 1024, 1);
+        let chunks = chunker.chunk_bytes(&data);
+
+        assert!(chunks.len() > 1);
+        let total: usize = chunks.iter().map(|c| c.size).sum();
+        assert_eq!(total, data.len());
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.size >= MIN_CHUNK_SIZE);
+            assert!(chunk.size <= 8 * MIN_CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_fastcdc_boundaries_survive_insertion() {
+        let chunker = small_cdc();
+        let original = pseudo_random(2 * 1024 * 1024, 7);
+        let mut shifted = vec![0xffu8];
+        shifted.extend_from_slice(&original);
+
+        let before: HashSet<ChunkHash> =
+            chunker.chunk_bytes(&original).into_iter().map(|c| c.hash).collect();
+        let after: Vec<ChunkHash> =
+            chunker.chunk_bytes(&shifted).into_iter().map(|c| c.hash).collect();
+
+        // Only the chunk containing the inserted byte should change
+        let shared = after.iter().filter(|h| before.contains(h)).count();
+        assert!(shared >= after.len() - 2, "shared {} of {}", shared, after.len());
+    }
+
+    #[test]
+    fn test_fastcdc_file_matches_bytes() -> Result<()> {
+        use std::io::Write;
+        use tempfile::NamedTempFile;
+
+        let data = pseudo_random(3 * 1024 * 1024 + 17, 3);
+        let mut temp_file = NamedTempFile::new()?;
+        temp_file.write_all(&data)?;
+        temp_file.flush()?;
+
+        let chunker = small_cdc();
+        assert_eq!(chunker.chunk_file(temp_file.path())?, chunker.chunk_bytes(&data));
+        Ok(())
+    }
+
+    /// Reader returning at most `step` bytes per read
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.step).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_stream_yields_chunk_data_and_content_hash() -> Result<()> {
+        let data = pseudo_random(3 * 1024 * 1024 + 5, 11);
+        let chunker = small_cdc();
+        let mut stream = chunker.stream(Trickle {
+            data: &data,
+            step: 4093,
+        });
+
+        let mut chunks = Vec::new();
+        let mut rebuilt = Vec::new();
+        for item in &mut stream {
+            let (chunk, chunk_data) = item?;
+            assert_eq!(chunk.size, chunk_data.len());
+            assert_eq!(chunk.offset as usize, rebuilt.len());
+            rebuilt.extend_from_slice(&chunk_data);
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks, chunker.chunk_bytes(&data));
+        assert_eq!(rebuilt, data);
+        assert_eq!(stream.content_hash(), hash_bytes(&data));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_stops_after_read_error() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk on fire"))
+            }
+        }
+
+        let chunker = Chunker::new();
+        let mut stream = chunker.stream(Failing);
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn test_keyed_hasher() {
+        let data = b"hello world";
+        let keyed = ChunkHasher::Keyed([7u8; 32]);
+
+        assert_eq!(ChunkHasher::Plain.hash(data), hash_bytes(data));
+        assert_ne!(keyed.hash(data), hash_bytes(data));
+        assert_ne!(keyed.hash(data), ChunkHasher::Keyed([8u8; 32]).hash(data));
+
+        let chunks = Chunker::new().with_hasher(keyed.clone()).chunk_bytes(data);
+        assert_eq!(chunks[0].hash, keyed.hash(data));
+    }
+
+    #[test]
+    fn test_chunker_config_validate() {
+        assert!(ChunkerConfig::fixed().validate().is_ok());
+        assert!(ChunkerConfig::fastcdc().validate().is_ok());
+        assert!(ChunkerConfig::Fixed { chunk_size: 1024 }.validate().is_err());
+        assert!(ChunkerConfig::FastCdc {
+            min_size: 2 * MIN_CHUNK_SIZE,
+            avg_size: MIN_CHUNK_SIZE,
+            max_size: MAX_CHUNK_SIZE,
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_chunker_config_serde() {
+        let json = serde_json::to_string(&ChunkerConfig::fastcdc()).unwrap();
+        assert!(json.contains("\"algorithm\":\"fastcdc\""));
+        let parsed: ChunkerConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, ChunkerConfig::fastcdc());
+    }
+
     #[test]
     fn test_hash_file() -> Result<()> {
         use std::io::Write;
@@ -1,6 +1,18 @@
-use clap::{Parser, Subcommand};
+use crate::chunking::ChunkerConfig;
+use crate::commands::check::Percentage;
+use crate::commands::forget::GroupBy;
+use crate::commands::restore::DEFAULT_CACHE_SIZE;
+use crate::compression::{CompressionConfig, DEFAULT_ZSTD_LEVEL};
+use crate::exclude::ByteSize;
+use crate::pack::DEFAULT_PACK_SIZE;
+use crate::repository::locator::{RepoLocator, REPOSITORY_ENV};
+use crate::retention::RetentionPeriod;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
+/// Bytes per MiB, the unit of `--pack-size` and `--cache-size`
+pub const MIB: u64 = 1024 * 1024;
+
 #[derive(Parser)]
 #[command(name = "snapvault")]
 #[command(version, about, long_about = None)]
@@ -13,30 +25,89 @@ pub struct Cli {
 pub enum Commands {
     /// Initialize a new backup repository
     Init {
-        /// Path where the repository will be created
+        /// Location where the repository will be created
+        /// (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Chunking algorithm used for all backups into this repository
+        #[arg(long, value_enum, default_value_t = ChunkerKind::Fixed)]
+        chunker: ChunkerKind,
+        /// Compression codec for stored chunks
+        #[arg(long, value_enum, default_value_t = CompressionKind::Zstd)]
+        compression: CompressionKind,
+        /// Compression level (zstd only, 1-22)
+        #[arg(long, default_value_t = DEFAULT_ZSTD_LEVEL)]
+        compression_level: i32,
+        /// Target size of pack files in MiB (1-128)
+        #[arg(long, default_value_t = DEFAULT_PACK_SIZE / MIB)]
+        pack_size: u64,
+        /// Encrypt chunks, manifests and the index with a passphrase
+        /// (read from SNAPVAULT_PASSWORD or prompted for)
         #[arg(long)]
-        repo: PathBuf,
+        encrypt: bool,
     },
     /// Create a backup snapshot (basic: full copy + manifest)
     Backup {
         /// Source directory to backup
         #[arg(long)]
         source: PathBuf,
-        /// Repository path
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Tag the snapshot (repeatable), e.g. to group snapshots in `forget`
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Snapshot to take unchanged files from (default: the latest
+        /// snapshot of the same source on this host)
+        #[arg(long, value_name = "SNAPSHOT_ID", conflicts_with = "force")]
+        parent: Option<String>,
+        /// Read every file, even those unchanged since the parent snapshot
+        #[arg(long)]
+        force: bool,
+        /// Number of files read, chunked and stored at once (default: one
+        /// per CPU)
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+        threads: Option<u16>,
+        /// Leave out paths matching a gitignore-style pattern (repeatable);
+        /// `!PATTERN` takes back a path an earlier pattern left out
+        #[arg(long = "exclude", value_name = "PATTERN")]
+        excludes: Vec<String>,
+        /// Like --exclude, ignoring case (repeatable)
+        #[arg(long = "iexclude", value_name = "PATTERN")]
+        iexcludes: Vec<String>,
+        /// Read --exclude patterns from a file, one per line (repeatable)
+        #[arg(long = "exclude-file", value_name = "FILE")]
+        exclude_files: Vec<PathBuf>,
+        /// Leave out directories containing a file with this name
+        /// (repeatable), e.g. CACHEDIR.TAG
+        #[arg(long = "exclude-if-present", value_name = "FILENAME")]
+        exclude_if_present: Vec<String>,
+        /// Leave out files larger than this size, e.g. 500M or 2G
+        #[arg(long, value_name = "SIZE")]
+        exclude_larger_than: Option<ByteSize>,
+        /// Leave out file systems mounted below the source
         #[arg(long)]
-        repo: PathBuf,
+        one_file_system: bool,
+        /// Only record extended attributes matching this pattern
+        /// (repeatable), e.g. `user.*`; all are recorded by default
+        #[arg(long = "xattr-include", value_name = "PATTERN")]
+        xattr_includes: Vec<String>,
+        /// Do not record extended attributes matching this pattern
+        /// (repeatable), e.g. `security.selinux`
+        #[arg(long = "xattr-exclude", value_name = "PATTERN")]
+        xattr_excludes: Vec<String>,
     },
     /// List all snapshots in the repository
     List {
-        /// Repository path
-        #[arg(long)]
-        repo: PathBuf,
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
     },
     /// Delete a snapshot or all snapshots from the repository
     Delete {
-        /// Repository path
-        #[arg(long)]
-        repo: PathBuf,
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
         /// Snapshot ID to delete
         #[arg(long)]
         snapshot: Option<String>,
@@ -52,8 +123,181 @@ pub enum Commands {
         /// Snapshot ID to restore (latest if not provided)
         #[arg(long)]
         snapshot: Option<String>,
-        /// Repository path
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Number of files written at once (default: one per CPU)
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+        threads: Option<u16>,
+        /// Memory in MiB for chunks that more files need, so that each chunk
+        /// is read from the repository only once
+        #[arg(long, value_name = "MIB", default_value_t = DEFAULT_CACHE_SIZE / MIB)]
+        cache_size: u64,
+        /// Restore owners by their recorded user and group IDs rather than
+        /// names (owners are otherwise only restored when running as root)
+        #[arg(long)]
+        numeric_owner: bool,
+        /// Leave chunks of zeros unwritten, as holes, even in files that
+        /// were not sparse (recorded holes are always kept)
         #[arg(long)]
-        repo: PathBuf,
+        sparse: bool,
+        /// Only restore extended attributes matching this pattern
+        /// (repeatable), e.g. `user.*`; all are restored by default
+        #[arg(long = "xattr-include", value_name = "PATTERN")]
+        xattr_includes: Vec<String>,
+        /// Do not restore extended attributes matching this pattern
+        /// (repeatable), e.g. `security.selinux`
+        #[arg(long = "xattr-exclude", value_name = "PATTERN")]
+        xattr_excludes: Vec<String>,
     },
+    /// Remove snapshots according to a retention policy
+    Forget {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Keep the newest N snapshots
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_last: usize,
+        /// Keep the newest snapshot of each of the last N hours
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_hourly: usize,
+        /// Keep the newest snapshot of each of the last N days
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_daily: usize,
+        /// Keep the newest snapshot of each of the last N weeks
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_weekly: usize,
+        /// Keep the newest snapshot of each of the last N months
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_monthly: usize,
+        /// Keep the newest snapshot of each of the last N years
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_yearly: usize,
+        /// Keep every snapshot taken within this period before the newest
+        /// one, e.g. 7d, 2w or 1y6m
+        #[arg(long, value_name = "PERIOD")]
+        keep_within: Option<RetentionPeriod>,
+        /// Fields separating groups the policy is applied to independently
+        #[arg(long, value_name = "FIELDS", default_value = "source,host,tags")]
+        group_by: GroupBy,
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Delete chunks the removed snapshots leave unreferenced
+        #[arg(long)]
+        prune: bool,
+    },
+    /// Delete chunks that no snapshot references
+    Prune {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Only report what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Verify the integrity of the repository
+    Check {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Read back every chunk and verify its content hash
+        #[arg(long, conflicts_with = "read_data_subset")]
+        read_data: bool,
+        /// Read back a random sample of chunks, e.g. 10%
+        #[arg(long, value_name = "PERCENT")]
+        read_data_subset: Option<Percentage>,
+        /// Replace an index.json that disagrees with the snapshots by one
+        /// rebuilt from them
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Remove stale locks left behind by crashed or killed processes
+    Unlock {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Remove every lock, including locks of processes still running
+        #[arg(long)]
+        remove_all: bool,
+    },
+    /// Manage passphrase key slots of an encrypted repository
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommands {
+    /// Add a key slot with a new passphrase (SNAPVAULT_NEW_PASSWORD or prompt)
+    Add {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// Label describing who or what uses this key
+        #[arg(long)]
+        label: Option<String>,
+    },
+    /// List key slots (the key used to unlock is marked with *)
+    List {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+    },
+    /// Remove a key slot, revoking its passphrase
+    Remove {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+        /// ID of the key to remove
+        #[arg(long)]
+        key: String,
+    },
+    /// Change the passphrase of the key used to unlock
+    Passwd {
+        /// Repository location (path, local:, sftp://, s3:// or rest:// URL)
+        #[arg(long, env = REPOSITORY_ENV)]
+        repo: RepoLocator,
+    },
+}
+
+/// Chunking algorithms selectable at `init`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChunkerKind {
+    /// Fixed-size 1 MiB chunks
+    Fixed,
+    /// Content-defined chunking (FastCDC), robust to insertions and shifts
+    Fastcdc,
+}
+
+impl From<ChunkerKind> for ChunkerConfig {
+    fn from(kind: ChunkerKind) -> Self {
+        match kind {
+            ChunkerKind::Fixed => ChunkerConfig::fixed(),
+            ChunkerKind::Fastcdc => ChunkerConfig::fastcdc(),
+        }
+    }
+}
+
+/// Compression codecs selectable at `init`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CompressionKind {
+    /// Store chunks uncompressed
+    None,
+    /// Zstandard (good ratio, configurable level)
+    Zstd,
+    /// LZ4 (very fast, lower ratio)
+    Lz4,
+}
+
+impl CompressionKind {
+    /// Build the compression config, applying `level` where the codec supports it
+    pub fn with_level(self, level: i32) -> CompressionConfig {
+        match self {
+            CompressionKind::None => CompressionConfig::None,
+            CompressionKind::Zstd => CompressionConfig::Zstd { level },
+            CompressionKind::Lz4 => CompressionConfig::Lz4,
+        }
+    }
 }
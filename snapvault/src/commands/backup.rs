@@ -1,20 +1,56 @@
-use crate::chunking::{hash_file, Chunker};
+use crate::chunking::Chunker;
 use crate::error::{Result, SnapVaultError};
-use crate::index::ChunkIndex;
-use crate::repository::snapshot::{FileRecord, SnapshotManifest};
+use crate::exclude::{ExcludeRules, Excluder, IgnoreStack};
+use crate::metadata::{read_metadata, Accounts};
+use crate::repository::lock::LockMode;
+use crate::repository::snapshot::{EntryKind, FileRecord, SnapshotManifest};
+use crate::repository::tree::write_trees;
+use crate::repository::locator::RepoLocator;
 use crate::repository::Repository;
 use crate::storage::ChunkStore;
-use crate::utils::SNAPSHOT_UUID_LEN;
-use log::{info, warn};
-use std::collections::HashSet;
+use crate::sparse::{find_holes, DataReader};
+use crate::utils::{hostname, SNAPSHOT_UUID_LEN};
+use crate::xattrs::{read_xattrs, XattrFilter};
+use log::{debug, info, warn};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::Read;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::SystemTime;
 use uuid::Uuid;
 use walkdir::WalkDir;
 
-pub fn backup(source_path: &Path, repo_path: &Path) -> Result<()> {
+/// Options for taking a backup
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    /// Labels recorded in the snapshot, used to group snapshots in `forget`
+    pub tags: Vec<String>,
+    /// Snapshot to take unchanged files from. If unset, the latest snapshot
+    /// of the same source on this host is used.
+    pub parent: Option<String>,
+    /// Read every file, even those unchanged since the parent snapshot
+    pub force: bool,
+    /// Number of files read, chunked and stored at once (one per CPU if unset)
+    pub threads: Option<usize>,
+    /// Paths to leave out, recorded in the snapshot
+    pub exclude: ExcludeRules,
+    /// Which extended attributes to record
+    pub xattrs: XattrFilter,
+}
+
+pub fn backup(source_path: &Path, repo_location: &RepoLocator) -> Result<()> {
+    backup_with_options(source_path, repo_location, &BackupOptions::default())
+}
+
+pub fn backup_with_options(
+    source_path: &Path,
+    repo_location: &RepoLocator,
+    options: &BackupOptions,
+) -> Result<()> {
     // Validate source
     if !source_path.exists() {
         return Err(SnapVaultError::SourceNotFound(source_path.to_path_buf()));
@@ -25,15 +61,16 @@ pub fn backup(source_path: &Path, repo_path: &Path) -> Result<()> {
         ));
     }
 
-    let repo = Repository::open(repo_path)?;
+    // Fail on invalid patterns before touching the repository
+    let excluder = Excluder::new(source_path, &options.exclude)?;
+
+    let repo = Repository::open_at(repo_location, None)?;
+    let _lock = repo.lock(LockMode::Shared)?;
 
     // Initialize chunk storage
-    let chunk_store = ChunkStore::new(repo.chunks_dir());
+    let chunk_store = repo.chunk_store();
     chunk_store.init()?;
 
-    // Load chunk index
-    let mut index = ChunkIndex::load(repo.index_path())?;
-
     let snapshot_id = format!(
         "{}-{}",
         chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
@@ -41,13 +78,44 @@ pub fn backup(source_path: &Path, repo_path: &Path) -> Result<()> {
     );
 
     info!(
-        "Starting chunked backup: source={}, repo={}, snapshot_id={}",
+        "Starting chunked backup: source={}, repo={}, snapshot_id={}, chunker={}",
         source_path.display(),
-        repo_path.display(),
-        snapshot_id
+        repo_location,
+        snapshot_id,
+        repo.config().chunker.name()
     );
 
-    let backup_result = perform_chunked_backup(source_path, &chunk_store);
+    let parent = if options.force {
+        None
+    } else {
+        match &options.parent {
+            Some(id) => Some(repo.load_manifest(id, &chunk_store)?),
+            None => find_parent(&repo, &chunk_store, source_path),
+        }
+    };
+    let parent_files: HashMap<String, FileRecord> = match &parent {
+        Some(parent) => {
+            info!("Using parent snapshot {}", parent.snapshot_id);
+            parent
+                .files
+                .iter()
+                .map(|file| (file.rel_path.clone(), file.clone()))
+                .collect()
+        }
+        None => HashMap::new(),
+    };
+
+    let chunker = repo.chunker();
+    let threads = options.threads.unwrap_or_else(default_threads);
+    let backup_result = perform_chunked_backup(
+        source_path,
+        &chunk_store,
+        &chunker,
+        &excluder,
+        &options.xattrs,
+        &parent_files,
+        threads,
+    );
 
     let (mut manifest, stats) = match backup_result {
         Ok(result) => result,
@@ -57,33 +125,45 @@ pub fn backup(source_path: &Path, repo_path: &Path) -> Result<()> {
         }
     };
 
+    // Store the directory structure, then write the last pack and the pack
+    // index before anything refers to them
+    let trees = write_trees(&chunk_store, &manifest.files)?;
+    manifest.tree = Some(trees.root);
+    manifest.trees = trees.trees;
+    chunk_store.flush()?;
+
     // Set snapshot metadata
     manifest.snapshot_id = snapshot_id.clone();
     manifest.created_at = chrono::Utc::now().to_rfc3339();
     manifest.source_root = source_path.to_string_lossy().to_string();
+    manifest.hostname = hostname();
+    manifest.tags = options.tags.clone();
+    manifest.tags.sort();
+    manifest.tags.dedup();
+    manifest.parent = parent.map(|parent| parent.snapshot_id);
+    manifest.excludes = options.exclude.clone();
 
-    // Update chunk index
-    index.add_snapshot(&manifest);
-    index.save(repo.index_path())?;
+    // Update chunk index, one backup at a time
+    repo.add_to_index(&manifest)?;
 
     // Save manifest
-    let snapshot_manifest_path = repo
-        .snapshots_dir()
-        .join(format!("{}.json", snapshot_id));
-    fs::write(
-        &snapshot_manifest_path,
-        serde_json::to_string_pretty(&manifest)?,
-    )?;
+    repo.save_manifest(&manifest)?;
 
     // Print summary
     println!("✓ Backup complete");
     println!("  Snapshot:         {}", snapshot_id);
+    if let Some(parent) = &manifest.parent {
+        println!("  Parent snapshot:  {}", parent);
+    }
     println!("  Files:            {}", manifest.total_files);
+    println!("  Directories:      {}", manifest.total_dirs);
     println!("  Total size:       {} ({} bytes)", 
         format_size(manifest.total_bytes), manifest.total_bytes);
     println!("  Unique chunks:    {}", manifest.total_chunks);
     println!("  Stored size:      {} ({} bytes)", 
         format_size(manifest.deduplicated_bytes), manifest.deduplicated_bytes);
+    println!("  Compressed size:  {} ({})",
+        format_size(stats.compressed_bytes), repo.config().compression.name());
     if let Some(ratio) = manifest.dedup_ratio() {
         let saved = manifest.space_saved();
         println!("  Space saved:      {} ({:.1}% dedup)", 
@@ -91,32 +171,250 @@ pub fn backup(source_path: &Path, repo_path: &Path) -> Result<()> {
     }
     println!("  New chunks:       {}", stats.new_chunks);
     println!("  Reused chunks:    {}", stats.reused_chunks);
-    println!("  Manifest:         {}", snapshot_manifest_path.display());
+    println!(
+        "  Trees:            {} ({} new)",
+        manifest.trees.len(),
+        trees.new_trees
+    );
+    println!("  Unchanged files:  {} (not read again)", stats.unchanged_files);
+    println!(
+        "  Links:            {} symlinks, {} hardlinks",
+        stats.symlinks, stats.hardlinks
+    );
+    println!("  Special files:    {}", stats.special_files);
+    if stats.sparse_files > 0 {
+        println!(
+            "  Sparse files:     {} ({} in holes, not read)",
+            stats.sparse_files,
+            format_size(stats.hole_bytes)
+        );
+    }
+    println!(
+        "  Excluded:         {} files, {} directories",
+        stats.excluded.files, stats.excluded.dirs
+    );
+    println!("  Repository:       {}", repo.location());
 
     Ok(())
 }
 
+/// Latest snapshot of `source_path` taken on this host, whose file records
+/// let unchanged files be skipped. Manifests that cannot be read are passed
+/// over, as the backup works without a parent.
+fn find_parent(repo: &Repository, chunk_store: &ChunkStore, source_path: &Path) -> Option<SnapshotManifest> {
+    let source_root = source_path.to_string_lossy();
+    let host = hostname();
+    let mut ids = match repo.snapshot_ids() {
+        Ok(ids) => ids,
+        Err(e) => {
+            warn!("Failed to list snapshots, backing up without a parent: {}", e);
+            return None;
+        }
+    };
+    // Snapshot IDs start with their creation time
+    ids.sort_unstable_by(|a, b| b.cmp(a));
+    for id in ids {
+        let found = match repo.load_manifest_header(&id) {
+            Ok(header) => header.source_root == source_root && header.hostname == host,
+            Err(e) => {
+                warn!("Failed to load snapshot {}: {}", id, e);
+                false
+            }
+        };
+        if found {
+            match repo.load_manifest(&id, chunk_store) {
+                Ok(manifest) => return Some(manifest),
+                Err(e) => warn!("Failed to load snapshot {}: {}", id, e),
+            }
+        }
+    }
+    None
+}
+
 /// Statistics about a backup operation
 struct BackupStats {
     new_chunks: usize,
     reused_chunks: usize,
+    /// Files taken from the parent snapshot without reading them
+    unchanged_files: usize,
+    /// Entries left out by the exclude rules
+    excluded: Excluded,
+    symlinks: usize,
+    hardlinks: usize,
+    /// FIFOs, devices and sockets
+    special_files: usize,
+    /// Files with holes, and the bytes in them
+    sparse_files: usize,
+    hole_bytes: u64,
+    /// On-disk size of the unique chunks referenced by this snapshot
+    compressed_bytes: u64,
+}
+
+/// Number of entries left out of a backup
+#[derive(Debug, Clone, Copy, Default)]
+struct Excluded {
+    files: usize,
+    /// Directories not entered, whose contents are not counted
+    dirs: usize,
 }
 
+/// Files queued for the workers per worker thread
+const QUEUED_FILES_PER_THREAD: usize = 2;
+
+/// Number of worker threads used when none is given: one per CPU
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// A file to be read by a worker
+struct FileJob {
+    /// Position of the file in walk order
+    seq: usize,
+    path: PathBuf,
+    file_record: FileRecord,
+}
+
+/// A file backed up by a worker, or taken unchanged from the parent
+struct FileDone {
+    /// Position of the file in walk order
+    seq: usize,
+    file_record: FileRecord,
+    new_chunks: usize,
+    reused_chunks: usize,
+    unchanged: bool,
+}
+
+/// Back up every file under `source_path` that `excluder` keeps. One thread
+/// walks the source and queues files for `threads` workers, each of which
+/// reads a file exactly once, chunking, hashing and storing it in the same
+/// pass. The chunk store deduplicates chunks that several workers store at
+/// once. Files are listed in walk order however the workers happen to be
+/// scheduled, and memory use is bounded by a chunk and a pack per worker,
+/// whatever the file sizes.
 fn perform_chunked_backup(
     source_path: &Path,
     chunk_store: &ChunkStore,
+    chunker: &Chunker,
+    excluder: &Excluder,
+    xattrs: &XattrFilter,
+    parent_files: &HashMap<String, FileRecord>,
+    threads: usize,
 ) -> Result<(SnapshotManifest, BackupStats)> {
     let mut manifest = SnapshotManifest::new(String::new(), String::new());
     let mut stats = BackupStats {
         new_chunks: 0,
         reused_chunks: 0,
+        unchanged_files: 0,
+        excluded: Excluded::default(),
+        symlinks: 0,
+        hardlinks: 0,
+        special_files: 0,
+        sparse_files: 0,
+        hole_bytes: 0,
+        compressed_bytes: 0,
     };
     
     // Track unique chunks in this snapshot for dedup calculation
     let mut unique_chunks = HashSet::new();
-    let chunker = Chunker::new();
 
-    for entry in WalkDir::new(source_path).follow_links(false) {
+    let threads = threads.max(1);
+    let mut files = Vec::new();
+    thread::scope(|scope| -> Result<()> {
+        let (jobs, queue) = mpsc::sync_channel(threads * QUEUED_FILES_PER_THREAD);
+        let (results, received) = mpsc::channel();
+
+        // The queue is gone once every worker has stopped, which stops the walk
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads {
+            let queue = queue.clone();
+            let results = results.clone();
+            scope.spawn(move || back_up_files(&queue, chunk_store, chunker, results));
+        }
+        drop(queue);
+        let scanner = scope.spawn(move || {
+            scan_files(source_path, chunk_store, excluder, xattrs, parent_files, jobs, results)
+        });
+
+        // Returning early drops the receiver; workers stop at their next file
+        for done in received {
+            let done: FileDone = done?;
+            if done.unchanged {
+                stats.unchanged_files += 1;
+            }
+            match done.file_record.kind {
+                EntryKind::File | EntryKind::Directory => {}
+                EntryKind::Symlink { .. } => stats.symlinks += 1,
+                EntryKind::Hardlink { .. } => stats.hardlinks += 1,
+                _ => stats.special_files += 1,
+            }
+            if !done.file_record.holes.is_empty() {
+                stats.sparse_files += 1;
+                stats.hole_bytes += done.file_record.holes.iter().map(|hole| hole.len).sum::<u64>();
+            }
+            stats.new_chunks += done.new_chunks;
+            stats.reused_chunks += done.reused_chunks;
+            unique_chunks.extend(done.file_record.chunks.iter().cloned());
+            files.push((done.seq, done.file_record));
+        }
+        stats.excluded = scanner.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+        Ok(())
+    })?;
+
+    files.sort_by_key(|(seq, _)| *seq);
+    for (_, file_record) in files {
+        manifest.add_file(file_record);
+    }
+
+    // Calculate deduplicated size
+    manifest.total_chunks = unique_chunks.len() as u64;
+    for chunk_hash in &unique_chunks {
+        if let Ok(size) = chunk_store.chunk_size(chunk_hash) {
+            manifest.deduplicated_bytes += size;
+        }
+        if let Ok(size) = chunk_store.stored_size(chunk_hash) {
+            stats.compressed_bytes += size;
+        }
+    }
+
+    info!(
+        "Backup scan complete: {} files ({} unchanged, {} excluded), {} bytes, {} unique chunks ({} new, {} reused)",
+        manifest.total_files,
+        stats.unchanged_files,
+        stats.excluded.files,
+        manifest.total_bytes,
+        manifest.total_chunks,
+        stats.new_chunks,
+        stats.reused_chunks
+    );
+
+    Ok((manifest, stats))
+}
+
+/// Walk `source_path`, skipping what `excluder` leaves out, reporting
+/// directories, links, special files and files unchanged since the parent
+/// snapshot to `results` and queueing the other files for the workers.
+/// Stops early once nobody receives anymore. Returns the number of entries
+/// left out.
+fn scan_files(
+    source_path: &Path,
+    chunk_store: &ChunkStore,
+    excluder: &Excluder,
+    xattrs: &XattrFilter,
+    parent_files: &HashMap<String, FileRecord>,
+    jobs: SyncSender<FileJob>,
+    results: Sender<Result<FileDone>>,
+) -> Excluded {
+    let mut seq = 0;
+    let mut excluded = Excluded::default();
+    let mut ignores = IgnoreStack::default();
+    let mut accounts = Accounts::default();
+    // First path seen of each file with several hard links
+    let mut link_groups = HashMap::new();
+    let mut walk = WalkDir::new(source_path)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter();
+    while let Some(entry) = walk.next() {
         let entry = match entry {
             Ok(e) => e,
             Err(e) => {
@@ -128,17 +426,27 @@ fn perform_chunked_backup(
         let path = entry.path();
         let ft = entry.file_type();
 
-        if ft.is_dir() {
-            continue;
-        }
-        if ft.is_symlink() {
-            warn!("Skipping symlink: {}", path.display());
-            continue;
-        }
-        if !ft.is_file() {
+        ignores.leave(entry.depth());
+        if entry.depth() > 0
+            && let Some(reason) = excluder.excludes(&entry, &ignores)
+        {
+            debug!("Excluding {} ({:?})", path.display(), reason);
+            if ft.is_dir() {
+                walk.skip_current_dir();
+                excluded.dirs += 1;
+            } else {
+                excluded.files += 1;
+            }
             continue;
         }
 
+        if ft.is_dir() {
+            ignores.enter(path, entry.depth());
+            // The source itself is restored to the destination the user picks
+            if entry.depth() == 0 {
+                continue;
+            }
+        }
         let md = match entry.metadata() {
             Ok(m) => m,
             Err(e) => {
@@ -147,7 +455,6 @@ fn perform_chunked_backup(
             }
         };
 
-        let file_size = md.len();
         let modified = md
             .modified()
             .ok()
@@ -168,81 +475,234 @@ fn perform_chunked_backup(
             .collect::<Vec<_>>()
             .join("/");
 
-        // Chunk the file
-        let chunks = match chunker.chunk_file(path) {
-            Ok(c) => c,
+        let kind = match entry_kind(path, &md, &rel_str, &mut link_groups) {
+            Ok(Some(kind)) => kind,
+            Ok(None) => {
+                warn!("Skipping {}: unsupported file type", path.display());
+                continue;
+            }
             Err(e) => {
-                warn!("Failed to chunk file {}: {}", path.display(), e);
+                warn!("Failed to read symlink {}: {}", path.display(), e);
                 continue;
             }
         };
+        let file_size = match kind {
+            EntryKind::File | EntryKind::Hardlink { .. } => md.len(),
+            _ => 0,
+        };
 
-        // Store chunks with deduplication
-        for chunk in &chunks {
-            let newly_stored = chunk_store.store(&chunk.hash, &read_chunk(path, chunk.offset, chunk.size)?)?;
-            
-            if newly_stored {
-                stats.new_chunks += 1;
-            } else {
-                stats.reused_chunks += 1;
-            }
+        let mut file_record = FileRecord::new(rel_str, file_size, modified, Vec::new(), None);
+        file_record.kind = kind;
+        read_metadata(&mut file_record, &md, &mut accounts);
+        match read_xattrs(path, xattrs) {
+            Ok(attrs) => file_record.xattrs = attrs,
+            Err(e) => warn!("Failed to read extended attributes of {}: {}", path.display(), e),
+        }
+        seq += 1;
 
-            unique_chunks.insert(chunk.hash.clone());
+        // Backing up a file or listing a directory updates its access time.
+        // While the entry is unchanged, keep the one recorded before the
+        // last backup read it, so that its tree stays the same too.
+        if let Some(previous) = parent_files.get(&file_record.rel_path)
+            && previous.is_unchanged(&file_record)
+        {
+            file_record.accessed = previous.accessed.clone();
         }
 
-        // Compute file content hash
-        let content_hash = match hash_file(path) {
-            Ok(h) => Some(h),
-            Err(e) => {
-                warn!("Failed to hash file {}: {}", path.display(), e);
-                None
+        // Only regular files have contents to read
+        if file_record.kind != EntryKind::File {
+            let done = FileDone {
+                seq,
+                file_record,
+                new_chunks: 0,
+                reused_chunks: 0,
+                unchanged: false,
+            };
+            if results.send(Ok(done)).is_err() {
+                return excluded;
             }
-        };
+            continue;
+        }
 
-        // Create file record
-        let file_record = FileRecord::new(
-            rel_str,
-            file_size,
-            modified,
-            chunks.iter().map(|c| c.hash.clone()).collect(),
-            content_hash,
-        );
+        // Take unchanged files from the parent, as long as it has all their chunks
+        if let Some(previous) = parent_files.get(&file_record.rel_path)
+            && previous.is_unchanged(&file_record)
+            && previous.chunks.iter().all(|hash| chunk_store.contains(hash))
+        {
+            file_record.chunks = previous.chunks.clone();
+            file_record.holes = previous.holes.clone();
+            file_record.content_hash = previous.content_hash.clone();
+            let done = FileDone {
+                seq,
+                reused_chunks: file_record.chunks.len(),
+                file_record,
+                new_chunks: 0,
+                unchanged: true,
+            };
+            if results.send(Ok(done)).is_err() {
+                return excluded;
+            }
+            continue;
+        }
 
-        manifest.files.push(file_record);
-        manifest.total_files += 1;
-        manifest.total_bytes += file_size;
+        let job = FileJob {
+            seq,
+            path: path.to_path_buf(),
+            file_record,
+        };
+        if jobs.send(job).is_err() {
+            return excluded;
+        }
     }
+    excluded
+}
 
-    // Calculate deduplicated size
-    manifest.total_chunks = unique_chunks.len() as u64;
-    for chunk_hash in &unique_chunks {
-        if let Ok(size) = chunk_store.chunk_size(chunk_hash) {
-            manifest.deduplicated_bytes += size;
+/// Back up queued files until the queue closes or nobody receives results
+fn back_up_files(
+    queue: &Mutex<Receiver<FileJob>>,
+    chunk_store: &ChunkStore,
+    chunker: &Chunker,
+    results: Sender<Result<FileDone>>,
+) {
+    loop {
+        // Hold the lock only to take a job, not while working on it
+        let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let Ok(job) = job else { return };
+        let done = match back_up_file(job, chunk_store, chunker) {
+            Ok(Some(done)) => Ok(done),
+            // Left out of the snapshot, with a warning
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+        let failed = done.is_err();
+        if results.send(done).is_err() || failed {
+            return;
         }
     }
+}
 
-    info!(
-        "Backup scan complete: {} files, {} bytes, {} unique chunks ({} new, {} reused)",
-        manifest.total_files,
-        manifest.total_bytes,
-        manifest.total_chunks,
-        stats.new_chunks,
-        stats.reused_chunks
-    );
+/// Kind of the entry at `path`. A regular file with several names is a hard
+/// link to the first of them seen in the walk, if any. Returns `None` for
+/// types that cannot be backed up.
+fn entry_kind(
+    path: &Path,
+    md: &fs::Metadata,
+    rel_path: &str,
+    link_groups: &mut HashMap<(u64, u64), String>,
+) -> io::Result<Option<EntryKind>> {
+    let ft = md.file_type();
+    if ft.is_dir() {
+        return Ok(Some(EntryKind::Directory));
+    }
+    if ft.is_symlink() {
+        let target = fs::read_link(path)?;
+        return Ok(Some(EntryKind::Symlink {
+            target: target.to_string_lossy().into_owned(),
+        }));
+    }
+    if ft.is_file() {
+        if let Some(key) = link_key(md) {
+            match link_groups.entry(key) {
+                Entry::Occupied(first) => {
+                    return Ok(Some(EntryKind::Hardlink {
+                        target: first.get().clone(),
+                    }));
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert(rel_path.to_string());
+                }
+            }
+        }
+        return Ok(Some(EntryKind::File));
+    }
+    Ok(special_kind(md))
+}
 
-    Ok((manifest, stats))
+/// Device and inode of a file with more than one name
+#[cfg(unix)]
+fn link_key(md: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (md.nlink() > 1).then(|| (md.dev(), md.ino()))
 }
 
-/// Read a specific chunk from a file
-fn read_chunk(path: &Path, offset: u64, size: usize) -> Result<Vec<u8>> {
-    let mut file = fs::File::open(path)?;
-    let mut buffer = vec![0u8; size];
-    
-    use std::io::Seek;
-    file.seek(std::io::SeekFrom::Start(offset))?;
-    file.read_exact(&mut buffer)?;
-    
-    Ok(buffer)
+#[cfg(not(unix))]
+fn link_key(_md: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Kind of a FIFO, device or socket
+#[cfg(unix)]
+fn special_kind(md: &fs::Metadata) -> Option<EntryKind> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let ft = md.file_type();
+    if ft.is_fifo() {
+        Some(EntryKind::Fifo)
+    } else if ft.is_char_device() {
+        Some(EntryKind::CharDevice { rdev: md.rdev() })
+    } else if ft.is_block_device() {
+        Some(EntryKind::BlockDevice { rdev: md.rdev() })
+    } else if ft.is_socket() {
+        Some(EntryKind::Socket)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind(_md: &fs::Metadata) -> Option<EntryKind> {
+    None
+}
+
+/// Read, chunk, hash and store one file. Files that cannot be read are left
+/// out with a warning (`None`); failing to store a chunk fails the backup.
+fn back_up_file(job: FileJob, chunk_store: &ChunkStore, chunker: &Chunker) -> Result<Option<FileDone>> {
+    let file = match fs::File::open(&job.path) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open file {}: {}", job.path.display(), e);
+            return Ok(None);
+        }
+    };
+
+    let mut done = FileDone {
+        seq: job.seq,
+        file_record: job.file_record,
+        new_chunks: 0,
+        reused_chunks: 0,
+        unchanged: false,
+    };
+    // Only the data between holes is read and stored
+    let holes = match find_holes(&file) {
+        Ok(holes) => holes,
+        Err(e) => {
+            debug!("Cannot find holes in {}: {}", job.path.display(), e);
+            Vec::new()
+        }
+    };
+    // Record the bytes actually read, in case the file changed size since
+    // it was listed
+    done.file_record.size = holes.iter().map(|hole| hole.len).sum();
+    done.file_record.holes = holes.clone();
+
+    let mut stream = chunker.stream(DataReader::new(file, holes));
+    for item in &mut stream {
+        let (chunk, data) = match item {
+            Ok(item) => item,
+            Err(e) => {
+                warn!("Failed to read file {}: {}", job.path.display(), e);
+                return Ok(None);
+            }
+        };
+        if chunk_store.store(&chunk.hash, &data)? {
+            done.new_chunks += 1;
+        } else {
+            done.reused_chunks += 1;
+        }
+        done.file_record.size += chunk.size as u64;
+        done.file_record.chunks.push(chunk.hash);
+    }
+    done.file_record.content_hash = Some(stream.content_hash());
+    Ok(Some(done))
 }
 
 fn systemtime_to_rfc3339(t: SystemTime) -> Result<String> {
@@ -271,6 +731,7 @@ fn format_size(bytes: u64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunking::ChunkHash;
     use assert_fs::prelude::*;
     use tempfile::TempDir;
 
@@ -278,13 +739,14 @@ mod tests {
     fn test_backup_basic() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
 
         source.child("file1.txt").write_str("hello").unwrap();
         source.child("file2.txt").write_str("world").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
         // Check that snapshot was created
         let snapshots = fs::read_dir(repo_path.join("snapshots")).unwrap();
@@ -295,6 +757,7 @@ mod tests {
     fn test_backup_nested_directories() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
 
         source.child("dir1/file1.txt").write_str("content1").unwrap();
@@ -304,7 +767,7 @@ mod tests {
             .unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
         let snapshots: Vec<_> = fs::read_dir(repo_path.join("snapshots"))
             .unwrap()
@@ -316,10 +779,11 @@ mod tests {
     fn test_backup_nonexistent_source() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source_path = temp.path().join("nonexistent");
 
         Repository::init(&repo_path).unwrap();
-        let result = backup(&source_path, &repo_path);
+        let result = backup(&source_path, &locator);
         assert!(matches!(result, Err(SnapVaultError::SourceNotFound(_))));
     }
 
@@ -327,15 +791,428 @@ mod tests {
     fn test_backup_file_as_source() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         let file = source.child("file.txt");
         file.write_str("content").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        let result = backup(file.path(), &repo_path);
+        let result = backup(file.path(), &locator);
         assert!(matches!(
             result,
             Err(SnapVaultError::SourceNotDirectory(_))
         ));
     }
+
+    #[test]
+    fn test_backup_records_host_and_tags() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("file.txt").write_str("content").unwrap();
+
+        Repository::init(&repo_path).unwrap();
+        let options = BackupOptions {
+            tags: vec!["nightly".to_string(), "db".to_string(), "nightly".to_string()],
+            ..BackupOptions::default()
+        };
+        backup_with_options(source.path(), &locator, &options).unwrap();
+
+        let repo = Repository::open(&repo_path).unwrap();
+        let id = &repo.snapshot_ids().unwrap()[0];
+        let manifest = repo.load_manifest(id, &repo.chunk_store()).unwrap();
+        assert_eq!(manifest.hostname, hostname());
+        assert_eq!(manifest.tags, vec!["db", "nightly"]);
+    }
+
+    fn latest_manifest(repo: &Repository) -> SnapshotManifest {
+        let mut ids = repo.snapshot_ids().unwrap();
+        ids.sort();
+        repo.load_manifest(ids.last().unwrap(), &repo.chunk_store()).unwrap()
+    }
+
+    /// Exclude rules leaving nothing out
+    fn keep_all(source: &assert_fs::TempDir) -> Excluder {
+        Excluder::new(source.path(), &ExcludeRules::default()).unwrap()
+    }
+
+    fn file<'a>(manifest: &'a SnapshotManifest, rel_path: &str) -> &'a FileRecord {
+        manifest.files.iter().find(|f| f.rel_path == rel_path).unwrap()
+    }
+
+    #[test]
+    fn test_backup_uses_parent_snapshot() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("same.txt").write_str("unchanged").unwrap();
+        source.child("edited.txt").write_str("before").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let first = latest_manifest(&repo);
+        assert_eq!(first.parent, None);
+
+        source.child("edited.txt").write_str("after the edit").unwrap();
+        backup(source.path(), &locator).unwrap();
+        let second = latest_manifest(&repo);
+        assert_eq!(second.parent.as_deref(), Some(first.snapshot_id.as_str()));
+        assert_eq!(file(&second, "same.txt").chunks, file(&first, "same.txt").chunks);
+        assert_ne!(file(&second, "edited.txt").chunks, file(&first, "edited.txt").chunks);
+        assert_eq!(file(&second, "edited.txt").size, 14);
+
+        let forced = BackupOptions {
+            force: true,
+            ..BackupOptions::default()
+        };
+        backup_with_options(source.path(), &locator, &forced).unwrap();
+        assert_eq!(latest_manifest(&repo).parent, None);
+    }
+
+    #[test]
+    fn test_backup_records_directories() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("docs/readme.txt").write_str("hello").unwrap();
+        source.child("empty").create_dir_all().unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let manifest = latest_manifest(&repo);
+
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.rel_path.as_str()).collect();
+        assert_eq!(paths, vec!["docs", "docs/readme.txt", "empty"]);
+        assert_eq!(file(&manifest, "empty").kind, EntryKind::Directory);
+        assert!(file(&manifest, "empty").modified.is_some());
+        assert_eq!((manifest.total_files, manifest.total_dirs), (1, 2));
+        assert_eq!(manifest.total_bytes, 5);
+    }
+
+    #[test]
+    fn test_backup_shares_unchanged_trees() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("stable/a.txt").write_str("same").unwrap();
+        source.child("busy/b.txt").write_str("before").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let first = latest_manifest(&repo);
+        assert_eq!(first.trees.len(), 3);
+
+        source.child("busy/b.txt").write_str("after").unwrap();
+        backup(source.path(), &locator).unwrap();
+        let second = latest_manifest(&repo);
+
+        // Only the tree of `stable` is the same; listing it in the first
+        // backup did not change it
+        let shared: Vec<_> = second.trees.iter().filter(|tree| first.trees.contains(tree)).collect();
+        assert_eq!(shared.len(), 1);
+        assert_ne!(second.tree, first.tree);
+        assert_eq!(file(&second, "stable").accessed, file(&first, "stable").accessed);
+
+        // The index knows the trees, so deleting the first snapshot keeps the shared one
+        let index = repo.load_index().unwrap();
+        let refs = index.get_snapshots(shared[0]).unwrap();
+        assert!(refs.contains(&first.snapshot_id) && refs.contains(&second.snapshot_id));
+    }
+
+    #[test]
+    fn test_concurrent_backups_update_index() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let repo = Repository::init(&repo_path).unwrap();
+        let sources: Vec<assert_fs::TempDir> = (0..4)
+            .map(|i| {
+                let source = assert_fs::TempDir::new().unwrap();
+                source.child("file.txt").write_str(&format!("source {}", i)).unwrap();
+                source
+            })
+            .collect();
+
+        thread::scope(|scope| {
+            for source in &sources {
+                scope.spawn(|| backup(source.path(), &locator).unwrap());
+            }
+        });
+
+        // Every snapshot made it into the saved index, none was lost
+        let snapshots = repo.snapshot_ids().unwrap();
+        assert_eq!(snapshots.len(), 4);
+        let index = repo.load_index().unwrap();
+        let indexed = index.snapshot_ids();
+        assert!(snapshots.iter().all(|id| indexed.contains(id.as_str())));
+        assert!(repo.list_locks().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_records_links_and_special_files() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("a.txt").write_str("shared content").unwrap();
+        fs::hard_link(source.path().join("a.txt"), source.path().join("b.txt")).unwrap();
+        std::os::unix::fs::symlink("a.txt", source.path().join("link")).unwrap();
+        nix::unistd::mkfifo(
+            &source.path().join("pipe"),
+            nix::sys::stat::Mode::from_bits_truncate(0o644),
+        )
+        .unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let manifest = latest_manifest(&repo);
+
+        assert_eq!(file(&manifest, "a.txt").kind, EntryKind::File);
+        assert!(!file(&manifest, "a.txt").chunks.is_empty());
+        let hardlink = file(&manifest, "b.txt");
+        assert_eq!(
+            hardlink.kind,
+            EntryKind::Hardlink {
+                target: "a.txt".to_string()
+            }
+        );
+        assert!(hardlink.chunks.is_empty());
+        assert_eq!(
+            file(&manifest, "link").kind,
+            EntryKind::Symlink {
+                target: "a.txt".to_string()
+            }
+        );
+        assert_eq!(file(&manifest, "pipe").kind, EntryKind::Fifo);
+        assert_eq!(file(&manifest, "pipe").mode, Some(0o644));
+        // Only the regular file counts towards the size
+        assert_eq!(manifest.total_bytes, 14);
+    }
+
+    #[test]
+    fn test_backup_applies_exclude_rules() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("src/main.rs").write_str("fn main() {}").unwrap();
+        source.child("target/debug/app").write_str("binary").unwrap();
+        source.child("web/node_modules/lib.js").write_str("lib").unwrap();
+        source.child("web/app.LOG").write_str("log").unwrap();
+        source.child("web/keep.log").write_str("kept").unwrap();
+        source.child("cache/CACHEDIR.TAG").write_str("").unwrap();
+        source.child("cache/blob").write_str("cached").unwrap();
+        source.child("data/.snapvaultignore").write_str("*.tmp\n").unwrap();
+        source.child("data/scratch.tmp").write_str("tmp").unwrap();
+        source.child("data/big.bin").write_str(&"x".repeat(2000)).unwrap();
+        source.child("data/small.bin").write_str("small").unwrap();
+        source.child("other.tmp").write_str("not below data/").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        let exclude = ExcludeRules {
+            patterns: vec!["target/".to_string(), "node_modules".to_string()],
+            ipatterns: vec!["*.log".to_string(), "!KEEP.log".to_string()],
+            if_present: vec!["CACHEDIR.TAG".to_string()],
+            larger_than: Some(1000),
+            one_file_system: true,
+        };
+        let options = BackupOptions {
+            exclude: exclude.clone(),
+            ..BackupOptions::default()
+        };
+        backup_with_options(source.path(), &locator, &options).unwrap();
+
+        let manifest = latest_manifest(&repo);
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.rel_path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "data",
+                "data/.snapvaultignore",
+                "data/small.bin",
+                "other.tmp",
+                "src",
+                "src/main.rs",
+                "web",
+                "web/keep.log",
+            ]
+        );
+        assert_eq!(manifest.excludes, exclude);
+    }
+
+    #[test]
+    fn test_backup_rejects_invalid_exclude_pattern() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+
+        Repository::init(&repo_path).unwrap();
+        let options = BackupOptions {
+            exclude: ExcludeRules {
+                patterns: vec!["[z-a]".to_string()],
+                ..ExcludeRules::default()
+            },
+            ..BackupOptions::default()
+        };
+        let result = backup_with_options(source.path(), &locator, &options);
+        assert!(matches!(result, Err(SnapVaultError::InvalidExcludePattern(_))));
+        assert_eq!(fs::read_dir(repo_path.join("snapshots")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_unchanged_files_are_not_read() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("a.txt").write_str("contents of a").unwrap();
+        source.child("b.txt").write_str("contents of b").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        let chunk_store = repo.chunk_store();
+        let chunker = repo.chunker();
+        let (first, stats) = perform_chunked_backup(
+            source.path(),
+            &chunk_store,
+            &chunker,
+            &keep_all(&source),
+            &XattrFilter::default(),
+            &HashMap::new(),
+            2,
+        )
+        .unwrap();
+        assert_eq!(stats.unchanged_files, 0);
+
+        // A parent claiming a.txt holds b.txt's chunks: trusting the parent
+        // without reading a.txt carries the claim over
+        let mut parent_files: HashMap<String, FileRecord> = first
+            .files
+            .iter()
+            .map(|f| (f.rel_path.clone(), f.clone()))
+            .collect();
+        let b_chunks = parent_files["b.txt"].chunks.clone();
+        parent_files.get_mut("a.txt").unwrap().chunks = b_chunks.clone();
+
+        let (second, stats) = perform_chunked_backup(
+            source.path(),
+            &chunk_store,
+            &chunker,
+            &keep_all(&source),
+            &XattrFilter::default(),
+            &parent_files,
+            2,
+        )
+        .unwrap();
+        assert_eq!(stats.unchanged_files, 2);
+        assert_eq!(stats.new_chunks, 0);
+        assert_eq!(file(&second, "a.txt").chunks, b_chunks);
+
+        // A size or time mismatch makes the file be read again
+        parent_files.get_mut("a.txt").unwrap().modified = Some("2000-01-01T00:00:00+00:00".to_string());
+        let (third, stats) = perform_chunked_backup(
+            source.path(),
+            &chunk_store,
+            &chunker,
+            &keep_all(&source),
+            &XattrFilter::default(),
+            &parent_files,
+            2,
+        )
+        .unwrap();
+        assert_eq!(stats.unchanged_files, 1);
+        assert_eq!(file(&third, "a.txt").chunks, file(&first, "a.txt").chunks);
+    }
+
+    #[test]
+    fn test_backup_hashes_files_while_chunking() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let source = assert_fs::TempDir::new().unwrap();
+        // Several chunks' worth of data, none of them repeated
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let data: Vec<u8> = (0..3 * crate::chunking::DEFAULT_CHUNK_SIZE + 100)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        source.child("big.bin").write_binary(&data).unwrap();
+        source.child("empty.txt").touch().unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        let chunk_store = repo.chunk_store();
+        let (manifest, stats) = perform_chunked_backup(
+            source.path(),
+            &chunk_store,
+            &repo.chunker(),
+            &keep_all(&source),
+            &XattrFilter::default(),
+            &HashMap::new(),
+            2,
+        )
+        .unwrap();
+
+        let big = file(&manifest, "big.bin");
+        assert_eq!(big.size, data.len() as u64);
+        assert!(big.chunks.len() > 1);
+        assert_eq!(stats.new_chunks, big.chunks.len());
+        assert_eq!(
+            big.content_hash,
+            Some(crate::chunking::hash_file(source.child("big.bin").path()).unwrap())
+        );
+        let empty = file(&manifest, "empty.txt");
+        assert!(empty.chunks.is_empty());
+        assert_eq!(empty.content_hash, Some(crate::chunking::hash_bytes(b"")));
+        assert_eq!(manifest.total_bytes, data.len() as u64);
+    }
+
+    #[test]
+    fn test_parallel_backup_is_deterministic() {
+        let temp = TempDir::new().unwrap();
+        let source = assert_fs::TempDir::new().unwrap();
+        for i in 0..60 {
+            // Pairs of files with the same contents, for workers to store at once
+            let contents = format!("contents {}", i / 2).repeat(500);
+            source
+                .child(format!("dir{}/file{:02}.txt", i % 4, i))
+                .write_str(&contents)
+                .unwrap();
+        }
+
+        let mut listings = Vec::new();
+        for threads in [1, 8] {
+            let repo = Repository::init(&temp.path().join(format!("repo{}", threads))).unwrap();
+            let chunk_store = repo.chunk_store();
+            let (manifest, stats) = perform_chunked_backup(
+                source.path(),
+                &chunk_store,
+                &repo.chunker(),
+                &keep_all(&source),
+                &XattrFilter::default(),
+                &HashMap::new(),
+                threads,
+            )
+            .unwrap();
+            assert_eq!(stats.new_chunks, 30);
+            assert_eq!(stats.reused_chunks, 30);
+            chunk_store.flush().unwrap();
+            assert_eq!(chunk_store.list_chunks().unwrap().len(), 30);
+
+            let listing: Vec<(String, Vec<ChunkHash>)> = manifest
+                .files
+                .iter()
+                .map(|f| (f.rel_path.clone(), f.chunks.clone()))
+                .collect();
+            assert!(listing.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            listings.push(listing);
+        }
+        assert_eq!(listings[0], listings[1]);
+    }
 }
@@ -0,0 +1,477 @@
+use crate::chunking::ChunkHash;
+use crate::error::{Result, SnapVaultError};
+use crate::index::ChunkIndex;
+use crate::repository::locator::RepoLocator;
+use crate::repository::lock::LockMode;
+use crate::repository::Repository;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Which chunks `check` reads back and re-hashes
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReadData {
+    /// Only confirm that referenced chunks are indexed
+    #[default]
+    None,
+    /// Read every referenced chunk
+    All,
+    /// Read a random sample of about this percentage of referenced chunks
+    Subset(f64),
+}
+
+/// Percentage of chunks to read, parsed from `10%` or `10`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentage(pub f64);
+
+impl FromStr for Percentage {
+    type Err = SnapVaultError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let value = s.trim().trim_end_matches('%');
+        match value.parse::<f64>() {
+            Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(Percentage(percent)),
+            _ => Err(SnapVaultError::Other(format!(
+                "Invalid percentage {:?}: expected a value in (0, 100], e.g. 10%",
+                s
+            ))),
+        }
+    }
+}
+
+/// A problem found by `check`
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckProblem {
+    /// A snapshot manifest cannot be read or parsed
+    Manifest { snapshot_id: String, reason: String },
+    /// A snapshot references a chunk the chunk store does not have
+    MissingChunk {
+        snapshot_id: String,
+        path: String,
+        chunk: ChunkHash,
+    },
+    /// `index.json` disagrees with the snapshot manifests
+    Index(String),
+    /// A chunk cannot be read or its content does not match its hash
+    Data { chunk: ChunkHash, reason: String },
+}
+
+impl fmt::Display for CheckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckProblem::Manifest {
+                snapshot_id,
+                reason,
+            } => write!(f, "snapshot {}: unreadable manifest: {}", snapshot_id, reason),
+            CheckProblem::MissingChunk {
+                snapshot_id,
+                path,
+                chunk,
+            } => write!(f, "snapshot {}: {} references missing chunk {}", snapshot_id, path, chunk),
+            CheckProblem::Index(reason) => write!(f, "index.json: {}", reason),
+            CheckProblem::Data { chunk, reason } => write!(f, "chunk {}: {}", chunk, reason),
+        }
+    }
+}
+
+/// Outcome of a repository check
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    /// Snapshot manifests read
+    pub snapshots: usize,
+    /// Unique chunks referenced by those snapshots
+    pub chunks: usize,
+    /// Chunks read back and re-hashed
+    pub chunks_read: usize,
+    pub problems: Vec<CheckProblem>,
+}
+
+impl CheckReport {
+    /// Whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn count(&self, matches: impl Fn(&CheckProblem) -> bool) -> usize {
+        self.problems.iter().filter(|p| matches(p)).count()
+    }
+}
+
+/// Check a repository, print a summary and fail with `CheckFailed` if any
+/// problem was found. With `repair`, an index that disagrees with the
+/// snapshots is rebuilt and its problems no longer count.
+pub fn check(repo_location: &RepoLocator, read_data: ReadData, repair: bool) -> Result<()> {
+    // Opening the repository validates config.json
+    let repo = Repository::open_at(repo_location, None)?;
+    let _lock = repo.lock(LockMode::Exclusive)?;
+    info!("Checking repository {}", repo_location);
+
+    let mut report = check_repository(&repo, read_data)?;
+    let repaired = if repair { repair_index(&repo, &mut report)? } else { 0 };
+
+    println!("Checked repository {}:", repo_location);
+    println!(
+        "  Config:     ok ({} chunker, {} compression)",
+        repo.config().chunker.name(),
+        repo.config().compression.name()
+    );
+    println!(
+        "  Snapshots:  {} checked, {} unreadable",
+        report.snapshots,
+        report.count(|p| matches!(p, CheckProblem::Manifest { .. }))
+    );
+    println!(
+        "  Chunks:     {} referenced, {} missing",
+        report.chunks,
+        report.count(|p| matches!(p, CheckProblem::MissingChunk { .. }))
+    );
+    let index_problems = report.count(|p| matches!(p, CheckProblem::Index(_)));
+    if repaired > 0 {
+        println!("  Index:      rebuilt, {} problem(s) repaired", repaired);
+    } else if index_problems == 0 {
+        println!("  Index:      matches the snapshots");
+    } else if repair {
+        println!("  Index:      {} problem(s), not repaired as a snapshot is unreadable", index_problems);
+    } else {
+        println!("  Index:      {} problem(s) (use --repair to rebuild it)", index_problems);
+    }
+    match read_data {
+        ReadData::None => println!("  Data:       not read (use --read-data)"),
+        _ => println!(
+            "  Data:       {} chunks read, {} damaged",
+            report.chunks_read,
+            report.count(|p| matches!(p, CheckProblem::Data { .. }))
+        ),
+    }
+
+    if report.is_ok() {
+        println!("✓ No problems found");
+        return Ok(());
+    }
+    println!("✗ {} problem(s) found:", report.problems.len());
+    for problem in &report.problems {
+        println!("  - {}", problem);
+    }
+    Err(SnapVaultError::CheckFailed(report.problems.len()))
+}
+
+/// Check every snapshot, the chunk index and optionally chunk data,
+/// collecting problems instead of stopping at the first one
+pub fn check_repository(repo: &Repository, read_data: ReadData) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let chunk_store = repo.chunk_store();
+    let mut rebuilt = ChunkIndex::new();
+    let mut referenced = HashSet::new();
+    let mut manifests_read = true;
+
+    for snapshot_id in repo.snapshot_ids()? {
+        let manifest = match repo.load_manifest(&snapshot_id, &chunk_store) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!("Cannot read snapshot {}: {}", snapshot_id, e);
+                manifests_read = false;
+                report.problems.push(CheckProblem::Manifest {
+                    snapshot_id,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        report.snapshots += 1;
+
+        // Its trees were read to load it, so they exist
+        referenced.extend(manifest.trees.iter().cloned());
+        for file in &manifest.files {
+            for chunk in &file.chunks {
+                if !referenced.insert(chunk.clone()) {
+                    continue;
+                }
+                if !chunk_store.contains(chunk) {
+                    report.problems.push(CheckProblem::MissingChunk {
+                        snapshot_id: snapshot_id.clone(),
+                        path: file.rel_path.clone(),
+                        chunk: chunk.clone(),
+                    });
+                }
+            }
+        }
+        rebuilt.add_snapshot(&manifest);
+    }
+    report.chunks = referenced.len();
+
+    // Only compare when every manifest could be read; otherwise the rebuilt
+    // index is incomplete and the differences are already reported
+    if manifests_read {
+        let stored = repo.load_index()?;
+        report.problems.extend(compare_index(&stored, &rebuilt));
+    }
+
+    let to_read: Vec<&ChunkHash> = match read_data {
+        ReadData::None => Vec::new(),
+        ReadData::All => referenced.iter().collect(),
+        ReadData::Subset(percent) => sample(&referenced, percent),
+    };
+    for chunk in to_read {
+        if !chunk_store.contains(chunk) {
+            // Already reported as missing
+            continue;
+        }
+        report.chunks_read += 1;
+        if let Err(e) = chunk_store.read(chunk) {
+            report.problems.push(CheckProblem::Data {
+                chunk: chunk.clone(),
+                reason: e.to_string(),
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+/// Differences between the stored chunk index and one rebuilt from the manifests
+fn compare_index(stored: &ChunkIndex, rebuilt: &ChunkIndex) -> Vec<CheckProblem> {
+    let mut problems = Vec::new();
+    let mut wrong_refs = 0;
+    for chunk in rebuilt.all_chunks() {
+        match stored.get_snapshots(&chunk) {
+            None => problems.push(CheckProblem::Index(format!(
+                "chunk {} is referenced by snapshots but not indexed",
+                chunk
+            ))),
+            Some(refs) if Some(refs) != rebuilt.get_snapshots(&chunk) => wrong_refs += 1,
+            Some(_) => {}
+        }
+    }
+    if wrong_refs > 0 {
+        problems.push(CheckProblem::Index(format!(
+            "{} chunks list the wrong snapshots",
+            wrong_refs
+        )));
+    }
+    let stale = stored
+        .all_chunks()
+        .iter()
+        .filter(|chunk| !rebuilt.is_referenced(chunk))
+        .count();
+    if stale > 0 {
+        problems.push(CheckProblem::Index(format!(
+            "{} chunks are indexed but referenced by no snapshot",
+            stale
+        )));
+    }
+    problems
+}
+
+/// Replace `index.json` by an index rebuilt from the manifests if `report`
+/// found problems with it, and drop those problems from the report. Nothing
+/// is written if a manifest could not be read, as the rebuilt index would
+/// miss that snapshot. Returns the number of problems repaired. The caller
+/// must hold an exclusive lock.
+pub fn repair_index(repo: &Repository, report: &mut CheckReport) -> Result<usize> {
+    let index_problems = report.count(|p| matches!(p, CheckProblem::Index(_)));
+    if index_problems == 0 || report.count(|p| matches!(p, CheckProblem::Manifest { .. })) > 0 {
+        return Ok(0);
+    }
+    warn!("Chunk index disagrees with the snapshots, rebuilding it");
+    repo.save_index(&ChunkIndex::rebuild(repo)?)?;
+    report.problems.retain(|p| !matches!(p, CheckProblem::Index(_)));
+    Ok(index_problems)
+}
+
+/// Pick about `percent` of the chunks at random, and at least one.
+/// Chunk hashes are uniformly distributed, so comparing them against a
+/// threshold after mixing in a random seed gives an unbiased sample.
+fn sample(chunks: &HashSet<ChunkHash>, percent: f64) -> Vec<&ChunkHash> {
+    let seed = uuid::Uuid::new_v4().as_u64_pair().0;
+    let threshold = (percent / 100.0 * u64::MAX as f64) as u64;
+    let mut picked: Vec<&ChunkHash> = chunks
+        .iter()
+        .filter(|chunk| {
+            let prefix = u64::from_be_bytes(chunk.as_bytes()[..8].try_into().unwrap());
+            prefix ^ seed <= threshold
+        })
+        .collect();
+    if picked.is_empty()
+        && let Some(first) = chunks.iter().nth((seed % chunks.len().max(1) as u64) as usize)
+    {
+        picked.push(first);
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ObjectKind;
+    use crate::commands::backup;
+    use crate::pack::PACK_HEADER_SIZE;
+    use crate::repository::snapshot::{FileRecord, SnapshotManifest};
+    use assert_fs::prelude::*;
+    use tempfile::TempDir;
+
+    fn backed_up_repo(temp: &TempDir) -> (RepoLocator, Repository) {
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("a.txt").write_str("alpha").unwrap();
+        source.child("b.txt").write_str(&"beta".repeat(5000)).unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        (locator, repo)
+    }
+
+    #[test]
+    fn test_check_healthy_repository() {
+        let temp = TempDir::new().unwrap();
+        let (locator, repo) = backed_up_repo(&temp);
+
+        let report = check_repository(&repo, ReadData::All).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.snapshots, 1);
+        assert!(report.chunks > 0);
+        assert_eq!(report.chunks_read, report.chunks);
+
+        check(&locator, ReadData::Subset(10.0), false).unwrap();
+    }
+
+    #[test]
+    fn test_check_detects_corrupt_pack() {
+        let temp = TempDir::new().unwrap();
+        let (locator, repo) = backed_up_repo(&temp);
+
+        // Flip the first byte of the first chunk in every pack
+        let backend = repo.backend();
+        for pack in backend.list(ObjectKind::Pack).unwrap() {
+            let mut data = backend.get(ObjectKind::Pack, &pack).unwrap();
+            data[PACK_HEADER_SIZE] ^= 0xff;
+            backend.put(ObjectKind::Pack, &pack, &data).unwrap();
+        }
+
+        // Without reading data the damage goes unnoticed
+        assert!(check_repository(&repo, ReadData::None).unwrap().is_ok());
+
+        let report = check_repository(&repo, ReadData::All).unwrap();
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| matches!(p, CheckProblem::Data { .. })));
+        assert!(matches!(
+            check(&locator, ReadData::All, false),
+            Err(SnapVaultError::CheckFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_detects_missing_chunks_and_stale_index() {
+        let temp = TempDir::new().unwrap();
+        let (_, repo) = backed_up_repo(&temp);
+
+        let chunk_store = repo.chunk_store();
+        let manifest = repo.load_manifest(&repo.snapshot_ids().unwrap()[0], &chunk_store).unwrap();
+        let chunk = manifest.files[0].chunks[0].clone();
+        chunk_store.delete(&chunk).unwrap();
+        chunk_store.flush().unwrap();
+        // An index still listing a snapshot that is gone
+        let mut stale = repo.load_index().unwrap();
+        let mut gone = SnapshotManifest::new("gone".to_string(), "/src".to_string());
+        gone.add_file(FileRecord::new(
+            "old.txt".to_string(),
+            3,
+            None,
+            vec![repo.chunk_hasher().hash(b"old")],
+            None,
+        ));
+        stale.add_snapshot(&gone);
+        repo.save_index(&stale).unwrap();
+
+        let report = check_repository(&repo, ReadData::None).unwrap();
+        assert!(report.problems.iter().any(|p| matches!(
+            p,
+            CheckProblem::MissingChunk { chunk: missing, .. } if *missing == chunk
+        )));
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| matches!(p, CheckProblem::Index(_))));
+    }
+
+    #[test]
+    fn test_check_repairs_index_only_when_asked() {
+        let temp = TempDir::new().unwrap();
+        let (locator, repo) = backed_up_repo(&temp);
+        let first = repo.load_index().unwrap();
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("c.txt").write_str("gamma").unwrap();
+        backup(source.path(), &locator).unwrap();
+
+        // An index missing the second snapshot is reported, not rewritten
+        repo.save_index(&first).unwrap();
+        assert!(matches!(
+            check(&locator, ReadData::None, false),
+            Err(SnapVaultError::CheckFailed(_))
+        ));
+        assert_eq!(repo.load_index().unwrap().snapshot_ids().len(), 1);
+
+        check(&locator, ReadData::None, true).unwrap();
+        assert_eq!(repo.load_index().unwrap().snapshot_ids().len(), 2);
+        assert!(check_repository(&repo, ReadData::None).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_check_detects_missing_tree() {
+        let temp = TempDir::new().unwrap();
+        let (_, repo) = backed_up_repo(&temp);
+
+        let chunk_store = repo.chunk_store();
+        let manifest = repo.load_manifest(&repo.snapshot_ids().unwrap()[0], &chunk_store).unwrap();
+        chunk_store.delete(manifest.tree.as_ref().unwrap()).unwrap();
+        chunk_store.flush().unwrap();
+
+        // The snapshot's records cannot be read at all
+        let report = check_repository(&repo, ReadData::None).unwrap();
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| matches!(p, CheckProblem::Manifest { .. })));
+    }
+
+    #[test]
+    fn test_check_reports_unreadable_manifest() {
+        let temp = TempDir::new().unwrap();
+        let (_, repo) = backed_up_repo(&temp);
+        repo.backend()
+            .put(ObjectKind::Snapshot, "20240101T000000Z-broken00", b"{not json")
+            .unwrap();
+
+        let report = check_repository(&repo, ReadData::None).unwrap();
+        assert_eq!(report.snapshots, 1);
+        assert!(matches!(
+            report.problems.as_slice(),
+            [CheckProblem::Manifest { snapshot_id, .. }] if snapshot_id == "20240101T000000Z-broken00"
+        ));
+    }
+
+    #[test]
+    fn test_parse_percentage() {
+        assert_eq!("10%".parse::<Percentage>().unwrap(), Percentage(10.0));
+        assert_eq!("2.5".parse::<Percentage>().unwrap(), Percentage(2.5));
+        assert!("0%".parse::<Percentage>().is_err());
+        assert!("150%".parse::<Percentage>().is_err());
+        assert!("ten".parse::<Percentage>().is_err());
+    }
+
+    #[test]
+    fn test_sample_size() {
+        let chunks: HashSet<ChunkHash> = (0..2000u32)
+            .map(|i| crate::chunking::hash_bytes(&i.to_le_bytes()))
+            .collect();
+        let picked = sample(&chunks, 10.0).len();
+        assert!((100..=300).contains(&picked), "{}", picked);
+        assert_eq!(sample(&chunks, 100.0).len(), chunks.len());
+
+        let one: HashSet<ChunkHash> = chunks.iter().take(1).cloned().collect();
+        assert_eq!(sample(&one, 0.001).len(), 1);
+    }
+}
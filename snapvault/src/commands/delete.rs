@@ -1,14 +1,12 @@
 use crate::error::{Result, SnapVaultError};
 use crate::index::ChunkIndex;
-use crate::repository::snapshot::SnapshotManifest;
+use crate::repository::locator::RepoLocator;
+use crate::repository::lock::LockMode;
 use crate::repository::Repository;
 use crate::storage::ChunkStore;
-use crate::utils::validate_snapshot_id;
 use log::{info, warn};
-use std::fs;
-use std::path::Path;
 
-pub fn delete(repo_path: &Path, snapshot_id_opt: Option<&str>, all: bool) -> Result<()> {
+pub fn delete(repo_location: &RepoLocator, snapshot_id_opt: Option<&str>, all: bool) -> Result<()> {
     // Validate arguments
     match (snapshot_id_opt, all) {
         (Some(_), true) => return Err(SnapVaultError::DeleteArgsConflict),
@@ -16,40 +14,27 @@ pub fn delete(repo_path: &Path, snapshot_id_opt: Option<&str>, all: bool) -> Res
         _ => {}
     }
 
-    let repo = Repository::open(repo_path)?;
+    let repo = Repository::open_at(repo_location, None)?;
+    let _lock = repo.lock(LockMode::Exclusive)?;
+    let mut index = load_complete_index(&repo)?;
+    let chunk_store = repo.chunk_store();
 
     if let Some(snapshot_id) = snapshot_id_opt {
         info!(
             "Deleting snapshot {} from repository {}",
             snapshot_id,
-            repo_path.display()
+            repo_location
         );
-        delete_single_snapshot(&repo, snapshot_id)?;
+        delete_single_snapshot(&repo, &chunk_store, &mut index, snapshot_id)?;
         println!("✓ Snapshot {} deleted successfully", snapshot_id);
     } else {
         // all is true
         info!(
             "Deleting all snapshots from repository {}",
-            repo_path.display()
+            repo_location
         );
 
-        let snapshots_dir = repo.snapshots_dir();
-        if !snapshots_dir.exists() {
-            println!("No snapshots found in repository.");
-            return Ok(());
-        }
-
-        let mut snapshot_ids: Vec<String> = Vec::new();
-        for entry in fs::read_dir(&snapshots_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.extension() == Some(std::ffi::OsStr::new("json")) {
-                if let Some(stem) = path.file_stem() {
-                    snapshot_ids.push(stem.to_string_lossy().to_string());
-                }
-            }
-        }
-
+        let snapshot_ids = repo.snapshot_ids()?;
         if snapshot_ids.is_empty() {
             println!("No snapshots found in repository.");
             return Ok(());
@@ -58,7 +43,7 @@ pub fn delete(repo_path: &Path, snapshot_id_opt: Option<&str>, all: bool) -> Res
         let total_snapshots = snapshot_ids.len();
         let mut deleted_count = 0;
         for id in snapshot_ids {
-            match delete_single_snapshot(&repo, &id) {
+            match delete_single_snapshot(&repo, &chunk_store, &mut index, &id) {
                 Ok(()) => {
                     println!("✓ Snapshot {} deleted successfully", id);
                     deleted_count += 1;
@@ -75,33 +60,28 @@ pub fn delete(repo_path: &Path, snapshot_id_opt: Option<&str>, all: bool) -> Res
     Ok(())
 }
 
-fn delete_single_snapshot(repo: &Repository, snapshot_id: &str) -> Result<()> {
-    // Security: Validate snapshot ID
-    validate_snapshot_id(snapshot_id)?;
-
-    let manifest_path = repo
-        .snapshots_dir()
-        .join(format!("{}.json", snapshot_id));
-
-    // Check if manifest exists
-    if !manifest_path.exists() {
-        return Err(SnapVaultError::SnapshotNotFound(snapshot_id.to_string()));
-    }
-
-    // Load manifest to verify it's a valid snapshot
-    let raw = fs::read_to_string(&manifest_path)?;
-    let manifest: SnapshotManifest = serde_json::from_str(&raw)?;
-    if manifest.snapshot_id != snapshot_id {
-        return Err(SnapVaultError::Other(
-            "Manifest snapshot ID mismatch".to_string(),
-        ));
+/// Load the chunk index, rebuilding it from the manifests if it misses a
+/// snapshot. Backups of older versions running side by side could lose each
+/// other's index updates, and orphaned chunks must never be judged from such
+/// an index.
+pub(crate) fn load_complete_index(repo: &Repository) -> Result<ChunkIndex> {
+    let index = repo.load_index()?;
+    let indexed = index.snapshot_ids();
+    if repo.snapshot_ids()?.iter().all(|id| indexed.contains(id.as_str())) {
+        return Ok(index);
     }
+    warn!("Chunk index is missing snapshots, rebuilding it");
+    ChunkIndex::rebuild(repo)
+}
 
-    // Load chunk index
-    let mut index = ChunkIndex::load(repo.index_path())?;
-    
-    // Initialize chunk storage
-    let chunk_store = ChunkStore::new(repo.chunks_dir());
+fn delete_single_snapshot(
+    repo: &Repository,
+    chunk_store: &ChunkStore,
+    index: &mut ChunkIndex,
+    snapshot_id: &str,
+) -> Result<()> {
+    // Load manifest to verify it's a valid snapshot (also validates the ID)
+    let manifest = repo.load_manifest(snapshot_id, chunk_store)?;
 
     // Remove snapshot from index and get orphaned chunks
     info!("Removing snapshot {} from chunk index", snapshot_id);
@@ -125,12 +105,15 @@ fn delete_single_snapshot(repo: &Repository, snapshot_id: &str) -> Result<()> {
         );
     }
 
+    // Persist the pack index before the chunk index stops referencing them
+    chunk_store.flush()?;
+
     // Save updated index
-    index.save(repo.index_path())?;
+    repo.save_index(index)?;
 
     // Delete manifest file
-    info!("Removing snapshot manifest: {}", manifest_path.display());
-    fs::remove_file(&manifest_path)?;
+    info!("Removing snapshot manifest: {}", snapshot_id);
+    repo.delete_manifest(snapshot_id)?;
 
     Ok(())
 }
@@ -138,7 +121,10 @@ fn delete_single_snapshot(repo: &Repository, snapshot_id: &str) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
+    use std::path::Path;
     use crate::commands::backup;
+    use crate::repository::lock::LockMode;
     use assert_fs::prelude::*;
     use tempfile::TempDir;
 
@@ -158,14 +144,15 @@ mod tests {
     fn test_delete_single_snapshot() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         source.child("file.txt").write_str("content").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
         let snapshot_id = get_first_snapshot_id(&repo_path);
-        delete(&repo_path, Some(&snapshot_id), false).unwrap();
+        delete(&locator, Some(&snapshot_id), false).unwrap();
 
         // Verify snapshot is deleted
         let count = fs::read_dir(repo_path.join("snapshots")).unwrap().count();
@@ -176,14 +163,15 @@ mod tests {
     fn test_delete_all_snapshots() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         source.child("file.txt").write_str("content").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        backup(source.path(), &locator).unwrap();
 
-        delete(&repo_path, None, true).unwrap();
+        delete(&locator, None, true).unwrap();
 
         let count = fs::read_dir(repo_path.join("snapshots")).unwrap().count();
         assert_eq!(count, 0);
@@ -193,9 +181,10 @@ mod tests {
     fn test_delete_args_conflict() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
 
         Repository::init(&repo_path).unwrap();
-        let result = delete(&repo_path, Some("snap123"), true);
+        let result = delete(&locator, Some("snap123"), true);
         assert!(matches!(result, Err(SnapVaultError::DeleteArgsConflict)));
     }
 
@@ -203,9 +192,10 @@ mod tests {
     fn test_delete_args_required() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
 
         Repository::init(&repo_path).unwrap();
-        let result = delete(&repo_path, None, false);
+        let result = delete(&locator, None, false);
         assert!(matches!(result, Err(SnapVaultError::DeleteArgsRequired)));
     }
 
@@ -213,9 +203,54 @@ mod tests {
     fn test_delete_nonexistent_snapshot() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
 
         Repository::init(&repo_path).unwrap();
-        let result = delete(&repo_path, Some("nonexistent"), false);
+        let result = delete(&locator, Some("nonexistent"), false);
         assert!(matches!(result, Err(SnapVaultError::SnapshotNotFound(_))));
     }
+
+    #[test]
+    fn test_delete_blocked_by_lock() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("file.txt").write_str("content").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let snapshot_id = get_first_snapshot_id(&repo_path);
+
+        let lock = repo.lock(LockMode::Shared).unwrap();
+        let result = delete(&locator, Some(&snapshot_id), false);
+        assert!(matches!(result, Err(SnapVaultError::RepoLocked { .. })));
+        drop(lock);
+
+        delete(&locator, Some(&snapshot_id), false).unwrap();
+    }
+
+    #[test]
+    fn test_delete_rebuilds_incomplete_index() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("shared.txt").write_str("in both snapshots").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let first = get_first_snapshot_id(&repo_path);
+
+        // A second backup whose index update was lost to a concurrent one
+        let index_before = repo.load_index().unwrap();
+        backup(source.path(), &locator).unwrap();
+        repo.save_index(&index_before).unwrap();
+
+        delete(&locator, Some(&first), false).unwrap();
+
+        let dest = temp.path().join("restored");
+        crate::commands::restore(None, &dest, &locator).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("shared.txt")).unwrap(), "in both snapshots");
+    }
 }
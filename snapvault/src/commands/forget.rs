@@ -0,0 +1,434 @@
+use crate::commands::delete::load_complete_index;
+use crate::commands::prune::{self, prune_repository, PruneReport};
+use crate::error::{Result, SnapVaultError};
+use crate::repository::locator::RepoLocator;
+use crate::repository::lock::LockMode;
+use crate::repository::Repository;
+use crate::retention::{KeepReason, RetentionPolicy};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Snapshot fields that separate groups, parsed from a comma-separated list
+/// such as `source,host,tags` (an empty list puts all snapshots in one group)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupBy {
+    pub source: bool,
+    pub host: bool,
+    pub tags: bool,
+}
+
+impl Default for GroupBy {
+    fn default() -> Self {
+        Self {
+            source: true,
+            host: true,
+            tags: true,
+        }
+    }
+}
+
+impl FromStr for GroupBy {
+    type Err = SnapVaultError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut group_by = GroupBy {
+            source: false,
+            host: false,
+            tags: false,
+        };
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "source" => group_by.source = true,
+                "host" => group_by.host = true,
+                "tags" => group_by.tags = true,
+                _ => {
+                    return Err(SnapVaultError::Other(format!(
+                        "Invalid group field {:?}: expected source, host or tags",
+                        field
+                    )))
+                }
+            }
+        }
+        Ok(group_by)
+    }
+}
+
+/// Values of the grouping fields shared by the snapshots of a group
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GroupKey {
+    pub source: Option<String>,
+    pub host: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+impl fmt::Display for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(source) = &self.source {
+            parts.push(format!("source {}", source));
+        }
+        if let Some(host) = &self.host {
+            parts.push(format!("host {}", if host.is_empty() { "(unknown)" } else { host }));
+        }
+        if let Some(tags) = &self.tags {
+            parts.push(format!("tags [{}]", tags.join(", ")));
+        }
+        if parts.is_empty() {
+            return f.write_str("all snapshots");
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// What the policy decided for one snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForgetDecision {
+    pub snapshot_id: String,
+    pub created_at: String,
+    /// Why the snapshot is kept; empty if it is removed
+    pub reasons: Vec<KeepReason>,
+}
+
+impl ForgetDecision {
+    pub fn is_kept(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// Outcome of a forget: each group with its snapshots, newest first
+#[derive(Debug, Clone, Default)]
+pub struct ForgetReport {
+    pub groups: Vec<(GroupKey, Vec<ForgetDecision>)>,
+}
+
+impl ForgetReport {
+    /// IDs of the snapshots the policy does not keep
+    pub fn removed(&self) -> Vec<&str> {
+        self.groups
+            .iter()
+            .flat_map(|(_, decisions)| decisions)
+            .filter(|decision| !decision.is_kept())
+            .map(|decision| decision.snapshot_id.as_str())
+            .collect()
+    }
+}
+
+/// Options for `forget`
+#[derive(Debug, Clone, Default)]
+pub struct ForgetOptions {
+    pub policy: RetentionPolicy,
+    pub group_by: GroupBy,
+    /// Only report what would be removed
+    pub dry_run: bool,
+    /// Delete the chunks the removed snapshots leave unreferenced
+    pub prune: bool,
+}
+
+/// Remove the snapshots a retention policy does not keep
+pub fn forget(repo_location: &RepoLocator, options: &ForgetOptions) -> Result<()> {
+    let repo = Repository::open_at(repo_location, None)?;
+    let _lock = repo.lock(LockMode::Exclusive)?;
+    info!("Applying retention policy to repository {}", repo_location);
+
+    let (report, pruned) = forget_repository(&repo, options, &Local)?;
+    if report.groups.is_empty() {
+        println!("No snapshots found in repository.");
+        return Ok(());
+    }
+
+    for (key, decisions) in &report.groups {
+        println!("Snapshots of {}:", key);
+        for decision in decisions {
+            let reasons: Vec<String> = decision.reasons.iter().map(|r| r.to_string()).collect();
+            println!(
+                "  {:<7} {:<40} {:<25} {}",
+                if decision.is_kept() { "keep" } else { "remove" },
+                decision.snapshot_id,
+                decision.created_at,
+                reasons.join(", ")
+            );
+        }
+    }
+
+    let removed = report.removed().len();
+    let total: usize = report.groups.iter().map(|(_, decisions)| decisions.len()).sum();
+    if options.dry_run {
+        println!("✓ Dry run: would keep {} and remove {} snapshots", total - removed, removed);
+        return Ok(());
+    }
+    println!("✓ Kept {} and removed {} snapshots", total - removed, removed);
+    if let Some(pruned) = pruned {
+        println!("Pruned repository {}:", repo_location);
+        prune::print_report(&pruned, false);
+    }
+    Ok(())
+}
+
+/// Group the snapshots, apply the policy to each group with hours, days and
+/// so on counted in `tz`, and unless `dry_run` remove the snapshots it does
+/// not keep, followed by a prune if asked for. The caller must hold an
+/// exclusive lock.
+pub fn forget_repository<Tz: TimeZone>(
+    repo: &Repository,
+    options: &ForgetOptions,
+    tz: &Tz,
+) -> Result<(ForgetReport, Option<PruneReport>)> {
+    if options.policy.is_empty() {
+        return Err(SnapVaultError::InvalidRetentionPolicy(
+            "no keep rule given; it would remove every snapshot".to_string(),
+        ));
+    }
+
+    // A manifest that cannot be read fails the whole run: leaving it out
+    // could make the policy remove snapshots it should have kept
+    let mut groups: BTreeMap<GroupKey, Vec<(String, String)>> = BTreeMap::new();
+    for snapshot_id in repo.snapshot_ids()? {
+        let manifest = repo.load_manifest_header(&snapshot_id)?;
+        let group_by = options.group_by;
+        let key = GroupKey {
+            source: group_by.source.then(|| manifest.source_root.clone()),
+            host: group_by.host.then(|| manifest.hostname.clone()),
+            tags: group_by.tags.then(|| manifest.tags.clone()),
+        };
+        groups
+            .entry(key)
+            .or_default()
+            .push((manifest.snapshot_id, manifest.created_at));
+    }
+
+    let mut report = ForgetReport::default();
+    for (key, mut snapshots) in groups {
+        let created: Vec<Option<DateTime<Utc>>> = snapshots
+            .iter()
+            .map(|(_, created_at)| {
+                DateTime::parse_from_rfc3339(created_at)
+                    .ok()
+                    .map(|time| time.with_timezone(&Utc))
+            })
+            .collect();
+        let reasons = options.policy.apply(&created, tz);
+
+        let mut order: Vec<usize> = (0..snapshots.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(created[i]));
+        let decisions = order
+            .into_iter()
+            .map(|i| {
+                let (snapshot_id, created_at) = std::mem::take(&mut snapshots[i]);
+                ForgetDecision {
+                    snapshot_id,
+                    created_at,
+                    reasons: reasons[i].clone(),
+                }
+            })
+            .collect();
+        report.groups.push((key, decisions));
+    }
+
+    let removed = report.removed();
+    if options.dry_run || removed.is_empty() {
+        return Ok((report, None));
+    }
+
+    // Drop the snapshots from the index first: a crash before their
+    // manifests are gone leaves an index that `delete` and `prune` rebuild
+    let mut index = load_complete_index(repo)?;
+    let mut manifests = Vec::new();
+    let chunk_store = repo.chunk_store();
+    for snapshot_id in &removed {
+        let manifest = repo.load_manifest(snapshot_id, &chunk_store)?;
+        index.remove_snapshot(&manifest);
+        manifests.push(manifest.snapshot_id);
+    }
+    repo.save_index(&index)?;
+    for snapshot_id in manifests {
+        info!("Removing snapshot {}", snapshot_id);
+        repo.delete_manifest(&snapshot_id)?;
+    }
+
+    let pruned = if options.prune {
+        Some(prune_repository(repo, false)?)
+    } else {
+        None
+    };
+    Ok((report, pruned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::backup::{backup_with_options, BackupOptions};
+    use crate::commands::{backup, check_repository, ReadData};
+    use assert_fs::prelude::*;
+    use tempfile::TempDir;
+
+    /// Back up `source` once per creation time, rewriting the manifest to
+    /// carry that time
+    fn backup_at(repo: &Repository, locator: &RepoLocator, source: &assert_fs::TempDir, times: &[&str]) {
+        for time in times {
+            source.child("changing.txt").write_str(time).unwrap();
+            let before = repo.snapshot_ids().unwrap();
+            backup(source.path(), locator).unwrap();
+            let id = repo
+                .snapshot_ids()
+                .unwrap()
+                .into_iter()
+                .find(|id| !before.contains(id))
+                .unwrap();
+            let mut manifest = repo.load_manifest(&id, &repo.chunk_store()).unwrap();
+            manifest.created_at = time.to_string();
+            repo.save_manifest(&manifest).unwrap();
+        }
+    }
+
+    fn kept_times(repo: &Repository) -> Vec<String> {
+        let chunk_store = repo.chunk_store();
+        let mut times: Vec<String> = repo
+            .snapshot_ids()
+            .unwrap()
+            .iter()
+            .map(|id| repo.load_manifest(id, &chunk_store).unwrap().created_at)
+            .collect();
+        times.sort();
+        times
+    }
+
+    #[test]
+    fn test_forget_applies_policy() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("shared.txt").write_str("in every snapshot").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup_at(
+            &repo,
+            &locator,
+            &source,
+            &[
+                "2026-03-01T12:00:00+00:00",
+                "2026-03-02T08:00:00+00:00",
+                "2026-03-02T20:00:00+00:00",
+                "2026-03-03T12:00:00+00:00",
+            ],
+        );
+        let chunks_before = repo.chunk_store().list_chunks().unwrap().len();
+
+        let options = ForgetOptions {
+            policy: RetentionPolicy {
+                daily: 2,
+                ..RetentionPolicy::default()
+            },
+            ..ForgetOptions::default()
+        };
+        let (report, pruned) = forget_repository(&repo, &options, &Utc).unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.removed().len(), 2);
+        assert!(pruned.is_none());
+        assert_eq!(
+            kept_times(&repo),
+            vec!["2026-03-02T20:00:00+00:00", "2026-03-03T12:00:00+00:00"]
+        );
+
+        // The index no longer refers to the removed snapshots, but their
+        // chunks stay until a prune
+        let index = repo.load_index().unwrap();
+        assert_eq!(index.snapshot_ids().len(), 2);
+        assert_eq!(repo.chunk_store().list_chunks().unwrap().len(), chunks_before);
+        assert!(check_repository(&repo, ReadData::None).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_forget_with_prune_deletes_orphans() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup_at(
+            &repo,
+            &locator,
+            &source,
+            &["2026-01-01T00:00:00+00:00", "2026-02-01T00:00:00+00:00"],
+        );
+        let chunks_before = repo.chunk_store().list_chunks().unwrap().len();
+
+        let options = ForgetOptions {
+            policy: RetentionPolicy {
+                last: 1,
+                ..RetentionPolicy::default()
+            },
+            prune: true,
+            ..ForgetOptions::default()
+        };
+        let (_, pruned) = forget_repository(&repo, &options, &Utc).unwrap();
+        // Its version of the changing file and its root tree
+        assert_eq!(pruned.unwrap().deleted_chunks, 2);
+        assert_eq!(repo.chunk_store().list_chunks().unwrap().len(), chunks_before - 2);
+        assert!(check_repository(&repo, ReadData::All).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_forget_groups_snapshots() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let first = assert_fs::TempDir::new().unwrap();
+        let second = assert_fs::TempDir::new().unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup_at(&repo, &locator, &first, &["2026-01-01T00:00:00+00:00", "2026-01-02T00:00:00+00:00"]);
+        backup_at(&repo, &locator, &second, &["2026-01-03T00:00:00+00:00"]);
+        let tagged = BackupOptions {
+            tags: vec!["manual".to_string()],
+            ..BackupOptions::default()
+        };
+        backup_with_options(second.path(), &locator, &tagged).unwrap();
+
+        let mut options = ForgetOptions {
+            policy: RetentionPolicy {
+                last: 1,
+                ..RetentionPolicy::default()
+            },
+            dry_run: true,
+            ..ForgetOptions::default()
+        };
+        let (report, _) = forget_repository(&repo, &options, &Utc).unwrap();
+        assert_eq!(report.groups.len(), 3);
+        assert_eq!(report.removed().len(), 1);
+
+        options.group_by = "host".parse().unwrap();
+        let (report, _) = forget_repository(&repo, &options, &Utc).unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.removed().len(), 3);
+
+        // Dry runs remove nothing
+        assert_eq!(repo.snapshot_ids().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_forget_requires_a_rule() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        Repository::init(&repo_path).unwrap();
+
+        assert!(matches!(
+            forget(&locator, &ForgetOptions::default()),
+            Err(SnapVaultError::InvalidRetentionPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_group_by() {
+        assert_eq!("source,host,tags".parse::<GroupBy>().unwrap(), GroupBy::default());
+        let host = "host".parse::<GroupBy>().unwrap();
+        assert!(host.host && !host.source && !host.tags);
+        assert!(!"".parse::<GroupBy>().unwrap().source);
+        assert!("path".parse::<GroupBy>().is_err());
+    }
+}
@@ -1,9 +1,49 @@
+use crate::chunking::ChunkerConfig;
+use crate::compression::CompressionConfig;
+use crate::crypto::{self, EncryptionConfig};
 use crate::error::Result;
+use crate::pack::DEFAULT_PACK_SIZE;
+use crate::repository::config::RepoConfig;
+use crate::repository::locator::RepoLocator;
 use crate::repository::Repository;
-use std::path::Path;
 
-pub fn init(repo_path: &Path) -> Result<()> {
-    Repository::init(repo_path)?;
+/// Options for initializing a repository
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    /// Chunking algorithm recorded in the repository config
+    pub chunker: ChunkerConfig,
+    /// Compression applied to stored chunks
+    pub compression: CompressionConfig,
+    /// Encryption of repository objects
+    pub encryption: EncryptionConfig,
+    /// Target pack file size in bytes (the default when unset)
+    pub pack_size: Option<u64>,
+    /// Passphrase for encrypted repositories. If unset, it is taken from the
+    /// environment (see `crypto::passphrase_from_env`) or prompted for.
+    pub passphrase: Option<String>,
+}
+
+pub fn init(repo_location: &RepoLocator) -> Result<()> {
+    init_with_options(repo_location, &InitOptions::default())
+}
+
+pub fn init_with_options(repo_location: &RepoLocator, options: &InitOptions) -> Result<()> {
+    let config = RepoConfig {
+        chunker: options.chunker,
+        compression: options.compression,
+        encryption: options.encryption,
+        pack_size: options.pack_size.unwrap_or(DEFAULT_PACK_SIZE),
+        ..RepoConfig::new()
+    };
+    let passphrase = match (&options.passphrase, options.encryption.is_enabled()) {
+        (Some(p), true) => Some(p.clone()),
+        (None, true) => match crypto::passphrase_from_env()? {
+            Some(p) => Some(p),
+            None => Some(crypto::read_new_passphrase(crypto::PASSWORD_ENV)?),
+        },
+        (_, false) => None,
+    };
+    Repository::init_at(repo_location, config, passphrase.as_deref())?;
     Ok(())
 }
 
@@ -16,11 +56,78 @@ mod tests {
     fn test_init_command() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
 
-        init(&repo_path).unwrap();
+        init(&locator).unwrap();
         assert!(repo_path.exists());
         assert!(repo_path.join("config.json").exists());
         assert!(repo_path.join("snapshots").is_dir());
         assert!(repo_path.join("data").is_dir());
     }
+
+    #[test]
+    fn test_init_with_fastcdc() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+
+        let options = InitOptions {
+            chunker: ChunkerConfig::fastcdc(),
+            ..InitOptions::default()
+        };
+        init_with_options(&locator, &options).unwrap();
+
+        let raw = std::fs::read_to_string(repo_path.join("config.json")).unwrap();
+        assert!(raw.contains("fastcdc"));
+    }
+
+    #[test]
+    fn test_init_encrypted() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+
+        let options = InitOptions {
+            encryption: EncryptionConfig::XChaCha20Poly1305,
+            passphrase: Some("secret".to_string()),
+            ..InitOptions::default()
+        };
+        init_with_options(&locator, &options).unwrap();
+
+        assert!(repo_path.join("keys").is_dir());
+        assert!(Repository::open_with_passphrase(&repo_path, "secret").unwrap().is_encrypted());
+    }
+
+    #[test]
+    fn test_init_with_lz4() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+
+        let options = InitOptions {
+            compression: CompressionConfig::Lz4,
+            ..InitOptions::default()
+        };
+        init_with_options(&locator, &options).unwrap();
+
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(repo.config().compression, CompressionConfig::Lz4);
+    }
+
+    #[test]
+    fn test_init_with_pack_size() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+
+        let options = InitOptions {
+            pack_size: Some(64 * 1024 * 1024),
+            ..InitOptions::default()
+        };
+        init_with_options(&locator, &options).unwrap();
+
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(repo.config().pack_size, 64 * 1024 * 1024);
+        assert!(repo_path.join("data").join("packs").is_dir());
+    }
 }
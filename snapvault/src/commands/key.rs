@@ -0,0 +1,79 @@
+use crate::crypto::{self, NEW_PASSWORD_ENV};
+use crate::error::Result;
+use crate::repository::locator::RepoLocator;
+use crate::repository::Repository;
+use log::info;
+
+/// List all key slots of an encrypted repository
+pub fn key_list(repo_location: &RepoLocator) -> Result<()> {
+    let repo = Repository::open_at(repo_location, None)?;
+    let keys = repo.list_keys()?;
+
+    println!("Keys in repository {}:", repo_location);
+    println!("  {:<10} {:<34} Label", "ID", "Created At");
+    println!("{}", "-".repeat(60));
+    for (key_id, key_file) in keys {
+        let marker = if repo.current_key_id() == Some(key_id.as_str()) {
+            "*"
+        } else {
+            " "
+        };
+        println!(
+            "{} {:<10} {:<34} {}",
+            marker,
+            key_id,
+            key_file.created_at,
+            key_file.label.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
+
+/// Add a key slot with a new passphrase (read from SNAPVAULT_NEW_PASSWORD or prompted for)
+pub fn key_add(repo_location: &RepoLocator, label: Option<&str>) -> Result<()> {
+    let repo = Repository::open_at(repo_location, None)?;
+    let passphrase = crypto::read_new_passphrase(NEW_PASSWORD_ENV)?;
+
+    let key_id = repo.add_key(&passphrase, label.map(str::to_string))?;
+    println!("✓ Added key {}", key_id);
+    Ok(())
+}
+
+/// Remove a key slot, revoking its passphrase
+pub fn key_remove(repo_location: &RepoLocator, key_id: &str) -> Result<()> {
+    let repo = Repository::open_at(repo_location, None)?;
+    info!("Removing key {} from repository {}", key_id, repo_location);
+
+    repo.remove_key(key_id)?;
+    println!("✓ Removed key {}", key_id);
+    Ok(())
+}
+
+/// Change the passphrase of the key slot used to open the repository
+pub fn key_passwd(repo_location: &RepoLocator) -> Result<()> {
+    let mut repo = Repository::open_at(repo_location, None)?;
+    let passphrase = crypto::read_new_passphrase(NEW_PASSWORD_ENV)?;
+
+    let key_id = repo.change_passphrase(&passphrase)?;
+    println!("✓ Passphrase changed (new key {})", key_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SnapVaultError;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_key_list_unencrypted_repo() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+
+        Repository::init(&repo_path).unwrap();
+        let result = key_list(&locator);
+        assert!(matches!(result, Err(SnapVaultError::NotEncrypted)));
+    }
+}
@@ -1,13 +1,12 @@
 use crate::error::{Result, SnapVaultError};
+use crate::repository::lock::LockMode;
 use crate::repository::snapshot::SnapshotManifest;
+use crate::repository::locator::RepoLocator;
 use crate::repository::Repository;
-use crate::utils::MAX_MANIFEST_SIZE;
 use log::{info, warn};
-use std::fs;
-use std::path::Path;
 
 /// Format a size in bytes to human-readable format
-fn format_size(bytes: u64) -> String {
+pub(crate) fn format_size(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
     let mut size = bytes as f64;
     let mut unit_idx = 0;
@@ -24,48 +23,30 @@ fn format_size(bytes: u64) -> String {
     }
 }
 
-pub fn list(repo_path: &Path) -> Result<()> {
-    info!("Listing snapshots in repository: {}", repo_path.display());
+pub fn list(repo_location: &RepoLocator) -> Result<()> {
+    info!("Listing snapshots in repository: {}", repo_location);
 
-    let repo = Repository::open(repo_path)?;
-    let snapshots_dir = repo.snapshots_dir();
-
-    if !snapshots_dir.exists() {
-        println!("No snapshots found in repository.");
-        return Ok(());
-    }
+    let repo = Repository::open_at(repo_location, None)?;
+    let _lock = repo.lock(LockMode::Shared)?;
 
     let mut snapshots: Vec<SnapshotManifest> = Vec::new();
-    for entry in fs::read_dir(&snapshots_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.extension() == Some(std::ffi::OsStr::new("json")) {
-            // Security: Check manifest size before reading
-            let metadata = fs::metadata(&path)?;
-            if metadata.len() > MAX_MANIFEST_SIZE {
+    for snapshot_id in repo.snapshot_ids()? {
+        match repo.load_manifest_header(&snapshot_id) {
+            Ok(manifest) => snapshots.push(manifest),
+            Err(SnapVaultError::FileTooLarge { size, .. }) => {
                 warn!(
                     "Skipping oversized manifest: {} ({} bytes)",
-                    path.display(),
-                    metadata.len()
+                    snapshot_id,
+                    size
                 );
-                continue;
             }
-
-            let raw = fs::read_to_string(&path).map_err(|e| {
-                SnapVaultError::Io(std::io::Error::new(
-                    e.kind(),
-                    format!("Failed to read manifest {}: {}", path.display(), e),
-                ))
-            })?;
-            
-            let manifest: SnapshotManifest = serde_json::from_str(&raw).map_err(|e| {
-                SnapVaultError::Other(format!(
-                    "Failed to parse manifest {}: {}",
-                    path.display(),
+            Err(e) => {
+                return Err(SnapVaultError::Other(format!(
+                    "Failed to load manifest {}: {}",
+                    snapshot_id,
                     e
-                ))
-            })?;
-            snapshots.push(manifest);
+                )));
+            }
         }
     }
 
@@ -77,10 +58,10 @@ pub fn list(repo_path: &Path) -> Result<()> {
     // Sort by created_at descending (latest first)
     snapshots.sort_by(|a, b| b.created_at.cmp(&a.created_at));
 
-    println!("Snapshots in repository {}:", repo_path.display());
+    println!("Snapshots in repository {}:", repo_location);
     println!(
-        "{:<40} {:<25} {:<6} {:<12} {:<12} {:<8} {}",
-        "Snapshot ID", "Created At", "Files", "Size", "Stored", "Dedup%", "Source"
+        "{:<40} {:<25} {:<6} {:<12} {:<12} {:<8} Source",
+        "Snapshot ID", "Created At", "Files", "Size", "Stored", "Dedup%"
     );
     println!("{}", "-".repeat(120));
 
@@ -112,21 +93,23 @@ mod tests {
     fn test_list_empty_repository() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
 
         Repository::init(&repo_path).unwrap();
-        list(&repo_path).unwrap(); // Should not error on empty repo
+        list(&locator).unwrap(); // Should not error on empty repo
     }
 
     #[test]
     fn test_list_with_snapshots() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         source.child("file1.txt").write_str("content1").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
-        list(&repo_path).unwrap(); // Should list 1 snapshot
+        list(&locator).unwrap(); // Should list 1 snapshot
     }
 }
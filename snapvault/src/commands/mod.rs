@@ -1,11 +1,21 @@
 pub mod backup;
+pub mod check;
 pub mod delete;
+pub mod forget;
 pub mod init;
+pub mod key;
 pub mod list;
+pub mod prune;
 pub mod restore;
+pub mod unlock;
 
-pub use backup::backup;
+pub use backup::{backup, backup_with_options, BackupOptions};
+pub use check::{check, check_repository, CheckProblem, CheckReport, Percentage, ReadData};
 pub use delete::delete;
-pub use init::init;
+pub use forget::{forget, forget_repository, ForgetDecision, ForgetOptions, ForgetReport, GroupBy, GroupKey};
+pub use init::{init, init_with_options, InitOptions};
+pub use key::{key_add, key_list, key_passwd, key_remove};
 pub use list::list;
-pub use restore::restore;
+pub use prune::{prune, prune_repository, PruneReport};
+pub use restore::{restore, restore_with_options, RestoreOptions};
+pub use unlock::unlock;
@@ -0,0 +1,214 @@
+use crate::backend::ObjectKind;
+use crate::chunking::ChunkHash;
+use crate::commands::list::format_size;
+use crate::error::Result;
+use crate::index::ChunkIndex;
+use crate::repository::locator::RepoLocator;
+use crate::repository::lock::LockMode;
+use crate::repository::Repository;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+
+/// Outcome of a prune
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    /// Chunks in the chunk store
+    pub total_chunks: usize,
+    /// Chunks no snapshot references
+    pub orphaned_chunks: Vec<ChunkHash>,
+    /// Stored size of the orphaned chunks
+    pub reclaimable_bytes: u64,
+    /// Orphaned chunks actually deleted (0 for a dry run)
+    pub deleted_chunks: usize,
+    /// Fewer pack files than before, as packs left empty are removed and
+    /// the chunks still used in the others are repacked
+    pub removed_packs: usize,
+}
+
+/// Delete chunks no snapshot references, or only report them with `dry_run`
+pub fn prune(repo_location: &RepoLocator, dry_run: bool) -> Result<()> {
+    let repo = Repository::open_at(repo_location, None)?;
+    let _lock = repo.lock(LockMode::Exclusive)?;
+    info!("Pruning repository {}", repo_location);
+
+    let report = prune_repository(&repo, dry_run)?;
+
+    println!("Pruned repository {}:", repo_location);
+    print_report(&report, dry_run);
+    Ok(())
+}
+
+/// Print the chunk counts of a prune and what was deleted
+pub(crate) fn print_report(report: &PruneReport, dry_run: bool) {
+    println!("  Chunks:           {}", report.total_chunks);
+    println!("  Orphaned chunks:  {}", report.orphaned_chunks.len());
+    println!(
+        "  Reclaimable:      {} ({} bytes)",
+        format_size(report.reclaimable_bytes),
+        report.reclaimable_bytes
+    );
+    if dry_run {
+        println!("✓ Dry run: nothing was deleted");
+    } else {
+        println!(
+            "✓ Deleted {} chunks, {} fewer packs",
+            report.deleted_chunks, report.removed_packs
+        );
+    }
+}
+
+/// Rebuild chunk references from every snapshot manifest and delete the
+/// chunks none of them use. The caller must hold an exclusive lock: a
+/// backup in progress has stored chunks no manifest refers to yet.
+pub fn prune_repository(repo: &Repository, dry_run: bool) -> Result<PruneReport> {
+    // Fails if any manifest cannot be read, as judging orphans without it
+    // would delete chunks that snapshot still needs
+    let index = ChunkIndex::rebuild(repo)?;
+
+    let chunk_store = repo.chunk_store();
+    let stored: HashMap<ChunkHash, u64> = chunk_store.list_chunks()?.into_iter().collect();
+    let stored_hashes: HashSet<ChunkHash> = stored.keys().cloned().collect();
+    let orphans = index.find_orphans(&stored_hashes);
+
+    let mut report = PruneReport {
+        total_chunks: stored.len(),
+        reclaimable_bytes: orphans.iter().map(|hash| stored[hash]).sum(),
+        orphaned_chunks: orphans.into_iter().collect(),
+        ..PruneReport::default()
+    };
+    info!(
+        "Found {} orphaned chunks out of {} ({} bytes)",
+        report.orphaned_chunks.len(),
+        report.total_chunks,
+        report.reclaimable_bytes
+    );
+    if dry_run {
+        return Ok(report);
+    }
+
+    let packs_before = repo.backend().list(ObjectKind::Pack)?.len();
+    for hash in &report.orphaned_chunks {
+        match chunk_store.delete(hash) {
+            Ok(()) => report.deleted_chunks += 1,
+            Err(e) => warn!("Failed to delete chunk {}: {}", hash, e),
+        }
+    }
+    chunk_store.flush()?;
+    report.removed_packs = packs_before.saturating_sub(repo.backend().list(ObjectKind::Pack)?.len());
+
+    // The rebuilt index is exact; replace whatever index.json held
+    repo.save_index(&index)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{backup, check_repository, restore, ReadData};
+    use assert_fs::prelude::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_prune_removes_orphans() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("kept.txt").write_str("still referenced").unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+
+        // Chunks left behind by an interrupted backup, in a pack of their own
+        let chunk_store = repo.chunk_store();
+        let hasher = repo.chunk_hasher();
+        for data in [&b"orphan one"[..], b"orphan two"] {
+            chunk_store.store(&hasher.hash(data), data).unwrap();
+        }
+        chunk_store.flush().unwrap();
+        drop(chunk_store);
+
+        let report = prune_repository(&repo, true).unwrap();
+        assert_eq!(report.orphaned_chunks.len(), 2);
+        assert!(report.reclaimable_bytes > 0);
+        assert_eq!(report.deleted_chunks, 0);
+        assert_eq!(repo.chunk_store().list_chunks().unwrap().len(), report.total_chunks);
+
+        let packs_before = repo.backend().list(ObjectKind::Pack).unwrap().len();
+        prune(&locator, false).unwrap();
+        assert_eq!(repo.chunk_store().list_chunks().unwrap().len(), report.total_chunks - 2);
+        assert_eq!(repo.backend().list(ObjectKind::Pack).unwrap().len(), packs_before - 1);
+        assert!(check_repository(&repo, ReadData::All).unwrap().is_ok());
+
+        let dest = temp.path().join("restored");
+        restore(None, &dest, &locator).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("kept.txt")).unwrap(), "still referenced");
+
+        // Nothing is left to prune
+        assert!(prune_repository(&repo, false).unwrap().orphaned_chunks.is_empty());
+    }
+
+    /// Bytes of all pack files in a local repository
+    fn packs_size(repo_path: &std::path::Path) -> u64 {
+        walkdir::WalkDir::new(repo_path.join("data").join("packs"))
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn test_prune_shrinks_partly_used_packs() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("kept.txt").write_str("still referenced").unwrap();
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        source.child("gone.bin").write_binary(&noise).unwrap();
+
+        // The chunks of gone.bin share a pack with those of kept.txt, which
+        // the second snapshot still uses once the first one is gone
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let first = repo.snapshot_ids().unwrap().remove(0);
+        repo.delete_manifest(&first).unwrap();
+        fs::remove_file(source.path().join("gone.bin")).unwrap();
+        backup(source.path(), &locator).unwrap();
+
+        let before = packs_size(&repo_path);
+        let report = prune_repository(&repo, false).unwrap();
+        assert!(report.reclaimable_bytes > noise.len() as u64);
+        assert!(before - packs_size(&repo_path) >= report.reclaimable_bytes);
+        assert!(check_repository(&repo, ReadData::All).unwrap().is_ok());
+
+        let dest = temp.path().join("restored");
+        restore(None, &dest, &locator).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("kept.txt")).unwrap(), "still referenced");
+        assert!(!dest.join("gone.bin").exists());
+    }
+
+    #[test]
+    fn test_prune_blocked_during_backup() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let repo = Repository::init(&repo_path).unwrap();
+
+        let _backup_lock = repo.lock(LockMode::Shared).unwrap();
+        assert!(matches!(
+            prune(&locator, false),
+            Err(crate::error::SnapVaultError::RepoLocked { .. })
+        ));
+    }
+}
@@ -1,46 +1,80 @@
+use crate::cache::ChunkCache;
+use crate::chunking::ChunkHash;
+use crate::commands::backup::default_threads;
 use crate::error::{Result, SnapVaultError};
-use crate::repository::snapshot::SnapshotManifest;
+use crate::metadata::{apply_metadata, Accounts, Ownership};
+use crate::repository::locator::RepoLocator;
+use crate::repository::lock::LockMode;
+use crate::repository::snapshot::{EntryKind, FileRecord, SnapshotManifest};
 use crate::repository::Repository;
+use crate::sparse::SparseWriter;
 use crate::storage::ChunkStore;
-use crate::utils::{is_safe_path, validate_snapshot_id, MAX_MANIFEST_SIZE};
+use crate::utils::{is_safe_link_target, is_safe_path, validate_snapshot_id};
+use crate::xattrs::{XattrFilter, XattrWriter};
 use log::{info, warn};
-use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
 
-pub fn restore(snapshot_id_opt: Option<&str>, dest_path: &Path, repo_path: &Path) -> Result<()> {
-    let repo = Repository::open(repo_path)?;
+/// Memory for chunks needed again later in a restore, used when none is given
+pub const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024; // 256 MiB
+
+/// Options for restoring a snapshot
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Number of files written at once (one per CPU if unset)
+    pub threads: Option<usize>,
+    /// Bytes of chunks kept in memory for the files still needing them
+    /// (`DEFAULT_CACHE_SIZE` if unset)
+    pub cache_size: Option<u64>,
+    /// Restore owners by their recorded numeric IDs, ignoring user and group
+    /// names. Without it, owners are only restored when running as root.
+    pub numeric_owner: bool,
+    /// Which recorded extended attributes to set
+    pub xattrs: XattrFilter,
+    /// Also leave chunks of zeros unwritten, making holes of them even where
+    /// none were recorded
+    pub sparse: bool,
+}
+
+pub fn restore(
+    snapshot_id_opt: Option<&str>,
+    dest_path: &Path,
+    repo_location: &RepoLocator,
+) -> Result<()> {
+    restore_with_options(
+        snapshot_id_opt,
+        dest_path,
+        repo_location,
+        &RestoreOptions::default(),
+    )
+}
+
+pub fn restore_with_options(
+    snapshot_id_opt: Option<&str>,
+    dest_path: &Path,
+    repo_location: &RepoLocator,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let repo = Repository::open_at(repo_location, None)?;
+    let _lock = repo.lock(LockMode::Shared)?;
 
     // Determine snapshot ID
     let snapshot_id = if let Some(id) = snapshot_id_opt {
         // Security: Validate snapshot ID
         validate_snapshot_id(id)?;
 
-        if !repo
-            .snapshots_dir()
-            .join(format!("{}.json", id))
-            .exists()
-        {
+        if !repo.snapshot_exists(id)? {
             return Err(SnapVaultError::SnapshotNotFound(id.to_string()));
         }
         id.to_string()
     } else {
         // Find latest snapshot
-        let snapshots_dir = repo.snapshots_dir();
-        if !snapshots_dir.exists() {
-            return Err(SnapVaultError::NoSnapshots);
-        }
-        let mut snapshots: Vec<String> = fs::read_dir(&snapshots_dir)?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let path = entry.path();
-                if path.extension() == Some(std::ffi::OsStr::new("json")) {
-                    Some(path.file_stem()?.to_string_lossy().to_string())
-                } else {
-                    None
-                }
-            })
-            .collect();
+        let mut snapshots = repo.snapshot_ids()?;
         if snapshots.is_empty() {
             return Err(SnapVaultError::NoSnapshots);
         }
@@ -71,42 +105,107 @@ pub fn restore(snapshot_id_opt: Option<&str>, dest_path: &Path, repo_path: &Path
         fs::create_dir_all(dest_path)?;
     }
 
+    // Initialize chunk storage
+    let chunk_store = repo.chunk_store();
+
     // Load manifest
-    let manifest_path = repo
-        .snapshots_dir()
-        .join(format!("{}.json", snapshot_id));
-    if !manifest_path.is_file() {
-        return Err(SnapVaultError::SnapshotNotFound(snapshot_id.clone()));
-    }
-
-    // Security: Check manifest size before reading
-    let metadata = fs::metadata(&manifest_path)?;
-    if metadata.len() > MAX_MANIFEST_SIZE {
-        return Err(SnapVaultError::FileTooLarge {
-            size: metadata.len(),
-            max: MAX_MANIFEST_SIZE,
-        });
-    }
-
-    let raw = fs::read_to_string(&manifest_path)?;
-    let manifest: SnapshotManifest = serde_json::from_str(&raw)?;
-    if manifest.snapshot_id != snapshot_id {
-        return Err(SnapVaultError::Other(
-            "Manifest snapshot ID mismatch".to_string(),
-        ));
+    let manifest = repo.load_manifest(&snapshot_id, &chunk_store)?;
+
+    // Restore files by reassembling chunks
+    let stats = restore_files(&manifest, &chunk_store, dest_path, options)?;
+
+    println!("✓ Restore complete");
+    println!("  Snapshot:     {}", snapshot_id);
+    println!("  Files:        {}", stats.files);
+    println!("  Directories:  {}", stats.directories);
+    println!(
+        "  Links:        {} symlinks, {} hardlinks",
+        stats.symlinks, stats.hardlinks
+    );
+    println!("  Special:      {}", stats.special_files);
+    if stats.skipped > 0 {
+        println!("  Skipped:      {}", stats.skipped);
     }
+    println!("  Chunks read:  {}", stats.fetched);
+    println!(
+        "  Reused:       {} from memory, {} from restored files",
+        stats.cached, stats.copied
+    );
+    println!("  Destination:  {}", dest_path.display());
 
-    // Initialize chunk storage
-    let chunk_store = ChunkStore::new(repo.chunks_dir());
+    Ok(())
+}
 
-    // Restore files by reassembling chunks
-    let mut restored_count = 0;
-    let total_files = manifest.files.len();
+/// Statistics about a restore operation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct RestoreStats {
+    files: usize,
+    directories: usize,
+    symlinks: usize,
+    hardlinks: usize,
+    /// FIFOs and devices
+    special_files: usize,
+    /// Entries left out as unsafe or impossible to recreate
+    skipped: usize,
+    /// Chunks read from the repository
+    fetched: usize,
+    /// Chunk uses served from the in-memory cache
+    cached: usize,
+    /// Chunk uses copied from a file the chunk was already restored to
+    copied: usize,
+}
+
+/// Write every file of `manifest` under `dest_path`. `options.threads`
+/// workers each take the next file in manifest order, leaving the holes of
+/// sparse files unwritten. Every chunk is read from the repository once,
+/// however many files use it: while files still need it, it is kept in a
+/// cache of `options.cache_size` bytes, and once evicted from there it is
+/// copied from the first place it was restored to. Hard links, FIFOs and
+/// devices follow, then permissions, the extended attributes the options
+/// allow, timestamps and owners are set. Symlinks come after, so nothing is
+/// written through them, and only if they point inside `dest_path`.
+/// Directories get their metadata last, deepest first, as creating their
+/// children would change their modification times and a read-only mode
+/// would prevent it.
+fn restore_files(
+    manifest: &SnapshotManifest,
+    chunk_store: &ChunkStore,
+    dest_path: &Path,
+    options: &RestoreOptions,
+) -> Result<RestoreStats> {
+    let threads = options.threads.unwrap_or_else(default_threads);
+    let cache_size = options.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+    let ownership = Ownership::for_restore(options.numeric_owner);
+    let sparse = options.sparse;
+
+    let symlink_paths: HashSet<&str> = manifest
+        .files
+        .iter()
+        .filter(|file| matches!(file.kind, EntryKind::Symlink { .. }))
+        .map(|file| file.rel_path.as_str())
+        .collect();
 
-    for file in manifest.files.iter() {
+    let mut stats = RestoreStats::default();
+    let mut files = Vec::new();
+    let mut hardlinks = Vec::new();
+    let mut special_files = Vec::new();
+    let mut symlinks = Vec::new();
+    let mut directories = Vec::new();
+    for file in &manifest.files {
         // Security: Validate path safety
         if !is_safe_path(&file.rel_path) {
             warn!("Skipping unsafe path: {}", file.rel_path);
+            stats.skipped += 1;
+            continue;
+        }
+        // Security: Entries below a symlink would be written where it points
+        if Path::new(&file.rel_path)
+            .ancestors()
+            .skip(1)
+            .any(|dir| dir.to_str().is_some_and(|dir| symlink_paths.contains(dir)))
+        {
+            warn!("Skipping path below a symlink: {}", file.rel_path);
+            stats.skipped += 1;
             continue;
         }
 
@@ -116,37 +215,388 @@ pub fn restore(snapshot_id_opt: Option<&str>, dest_path: &Path, repo_path: &Path
         if let Some(parent) = dst_path.parent() {
             fs::create_dir_all(parent)?;
         }
+        match file.kind {
+            EntryKind::File => files.push((file, dst_path)),
+            EntryKind::Directory => {
+                fs::create_dir_all(&dst_path)?;
+                directories.push((file, dst_path));
+            }
+            EntryKind::Hardlink { .. } => hardlinks.push((file, dst_path)),
+            EntryKind::Symlink { .. } => symlinks.push((file, dst_path)),
+            _ => special_files.push((file, dst_path)),
+        }
+    }
+
+    let source = ChunkSource::new(
+        chunk_store,
+        cache_size,
+        files.iter().flat_map(|(file, _)| &file.chunks),
+    );
+    let next = AtomicUsize::new(0);
+    let restored_count = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let (files, source, next, restored_count, failed) =
+            (&files, &source, &next, &restored_count, &failed);
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                scope.spawn(move || -> Result<()> {
+                    // Stop taking files once any worker has failed
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some((file, dst_path)) = files.get(next.fetch_add(1, Ordering::Relaxed))
+                        else {
+                            break;
+                        };
+                        if let Err(e) = restore_file(file, dst_path, source, sparse) {
+                            failed.store(true, Ordering::Relaxed);
+                            return Err(e);
+                        }
 
-        // Reassemble file from chunks
-        let mut output_file = fs::File::create(&dst_path)?;
-        
-        for chunk_hash in &file.chunks {
-            // Read chunk from storage
-            let chunk_data = chunk_store.read(chunk_hash)?;
-            
-            // Write chunk to output file
-            output_file.write_all(&chunk_data)?;
+                        // Log progress every 100 files
+                        let count = restored_count.fetch_add(1, Ordering::Relaxed) + 1;
+                        if count % 100 == 0 {
+                            info!("Restored {}/{} files", count, files.len());
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect::<Result<Vec<()>>>()
+    })?;
+
+    // Link each hard link to the regular file first seen with its inode
+    let restored: HashSet<&str> = files.iter().map(|(file, _)| file.rel_path.as_str()).collect();
+    for (file, dst_path) in &hardlinks {
+        let EntryKind::Hardlink { target } = &file.kind else {
+            continue;
+        };
+        if !restored.contains(target.as_str()) {
+            warn!("Skipping hard link {}: {} was not restored", file.rel_path, target);
+            stats.skipped += 1;
+            continue;
         }
-        
-        // Ensure all data is written to disk
-        output_file.sync_all()?;
+        match fs::hard_link(dest_path.join(target), dst_path) {
+            Ok(()) => stats.hardlinks += 1,
+            Err(e) => {
+                warn!("Failed to create hard link {}: {}", dst_path.display(), e);
+                stats.skipped += 1;
+            }
+        }
+    }
 
-        restored_count += 1;
+    let mut created = Vec::new();
+    for (file, dst_path) in special_files {
+        match create_special_file(&file.kind, &dst_path) {
+            Ok(()) => {
+                stats.special_files += 1;
+                created.push((file, dst_path));
+            }
+            Err(e) => {
+                warn!("Skipping {}: {}", file.rel_path, e);
+                stats.skipped += 1;
+            }
+        }
+    }
 
-        // Log progress every 100 files
-        if restored_count % 100 == 0 {
-            info!("Restored {}/{} files", restored_count, total_files);
+    // Only now, as reading chunks back from restored files would disturb
+    // their access times, and a read-only mode would get in the way. Hard
+    // links share the metadata of the file they link to.
+    let mut accounts = Accounts::default();
+    let mut xattrs = XattrWriter::new(options.xattrs.clone());
+    for (file, dst_path) in files.iter().chain(&created) {
+        if let Err(e) = apply_metadata(file, dst_path, ownership, &mut accounts, &mut xattrs) {
+            warn!("Failed to restore metadata of {}: {}", dst_path.display(), e);
         }
     }
 
-    println!("✓ Restore complete");
-    println!("  Snapshot:     {}", snapshot_id);
-    println!("  Files:        {}", restored_count);
-    println!("  Destination:  {}", dest_path.display());
+    for (file, dst_path) in &symlinks {
+        let EntryKind::Symlink { target } = &file.kind else {
+            continue;
+        };
+        // Security: A link leading outside the destination could expose or
+        // overwrite files there when the restored tree is used
+        if !is_safe_link_target(&file.rel_path, target) {
+            warn!("Skipping symlink {} to unsafe target: {}", file.rel_path, target);
+            stats.skipped += 1;
+            continue;
+        }
+        if let Err(e) = create_symlink(target, dst_path) {
+            warn!("Failed to create symlink {}: {}", dst_path.display(), e);
+            stats.skipped += 1;
+            continue;
+        }
+        stats.symlinks += 1;
+        if let Err(e) = apply_metadata(file, dst_path, ownership, &mut accounts, &mut xattrs) {
+            warn!("Failed to restore metadata of {}: {}", dst_path.display(), e);
+        }
+    }
+
+    // The manifest lists directories before their contents
+    for (file, dst_path) in directories.iter().rev() {
+        if let Err(e) = apply_metadata(file, dst_path, ownership, &mut accounts, &mut xattrs) {
+            warn!("Failed to restore metadata of {}: {}", dst_path.display(), e);
+        }
+    }
+
+    let chunks = source.stats();
+    Ok(RestoreStats {
+        files: files.len(),
+        directories: directories.len(),
+        fetched: chunks.fetched,
+        cached: chunks.cached,
+        copied: chunks.copied,
+        ..stats
+    })
+}
+
+/// Create a FIFO or device node. Sockets belong to the program listening on
+/// them and cannot be recreated.
+#[cfg(unix)]
+fn create_special_file(kind: &EntryKind, path: &Path) -> io::Result<()> {
+    use nix::sys::stat::{mknod, Mode, SFlag};
+    use nix::unistd::mkfifo;
+
+    // The recorded permissions are set afterwards
+    let mode = Mode::from_bits_truncate(0o600);
+    match kind {
+        EntryKind::Fifo => mkfifo(path, mode)?,
+        EntryKind::CharDevice { rdev } => mknod(path, SFlag::S_IFCHR, mode, *rdev as _)?,
+        EntryKind::BlockDevice { rdev } => mknod(path, SFlag::S_IFBLK, mode, *rdev as _)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "sockets cannot be restored",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_special_file(_kind: &EntryKind, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "special files cannot be restored on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks cannot be restored on this platform",
+    ))
+}
 
+/// Reassemble one file from its chunks around its holes. With `sparse`,
+/// chunks of zeros become holes as well.
+fn restore_file(
+    file: &FileRecord,
+    dst_path: &Path,
+    source: &ChunkSource,
+    sparse: bool,
+) -> Result<()> {
+    let mut writer = SparseWriter::new(File::create(dst_path)?, &file.holes);
+    for chunk_hash in &file.chunks {
+        let chunk_data = source.get(chunk_hash)?;
+        let offset = if sparse && chunk_data.iter().all(|&byte| byte == 0) {
+            writer.skip(chunk_data.len() as u64)?
+        } else {
+            writer.write(&chunk_data)?
+        };
+        source.restored(chunk_hash, dst_path, offset, chunk_data.len());
+    }
+
+    // Ensure all data is written to disk
+    writer.finish()?;
     Ok(())
 }
 
+/// Hands chunks to the restore workers, reading each from the repository once
+struct ChunkSource<'a> {
+    store: &'a ChunkStore,
+    state: Mutex<SourceState>,
+    /// Signalled whenever a worker is done getting a chunk
+    got: Condvar,
+}
+
+struct SourceState {
+    /// Uses of each chunk not restored yet
+    remaining: HashMap<ChunkHash, usize>,
+    /// Chunks with uses left, as many as fit
+    cache: ChunkCache,
+    /// Chunks some worker is getting; others wait for it instead of reading
+    /// them as well
+    getting: HashSet<ChunkHash>,
+    /// Where each chunk with uses left was first restored to
+    copies: HashMap<ChunkHash, RestoredCopy>,
+    stats: RestoreStats,
+}
+
+/// A chunk written to a restored file
+#[derive(Clone)]
+struct RestoredCopy {
+    path: PathBuf,
+    offset: u64,
+    len: usize,
+}
+
+/// Ends getting a chunk that failed, so workers waiting for it do not hang
+struct GetGuard<'a, 'b> {
+    source: &'b ChunkSource<'a>,
+    hash: Option<&'b ChunkHash>,
+}
+
+impl Drop for GetGuard<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(hash) = self.hash.take() {
+            self.source.state().getting.remove(hash);
+            self.source.got.notify_all();
+        }
+    }
+}
+
+impl<'a> ChunkSource<'a> {
+    /// Prepare to restore the chunk uses `uses`, in any order
+    fn new<'h>(
+        store: &'a ChunkStore,
+        cache_size: u64,
+        uses: impl IntoIterator<Item = &'h ChunkHash>,
+    ) -> Self {
+        let mut remaining = HashMap::new();
+        for hash in uses {
+            *remaining.entry(hash.clone()).or_insert(0) += 1;
+        }
+        Self {
+            store,
+            state: Mutex::new(SourceState {
+                remaining,
+                cache: ChunkCache::new(cache_size),
+                getting: HashSet::new(),
+                copies: HashMap::new(),
+                stats: RestoreStats::default(),
+            }),
+            got: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SourceState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stats(&self) -> RestoreStats {
+        self.state().stats
+    }
+
+    /// Get the contents of a chunk: from the cache, from a file it was
+    /// restored to, or from the repository if neither has it
+    fn get(&self, hash: &ChunkHash) -> Result<Arc<[u8]>> {
+        let mut state = self.state();
+        loop {
+            if let Some(data) = state.cache.get(hash) {
+                state.stats.cached += 1;
+                return Ok(data);
+            }
+            if !state.getting.contains(hash) {
+                break;
+            }
+            state = self.got.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        let copy = state.copies.get(hash).cloned();
+        state.getting.insert(hash.clone());
+        drop(state);
+
+        let mut guard = GetGuard {
+            source: self,
+            hash: Some(hash),
+        };
+        let (data, copied) = match copy.and_then(|copy| self.read_copy(hash, &copy)) {
+            Some(data) => (data, true),
+            None => (Arc::from(self.store.read(hash)?), false),
+        };
+
+        let mut state = self.state();
+        guard.hash = None;
+        state.getting.remove(hash);
+        self.got.notify_all();
+        if copied {
+            state.stats.copied += 1;
+        } else {
+            state.stats.fetched += 1;
+        }
+        // Keep the chunk for the other uses, counting this one
+        if state.remaining.get(hash).is_some_and(|&uses| uses > 1) {
+            state.cache.insert(hash.clone(), data.clone());
+        }
+        Ok(data)
+    }
+
+    /// Note that a use of a chunk was written to `path` at `offset`, or
+    /// split by a hole if there is none
+    fn restored(&self, hash: &ChunkHash, path: &Path, offset: Option<u64>, len: usize) {
+        let mut state = self.state();
+        let Some(uses) = state.remaining.get_mut(hash) else {
+            return;
+        };
+        *uses -= 1;
+        if *uses == 0 {
+            state.remaining.remove(hash);
+            state.cache.remove(hash);
+            state.copies.remove(hash);
+        } else if let Some(offset) = offset {
+            state
+                .copies
+                .entry(hash.clone())
+                .or_insert_with(|| RestoredCopy {
+                    path: path.to_path_buf(),
+                    offset,
+                    len,
+                });
+        }
+    }
+
+    /// Read a chunk back from a restored file, unless it no longer matches
+    fn read_copy(&self, hash: &ChunkHash, copy: &RestoredCopy) -> Option<Arc<[u8]>> {
+        let read = || -> io::Result<Vec<u8>> {
+            let mut file = File::open(&copy.path)?;
+            file.seek(SeekFrom::Start(copy.offset))?;
+            let mut data = vec![0; copy.len];
+            file.read_exact(&mut data)?;
+            Ok(data)
+        };
+        match read() {
+            Ok(data) if self.store.hasher().hash(&data) == *hash => Some(data.into()),
+            Ok(_) => {
+                warn!(
+                    "Restored copy of chunk {} in {} changed, reading it again",
+                    hash,
+                    copy.path.display()
+                );
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to read chunk {} back from {}: {}",
+                    hash,
+                    copy.path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -170,6 +620,7 @@ mod tests {
     fn test_restore_snapshot() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         let dest = temp.path().join("restored");
 
@@ -177,10 +628,10 @@ mod tests {
         source.child("file2.txt").write_str("content2").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
         let snapshot_id = get_first_snapshot_id(&repo_path);
-        restore(Some(&snapshot_id), &dest, &repo_path).unwrap();
+        restore(Some(&snapshot_id), &dest, &locator).unwrap();
 
         // Verify files were restored
         assert!(dest.join("file1.txt").exists());
@@ -192,15 +643,16 @@ mod tests {
     fn test_restore_latest_snapshot() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         let dest = temp.path().join("restored");
 
         source.child("file.txt").write_str("content").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
-        restore(None, &dest, &repo_path).unwrap();
+        restore(None, &dest, &locator).unwrap();
 
         assert!(dest.join("file.txt").exists());
     }
@@ -209,6 +661,7 @@ mod tests {
     fn test_restore_to_nonempty_directory() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         let dest = assert_fs::TempDir::new().unwrap();
 
@@ -216,10 +669,10 @@ mod tests {
         dest.child("existing.txt").write_str("exists").unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
         let snapshot_id = get_first_snapshot_id(&repo_path);
-        let result = restore(Some(&snapshot_id), dest.path(), &repo_path);
+        let result = restore(Some(&snapshot_id), dest.path(), &locator);
         assert!(matches!(
             result,
             Err(SnapVaultError::DestinationNotEmpty(_))
@@ -230,10 +683,11 @@ mod tests {
     fn test_restore_nonexistent_snapshot() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let dest = temp.path().join("restored");
 
         Repository::init(&repo_path).unwrap();
-        let result = restore(Some("nonexistent"), &dest, &repo_path);
+        let result = restore(Some("nonexistent"), &dest, &locator);
         assert!(matches!(result, Err(SnapVaultError::SnapshotNotFound(_))));
     }
 
@@ -241,6 +695,7 @@ mod tests {
     fn test_restore_nested_directories() {
         let temp = TempDir::new().unwrap();
         let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
         let source = assert_fs::TempDir::new().unwrap();
         let dest = temp.path().join("restored");
 
@@ -251,12 +706,392 @@ mod tests {
             .unwrap();
 
         Repository::init(&repo_path).unwrap();
-        backup(source.path(), &repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
 
         let snapshot_id = get_first_snapshot_id(&repo_path);
-        restore(Some(&snapshot_id), &dest, &repo_path).unwrap();
+        restore(Some(&snapshot_id), &dest, &locator).unwrap();
 
         assert!(dest.join("dir1/file1.txt").exists());
         assert!(dest.join("dir1/dir2/file2.txt").exists());
     }
+
+    /// Store `blocks` and describe a file made of them
+    fn file_of(store: &ChunkStore, rel_path: &str, blocks: &[&[u8]]) -> FileRecord {
+        let chunks = blocks
+            .iter()
+            .map(|block| {
+                let hash = store.hasher().hash(block);
+                store.store(&hash, block).unwrap();
+                hash
+            })
+            .collect();
+        let size = blocks.iter().map(|block| block.len() as u64).sum();
+        FileRecord::new(rel_path.to_string(), size, None, chunks, None)
+    }
+
+    fn options(threads: usize, cache_size: u64) -> RestoreOptions {
+        RestoreOptions {
+            threads: Some(threads),
+            cache_size: Some(cache_size),
+            ..RestoreOptions::default()
+        }
+    }
+
+    fn memory_store() -> ChunkStore {
+        ChunkStore::from_backend(Arc::new(crate::backend::MemoryBackend::new()))
+    }
+
+    #[test]
+    fn test_repeated_chunks_are_read_once() {
+        let store = memory_store();
+        let zeros = [0u8; 1000];
+        let data = [7u8; 1000];
+        let mut manifest = SnapshotManifest::new(String::new(), String::new());
+        manifest.add_file(file_of(&store, "disk.img", &[&zeros, &data, &zeros, &zeros]));
+        manifest.add_file(file_of(&store, "copy.img", &[&data, &zeros]));
+
+        // With room in memory, repeated chunks come from the cache; without,
+        // they are copied from where they were restored first
+        for (cache_size, expected) in [(1 << 20, (2, 4, 0)), (0, (2, 0, 4))] {
+            let dest = TempDir::new().unwrap();
+            let stats = restore_files(&manifest, &store, dest.path(), &options(1, cache_size)).unwrap();
+            assert_eq!(stats.files, 2);
+            assert_eq!((stats.fetched, stats.cached, stats.copied), expected);
+            assert_eq!(
+                fs::read(dest.path().join("disk.img")).unwrap(),
+                [zeros, data, zeros, zeros].concat()
+            );
+            assert_eq!(
+                fs::read(dest.path().join("copy.img")).unwrap(),
+                [data, zeros].concat()
+            );
+        }
+    }
+
+    #[test]
+    fn test_parallel_restore_of_shared_chunks() {
+        let store = memory_store();
+        let blocks: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 500 + i as usize]).collect();
+        let mut manifest = SnapshotManifest::new(String::new(), String::new());
+        let mut expected = Vec::new();
+        for n in 0..40 {
+            // Each file uses a different mix of the shared blocks
+            let mix: Vec<&[u8]> = (0..n % 7 + 1)
+                .map(|i| blocks[(n * 3 + i) % blocks.len()].as_slice())
+                .collect();
+            let path = format!("dir{}/file{}.bin", n % 4, n);
+            manifest.add_file(file_of(&store, &path, &mix));
+            expected.push((path, mix.concat()));
+        }
+        let uses: usize = manifest.files.iter().map(|file| file.chunks.len()).sum();
+
+        let dest = TempDir::new().unwrap();
+        let stats = restore_files(&manifest, &store, dest.path(), &options(8, DEFAULT_CACHE_SIZE)).unwrap();
+        assert_eq!(stats.files, 40);
+        assert_eq!(stats.fetched, blocks.len());
+        assert_eq!(stats.fetched + stats.cached + stats.copied, uses);
+        for (path, content) in expected {
+            assert_eq!(fs::read(dest.path().join(&path)).unwrap(), content, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_restore_with_options() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        let dest = temp.path().join("restored");
+
+        for i in 0..20 {
+            source
+                .child(format!("dir{}/file{}.txt", i % 3, i))
+                .write_str(&format!("content {}", i % 5))
+                .unwrap();
+        }
+
+        Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+
+        let options = RestoreOptions {
+            threads: Some(4),
+            cache_size: Some(0),
+            ..RestoreOptions::default()
+        };
+        restore_with_options(None, &dest, &locator, &options).unwrap();
+
+        for i in 0..20 {
+            let path = format!("dir{}/file{}.txt", i % 3, i);
+            assert_eq!(
+                fs::read_to_string(dest.join(path)).unwrap(),
+                format!("content {}", i % 5)
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_links_and_special_files() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        let dest = temp.path().join("restored");
+
+        source.child("data/a.txt").write_str("shared").unwrap();
+        fs::hard_link(source.path().join("data/a.txt"), source.path().join("data/b.txt")).unwrap();
+        fs::hard_link(source.path().join("data/a.txt"), source.path().join("c.txt")).unwrap();
+        std::os::unix::fs::symlink("data/a.txt", source.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("data", source.path().join("dir_link")).unwrap();
+        std::os::unix::fs::symlink("missing", source.path().join("dangling")).unwrap();
+        nix::unistd::mkfifo(
+            &source.path().join("pipe"),
+            nix::sys::stat::Mode::from_bits_truncate(0o640),
+        )
+        .unwrap();
+
+        Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        restore(None, &dest, &locator).unwrap();
+
+        let a = fs::metadata(dest.join("data/a.txt")).unwrap();
+        for linked in ["data/b.txt", "c.txt"] {
+            let md = fs::metadata(dest.join(linked)).unwrap();
+            assert_eq!((md.dev(), md.ino()), (a.dev(), a.ino()), "{}", linked);
+        }
+        assert_eq!(a.nlink(), 3);
+        assert_eq!(fs::read_link(dest.join("link")).unwrap(), Path::new("data/a.txt"));
+        assert_eq!(fs::read_to_string(dest.join("link")).unwrap(), "shared");
+        assert_eq!(fs::read_link(dest.join("dir_link")).unwrap(), Path::new("data"));
+        assert_eq!(fs::read_link(dest.join("dangling")).unwrap(), Path::new("missing"));
+        let pipe = fs::symlink_metadata(dest.join("pipe")).unwrap();
+        assert!(pipe.file_type().is_fifo());
+        assert_eq!(pipe.mode() & 0o7777, 0o640);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_directories() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        let dest = temp.path().join("restored");
+
+        source.child("empty/nested").create_dir_all().unwrap();
+        source.child("locked/file.txt").write_str("inside").unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 0);
+        for dir in ["empty/nested", "empty", "locked"] {
+            fs::File::open(source.path().join(dir))
+                .unwrap()
+                .set_times(fs::FileTimes::new().set_modified(old).set_accessed(old))
+                .unwrap();
+        }
+        fs::set_permissions(source.path().join("locked"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        restore(None, &dest, &locator).unwrap();
+        fs::set_permissions(source.path().join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        // Times are set after the children are created, so they stick
+        for dir in ["empty/nested", "empty", "locked"] {
+            let md = fs::metadata(dest.join(dir)).unwrap();
+            assert!(md.is_dir(), "{}", dir);
+            assert_eq!(md.mtime(), 1_000_000_000, "{}", dir);
+        }
+        let locked = fs::metadata(dest.join("locked")).unwrap();
+        assert_eq!(locked.permissions().mode() & 0o7777, 0o555);
+        assert_eq!(fs::read_to_string(dest.join("locked/file.txt")).unwrap(), "inside");
+        fs::set_permissions(dest.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_extended_attributes() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        let dest = temp.path().join("restored");
+
+        let binary = source.child("bin/ping");
+        binary.write_str("#!/bin/sh\n").unwrap();
+        let shared = source.child("shared");
+        shared.create_dir_all().unwrap();
+        if xattr::set(binary.path(), "user.origin", b"package").is_err() {
+            // The file system has no extended attributes
+            return;
+        }
+        // cap_net_raw+ep, and an ACL giving user 1000 write access; setting
+        // them needs root
+        let capability = hex::decode("0100000200200000000000000000000000000000").unwrap();
+        let acl = hex::decode(concat!(
+            "02000000",
+            "0100070000000000",
+            "02000700e8030000",
+            "0400050000000000",
+            "1000070000000000",
+            "2000050000000000",
+        ))
+        .unwrap();
+        let privileged = crate::metadata::is_root()
+            && xattr::set(binary.path(), "security.capability", &capability).is_ok()
+            && xattr::set(shared.path(), "system.posix_acl_access", &acl).is_ok()
+            && xattr::set(shared.path(), "system.posix_acl_default", &acl).is_ok();
+
+        Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        restore(None, &dest, &locator).unwrap();
+
+        let restored = |path: &str, name: &str| xattr::get(dest.join(path), name).unwrap();
+        assert_eq!(restored("bin/ping", "user.origin").as_deref(), Some(&b"package"[..]));
+        if privileged {
+            // Setting the owner would drop the capability, so it comes first
+            assert_eq!(restored("bin/ping", "security.capability"), Some(capability));
+            for name in ["system.posix_acl_access", "system.posix_acl_default"] {
+                assert_eq!(restored("shared", name), xattr::get(shared.path(), name).unwrap());
+            }
+        }
+
+        // Filters choose which attributes are set
+        let filtered = temp.path().join("filtered");
+        let options = RestoreOptions {
+            xattrs: XattrFilter {
+                exclude: vec!["user.*".to_string()],
+                ..XattrFilter::default()
+            },
+            ..RestoreOptions::default()
+        };
+        restore_with_options(None, &filtered, &locator, &options).unwrap();
+        assert_eq!(xattr::get(filtered.join("bin/ping"), "user.origin").unwrap(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_restore_sparse_files() {
+        use std::io::Write;
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+
+        // 64 MiB of holes around two small blocks of data
+        let mut image = File::create(source.path().join("disk.img")).unwrap();
+        image.seek(SeekFrom::Start(8 << 20)).unwrap();
+        image.write_all(&[1u8; 4096]).unwrap();
+        image.seek(SeekFrom::Start(40 << 20)).unwrap();
+        image.write_all(&[2u8; 4096]).unwrap();
+        image.set_len(64 << 20).unwrap();
+        drop(image);
+        // Zeros written out in full, which only --sparse turns into holes
+        let mut zeros = vec![0u8; 8 << 20];
+        zeros[..5].copy_from_slice(b"start");
+        fs::write(source.path().join("zeros.bin"), &zeros).unwrap();
+
+        let repo = Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        let id = get_first_snapshot_id(&repo_path);
+        let manifest = repo.load_manifest(&id, &repo.chunk_store()).unwrap();
+        let record = manifest.files.iter().find(|f| f.rel_path == "disk.img").unwrap();
+        if record.holes.is_empty() {
+            // The file system does not report holes
+            return;
+        }
+        assert_eq!(record.size, 64 << 20);
+        let data: u64 = (64 << 20) - record.holes.iter().map(|hole| hole.len).sum::<u64>();
+        assert!(data < 1 << 20, "{} bytes of data", data);
+
+        let allocated = |path: &Path| fs::metadata(path).unwrap().blocks() * 512;
+        let expected_image = fs::read(source.path().join("disk.img")).unwrap();
+        for sparse in [false, true] {
+            let dest = temp.path().join(format!("restored-{}", sparse));
+            let options = RestoreOptions {
+                sparse,
+                ..RestoreOptions::default()
+            };
+            restore_with_options(None, &dest, &locator, &options).unwrap();
+
+            assert_eq!(fs::read(dest.join("disk.img")).unwrap(), expected_image);
+            assert!(allocated(&dest.join("disk.img")) < 1 << 20);
+            assert_eq!(fs::read(dest.join("zeros.bin")).unwrap(), zeros);
+            assert_eq!(allocated(&dest.join("zeros.bin")) < 4 << 20, sparse);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_skips_unsafe_symlinks() {
+        let store = memory_store();
+        let symlink = |rel_path: &str, target: &str| {
+            let mut record = FileRecord::new(rel_path.to_string(), 0, None, Vec::new(), None);
+            record.kind = EntryKind::Symlink {
+                target: target.to_string(),
+            };
+            record
+        };
+        let mut manifest = SnapshotManifest::new(String::new(), String::new());
+        manifest.add_file(symlink("escape", "../outside"));
+        manifest.add_file(symlink("absolute", "/etc"));
+        manifest.add_file(symlink("dir/inside", "../file.txt"));
+        // Each link alone stays inside, but `chain` would resolve to `../outside`
+        manifest.add_file(symlink("same", "."));
+        manifest.add_file(symlink("chain", "same/../outside"));
+        // A file below a symlink would be written wherever the link points
+        manifest.add_file(symlink("through", "dir"));
+        manifest.add_file(file_of(&store, "through/file.txt", &[b"x"]));
+        manifest.add_file(file_of(&store, "file.txt", &[b"content"]));
+
+        let dest = TempDir::new().unwrap();
+        let stats = restore_files(&manifest, &store, dest.path(), &options(1, 0)).unwrap();
+        assert_eq!((stats.files, stats.skipped), (1, 4));
+        assert!(fs::symlink_metadata(dest.path().join("escape")).is_err());
+        assert!(fs::symlink_metadata(dest.path().join("chain")).is_err());
+        assert!(fs::symlink_metadata(dest.path().join("absolute")).is_err());
+        assert_eq!(fs::read_to_string(dest.path().join("dir/inside")).unwrap(), "content");
+        assert!(fs::symlink_metadata(dest.path().join("through")).is_ok());
+        assert!(!dest.path().join("dir/file.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_preserves_permissions_and_times() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let source = assert_fs::TempDir::new().unwrap();
+        let dest = temp.path().join("restored");
+
+        let script = source.child("bin/run.sh");
+        script.write_str("#!/bin/sh\n").unwrap();
+        fs::set_permissions(script.path(), fs::Permissions::from_mode(0o750)).unwrap();
+        let secret = source.child("secret.txt");
+        secret.write_str("read only").unwrap();
+        fs::set_permissions(secret.path(), fs::Permissions::from_mode(0o400)).unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1_234_567_890, 5);
+        fs::File::open(secret.path())
+            .unwrap()
+            .set_times(fs::FileTimes::new().set_modified(old).set_accessed(old))
+            .unwrap();
+
+        Repository::init(&repo_path).unwrap();
+        backup(source.path(), &locator).unwrap();
+        restore(None, &dest, &locator).unwrap();
+
+        let script = fs::metadata(dest.join("bin/run.sh")).unwrap();
+        assert_eq!(script.permissions().mode() & 0o7777, 0o750);
+        let secret = fs::metadata(dest.join("secret.txt")).unwrap();
+        assert_eq!(secret.permissions().mode() & 0o7777, 0o400);
+        assert_eq!((secret.mtime(), secret.mtime_nsec()), (1_234_567_890, 5));
+        assert_eq!((secret.atime(), secret.atime_nsec()), (1_234_567_890, 5));
+        assert_eq!(fs::read_to_string(dest.join("secret.txt")).unwrap(), "read only");
+    }
 }
@@ -0,0 +1,68 @@
+use crate::error::Result;
+use crate::repository::locator::RepoLocator;
+use crate::repository::Repository;
+use log::info;
+
+/// Remove stale locks, or every lock when `remove_all` is set
+pub fn unlock(repo_location: &RepoLocator, remove_all: bool) -> Result<()> {
+    let repo = Repository::open_at(repo_location, None)?;
+    info!(
+        "Removing {} locks from repository {}",
+        if remove_all { "all" } else { "stale" },
+        repo_location
+    );
+
+    let removed = repo.remove_locks(remove_all)?;
+    let remaining = repo.list_locks()?;
+    println!("✓ Removed {} lock(s)", removed);
+    for (id, lock) in &remaining {
+        match lock {
+            Some(lock) => println!(
+                "  Still held: {} ({}, PID {} on {} since {})",
+                id,
+                lock.mode_name(),
+                lock.pid,
+                lock.hostname,
+                lock.created_at
+            ),
+            None => println!("  Cannot be parsed: {} (removed by --remove-all)", id),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ObjectKind;
+    use crate::repository::lock::{LockInfo, LockMode};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_unlock_removes_stale_locks() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::from(&repo_path);
+        let repo = Repository::init(&repo_path).unwrap();
+
+        let mut dead = LockInfo::current(LockMode::Exclusive);
+        dead.pid = u32::MAX;
+        repo.backend()
+            .put(ObjectKind::Lock, "dead", &serde_json::to_vec(&dead).unwrap())
+            .unwrap();
+        let held = repo.lock(LockMode::Shared).unwrap();
+        // Written by who knows what, so only removed with --remove-all
+        repo.backend().put(ObjectKind::Lock, "garbled", b"not json").unwrap();
+
+        unlock(&locator, false).unwrap();
+        let mut locks: Vec<String> = repo.list_locks().unwrap().into_iter().map(|(id, _)| id).collect();
+        locks.sort();
+        let mut expected = vec!["garbled".to_string(), held.id().to_string()];
+        expected.sort();
+        assert_eq!(locks, expected);
+
+        unlock(&locator, true).unwrap();
+        assert!(repo.list_locks().unwrap().is_empty());
+    }
+}
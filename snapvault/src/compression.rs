@@ -0,0 +1,273 @@
+//! Chunk compression module.
+//!
+//! Every chunk file starts with a small header naming the codec used for its
+//! payload, so chunks written with different settings can live side by side
+//! and be decompressed transparently.
+//!
+//! Header layout (13 bytes):
+//! - 4 bytes: magic `SVCK`
+//! - 1 byte: codec id (0 = raw, 1 = zstd, 2 = lz4)
+//! - 8 bytes: uncompressed length (little-endian u64)
+
+use crate::error::{Result, SnapVaultError};
+use crate::utils::MAX_MANIFEST_SIZE;
+use serde::{Deserialize, Serialize};
+
+/// Magic bytes at the start of every framed chunk
+pub const CHUNK_MAGIC: &[u8; 4] = b"SVCK";
+
+/// Size of the chunk header in bytes
+pub const HEADER_SIZE: usize = 13;
+
+/// Largest uncompressed length a chunk may have. Data chunks are at most
+/// `MAX_CHUNK_SIZE`, but a tree lists a whole directory and is only bounded
+/// like a manifest. Headers claiming more are rejected before anything is
+/// allocated for them.
+pub const MAX_LOGICAL_SIZE: u64 = MAX_MANIFEST_SIZE;
+
+/// Default zstd compression level
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Compression settings recorded in the repository config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "codec", rename_all = "lowercase")]
+pub enum CompressionConfig {
+    /// Store chunks uncompressed
+    None,
+    /// Zstandard with the given level (1-22)
+    Zstd { level: i32 },
+    /// LZ4 block compression (no levels)
+    Lz4,
+}
+
+impl CompressionConfig {
+    /// Validate the compression level
+    pub fn validate(&self) -> Result<()> {
+        if let CompressionConfig::Zstd { level } = *self
+            && !(1..=22).contains(&level)
+        {
+            return Err(SnapVaultError::InvalidCompressionConfig(format!(
+                "zstd level {} outside 1..=22",
+                level
+            )));
+        }
+        Ok(())
+    }
+
+    /// Short human-readable name of the codec
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionConfig::None => "none",
+            CompressionConfig::Zstd { .. } => "zstd",
+            CompressionConfig::Lz4 => "lz4",
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig::Zstd {
+            level: DEFAULT_ZSTD_LEVEL,
+        }
+    }
+}
+
+/// Codec actually used for a stored chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    Raw = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl Codec {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::Raw),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// Parsed chunk header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub codec: Codec,
+    /// Length of the chunk before compression
+    pub logical_size: u64,
+}
+
+impl ChunkHeader {
+    /// Parse a header from the start of a framed chunk.
+    /// Returns None if the data does not start with a valid header.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || &data[..4] != CHUNK_MAGIC {
+            return None;
+        }
+        let codec = Codec::from_id(data[4])?;
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&data[5..HEADER_SIZE]);
+        Some(ChunkHeader {
+            codec,
+            logical_size: u64::from_le_bytes(len),
+        })
+    }
+
+    /// Serialize the header
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut out = [0u8; HEADER_SIZE];
+        out[..4].copy_from_slice(CHUNK_MAGIC);
+        out[4] = self.codec as u8;
+        out[5..].copy_from_slice(&self.logical_size.to_le_bytes());
+        out
+    }
+}
+
+/// Compress a chunk, returning its header and compressed payload.
+///
+/// Falls back to storing the data raw when compression does not make it
+/// smaller (already-compressed or random data). Fails for data larger than
+/// `MAX_LOGICAL_SIZE`, which could not be read back.
+pub fn compress(data: &[u8], config: &CompressionConfig) -> Result<(ChunkHeader, Vec<u8>)> {
+    check_logical_size(data.len() as u64)?;
+    let compressed = match *config {
+        CompressionConfig::None => None,
+        CompressionConfig::Zstd { level } => Some((Codec::Zstd, zstd::bulk::compress(data, level)?)),
+        CompressionConfig::Lz4 => Some((Codec::Lz4, lz4_flex::block::compress(data))),
+    };
+
+    let (codec, payload) = match compressed {
+        Some((codec, payload)) if payload.len() < data.len() => (codec, payload),
+        _ => (Codec::Raw, data.to_vec()),
+    };
+
+    let header = ChunkHeader {
+        codec,
+        logical_size: data.len() as u64,
+    };
+    Ok((header, payload))
+}
+
+/// Compress a chunk and prepend its header
+pub fn encode(data: &[u8], config: &CompressionConfig) -> Result<Vec<u8>> {
+    let (header, payload) = compress(data, config)?;
+    let mut out = Vec::with_capacity(HEADER_SIZE + payload.len());
+    out.extend_from_slice(&header.to_bytes());
+    out.extend_from_slice(&payload);
+    Ok(out)
+}
+
+/// Strip the header from a framed chunk and decompress its payload
+pub fn decode(data: &[u8]) -> Result<Vec<u8>> {
+    let header = ChunkHeader::parse(data)
+        .ok_or_else(|| SnapVaultError::Other("Invalid chunk header".to_string()))?;
+    decompress(&header, &data[HEADER_SIZE..])
+}
+
+/// Decompress a payload described by `header`. The length in the header is
+/// checked first, as the decompressors allocate that much up front.
+pub fn decompress(header: &ChunkHeader, payload: &[u8]) -> Result<Vec<u8>> {
+    check_logical_size(header.logical_size)?;
+    let size = usize::try_from(header.logical_size)
+        .map_err(|_| SnapVaultError::Other("Chunk too large".to_string()))?;
+
+    let plain = match header.codec {
+        Codec::Raw => payload.to_vec(),
+        Codec::Zstd => zstd::bulk::decompress(payload, size)?,
+        Codec::Lz4 => lz4_flex::block::decompress(payload, size)
+            .map_err(|e| SnapVaultError::Other(format!("LZ4 decompression failed: {}", e)))?,
+    };
+
+    if plain.len() != size {
+        return Err(SnapVaultError::Other(format!(
+            "Decompressed size mismatch: expected {}, got {}",
+            size,
+            plain.len()
+        )));
+    }
+    Ok(plain)
+}
+
+fn check_logical_size(size: u64) -> Result<()> {
+    if size > MAX_LOGICAL_SIZE {
+        return Err(SnapVaultError::Other(format!(
+            "Chunk too large: {} bytes (max: {} bytes)",
+            size, MAX_LOGICAL_SIZE
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressible() -> Vec<u8> {
+        b"snapvault ".repeat(1000)
+    }
+
+    #[test]
+    fn test_roundtrip_all_codecs() {
+        for config in [
+            CompressionConfig::None,
+            CompressionConfig::Zstd { level: 3 },
+            CompressionConfig::Lz4,
+        ] {
+            let data = compressible();
+            let encoded = encode(&data, &config).unwrap();
+            assert_eq!(decode(&encoded).unwrap(), data, "codec {}", config.name());
+        }
+    }
+
+    #[test]
+    fn test_compressible_data_shrinks() {
+        let data = compressible();
+        let encoded = encode(&data, &CompressionConfig::default()).unwrap();
+        let header = ChunkHeader::parse(&encoded).unwrap();
+
+        assert_eq!(header.codec, Codec::Zstd);
+        assert_eq!(header.logical_size, data.len() as u64);
+        assert!(encoded.len() < data.len());
+    }
+
+    #[test]
+    fn test_incompressible_data_stored_raw() {
+        let mut data = vec![0u8; 4096];
+        blake3::Hasher::new()
+            .update(b"incompressible")
+            .finalize_xof()
+            .fill(&mut data);
+        let encoded = encode(&data, &CompressionConfig::Lz4).unwrap();
+
+        assert_eq!(ChunkHeader::parse(&encoded).unwrap().codec, Codec::Raw);
+        assert_eq!(encoded.len(), HEADER_SIZE + data.len());
+    }
+
+    #[test]
+    fn test_parse_rejects_missing_magic() {
+        assert!(ChunkHeader::parse(b"hello world, no header").is_none());
+        assert!(decode(b"hello world, no header").is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_header() {
+        for config in [CompressionConfig::Zstd { level: 3 }, CompressionConfig::Lz4] {
+            let mut encoded = encode(&compressible(), &config).unwrap();
+            let mut header = ChunkHeader::parse(&encoded).unwrap();
+            header.logical_size = u64::MAX / 2;
+            encoded[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+            let err = decode(&encoded).unwrap_err();
+            assert!(err.to_string().contains("Chunk too large"), "codec {}", config.name());
+        }
+    }
+
+    #[test]
+    fn test_validate_zstd_level() {
+        assert!(CompressionConfig::Zstd { level: 19 }.validate().is_ok());
+        assert!(CompressionConfig::Zstd { level: 0 }.validate().is_err());
+        assert!(CompressionConfig::Zstd { level: 23 }.validate().is_err());
+    }
+}
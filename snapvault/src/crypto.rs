@@ -0,0 +1,378 @@
+//! Repository encryption module.
+//!
+//! An encrypted repository has a random master key, wrapped by a
+//! passphrase-derived key (Argon2id) and stored in key files under `keys/`.
+//! Each key file is an independent slot: several passphrases can unlock the
+//! same master key, and revoking one never requires re-encrypting data.
+//! The master key holds two independent secrets:
+//! - an encryption key for XChaCha20-Poly1305, used to seal chunks,
+//!   snapshot manifests and the chunk index
+//! - an ID key for keyed BLAKE3, used to derive chunk names so that they do
+//!   not reveal plaintext content hashes
+
+use crate::error::{Result, SnapVaultError};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Environment variable holding the repository passphrase
+pub const PASSWORD_ENV: &str = "SNAPVAULT_PASSWORD";
+
+/// Environment variable naming a file whose first line is the repository passphrase
+pub const PASSWORD_FILE_ENV: &str = "SNAPVAULT_PASSWORD_FILE";
+
+/// Environment variable holding a shell command that prints the repository passphrase
+pub const PASSWORD_COMMAND_ENV: &str = "SNAPVAULT_PASSWORD_COMMAND";
+
+/// Environment variable holding the new passphrase for `key add`/`key passwd`
+pub const NEW_PASSWORD_ENV: &str = "SNAPVAULT_NEW_PASSWORD";
+
+/// Size of the XChaCha20-Poly1305 nonce prefixed to every sealed object
+pub const NONCE_SIZE: usize = 24;
+
+/// Size of the Poly1305 authentication tag appended to every sealed object
+pub const TAG_SIZE: usize = 16;
+
+/// Length of the random salt used for passphrase key derivation
+const SALT_SIZE: usize = 16;
+
+/// Encryption settings recorded in the repository config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "cipher", rename_all = "lowercase")]
+pub enum EncryptionConfig {
+    /// Objects are stored in plaintext
+    #[default]
+    None,
+    /// Objects are sealed with XChaCha20-Poly1305
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl EncryptionConfig {
+    /// Whether objects in this repository are encrypted
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, EncryptionConfig::None)
+    }
+}
+
+/// Repository master key
+#[derive(Clone)]
+pub struct MasterKey {
+    /// Key for sealing objects
+    encryption: [u8; 32],
+    /// Key for deriving chunk IDs
+    id: [u8; 32],
+}
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    /// Generate a new random master key
+    pub fn generate() -> Self {
+        let mut encryption = [0u8; 32];
+        let mut id = [0u8; 32];
+        encryption.copy_from_slice(&XChaCha20Poly1305::generate_key(&mut OsRng));
+        id.copy_from_slice(&XChaCha20Poly1305::generate_key(&mut OsRng));
+        Self { encryption, id }
+    }
+
+    /// Key used for keyed BLAKE3 chunk IDs
+    pub fn id_key(&self) -> &[u8; 32] {
+        &self.id
+    }
+
+    /// Encrypt and authenticate `plaintext`, binding it to `aad`.
+    /// Returns `nonce || ciphertext || tag`.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        seal_with(&self.encryption, plaintext, aad)
+    }
+
+    /// Verify and decrypt an object produced by `seal` with the same `aad`
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        open_with(&self.encryption, sealed, aad)
+    }
+
+    fn to_bytes(&self) -> [u8; 64] {
+        let mut out = [0u8; 64];
+        out[..32].copy_from_slice(&self.encryption);
+        out[32..].copy_from_slice(&self.id);
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 64 {
+            return Err(SnapVaultError::Crypto(format!(
+                "Invalid master key length: {}",
+                bytes.len()
+            )));
+        }
+        let mut encryption = [0u8; 32];
+        let mut id = [0u8; 32];
+        encryption.copy_from_slice(&bytes[..32]);
+        id.copy_from_slice(&bytes[32..]);
+        Ok(Self { encryption, id })
+    }
+}
+
+fn seal_with(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| SnapVaultError::Crypto("Encryption failed".to_string()))?;
+
+    let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+fn open_with(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(SnapVaultError::Crypto(
+            "Encrypted object is truncated".to_string(),
+        ));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| {
+            SnapVaultError::Crypto("Authentication failed: object corrupted or tampered".to_string())
+        })
+}
+
+/// Argon2id parameters used to derive the key-wrapping key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub memory_kib: u32,
+    /// Number of passes
+    pub iterations: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    fn derive(&self, passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| SnapVaultError::Crypto(format!("Invalid KDF parameters: {}", e)))?;
+        let mut out = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut out)
+            .map_err(|e| SnapVaultError::Crypto(format!("Key derivation failed: {}", e)))?;
+        Ok(out)
+    }
+}
+
+/// A master key wrapped by a passphrase, as stored in `keys/<id>.json`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyFile {
+    pub created_at: String,
+    /// Optional human-readable label (e.g. who or what uses this key)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub kdf: KdfParams,
+    #[serde(with = "hex")]
+    pub salt: Vec<u8>,
+    /// Master key sealed with the passphrase-derived key
+    #[serde(with = "hex")]
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Associated data binding a wrapped key to its purpose
+const KEY_FILE_AAD: &[u8] = b"snapvault key file";
+
+impl KeyFile {
+    /// Wrap `master` with a key derived from `passphrase`
+    pub fn create(master: &MasterKey, passphrase: &str, kdf: KdfParams) -> Result<Self> {
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(&XChaCha20Poly1305::generate_key(&mut OsRng)[..SALT_SIZE]);
+
+        let kek = kdf.derive(passphrase, &salt)?;
+        let wrapped_key = seal_with(&kek, &master.to_bytes(), KEY_FILE_AAD)?;
+
+        Ok(Self {
+            created_at: chrono::Utc::now().to_rfc3339(),
+            label: None,
+            kdf,
+            salt: salt.to_vec(),
+            wrapped_key,
+        })
+    }
+
+    /// Recover the master key using `passphrase`
+    pub fn unlock(&self, passphrase: &str) -> Result<MasterKey> {
+        let kek = self.kdf.derive(passphrase, &self.salt)?;
+        let bytes = open_with(&kek, &self.wrapped_key, KEY_FILE_AAD)
+            .map_err(|_| SnapVaultError::WrongPassphrase)?;
+        MasterKey::from_bytes(&bytes)
+    }
+}
+
+/// Repository passphrase from the environment, checking in order
+/// `SNAPVAULT_PASSWORD`, `SNAPVAULT_PASSWORD_FILE` and `SNAPVAULT_PASSWORD_COMMAND`
+pub fn passphrase_from_env() -> Result<Option<String>> {
+    if let Ok(passphrase) = std::env::var(PASSWORD_ENV) {
+        return Ok(Some(passphrase));
+    }
+    if let Some(path) = std::env::var_os(PASSWORD_FILE_ENV) {
+        return passphrase_from_file(Path::new(&path)).map(Some);
+    }
+    if let Ok(command) = std::env::var(PASSWORD_COMMAND_ENV) {
+        return passphrase_from_command(&command).map(Some);
+    }
+    Ok(None)
+}
+
+/// Read a passphrase from the first line of a file
+pub fn passphrase_from_file(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        SnapVaultError::PassphraseSource(format!("cannot read {}: {}", path.display(), e))
+    })?;
+    Ok(contents.lines().next().unwrap_or("").to_string())
+}
+
+/// Run a shell command and use the first line of its output as the passphrase
+pub fn passphrase_from_command(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| SnapVaultError::PassphraseSource(format!("cannot run command: {}", e)))?;
+    if !output.status.success() {
+        return Err(SnapVaultError::PassphraseSource(format!(
+            "command exited with {}",
+            output.status
+        )));
+    }
+    let stdout = String::from_utf8(output.stdout).map_err(|_| {
+        SnapVaultError::PassphraseSource("command output is not valid UTF-8".to_string())
+    })?;
+    Ok(stdout.lines().next().unwrap_or("").to_string())
+}
+
+/// Read the repository passphrase from the environment (see
+/// `passphrase_from_env`), or prompt for it on the terminal
+pub fn read_passphrase(prompt: &str) -> Result<String> {
+    if let Some(passphrase) = passphrase_from_env()? {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password(prompt).map_err(|_| SnapVaultError::PassphraseRequired)
+}
+
+/// Read a new passphrase from `env_var`, or prompt twice when it is unset
+pub fn read_new_passphrase(env_var: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(env_var) {
+        return Ok(passphrase);
+    }
+    let first = rpassword::prompt_password("Enter new repository passphrase: ")
+        .map_err(|_| SnapVaultError::PassphraseRequired)?;
+    let second = rpassword::prompt_password("Confirm passphrase: ")
+        .map_err(|_| SnapVaultError::PassphraseRequired)?;
+    if first != second {
+        return Err(SnapVaultError::Other("Passphrases do not match".to_string()));
+    }
+    Ok(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap KDF parameters so tests stay fast
+    fn test_kdf() -> KdfParams {
+        KdfParams {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let key = MasterKey::generate();
+        let sealed = key.seal(b"secret data", b"aad").unwrap();
+
+        assert_eq!(sealed.len(), NONCE_SIZE + 11 + TAG_SIZE);
+        assert_eq!(key.open(&sealed, b"aad").unwrap(), b"secret data");
+    }
+
+    #[test]
+    fn test_open_rejects_tampering() {
+        let key = MasterKey::generate();
+        let mut sealed = key.seal(b"secret data", b"aad").unwrap();
+
+        assert!(key.open(&sealed, b"other aad").is_err());
+        sealed[NONCE_SIZE] ^= 1;
+        assert!(matches!(key.open(&sealed, b"aad"), Err(SnapVaultError::Crypto(_))));
+    }
+
+    #[test]
+    fn test_open_with_wrong_key_fails() {
+        let sealed = MasterKey::generate().seal(b"secret", b"").unwrap();
+        assert!(MasterKey::generate().open(&sealed, b"").is_err());
+    }
+
+    #[test]
+    fn test_key_file_unlock() {
+        let master = MasterKey::generate();
+        let key_file = KeyFile::create(&master, "correct horse", test_kdf()).unwrap();
+
+        let unlocked = key_file.unlock("correct horse").unwrap();
+        assert_eq!(unlocked.to_bytes(), master.to_bytes());
+        assert!(matches!(
+            key_file.unlock("wrong"),
+            Err(SnapVaultError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_key_file_serde_roundtrip() {
+        let master = MasterKey::generate();
+        let key_file = KeyFile::create(&master, "pw", test_kdf()).unwrap();
+
+        let json = serde_json::to_string(&key_file).unwrap();
+        let parsed: KeyFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.unlock("pw").unwrap().to_bytes(), master.to_bytes());
+    }
+
+    #[test]
+    fn test_passphrase_from_file() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("passphrase");
+        std::fs::write(&path, "correct horse\nignored\n").unwrap();
+        assert_eq!(passphrase_from_file(&path).unwrap(), "correct horse");
+
+        let missing = passphrase_from_file(&temp.path().join("missing"));
+        assert!(matches!(missing, Err(SnapVaultError::PassphraseSource(_))));
+    }
+
+    #[test]
+    fn test_passphrase_from_command() {
+        assert_eq!(passphrase_from_command("echo 'battery staple'").unwrap(), "battery staple");
+        assert!(matches!(
+            passphrase_from_command("exit 3"),
+            Err(SnapVaultError::PassphraseSource(_))
+        ));
+    }
+}
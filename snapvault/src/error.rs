@@ -1,3 +1,4 @@
+use crate::backend::ObjectKind;
 use std::path::PathBuf;
 use thiserror::Error;
 
@@ -9,6 +10,12 @@ pub enum SnapVaultError {
     #[error("Repository not found: {0}")]
     RepoNotFound(PathBuf),
 
+    #[error("Invalid repository location {location}: {reason}")]
+    InvalidRepoLocation { location: String, reason: String },
+
+    #[error("No repository given: use --repo or set SNAPVAULT_REPOSITORY")]
+    RepoLocationRequired,
+
     #[error("Not a SnapVault repository: missing config at {0}")]
     InvalidRepo(PathBuf),
 
@@ -42,6 +49,58 @@ pub enum SnapVaultError {
     #[error("File too large: {size} bytes (max: {max} bytes)")]
     FileTooLarge { size: u64, max: u64 },
 
+    #[error("Invalid chunker configuration: {0}")]
+    InvalidChunkerConfig(String),
+
+    #[error("Invalid compression configuration: {0}")]
+    InvalidCompressionConfig(String),
+
+    #[error("Invalid pack size: {size} bytes (allowed: {min}-{max} bytes)")]
+    InvalidPackSize { size: u64, min: u64, max: u64 },
+
+    #[error("Repository is encrypted: a passphrase is required (set SNAPVAULT_PASSWORD or a password file or command)")]
+    PassphraseRequired,
+
+    #[error("Failed to read passphrase: {0}")]
+    PassphraseSource(String),
+
+    #[error("Wrong passphrase: no key in the repository could be unlocked")]
+    WrongPassphrase,
+
+    #[error("Repository is not encrypted")]
+    NotEncrypted,
+
+    #[error("Key not found: {0}")]
+    KeyNotFound(String),
+
+    #[error("Cannot remove key {0}: it is the key currently in use (use `key passwd` to replace it)")]
+    KeyInUse(String),
+
+    #[error("Cryptographic error: {0}")]
+    Crypto(String),
+
+    #[error("{kind} not found: {name}")]
+    ObjectNotFound { kind: ObjectKind, name: String },
+
+    #[error("Storage backend error: {0}")]
+    Backend(String),
+
+    #[error("Repository is locked by {holder} (lock {lock}); {remedy}")]
+    RepoLocked {
+        lock: String,
+        holder: String,
+        remedy: String,
+    },
+
+    #[error("Repository check found {0} problem(s)")]
+    CheckFailed(usize),
+
+    #[error("Invalid retention policy: {0}")]
+    InvalidRetentionPolicy(String),
+
+    #[error("Invalid exclude pattern: {0}")]
+    InvalidExcludePattern(String),
+
     #[error("No snapshots found in repository")]
     NoSnapshots,
 
@@ -0,0 +1,348 @@
+//! Rules leaving paths out of a backup.
+//!
+//! Patterns follow gitignore syntax and are matched against paths relative
+//! to the backup source: `target/` matches directories named `target`
+//! anywhere, `/cache` only at the top, and `!keep.log` takes back a path an
+//! earlier pattern left out. Directories may hold a `.snapvaultignore` file
+//! with more patterns, relative to that directory. Of all patterns matching
+//! a path, the last one decides: command line patterns come first, then the
+//! ignore files from the source down. An excluded directory is not entered,
+//! so nothing below it can be taken back.
+
+use crate::error::{Result, SnapVaultError};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use walkdir::DirEntry;
+
+/// Name of the per-directory ignore files
+pub const IGNORE_FILE: &str = ".snapvaultignore";
+
+/// What to leave out of a backup, as given on the command line
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExcludeRules {
+    /// Gitignore-style patterns of paths to leave out
+    pub patterns: Vec<String>,
+    /// Patterns matched ignoring case
+    pub ipatterns: Vec<String>,
+    /// Leave out directories containing a file with one of these names
+    pub if_present: Vec<String>,
+    /// Leave out files larger than this many bytes
+    pub larger_than: Option<u64>,
+    /// Leave out file systems mounted below the source
+    pub one_file_system: bool,
+}
+
+impl ExcludeRules {
+    /// Whether no rule is set
+    pub fn is_empty(&self) -> bool {
+        *self == ExcludeRules::default()
+    }
+
+    /// Add the patterns in a file, one per line. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn read_patterns(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            SnapVaultError::InvalidExcludePattern(format!(
+                "cannot read {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        self.patterns.extend(
+            text.lines()
+                .map(str::trim_end)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+        Ok(())
+    }
+}
+
+/// Why an entry was left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExcludeReason {
+    Pattern,
+    MarkerFile,
+    TooLarge,
+    OtherFileSystem,
+}
+
+/// Exclude rules compiled for one source directory
+pub struct Excluder {
+    rules: ExcludeRules,
+    patterns: Gitignore,
+    ipatterns: Gitignore,
+    /// Device of the source, for `one_file_system`
+    device: Option<u64>,
+}
+
+impl Excluder {
+    /// Compile `rules` for a backup of `source`
+    pub fn new(source: &Path, rules: &ExcludeRules) -> Result<Self> {
+        let device = if rules.one_file_system {
+            let device = device(&fs::metadata(source)?);
+            if device.is_none() {
+                warn!("Cannot tell file systems apart on this platform; ignoring --one-file-system");
+            }
+            device
+        } else {
+            None
+        };
+        Ok(Self {
+            rules: rules.clone(),
+            patterns: compile(source, &rules.patterns, false)?,
+            ipatterns: compile(source, &rules.ipatterns, true)?,
+            device,
+        })
+    }
+
+    /// Decide whether a walk entry below the source is left out. Entries
+    /// whose metadata cannot be read are kept, for the walk to report.
+    pub fn excludes(&self, entry: &DirEntry, ignores: &IgnoreStack) -> Option<ExcludeReason> {
+        let path = entry.path();
+        let is_dir = entry.file_type().is_dir();
+        if self.matches(path, is_dir, ignores) {
+            return Some(ExcludeReason::Pattern);
+        }
+        if is_dir
+            && self
+                .rules
+                .if_present
+                .iter()
+                .any(|name| path.join(name).symlink_metadata().is_ok())
+        {
+            return Some(ExcludeReason::MarkerFile);
+        }
+        if self.device.is_none() && self.rules.larger_than.is_none() {
+            return None;
+        }
+
+        let md = entry.metadata().ok()?;
+        if self.device.is_some() && device(&md) != self.device {
+            return Some(ExcludeReason::OtherFileSystem);
+        }
+        match self.rules.larger_than {
+            Some(limit) if entry.file_type().is_file() && md.len() > limit => {
+                Some(ExcludeReason::TooLarge)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the last pattern matching `path` leaves it out
+    pub fn matches(&self, path: &Path, is_dir: bool, ignores: &IgnoreStack) -> bool {
+        let mut excluded = false;
+        let matchers = [&self.patterns, &self.ipatterns]
+            .into_iter()
+            .chain(ignores.files.iter().map(|(_, file)| file));
+        for matcher in matchers {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(_) => excluded = true,
+                Match::Whitelist(_) => excluded = false,
+                Match::None => {}
+            }
+        }
+        excluded
+    }
+}
+
+/// The `.snapvaultignore` files of the directories a walk is in
+#[derive(Default)]
+pub struct IgnoreStack {
+    /// Depth of each directory with an ignore file, and its patterns
+    files: Vec<(usize, Gitignore)>,
+}
+
+impl IgnoreStack {
+    /// Drop the ignore files of directories the walk has left, given the
+    /// depth of its next entry
+    pub fn leave(&mut self, depth: usize) {
+        while self.files.last().is_some_and(|(dir_depth, _)| *dir_depth >= depth) {
+            self.files.pop();
+        }
+    }
+
+    /// Read the ignore file of a directory the walk enters, if it has one.
+    /// Invalid lines are skipped with a warning.
+    pub fn enter(&mut self, dir: &Path, depth: usize) {
+        let path = dir.join(IGNORE_FILE);
+        if !path.is_file() {
+            return;
+        }
+        let (file, error) = Gitignore::new(&path);
+        if let Some(e) = error {
+            warn!("Problem in {}: {}", path.display(), e);
+        }
+        self.files.push((depth, file));
+    }
+}
+
+fn compile(root: &Path, patterns: &[String], case_insensitive: bool) -> Result<Gitignore> {
+    let invalid = |e: ignore::Error| SnapVaultError::InvalidExcludePattern(e.to_string());
+    let mut builder = GitignoreBuilder::new(root);
+    builder.case_insensitive(case_insensitive).map_err(invalid)?;
+    for pattern in patterns {
+        builder.add_line(None, pattern).map_err(invalid)?;
+    }
+    builder.build().map_err(invalid)
+}
+
+/// Device a file is on
+#[cfg(unix)]
+fn device(md: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(md.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_md: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// A size in bytes, parsed from e.g. `500`, `64k`, `100M`, `2G` or `1TiB`
+/// (binary multiples, case ignored)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = SnapVaultError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            SnapVaultError::Other(format!(
+                "Invalid size {:?}: expected e.g. 500, 64k, 100M or 2G",
+                s
+            ))
+        };
+        let lower = s.trim().to_ascii_lowercase();
+        let digits = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+        let (number, unit) = lower.split_at(digits);
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let unit = unit.trim_start();
+        let unit = unit
+            .strip_suffix("ib")
+            .or_else(|| unit.strip_suffix('b'))
+            .unwrap_or(unit);
+        let shift = match unit {
+            "" => 0,
+            "k" => 10,
+            "m" => 20,
+            "g" => 30,
+            "t" => 40,
+            _ => return Err(invalid()),
+        };
+        number.checked_mul(1 << shift).map(ByteSize).ok_or_else(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+
+    fn rules(patterns: &[&str]) -> ExcludeRules {
+        ExcludeRules {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            ..ExcludeRules::default()
+        }
+    }
+
+    #[test]
+    fn test_patterns_follow_gitignore_syntax() {
+        let root = Path::new("/src");
+        let excluder = Excluder::new(
+            root,
+            &rules(&["target/", "/cache", "*.log", "!keep.log", "node_modules"]),
+        )
+        .unwrap();
+        let ignores = IgnoreStack::default();
+        let excluded = |path: &str, is_dir| excluder.matches(&root.join(path), is_dir, &ignores);
+
+        assert!(excluded("target", true));
+        assert!(excluded("crates/a/target", true));
+        assert!(!excluded("target", false));
+        assert!(excluded("cache", true));
+        assert!(!excluded("sub/cache", true));
+        assert!(excluded("logs/app.log", false));
+        assert!(!excluded("logs/keep.log", false));
+        assert!(excluded("web/node_modules", true));
+        assert!(!excluded("src/main.rs", false));
+    }
+
+    #[test]
+    fn test_case_insensitive_patterns() {
+        let root = Path::new("/src");
+        let excluder = Excluder::new(
+            root,
+            &ExcludeRules {
+                ipatterns: vec!["*.ISO".to_string()],
+                ..ExcludeRules::default()
+            },
+        )
+        .unwrap();
+        let ignores = IgnoreStack::default();
+        assert!(excluder.matches(&root.join("disk.iso"), false, &ignores));
+        assert!(excluder.matches(&root.join("Disk.Iso"), false, &ignores));
+        assert!(!excluder.matches(&root.join("disk.img"), false, &ignores));
+    }
+
+    #[test]
+    fn test_ignore_files_apply_below_their_directory() {
+        let source = assert_fs::TempDir::new().unwrap();
+        source.child("app/.snapvaultignore").write_str("*.tmp\n").unwrap();
+        source.child("app/web/.snapvaultignore").write_str("!important.tmp\n").unwrap();
+        let excluder = Excluder::new(source.path(), &rules(&["*.bak"])).unwrap();
+        let path = |rel: &str| source.path().join(rel);
+
+        let mut ignores = IgnoreStack::default();
+        ignores.enter(source.path(), 0);
+        ignores.enter(&path("app"), 1);
+        assert!(excluder.matches(&path("app/x.tmp"), false, &ignores));
+        assert!(excluder.matches(&path("app/x.bak"), false, &ignores));
+
+        ignores.enter(&path("app/web"), 2);
+        assert!(excluder.matches(&path("app/web/x.tmp"), false, &ignores));
+        assert!(!excluder.matches(&path("app/web/important.tmp"), false, &ignores));
+
+        // Back at the top, neither ignore file applies
+        ignores.leave(1);
+        assert!(!excluder.matches(&path("other.tmp"), false, &ignores));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let result = Excluder::new(Path::new("/src"), &rules(&["[z-a]"]));
+        assert!(matches!(result, Err(SnapVaultError::InvalidExcludePattern(_))));
+    }
+
+    #[test]
+    fn test_read_patterns() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let file = dir.child("excludes.txt");
+        file.write_str("# build output\ntarget/\n\n*.o\r\n").unwrap();
+
+        let mut rules = rules(&["*.log"]);
+        rules.read_patterns(file.path()).unwrap();
+        assert_eq!(rules.patterns, vec!["*.log", "target/", "*.o"]);
+        assert!(rules.read_patterns(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!("500".parse::<ByteSize>().unwrap(), ByteSize(500));
+        assert_eq!("64k".parse::<ByteSize>().unwrap(), ByteSize(64 << 10));
+        assert_eq!("100M".parse::<ByteSize>().unwrap(), ByteSize(100 << 20));
+        assert_eq!("2 GiB".parse::<ByteSize>().unwrap(), ByteSize(2 << 30));
+        assert_eq!("1tb".parse::<ByteSize>().unwrap(), ByteSize(1 << 40));
+        assert!("".parse::<ByteSize>().is_err());
+        assert!("M".parse::<ByteSize>().is_err());
+        assert!("10x".parse::<ByteSize>().is_err());
+        assert!("99999999999T".parse::<ByteSize>().is_err());
+    }
+}
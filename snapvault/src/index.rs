@@ -6,6 +6,8 @@
 use crate::chunking::ChunkHash;
 use crate::error::Result;
 use crate::repository::snapshot::SnapshotManifest;
+use crate::repository::Repository;
+use crate::utils::write_atomic;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
@@ -95,7 +97,7 @@ impl ChunkIndex {
         }
 
         let content = serde_json::to_string_pretty(&self)?;
-        fs::write(path, content)?;
+        write_atomic(path, content.as_bytes())?;
         debug!("Saved chunk index with {} chunks", self.chunk_refs.len());
         Ok(())
     }
@@ -105,13 +107,11 @@ impl ChunkIndex {
         let snapshot_id = &manifest.snapshot_id;
         info!("Adding snapshot {} to chunk index", snapshot_id);
 
-        for file in &manifest.files {
-            for chunk in &file.chunks {
-                self.chunk_refs
-                    .entry(chunk.clone())
-                    .or_insert_with(HashSet::new)
-                    .insert(snapshot_id.clone());
-            }
+        for chunk in manifest.referenced_chunks() {
+            self.chunk_refs
+                .entry(chunk.clone())
+                .or_default()
+                .insert(snapshot_id.clone());
         }
     }
 
@@ -123,16 +123,14 @@ impl ChunkIndex {
 
         let mut orphaned_chunks = HashSet::new();
 
-        for file in &manifest.files {
-            for chunk in &file.chunks {
-                if let Some(refs) = self.chunk_refs.get_mut(chunk) {
-                    refs.remove(snapshot_id);
-                    
-                    // If no more references, mark as orphaned
-                    if refs.is_empty() {
-                        self.chunk_refs.remove(chunk);
-                        orphaned_chunks.insert(chunk.clone());
-                    }
+        for chunk in manifest.referenced_chunks() {
+            if let Some(refs) = self.chunk_refs.get_mut(chunk) {
+                refs.remove(snapshot_id);
+
+                // If no more references, mark as orphaned
+                if refs.is_empty() {
+                    self.chunk_refs.remove(chunk);
+                    orphaned_chunks.insert(chunk.clone());
                 }
             }
         }
@@ -160,6 +158,14 @@ impl ChunkIndex {
         self.chunk_refs.len()
     }
 
+    /// IDs of all snapshots referencing at least one chunk
+    pub fn snapshot_ids(&self) -> HashSet<&str> {
+        self.chunk_refs
+            .values()
+            .flat_map(|refs| refs.iter().map(String::as_str))
+            .collect()
+    }
+
     /// Get all chunks in the index
     pub fn all_chunks(&self) -> Vec<ChunkHash> {
         self.chunk_refs.keys().cloned().collect()
@@ -176,28 +182,16 @@ impl ChunkIndex {
     }
 
     /// Rebuild index from scratch by scanning all manifests
-    pub fn rebuild<P: AsRef<Path>>(snapshots_dir: P) -> Result<Self> {
+    pub fn rebuild(repo: &Repository) -> Result<Self> {
         let mut index = Self::new();
-        let snapshots_dir = snapshots_dir.as_ref();
-
-        if !snapshots_dir.exists() {
-            return Ok(index);
-        }
 
-        info!("Rebuilding chunk index from {}", snapshots_dir.display());
-
-        for entry in fs::read_dir(snapshots_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-
-            if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some("json") {
-                continue;
-            }
+        info!("Rebuilding chunk index from {}", repo.location());
 
+        let chunk_store = repo.chunk_store();
+        for snapshot_id in repo.snapshot_ids()? {
             // Load manifest
-            let content = fs::read_to_string(&path)?;
-            let manifest: SnapshotManifest = serde_json::from_str(&content)?;
-            
+            let manifest = repo.load_manifest(&snapshot_id, &chunk_store)?;
+
             // Add to index
             index.add_snapshot(&manifest);
         }
@@ -351,6 +345,24 @@ mod tests {
         assert_eq!(stats.avg_refs_per_chunk, 1.5);
     }
 
+    #[test]
+    fn test_rebuild_from_repository() -> Result<()> {
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new()?;
+        let repo = Repository::init(&temp_dir.path().join("repo"))?;
+        let chunk1 = hash_bytes(b"chunk1");
+        let chunk2 = hash_bytes(b"chunk2");
+        repo.save_manifest(&create_test_manifest("snap1", vec![chunk1.clone()]))?;
+        repo.save_manifest(&create_test_manifest("snap2", vec![chunk1.clone(), chunk2.clone()]))?;
+
+        let index = ChunkIndex::rebuild(&repo)?;
+        assert_eq!(index.total_chunks(), 2);
+        assert_eq!(index.get_snapshots(&chunk1).unwrap().len(), 2);
+        assert_eq!(index.get_snapshots(&chunk2).unwrap().len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn test_find_orphans() {
         let mut index = ChunkIndex::new();
@@ -1,14 +1,29 @@
+pub mod backend;
+pub mod cache;
 pub mod chunking;
 pub mod cli;
 pub mod commands;
+pub mod compression;
+pub mod crypto;
 pub mod error;
+pub mod exclude;
 pub mod index;
+pub mod metadata;
+pub mod pack;
 pub mod repository;
+pub mod retention;
+pub mod sparse;
 pub mod storage;
 pub mod utils;
+pub mod xattrs;
 
-pub use chunking::{Chunk, ChunkHash, Chunker};
+pub use backend::{Backend, LocalBackend, MemoryBackend, ObjectKind, S3Backend, S3Config};
+pub use chunking::{Chunk, ChunkHash, ChunkHasher, Chunker, ChunkerConfig};
+pub use compression::CompressionConfig;
+pub use crypto::EncryptionConfig;
 pub use error::{Result, SnapVaultError};
 pub use index::{ChunkIndex, IndexStats};
+pub use repository::locator::RepoLocator;
 pub use repository::Repository;
+pub use retention::{KeepReason, RetentionPeriod, RetentionPolicy};
 pub use storage::{ChunkStore, StorageStats};
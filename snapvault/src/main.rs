@@ -1,7 +1,11 @@
 use clap::Parser;
-use snapvault::cli::{Cli, Commands};
-use snapvault::commands;
+use snapvault::cli::{Cli, Commands, KeyCommands, MIB};
+use snapvault::commands::{self, Percentage, ReadData};
+use snapvault::crypto::EncryptionConfig;
 use snapvault::error::Result;
+use snapvault::exclude::{ByteSize, ExcludeRules};
+use snapvault::retention::RetentionPolicy;
+use snapvault::xattrs::XattrFilter;
 
 fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
@@ -9,8 +13,69 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Init { repo } => commands::init(&repo),
-        Commands::Backup { source, repo } => commands::backup(&source, &repo),
+        Commands::Init {
+            repo,
+            chunker,
+            compression,
+            compression_level,
+            pack_size,
+            encrypt,
+        } => commands::init_with_options(
+            &repo,
+            &commands::InitOptions {
+                chunker: chunker.into(),
+                compression: compression.with_level(compression_level),
+                encryption: if encrypt {
+                    EncryptionConfig::XChaCha20Poly1305
+                } else {
+                    EncryptionConfig::None
+                },
+                pack_size: Some(pack_size.saturating_mul(MIB)),
+                passphrase: None,
+            },
+        ),
+        Commands::Backup {
+            source,
+            repo,
+            tags,
+            parent,
+            force,
+            threads,
+            excludes,
+            iexcludes,
+            exclude_files,
+            exclude_if_present,
+            exclude_larger_than,
+            one_file_system,
+            xattr_includes,
+            xattr_excludes,
+        } => {
+            let mut exclude = ExcludeRules {
+                patterns: excludes,
+                ipatterns: iexcludes,
+                if_present: exclude_if_present,
+                larger_than: exclude_larger_than.map(|ByteSize(bytes)| bytes),
+                one_file_system,
+            };
+            for file in &exclude_files {
+                exclude.read_patterns(file)?;
+            }
+            commands::backup_with_options(
+                &source,
+                &repo,
+                &commands::BackupOptions {
+                    tags,
+                    parent,
+                    force,
+                    threads: threads.map(usize::from),
+                    exclude,
+                    xattrs: XattrFilter {
+                        include: xattr_includes,
+                        exclude: xattr_excludes,
+                    },
+                },
+            )
+        }
         Commands::List { repo } => commands::list(&repo),
         Commands::Delete {
             repo,
@@ -21,6 +86,77 @@ fn main() -> Result<()> {
             dest,
             snapshot,
             repo,
-        } => commands::restore(snapshot.as_deref(), &dest, &repo),
+            threads,
+            cache_size,
+            numeric_owner,
+            sparse,
+            xattr_includes,
+            xattr_excludes,
+        } => commands::restore_with_options(
+            snapshot.as_deref(),
+            &dest,
+            &repo,
+            &commands::RestoreOptions {
+                threads: threads.map(usize::from),
+                cache_size: Some(cache_size.saturating_mul(MIB)),
+                numeric_owner,
+                xattrs: XattrFilter {
+                    include: xattr_includes,
+                    exclude: xattr_excludes,
+                },
+                sparse,
+            },
+        ),
+        Commands::Forget {
+            repo,
+            keep_last,
+            keep_hourly,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            keep_yearly,
+            keep_within,
+            group_by,
+            dry_run,
+            prune,
+        } => commands::forget(
+            &repo,
+            &commands::ForgetOptions {
+                policy: RetentionPolicy {
+                    last: keep_last,
+                    hourly: keep_hourly,
+                    daily: keep_daily,
+                    weekly: keep_weekly,
+                    monthly: keep_monthly,
+                    yearly: keep_yearly,
+                    within: keep_within,
+                },
+                group_by,
+                dry_run,
+                prune,
+            },
+        ),
+        Commands::Prune { repo, dry_run } => commands::prune(&repo, dry_run),
+        Commands::Check {
+            repo,
+            read_data,
+            read_data_subset,
+            repair,
+        } => commands::check(
+            &repo,
+            match (read_data, read_data_subset) {
+                (true, _) => ReadData::All,
+                (false, Some(Percentage(percent))) => ReadData::Subset(percent),
+                (false, None) => ReadData::None,
+            },
+            repair,
+        ),
+        Commands::Unlock { repo, remove_all } => commands::unlock(&repo, remove_all),
+        Commands::Key { command } => match command {
+            KeyCommands::Add { repo, label } => commands::key_add(&repo, label.as_deref()),
+            KeyCommands::List { repo } => commands::key_list(&repo),
+            KeyCommands::Remove { repo, key } => commands::key_remove(&repo, &key),
+            KeyCommands::Passwd { repo } => commands::key_passwd(&repo),
+        },
     }
 }
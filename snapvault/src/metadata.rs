@@ -0,0 +1,336 @@
+//! POSIX file metadata: permissions, ownership and timestamps.
+//!
+//! A backup records each file's mode bits, its owner and group both by ID
+//! and by name, and its access, modification and change times with
+//! nanoseconds. A restore sets them back, except the change time, which only
+//! the kernel sets. Only root can give files away, so ownership is restored
+//! when running as root, where owners are looked up by name first so that
+//! accounts with different IDs on another host still match, or when numeric
+//! IDs are asked for, which are then used as recorded.
+
+use crate::error::Result;
+use crate::repository::snapshot::{EntryKind, FileRecord};
+use crate::xattrs::XattrWriter;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// How a restore sets the owner and group of files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ownership {
+    /// Leave files owned by the restoring user
+    Keep,
+    /// Use the accounts with the recorded names, or the recorded IDs if
+    /// there are none
+    ByName,
+    /// Use the recorded IDs, ignoring names
+    Numeric,
+}
+
+impl Ownership {
+    /// What a restore by the current user should do
+    pub fn for_restore(numeric_owner: bool) -> Self {
+        match (numeric_owner, is_root()) {
+            (true, _) => Ownership::Numeric,
+            (false, true) => Ownership::ByName,
+            (false, false) => Ownership::Keep,
+        }
+    }
+}
+
+/// Whether the process runs as root
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    false
+}
+
+/// Looks up users and groups, remembering the answers
+#[derive(Debug, Default)]
+pub struct Accounts {
+    user_names: HashMap<u32, Option<String>>,
+    group_names: HashMap<u32, Option<String>>,
+    uids: HashMap<String, Option<u32>>,
+    gids: HashMap<String, Option<u32>>,
+}
+
+#[cfg(unix)]
+impl Accounts {
+    /// Name of the user with ID `uid`
+    pub fn user_name(&mut self, uid: u32) -> Option<String> {
+        use nix::unistd::{Uid, User};
+        self.user_names
+            .entry(uid)
+            .or_insert_with(|| User::from_uid(Uid::from_raw(uid)).ok().flatten().map(|u| u.name))
+            .clone()
+    }
+
+    /// Name of the group with ID `gid`
+    pub fn group_name(&mut self, gid: u32) -> Option<String> {
+        use nix::unistd::{Gid, Group};
+        self.group_names
+            .entry(gid)
+            .or_insert_with(|| Group::from_gid(Gid::from_raw(gid)).ok().flatten().map(|g| g.name))
+            .clone()
+    }
+
+    /// ID of the user named `name` on this host
+    pub fn uid(&mut self, name: &str) -> Option<u32> {
+        use nix::unistd::User;
+        *self
+            .uids
+            .entry(name.to_string())
+            .or_insert_with(|| User::from_name(name).ok().flatten().map(|u| u.uid.as_raw()))
+    }
+
+    /// ID of the group named `name` on this host
+    pub fn gid(&mut self, name: &str) -> Option<u32> {
+        use nix::unistd::Group;
+        *self
+            .gids
+            .entry(name.to_string())
+            .or_insert_with(|| Group::from_name(name).ok().flatten().map(|g| g.gid.as_raw()))
+    }
+}
+
+#[cfg(not(unix))]
+impl Accounts {
+    pub fn user_name(&mut self, _uid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn group_name(&mut self, _gid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn uid(&mut self, _name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn gid(&mut self, _name: &str) -> Option<u32> {
+        None
+    }
+}
+
+/// Record the mode, ownership, inode, access time and change time of a
+/// file in `record`. The modification time is recorded by the caller, as on
+/// every platform.
+#[cfg(unix)]
+pub fn read_metadata(record: &mut FileRecord, md: &fs::Metadata, accounts: &mut Accounts) {
+    use std::os::unix::fs::MetadataExt;
+    record.mode = Some(md.mode() & 0o7777);
+    record.uid = Some(md.uid());
+    record.gid = Some(md.gid());
+    record.user = accounts.user_name(md.uid());
+    record.group = accounts.group_name(md.gid());
+    record.inode = Some(md.ino());
+    record.accessed = rfc3339(md.atime(), md.atime_nsec());
+    record.changed = rfc3339(md.ctime(), md.ctime_nsec());
+}
+
+#[cfg(not(unix))]
+pub fn read_metadata(record: &mut FileRecord, md: &fs::Metadata, _accounts: &mut Accounts) {
+    record.accessed = md.accessed().ok().map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339());
+}
+
+fn rfc3339(secs: i64, nanos: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp(secs, u32::try_from(nanos).ok()?).map(|t| t.to_rfc3339())
+}
+
+/// Set the recorded ownership, extended attributes, permissions and
+/// timestamps on a restored file, in that order. Failing to change the
+/// owner or to set an attribute only warns, as it is expected when not
+/// running as root.
+#[cfg(unix)]
+pub fn apply_metadata(
+    record: &FileRecord,
+    path: &Path,
+    ownership: Ownership,
+    accounts: &mut Accounts,
+    xattrs: &mut XattrWriter,
+) -> Result<()> {
+    use nix::sys::stat::{utimensat, UtimensatFlags};
+    use nix::sys::time::TimeSpec;
+    use std::os::unix::fs::{lchown, PermissionsExt};
+
+    // Changing the owner clears the setuid and setgid bits, so it goes first
+    let (uid, gid) = match ownership {
+        Ownership::Keep => (None, None),
+        Ownership::ByName => (
+            record.user.as_deref().and_then(|name| accounts.uid(name)).or(record.uid),
+            record.group.as_deref().and_then(|name| accounts.gid(name)).or(record.gid),
+        ),
+        Ownership::Numeric => (record.uid, record.gid),
+    };
+    if (uid.is_some() || gid.is_some())
+        && let Err(e) = lchown(path, uid, gid)
+    {
+        log::warn!("Failed to set owner of {}: {}", path.display(), e);
+    }
+
+    // After the owner, whose change drops file capabilities, and before the
+    // mode, which may leave the file read-only and the attributes unsettable
+    xattrs.apply(record, path);
+
+    // Symlinks have no permissions of their own; setting them would follow
+    // the link. The recorded mode carries the ACL mask the ACL was saved with.
+    if let Some(mode) = record.mode
+        && !matches!(record.kind, EntryKind::Symlink { .. })
+    {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    let timespec = |time: &Option<String>| {
+        time.as_deref()
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map_or(TimeSpec::UTIME_OMIT, |time| {
+                TimeSpec::new(time.timestamp(), time.timestamp_subsec_nanos() as _)
+            })
+    };
+    let (accessed, modified) = (timespec(&record.accessed), timespec(&record.modified));
+    if accessed != TimeSpec::UTIME_OMIT || modified != TimeSpec::UTIME_OMIT {
+        utimensat(None, path, &accessed, &modified, UtimensatFlags::NoFollowSymlink)
+            .map_err(std::io::Error::from)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply_metadata(
+    record: &FileRecord,
+    path: &Path,
+    _ownership: Ownership,
+    _accounts: &mut Accounts,
+    _xattrs: &mut XattrWriter,
+) -> Result<()> {
+    let parse = |time: &Option<String>| {
+        time.as_deref()
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map(std::time::SystemTime::from)
+    };
+    let mut times = fs::FileTimes::new();
+    if let Some(accessed) = parse(&record.accessed) {
+        times = times.set_accessed(accessed);
+    }
+    if let Some(modified) = parse(&record.modified) {
+        times = times.set_modified(modified);
+    }
+    fs::File::options().write(true).open(path)?.set_times(times)?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use nix::sys::stat::{utimensat, UtimensatFlags};
+    use nix::sys::time::TimeSpec;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use tempfile::TempDir;
+
+    fn record_of(path: &Path, accounts: &mut Accounts) -> FileRecord {
+        let md = fs::symlink_metadata(path).unwrap();
+        let modified = rfc3339(md.mtime(), md.mtime_nsec());
+        let mut record = FileRecord::new("file".to_string(), md.len(), modified, Vec::new(), None);
+        read_metadata(&mut record, &md, accounts);
+        record
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let original = temp.path().join("original");
+        let restored = temp.path().join("restored");
+        fs::write(&original, "content").unwrap();
+        fs::write(&restored, "content").unwrap();
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o4751)).unwrap();
+        let accessed = TimeSpec::new(1_600_000_000, 123_456_789);
+        let modified = TimeSpec::new(1_500_000_000, 987_654_321);
+        utimensat(None, &original, &accessed, &modified, UtimensatFlags::NoFollowSymlink).unwrap();
+
+        let mut accounts = Accounts::default();
+        let mut xattrs = XattrWriter::default();
+        let record = record_of(&original, &mut accounts);
+        assert_eq!(record.mode, Some(0o4751));
+        assert_eq!(record.modified.as_deref(), Some("2017-07-14T02:40:00.987654321+00:00"));
+        assert_eq!(record.accessed.as_deref(), Some("2020-09-13T12:26:40.123456789+00:00"));
+
+        apply_metadata(&record, &restored, Ownership::Keep, &mut accounts, &mut xattrs).unwrap();
+        let md = fs::metadata(&restored).unwrap();
+        assert_eq!(md.mode() & 0o7777, 0o4751);
+        assert_eq!((md.mtime(), md.mtime_nsec()), (1_500_000_000, 987_654_321));
+        assert_eq!((md.atime(), md.atime_nsec()), (1_600_000_000, 123_456_789));
+    }
+
+    #[test]
+    fn test_xattrs_on_read_only_file() {
+        let temp = TempDir::new().unwrap();
+        let original = temp.path().join("original");
+        let restored = temp.path().join("restored");
+        fs::write(&original, "content").unwrap();
+        fs::write(&restored, "content").unwrap();
+        if xattr::set(&original, "user.comment", b"kept").is_err() {
+            // The file system has no user attributes
+            return;
+        }
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o400)).unwrap();
+
+        let mut accounts = Accounts::default();
+        let mut record = record_of(&original, &mut accounts);
+        record.xattrs = crate::xattrs::read_xattrs(&original, &Default::default()).unwrap();
+        let mut xattrs = XattrWriter::default();
+        apply_metadata(&record, &restored, Ownership::Keep, &mut accounts, &mut xattrs).unwrap();
+
+        assert_eq!(fs::metadata(&restored).unwrap().mode() & 0o7777, 0o400);
+        assert_eq!(
+            xattr::get(&restored, "user.comment").unwrap().as_deref(),
+            Some(&b"kept"[..])
+        );
+    }
+
+    #[test]
+    fn test_ownership() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("file");
+        fs::write(&path, "content").unwrap();
+        let mut accounts = Accounts::default();
+        let mut xattrs = XattrWriter::default();
+        let mut record = record_of(&path, &mut accounts);
+        let owner = (record.uid, record.gid);
+
+        // Unknown names fall back to the recorded IDs
+        record.uid = Some(4242);
+        record.gid = Some(4343);
+        record.user = Some("no-such-snapvault-user".to_string());
+        record.group = Some("no-such-snapvault-group".to_string());
+        apply_metadata(&record, &path, Ownership::Keep, &mut accounts, &mut xattrs).unwrap();
+        let md = fs::metadata(&path).unwrap();
+        assert_eq!((Some(md.uid()), Some(md.gid())), owner);
+
+        // Only root can give files away
+        if !is_root() {
+            return;
+        }
+        apply_metadata(&record, &path, Ownership::ByName, &mut accounts, &mut xattrs).unwrap();
+        let md = fs::metadata(&path).unwrap();
+        assert_eq!((md.uid(), md.gid()), (4242, 4343));
+
+        // Names win over IDs, unless IDs are asked for
+        record.user = Some("root".to_string());
+        apply_metadata(&record, &path, Ownership::ByName, &mut accounts, &mut xattrs).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().uid(), 0);
+        apply_metadata(&record, &path, Ownership::Numeric, &mut accounts, &mut xattrs).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().uid(), 4242);
+    }
+
+    #[test]
+    fn test_accounts_resolve_root() {
+        let mut accounts = Accounts::default();
+        assert_eq!(accounts.user_name(0).as_deref(), Some("root"));
+        assert_eq!(accounts.uid("root"), Some(0));
+        assert_eq!(accounts.uid("no-such-snapvault-user"), None);
+    }
+}
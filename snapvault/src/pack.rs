@@ -0,0 +1,268 @@
+//! Pack file format.
+//!
+//! Chunks are appended into pack files of a configurable target size rather
+//! than stored one file per chunk, which keeps the number of files in a
+//! repository small. Each blob in a pack is a framed chunk exactly as
+//! `ChunkStore` encodes it (compression header, then the possibly sealed
+//! payload).
+//!
+//! Pack layout:
+//! - 4 bytes: magic `SVPK`
+//! - 1 byte: format version
+//! - blobs, back to back
+//! - trailer: JSON list of the blobs in the pack (sealed in encrypted repositories)
+//! - 4 bytes: trailer length (little-endian u32)
+//!
+//! Packs are named by the BLAKE3 hash of their contents. The trailer makes
+//! every pack self-describing; the pack index under `data/index/` maps chunk
+//! hashes to their location so reads never have to scan packs.
+
+use crate::chunking::ChunkHash;
+use crate::crypto::MasterKey;
+use crate::error::{Result, SnapVaultError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Magic bytes at the start of every pack file
+pub const PACK_MAGIC: &[u8; 4] = b"SVPK";
+
+/// Current pack format version
+pub const PACK_VERSION: u8 = 1;
+
+/// Size of the pack header in bytes
+pub const PACK_HEADER_SIZE: usize = 5;
+
+/// Default target size of a pack file
+pub const DEFAULT_PACK_SIZE: u64 = 16 * 1024 * 1024; // 16 MiB
+
+/// Smallest allowed target pack size
+pub const MIN_PACK_SIZE: u64 = 1024 * 1024; // 1 MiB
+
+/// Largest allowed target pack size
+pub const MAX_PACK_SIZE: u64 = 128 * 1024 * 1024; // 128 MiB
+
+/// Associated data for a sealed pack trailer
+const TRAILER_AAD: &[u8] = b"snapvault pack trailer";
+
+/// A chunk stored inside a pack
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PackBlob {
+    pub hash: ChunkHash,
+    /// Offset of the framed chunk from the start of the pack
+    pub offset: u64,
+    /// Length of the framed chunk in bytes
+    pub length: u64,
+}
+
+/// Where a chunk lives in the repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackLocation {
+    /// ID of the pack holding the chunk
+    pub pack: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The blobs of one pack, as recorded in a pack index file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexedPack {
+    pub id: String,
+    pub blobs: Vec<PackBlob>,
+}
+
+/// Contents of one pack index file.
+/// The full pack index is the union of all index files in the repository.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IndexFile {
+    pub packs: Vec<IndexedPack>,
+}
+
+/// Validate a target pack size
+pub fn validate_pack_size(size: u64) -> Result<()> {
+    if !(MIN_PACK_SIZE..=MAX_PACK_SIZE).contains(&size) {
+        return Err(SnapVaultError::InvalidPackSize {
+            size,
+            min: MIN_PACK_SIZE,
+            max: MAX_PACK_SIZE,
+        });
+    }
+    Ok(())
+}
+
+/// A pack being assembled in memory
+#[derive(Debug)]
+pub struct PackWriter {
+    buf: Vec<u8>,
+    blobs: Vec<PackBlob>,
+    /// Position of each chunk in `blobs`
+    lookup: HashMap<ChunkHash, usize>,
+}
+
+impl Default for PackWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PackWriter {
+    /// Start a new, empty pack
+    pub fn new() -> Self {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(PACK_MAGIC);
+        buf.push(PACK_VERSION);
+        Self {
+            buf,
+            blobs: Vec::new(),
+            lookup: HashMap::new(),
+        }
+    }
+
+    /// Whether no blobs have been added yet
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+
+    /// Current size of the pack in bytes, excluding the trailer
+    pub fn size(&self) -> u64 {
+        self.buf.len() as u64
+    }
+
+    /// Blobs added so far
+    pub fn blobs(&self) -> &[PackBlob] {
+        &self.blobs
+    }
+
+    /// Append a framed chunk to the pack
+    pub fn add(&mut self, hash: &ChunkHash, framed: &[u8]) {
+        let blob = PackBlob {
+            hash: hash.clone(),
+            offset: self.buf.len() as u64,
+            length: framed.len() as u64,
+        };
+        self.buf.extend_from_slice(framed);
+        self.lookup.insert(hash.clone(), self.blobs.len());
+        self.blobs.push(blob);
+    }
+
+    /// Get the framed bytes of a chunk already added to this pack
+    pub fn get(&self, hash: &ChunkHash) -> Option<&[u8]> {
+        let blob = &self.blobs[*self.lookup.get(hash)?];
+        Some(&self.buf[blob.offset as usize..(blob.offset + blob.length) as usize])
+    }
+
+    /// Append the trailer and return the pack's ID, bytes and blobs
+    pub fn finish(mut self, key: Option<&MasterKey>) -> Result<(String, Vec<u8>, Vec<PackBlob>)> {
+        let trailer = serde_json::to_vec(&self.blobs)?;
+        let trailer = match key {
+            Some(key) => key.seal(&trailer, TRAILER_AAD)?,
+            None => trailer,
+        };
+        let trailer_len = u32::try_from(trailer.len())
+            .map_err(|_| SnapVaultError::Other("Pack trailer too large".to_string()))?;
+        self.buf.extend_from_slice(&trailer);
+        self.buf.extend_from_slice(&trailer_len.to_le_bytes());
+
+        let id = blake3::hash(&self.buf).to_hex().to_string();
+        Ok((id, self.buf, self.blobs))
+    }
+}
+
+/// Parse the list of blobs from the trailer of a complete pack
+pub fn read_trailer(pack: &[u8], key: Option<&MasterKey>) -> Result<Vec<PackBlob>> {
+    let invalid = || SnapVaultError::Other("Invalid pack file".to_string());
+    if pack.len() < PACK_HEADER_SIZE + 4 || &pack[..4] != PACK_MAGIC {
+        return Err(invalid());
+    }
+    if pack[4] != PACK_VERSION {
+        return Err(SnapVaultError::UnsupportedVersion {
+            version: pack[4] as u32,
+            expected: PACK_VERSION as u32,
+        });
+    }
+
+    let (body, len) = pack.split_at(pack.len() - 4);
+    let trailer_len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if trailer_len > body.len() - PACK_HEADER_SIZE {
+        return Err(invalid());
+    }
+    let trailer = &body[body.len() - trailer_len..];
+    let trailer = match key {
+        Some(key) => key.open(trailer, TRAILER_AAD)?,
+        None => trailer.to_vec(),
+    };
+    let blobs: Vec<PackBlob> = serde_json::from_slice(&trailer)?;
+
+    let data_end = (body.len() - trailer_len) as u64;
+    if blobs
+        .iter()
+        .any(|b| b.offset < PACK_HEADER_SIZE as u64 || b.offset + b.length > data_end)
+    {
+        return Err(invalid());
+    }
+    Ok(blobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::hash_bytes;
+
+    fn sample_pack(key: Option<&MasterKey>) -> (String, Vec<u8>, Vec<PackBlob>) {
+        let mut writer = PackWriter::new();
+        writer.add(&hash_bytes(b"one"), b"framed one");
+        writer.add(&hash_bytes(b"two"), b"framed two!");
+        writer.finish(key).unwrap()
+    }
+
+    #[test]
+    fn test_writer_layout() {
+        let mut writer = PackWriter::new();
+        assert!(writer.is_empty());
+        assert_eq!(writer.size(), PACK_HEADER_SIZE as u64);
+
+        let hash = hash_bytes(b"one");
+        writer.add(&hash, b"framed one");
+        assert_eq!(writer.get(&hash), Some(&b"framed one"[..]));
+        assert_eq!(writer.blobs()[0].offset, PACK_HEADER_SIZE as u64);
+        assert!(writer.get(&hash_bytes(b"missing")).is_none());
+    }
+
+    #[test]
+    fn test_trailer_roundtrip() {
+        let (id, bytes, blobs) = sample_pack(None);
+        assert_eq!(id, blake3::hash(&bytes).to_hex().to_string());
+        assert_eq!(read_trailer(&bytes, None).unwrap(), blobs);
+
+        let second = &blobs[1];
+        let start = second.offset as usize;
+        assert_eq!(&bytes[start..start + second.length as usize], b"framed two!");
+    }
+
+    #[test]
+    fn test_encrypted_trailer() {
+        let key = MasterKey::generate();
+        let (_, bytes, blobs) = sample_pack(Some(&key));
+
+        assert_eq!(read_trailer(&bytes, Some(&key)).unwrap(), blobs);
+        assert!(read_trailer(&bytes, Some(&MasterKey::generate())).is_err());
+        assert!(read_trailer(&bytes, None).is_err());
+    }
+
+    #[test]
+    fn test_read_trailer_rejects_garbage() {
+        assert!(read_trailer(b"not a pack", None).is_err());
+
+        let (_, mut bytes, _) = sample_pack(None);
+        let len = bytes.len();
+        bytes[len - 1] = 0xff; // absurd trailer length
+        assert!(read_trailer(&bytes, None).is_err());
+    }
+
+    #[test]
+    fn test_validate_pack_size() {
+        assert!(validate_pack_size(DEFAULT_PACK_SIZE).is_ok());
+        assert!(validate_pack_size(MAX_PACK_SIZE).is_ok());
+        assert!(validate_pack_size(MIN_PACK_SIZE - 1).is_err());
+        assert!(validate_pack_size(MAX_PACK_SIZE + 1).is_err());
+    }
+}
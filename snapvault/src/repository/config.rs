@@ -1,9 +1,32 @@
+use crate::chunking::ChunkerConfig;
+use crate::compression::CompressionConfig;
+use crate::crypto::EncryptionConfig;
+use crate::pack::DEFAULT_PACK_SIZE;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RepoConfig {
     pub version: u32,
     pub created_at: String,
+    /// Chunking algorithm used for every backup into this repository.
+    /// Repositories created before this field existed use fixed-size chunks.
+    #[serde(default)]
+    pub chunker: ChunkerConfig,
+    /// Compression applied to newly stored chunks. Each chunk records its
+    /// own codec, so changing this never affects existing data.
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Encryption of chunks, manifests and the index. The wrapped master key
+    /// lives in `keys/`.
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    /// Target size in bytes of the pack files chunks are appended to
+    #[serde(default = "default_pack_size")]
+    pub pack_size: u64,
+}
+
+fn default_pack_size() -> u64 {
+    DEFAULT_PACK_SIZE
 }
 
 impl RepoConfig {
@@ -11,6 +34,10 @@ impl RepoConfig {
         Self {
             version: 1,
             created_at: chrono::Utc::now().to_rfc3339(),
+            chunker: ChunkerConfig::default(),
+            compression: CompressionConfig::default(),
+            encryption: EncryptionConfig::default(),
+            pack_size: DEFAULT_PACK_SIZE,
         }
     }
 }
@@ -0,0 +1,234 @@
+//! Repository locations.
+//!
+//! Every command names its repository with a `RepoLocator`, parsed from
+//! `--repo` or the `SNAPVAULT_REPOSITORY` environment variable:
+//!
+//! - `/path/to/repo` or `local:/path/to/repo`: a local directory
+//! - `sftp://[user@]host[:port]/path`: a directory on a server reachable over SSH
+//! - `s3://bucket[/prefix]`: an S3 bucket; the endpoint defaults to AWS and
+//!   can be changed with `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL`
+//! - `rest://host[:port]/path`: a REST server (`rest+http://` for plain HTTP)
+
+use crate::backend::sftp::SFTP_SCHEME;
+use crate::backend::{Backend, LocalBackend, S3Backend, S3Config, SftpBackend, SftpConfig};
+use crate::error::{Result, SnapVaultError};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Environment variable naming the repository when `--repo` is not given
+pub const REPOSITORY_ENV: &str = "SNAPVAULT_REPOSITORY";
+
+const LOCAL_SCHEME: &str = "local:";
+const S3_SCHEME: &str = "s3://";
+const REST_SCHEME: &str = "rest://";
+const REST_HTTP_SCHEME: &str = "rest+http://";
+
+/// Where a repository lives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepoLocator {
+    /// A local directory
+    Local(PathBuf),
+    /// A directory on a server reachable over SSH
+    Sftp(SftpConfig),
+    /// A key prefix in an S3 bucket
+    S3 { bucket: String, prefix: String },
+    /// A REST server, as the base URL of the repository
+    Rest(String),
+}
+
+impl RepoLocator {
+    /// Parse a repository location
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| SnapVaultError::InvalidRepoLocation {
+            location: s.to_string(),
+            reason: reason.to_string(),
+        };
+
+        if let Some(path) = s.strip_prefix(LOCAL_SCHEME) {
+            if path.is_empty() {
+                return Err(invalid("missing path"));
+            }
+            return Ok(RepoLocator::Local(PathBuf::from(path)));
+        }
+        if s.starts_with(SFTP_SCHEME) {
+            return Ok(RepoLocator::Sftp(SftpConfig::parse(s)?));
+        }
+        if let Some(rest) = s.strip_prefix(S3_SCHEME) {
+            let rest = rest.trim_matches('/');
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            if bucket.is_empty() {
+                return Err(invalid("missing bucket"));
+            }
+            return Ok(RepoLocator::S3 {
+                bucket: bucket.to_string(),
+                prefix: prefix.to_string(),
+            });
+        }
+        for (scheme, http_scheme) in [(REST_SCHEME, "https://"), (REST_HTTP_SCHEME, "http://")] {
+            if let Some(rest) = s.strip_prefix(scheme) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    return Err(invalid("missing host"));
+                }
+                return Ok(RepoLocator::Rest(format!("{}{}", http_scheme, rest)));
+            }
+        }
+        if let Some((scheme, _)) = s.split_once("://") {
+            return Err(invalid(&format!("unknown scheme {}://", scheme)));
+        }
+        if s.is_empty() {
+            return Err(invalid("empty location"));
+        }
+        Ok(RepoLocator::Local(PathBuf::from(s)))
+    }
+
+    /// Parse the location in `SNAPVAULT_REPOSITORY`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(REPOSITORY_ENV) {
+            Ok(location) => Self::parse(&location),
+            Err(_) => Err(SnapVaultError::RepoLocationRequired),
+        }
+    }
+
+    /// Path of a local repository
+    pub fn local_path(&self) -> Option<&Path> {
+        match self {
+            RepoLocator::Local(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Connect to the storage holding the repository
+    pub fn backend(&self) -> Result<Arc<dyn Backend>> {
+        Ok(match self {
+            RepoLocator::Local(path) => Arc::new(LocalBackend::new(path)),
+            RepoLocator::Sftp(config) => Arc::new(SftpBackend::new(config.clone())),
+            RepoLocator::S3 { bucket, prefix } => {
+                let config = S3Config::from_env(&s3_endpoint(), bucket, prefix)?;
+                Arc::new(S3Backend::new(config)?)
+            }
+            RepoLocator::Rest(url) => {
+                return Err(SnapVaultError::Backend(format!(
+                    "REST repositories are not supported yet: {}",
+                    url
+                )));
+            }
+        })
+    }
+}
+
+/// Endpoint of the S3 service: the standard AWS endpoint overrides, or the
+/// regional AWS endpoint
+fn s3_endpoint() -> String {
+    let endpoint = std::env::var("AWS_ENDPOINT_URL_S3").or_else(|_| std::env::var("AWS_ENDPOINT_URL"));
+    if let Ok(endpoint) = endpoint {
+        return endpoint;
+    }
+    match std::env::var("AWS_REGION").or_else(|_| std::env::var("AWS_DEFAULT_REGION")) {
+        Ok(region) => format!("https://s3.{}.amazonaws.com", region),
+        Err(_) => "https://s3.amazonaws.com".to_string(),
+    }
+}
+
+impl FromStr for RepoLocator {
+    type Err = SnapVaultError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for RepoLocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoLocator::Local(path) => write!(f, "{}", path.display()),
+            RepoLocator::Sftp(config) => write!(f, "{}", config),
+            RepoLocator::S3 { bucket, prefix } if prefix.is_empty() => {
+                write!(f, "{}{}", S3_SCHEME, bucket)
+            }
+            RepoLocator::S3 { bucket, prefix } => write!(f, "{}{}/{}", S3_SCHEME, bucket, prefix),
+            RepoLocator::Rest(url) => match url.strip_prefix("https://") {
+                Some(rest) => write!(f, "{}{}", REST_SCHEME, rest),
+                None => write!(f, "{}{}", REST_HTTP_SCHEME, url.trim_start_matches("http://")),
+            },
+        }
+    }
+}
+
+impl From<&Path> for RepoLocator {
+    fn from(path: &Path) -> Self {
+        RepoLocator::Local(path.to_path_buf())
+    }
+}
+
+impl From<&PathBuf> for RepoLocator {
+    fn from(path: &PathBuf) -> Self {
+        RepoLocator::Local(path.clone())
+    }
+}
+
+impl From<PathBuf> for RepoLocator {
+    fn from(path: PathBuf) -> Self {
+        RepoLocator::Local(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_local() {
+        assert_eq!(
+            RepoLocator::parse("/srv/repo").unwrap(),
+            RepoLocator::Local(PathBuf::from("/srv/repo"))
+        );
+        assert_eq!(
+            RepoLocator::parse("local:relative/repo").unwrap(),
+            RepoLocator::Local(PathBuf::from("relative/repo"))
+        );
+        assert!(RepoLocator::parse("local:").is_err());
+        assert!(RepoLocator::parse("").is_err());
+    }
+
+    #[test]
+    fn test_parse_remote() {
+        let sftp = RepoLocator::parse("sftp://backup@nas:/srv/repo").unwrap();
+        assert_eq!(sftp.to_string(), "sftp://backup@nas/srv/repo");
+        assert!(sftp.local_path().is_none());
+
+        assert_eq!(
+            RepoLocator::parse("s3://backups/hosts/web1/").unwrap(),
+            RepoLocator::S3 {
+                bucket: "backups".to_string(),
+                prefix: "hosts/web1".to_string(),
+            }
+        );
+        assert_eq!(RepoLocator::parse("s3://backups").unwrap().to_string(), "s3://backups");
+        assert!(RepoLocator::parse("s3://").is_err());
+
+        let rest = RepoLocator::parse("rest+http://localhost:8000/repo").unwrap();
+        assert_eq!(rest, RepoLocator::Rest("http://localhost:8000/repo".to_string()));
+        assert_eq!(rest.to_string(), "rest+http://localhost:8000/repo");
+        assert_eq!(
+            RepoLocator::parse("rest://host/repo").unwrap(),
+            RepoLocator::Rest("https://host/repo".to_string())
+        );
+        assert!(RepoLocator::parse("rest:///repo").is_err());
+    }
+
+    #[test]
+    fn test_parse_unknown_scheme() {
+        assert!(matches!(
+            RepoLocator::parse("ftp://host/repo"),
+            Err(SnapVaultError::InvalidRepoLocation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rest_is_not_supported() {
+        let rest = RepoLocator::parse("rest://host/repo").unwrap();
+        assert!(matches!(rest.backend(), Err(SnapVaultError::Backend(_))));
+    }
+}
@@ -0,0 +1,402 @@
+//! Repository locks.
+//!
+//! Every command that reads or writes a repository first writes a lock file
+//! under `locks/`. Shared locks (backup, restore, list) can be held by any
+//! number of processes at once; an exclusive lock (delete, forget, check,
+//! prune) excludes every other lock. A backup also takes a short index lock
+//! while it adds its snapshot to `index.json`, which excludes other index
+//! locks but not shared ones, so backups running side by side update the
+//! index one after the other. A lock records the host, PID and time it was
+//! taken and is refreshed while held, so locks left behind by crashed
+//! processes can be recognized as stale and removed with `snapvault unlock`.
+//!
+//! Lock files are plain JSON even in encrypted repositories, as they hold
+//! nothing secret. A lock file that cannot be parsed, such as one written by
+//! a newer version, conflicts with every lock and is only removed by
+//! `snapvault unlock --remove-all`.
+
+use crate::backend::{Backend, ObjectKind};
+use crate::error::{Result, SnapVaultError};
+use crate::utils::hostname;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often a held lock is refreshed
+pub const LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A lock not refreshed for this long is stale
+pub const STALE_LOCK_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait for another process to finish its index update
+pub const INDEX_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Kind of repository lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Held together with other shared locks (backup, restore, list)
+    Shared,
+    /// Held alone (delete, forget, check, prune)
+    Exclusive,
+    /// Held alone among index locks, alongside shared locks (a backup
+    /// updating the chunk index)
+    Index,
+}
+
+/// Contents of a lock file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub exclusive: bool,
+    /// Whether this is an index lock
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub index: bool,
+    pub hostname: String,
+    pub pid: u32,
+    pub created_at: String,
+    /// Last time the holder refreshed the lock
+    pub refreshed_at: String,
+}
+
+impl LockInfo {
+    /// Lock information for the current process
+    pub fn current(mode: LockMode) -> Self {
+        let now = Utc::now().to_rfc3339();
+        Self {
+            exclusive: mode == LockMode::Exclusive,
+            index: mode == LockMode::Index,
+            hostname: hostname(),
+            pid: std::process::id(),
+            created_at: now.clone(),
+            refreshed_at: now,
+        }
+    }
+
+    /// Whether the lock was left behind by a process that is gone: its
+    /// holder runs on this host and has exited, or it has not been
+    /// refreshed for `STALE_LOCK_AGE`
+    pub fn is_stale(&self) -> bool {
+        if self.hostname == hostname() && !process_alive(self.pid) {
+            return true;
+        }
+        match DateTime::parse_from_rfc3339(&self.refreshed_at) {
+            Ok(refreshed) => {
+                let age = Utc::now().signed_duration_since(refreshed);
+                age.to_std().is_ok_and(|age| age > STALE_LOCK_AGE)
+            }
+            // Keep locks we cannot interpret; `unlock --remove-all` clears them
+            Err(_) => false,
+        }
+    }
+
+    /// Whether this lock prevents taking a lock of `mode`
+    fn conflicts_with(&self, mode: LockMode) -> bool {
+        match mode {
+            LockMode::Shared => self.exclusive,
+            LockMode::Exclusive => true,
+            LockMode::Index => self.exclusive || self.index,
+        }
+    }
+
+    /// Short name of the kind of lock
+    pub fn mode_name(&self) -> &'static str {
+        if self.exclusive {
+            "exclusive"
+        } else if self.index {
+            "index"
+        } else {
+            "shared"
+        }
+    }
+
+    fn held_error(&self, id: &str) -> SnapVaultError {
+        let article = if self.exclusive || self.index { "an" } else { "a" };
+        SnapVaultError::RepoLocked {
+            lock: id.to_string(),
+            holder: format!(
+                "{} {} lock of PID {} on {} since {}",
+                article,
+                self.mode_name(),
+                self.pid,
+                self.hostname,
+                self.created_at
+            ),
+            remedy: "run `snapvault unlock` if that process is gone".to_string(),
+        }
+    }
+}
+
+/// A lock held on a repository, removed when dropped
+pub struct RepoLock {
+    backend: Arc<dyn Backend>,
+    id: String,
+    refresher: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl RepoLock {
+    /// Take a lock, failing with `RepoLocked` if a conflicting lock that is
+    /// not stale is held
+    pub fn acquire(backend: Arc<dyn Backend>, mode: LockMode) -> Result<Self> {
+        check_conflicts(backend.as_ref(), mode, None)?;
+
+        let info = LockInfo::current(mode);
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        backend.put(ObjectKind::Lock, &id, &serde_json::to_vec_pretty(&info)?)?;
+
+        // Another process may have written a conflicting lock between the
+        // check and our write; whoever sees the other backs off
+        if let Err(e) = check_conflicts(backend.as_ref(), mode, Some(&id)) {
+            backend.delete(ObjectKind::Lock, &id)?;
+            return Err(e);
+        }
+        debug!("Acquired {:?} lock {} on {}", mode, id, backend.location());
+
+        let refresher = spawn_refresher(backend.clone(), id.clone(), info);
+        Ok(Self {
+            backend,
+            id,
+            refresher: Some(refresher),
+        })
+    }
+
+    /// Take a lock like `acquire`, retrying for up to `timeout` while a
+    /// conflicting lock is held
+    pub fn acquire_waiting(backend: Arc<dyn Backend>, mode: LockMode, timeout: Duration) -> Result<Self> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match Self::acquire(backend.clone(), mode) {
+                Err(SnapVaultError::RepoLocked { .. }) if Instant::now() < deadline => {
+                    // Two processes that both saw the other's lock and backed
+                    // off must not retry in step
+                    let jitter = uuid::Uuid::new_v4().as_u128() % 100;
+                    thread::sleep(Duration::from_millis(50 + jitter as u64));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// ID of the lock file
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.refresher.take() {
+            drop(stop);
+            let _ = handle.join();
+        }
+        if let Err(e) = self.backend.delete(ObjectKind::Lock, &self.id) {
+            warn!("Failed to remove lock {}: {}", self.id, e);
+        }
+    }
+}
+
+/// Rewrite the lock every `LOCK_REFRESH_INTERVAL` until the sender is dropped
+fn spawn_refresher(
+    backend: Arc<dyn Backend>,
+    id: String,
+    mut info: LockInfo,
+) -> (Sender<()>, JoinHandle<()>) {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let handle = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(LOCK_REFRESH_INTERVAL) {
+            info.refreshed_at = Utc::now().to_rfc3339();
+            let written = serde_json::to_vec_pretty(&info)
+                .map_err(SnapVaultError::from)
+                .and_then(|data| backend.put(ObjectKind::Lock, &id, &data));
+            if let Err(e) = written {
+                warn!("Failed to refresh lock {}: {}", id, e);
+            }
+        }
+    });
+    (stop, handle)
+}
+
+/// Fail if a lock other than `own` that is not stale conflicts with `mode`.
+/// A lock file that cannot be parsed conflicts with every mode.
+fn check_conflicts(backend: &dyn Backend, mode: LockMode, own: Option<&str>) -> Result<()> {
+    for (id, info) in read_locks(backend)? {
+        if Some(id.as_str()) == own {
+            continue;
+        }
+        let Some(info) = info else {
+            return Err(SnapVaultError::RepoLocked {
+                lock: id,
+                holder: "a lock file that cannot be parsed".to_string(),
+                remedy: "remove it with `snapvault unlock --remove-all` if no process holds it"
+                    .to_string(),
+            });
+        };
+        if !info.conflicts_with(mode) {
+            continue;
+        }
+        if info.is_stale() {
+            debug!("Ignoring stale lock {}", id);
+            continue;
+        }
+        return Err(info.held_error(&id));
+    }
+    Ok(())
+}
+
+/// Read every lock file as (lock ID, contents). Lock files that cannot be
+/// parsed come without contents.
+pub fn read_locks(backend: &dyn Backend) -> Result<Vec<(String, Option<LockInfo>)>> {
+    let mut locks = Vec::new();
+    for id in backend.list(ObjectKind::Lock)? {
+        let raw = match backend.get(ObjectKind::Lock, &id) {
+            Ok(raw) => raw,
+            // Released while we were listing
+            Err(SnapVaultError::ObjectNotFound { .. }) => continue,
+            Err(e) => return Err(e),
+        };
+        let info = match serde_json::from_slice(&raw) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                warn!("Cannot parse lock {}: {}", id, e);
+                None
+            }
+        };
+        locks.push((id, info));
+    }
+    Ok(locks)
+}
+
+/// Whether a process with this PID runs on this host. Without `/proc` this
+/// cannot be told, and the process is assumed alive.
+fn process_alive(pid: u32) -> bool {
+    let proc = Path::new("/proc");
+    !proc.is_dir() || proc.join(pid.to_string()).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    fn backend() -> Arc<dyn Backend> {
+        Arc::new(MemoryBackend::new())
+    }
+
+    fn put_lock(backend: &dyn Backend, id: &str, info: &LockInfo) {
+        backend
+            .put(ObjectKind::Lock, id, &serde_json::to_vec(info).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_shared_locks_coexist() {
+        let backend = backend();
+        let first = RepoLock::acquire(backend.clone(), LockMode::Shared).unwrap();
+        let second = RepoLock::acquire(backend.clone(), LockMode::Shared).unwrap();
+        let locks = read_locks(backend.as_ref()).unwrap();
+        assert_eq!(locks.len(), 2);
+        assert!(locks.iter().all(|(_, info)| info.is_some()));
+
+        drop(first);
+        drop(second);
+        assert!(backend.list(ObjectKind::Lock).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_exclusive_lock_conflicts() {
+        let backend = backend();
+        let shared = RepoLock::acquire(backend.clone(), LockMode::Shared).unwrap();
+        assert!(matches!(
+            RepoLock::acquire(backend.clone(), LockMode::Exclusive),
+            Err(SnapVaultError::RepoLocked { .. })
+        ));
+        drop(shared);
+
+        let _exclusive = RepoLock::acquire(backend.clone(), LockMode::Exclusive).unwrap();
+        assert!(matches!(
+            RepoLock::acquire(backend.clone(), LockMode::Shared),
+            Err(SnapVaultError::RepoLocked { .. })
+        ));
+        // The failed attempts left no lock files behind
+        assert_eq!(backend.list(ObjectKind::Lock).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_index_locks_exclude_each_other() {
+        let backend = backend();
+        let _shared = RepoLock::acquire(backend.clone(), LockMode::Shared).unwrap();
+        let index = RepoLock::acquire(backend.clone(), LockMode::Index).unwrap();
+        // Alongside shared locks, but not other index or exclusive locks
+        RepoLock::acquire(backend.clone(), LockMode::Shared).unwrap();
+        for mode in [LockMode::Index, LockMode::Exclusive] {
+            assert!(matches!(
+                RepoLock::acquire(backend.clone(), mode),
+                Err(SnapVaultError::RepoLocked { .. })
+            ));
+        }
+
+        // A waiting index lock is taken once the other one is released
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(index);
+        });
+        RepoLock::acquire_waiting(backend.clone(), LockMode::Index, INDEX_LOCK_TIMEOUT).unwrap();
+        release.join().unwrap();
+
+        let _held = RepoLock::acquire(backend.clone(), LockMode::Index).unwrap();
+        assert!(RepoLock::acquire_waiting(backend, LockMode::Index, Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_stale_locks_are_ignored() {
+        let backend = backend();
+
+        // Held by a process on this host that no longer exists
+        let mut dead = LockInfo::current(LockMode::Exclusive);
+        dead.pid = u32::MAX;
+        put_lock(backend.as_ref(), "dead", &dead);
+
+        // Held on another host but not refreshed for too long
+        let mut old = LockInfo::current(LockMode::Exclusive);
+        old.hostname = "elsewhere".to_string();
+        old.refreshed_at = (Utc::now() - chrono::Duration::hours(1)).to_rfc3339();
+        put_lock(backend.as_ref(), "old", &old);
+
+        assert!(dead.is_stale());
+        assert!(old.is_stale());
+        assert!(!LockInfo::current(LockMode::Shared).is_stale());
+        RepoLock::acquire(backend, LockMode::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_lock_conflicts() {
+        let backend = backend();
+        backend.put(ObjectKind::Lock, "garbled", b"{\"exclusive\": tr").unwrap();
+
+        for mode in [LockMode::Shared, LockMode::Exclusive, LockMode::Index] {
+            let err = RepoLock::acquire(backend.clone(), mode).err().unwrap();
+            let message = err.to_string();
+            assert!(message.contains("garbled"), "{}", message);
+            assert!(message.contains("--remove-all"), "{}", message);
+        }
+        assert_eq!(backend.list(ObjectKind::Lock).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_locked_error_names_holder() {
+        let backend = backend();
+        let mut info = LockInfo::current(LockMode::Exclusive);
+        info.hostname = "backup-host".to_string();
+        info.pid = 4242;
+        put_lock(backend.as_ref(), "held", &info);
+
+        let err = RepoLock::acquire(backend, LockMode::Shared).err().unwrap();
+        let message = err.to_string();
+        assert!(message.contains("backup-host"), "{}", message);
+        assert!(message.contains("4242"), "{}", message);
+        assert!(message.contains("held"), "{}", message);
+    }
+}
@@ -1,92 +1,210 @@
 pub mod config;
+pub mod locator;
+pub mod lock;
 pub mod snapshot;
+pub mod tree;
 
+use crate::backend::{Backend, ObjectKind};
+use crate::chunking::{ChunkHasher, Chunker};
+use crate::crypto::{self, KdfParams, KeyFile, MasterKey};
 use crate::error::{Result, SnapVaultError};
-use crate::utils::MAX_CONFIG_SIZE;
+use crate::index::ChunkIndex;
+use crate::pack;
+use crate::storage::ChunkStore;
+use crate::utils::{validate_snapshot_id, MAX_CONFIG_SIZE, MAX_MANIFEST_SIZE, SNAPSHOT_UUID_LEN};
 use config::RepoConfig;
-use log::info;
-use std::fs;
+use locator::RepoLocator;
+use lock::{LockInfo, LockMode, RepoLock, INDEX_LOCK_TIMEOUT};
+use log::{debug, info};
+use snapshot::SnapshotManifest;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Repository structure representing a SnapVault backup repository
 pub struct Repository {
-    root: PathBuf,
+    /// Storage holding every object of the repository
+    backend: Arc<dyn Backend>,
     config: RepoConfig,
+    /// Master key, present only for encrypted repositories
+    key: Option<MasterKey>,
+    /// ID of the key slot that unlocked the master key
+    key_id: Option<String>,
 }
 
 impl Repository {
-    /// Open an existing repository
+    /// Open an existing local repository.
+    /// For encrypted repositories the passphrase comes from the environment
+    /// (see `crypto::read_passphrase`) or is prompted for on the terminal.
     pub fn open(path: &Path) -> Result<Self> {
-        if !path.exists() {
+        Self::open_at(&path.into(), None)
+    }
+
+    /// Open an existing local repository, unlocking it with `passphrase` if encrypted
+    pub fn open_with_passphrase(path: &Path, passphrase: &str) -> Result<Self> {
+        Self::open_at(&path.into(), Some(passphrase))
+    }
+
+    /// Open an existing repository at any location.
+    /// Encrypted repositories are unlocked with `passphrase`, or with the
+    /// passphrase from the environment or the terminal when it is None.
+    pub fn open_at(repo: &RepoLocator, passphrase: Option<&str>) -> Result<Self> {
+        if let Some(path) = repo.local_path()
+            && !path.exists()
+        {
             return Err(SnapVaultError::RepoNotFound(path.to_path_buf()));
         }
+        Self::open_backend(repo.backend()?, passphrase)
+    }
 
-        let config = Self::load_config(path)?;
+    /// Open an existing repository stored in `backend`.
+    /// Encrypted repositories are unlocked with `passphrase`, or with the
+    /// passphrase from `SNAPVAULT_PASSWORD` or the terminal when it is None.
+    pub fn open_backend(backend: Arc<dyn Backend>, passphrase: Option<&str>) -> Result<Self> {
+        let config = Self::load_config(backend.as_ref())?;
+
+        let (key, key_id) = if config.encryption.is_enabled() {
+            let passphrase = match passphrase {
+                Some(p) => p.to_string(),
+                None => crypto::read_passphrase("Enter repository passphrase: ")?,
+            };
+            let (key, key_id) = Self::unlock(backend.as_ref(), &passphrase)?;
+            (Some(key), Some(key_id))
+        } else {
+            (None, None)
+        };
 
         Ok(Self {
-            root: path.to_path_buf(),
+            backend,
             config,
+            key,
+            key_id,
         })
     }
 
-    /// Initialize a new repository
+    /// Try every key slot in the repository until one unlocks with `passphrase`.
+    /// Returns the master key and the ID of the slot that unlocked it.
+    fn unlock(backend: &dyn Backend, passphrase: &str) -> Result<(MasterKey, String)> {
+        let key_files = read_key_files(backend)?;
+        if key_files.is_empty() {
+            return Err(SnapVaultError::InvalidRepo(
+                PathBuf::from(backend.location()).join("keys"),
+            ));
+        }
+
+        for (key_id, key_file) in key_files {
+            match key_file.unlock(passphrase) {
+                Ok(key) => {
+                    debug!("Unlocked repository with key {}", key_id);
+                    return Ok((key, key_id));
+                }
+                Err(SnapVaultError::WrongPassphrase) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(SnapVaultError::WrongPassphrase)
+    }
+
+    /// Initialize a new unencrypted repository with the default configuration
     pub fn init(path: &Path) -> Result<Self> {
-        info!("Initializing repository at: {}", path.display());
+        Self::init_with_config(path, RepoConfig::new(), None)
+    }
+
+    /// Initialize a new local repository with the given configuration.
+    /// `passphrase` is required when `config.encryption` is enabled.
+    pub fn init_with_config(
+        path: &Path,
+        config: RepoConfig,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        Self::init_at(&path.into(), config, passphrase)
+    }
 
-        if path.exists() {
+    /// Initialize a new repository at any location.
+    /// `passphrase` is required when `config.encryption` is enabled.
+    pub fn init_at(
+        repo: &RepoLocator,
+        config: RepoConfig,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        if let Some(path) = repo.local_path()
+            && path.exists()
+        {
             return Err(SnapVaultError::RepoAlreadyExists(path.to_path_buf()));
         }
+        Self::init_backend(repo.backend()?, config, passphrase)
+    }
 
-        fs::create_dir_all(path).map_err(|e| {
-            SnapVaultError::Io(std::io::Error::new(
-                e.kind(),
-                format!("Failed to create repository directory: {}", e),
-            ))
-        })?;
+    /// Initialize a new repository in `backend` with the given configuration.
+    /// `passphrase` is required when `config.encryption` is enabled.
+    pub fn init_backend(
+        backend: Arc<dyn Backend>,
+        config: RepoConfig,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        info!("Initializing repository at: {}", backend.location());
+
+        config.chunker.validate()?;
+        config.compression.validate()?;
+        pack::validate_pack_size(config.pack_size)?;
+        if config.encryption.is_enabled() && passphrase.is_none() {
+            return Err(SnapVaultError::PassphraseRequired);
+        }
 
-        // Set permissions on Unix (owner only)
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(path)?.permissions();
-            perms.set_mode(0o700);
-            fs::set_permissions(path, perms)?;
+        if backend.exists(ObjectKind::Config, "")? {
+            return Err(SnapVaultError::RepoAlreadyExists(PathBuf::from(
+                backend.location(),
+            )));
         }
 
-        fs::create_dir_all(path.join("snapshots"))?;
-        fs::create_dir_all(path.join("data"))?;
-        fs::create_dir_all(path.join("data").join("chunks"))?;
+        backend.create()?;
+
+        let (key, key_id) = match passphrase.filter(|_| config.encryption.is_enabled()) {
+            Some(passphrase) => {
+                let key = MasterKey::generate();
+                let key_file = KeyFile::create(&key, passphrase, KdfParams::default())?;
+                let key_id = write_key_file(backend.as_ref(), &key_file)?;
+                (Some(key), Some(key_id))
+            }
+            None => (None, None),
+        };
 
-        let config = RepoConfig::new();
-        let cfg_path = path.join("config.json");
-        fs::write(&cfg_path, serde_json::to_string_pretty(&config)?)?;
+        // The config is written last: its presence marks a complete repository
+        backend.put(
+            ObjectKind::Config,
+            "",
+            serde_json::to_string_pretty(&config)?.as_bytes(),
+        )?;
 
-        println!("✓ Repo initialized at {}", path.display());
+        println!("✓ Repo initialized at {}", backend.location());
 
         Ok(Self {
-            root: path.to_path_buf(),
+            backend,
             config,
+            key,
+            key_id,
         })
     }
 
     /// Load repository configuration
-    fn load_config(repo_path: &Path) -> Result<RepoConfig> {
-        let config_path = repo_path.join("config.json");
-        if !config_path.is_file() {
-            return Err(SnapVaultError::InvalidRepo(config_path));
+    fn load_config(backend: &dyn Backend) -> Result<RepoConfig> {
+        if !backend.exists(ObjectKind::Config, "")? {
+            return Err(SnapVaultError::InvalidRepo(
+                PathBuf::from(backend.location()).join("config.json"),
+            ));
         }
 
         // Security: Check file size before reading
-        let metadata = fs::metadata(&config_path)?;
-        if metadata.len() > MAX_CONFIG_SIZE {
+        let size = backend.size(ObjectKind::Config, "")?;
+        if size > MAX_CONFIG_SIZE {
             return Err(SnapVaultError::FileTooLarge {
-                size: metadata.len(),
+                size,
                 max: MAX_CONFIG_SIZE,
             });
         }
 
-        let raw = fs::read_to_string(&config_path)?;
-        let cfg: RepoConfig = serde_json::from_str(&raw)?;
+        let raw = backend.get(ObjectKind::Config, "")?;
+        let cfg: RepoConfig = serde_json::from_slice(&raw)?;
 
         if cfg.version != 1 {
             return Err(SnapVaultError::UnsupportedVersion {
@@ -94,33 +212,267 @@ impl Repository {
                 expected: 1,
             });
         }
+        cfg.chunker.validate()?;
+        cfg.compression.validate()?;
+        pack::validate_pack_size(cfg.pack_size)?;
 
         Ok(cfg)
     }
 
-    /// Get the root path of the repository
-    pub fn root(&self) -> &Path {
-        &self.root
+    /// Get the storage backend holding this repository
+    pub fn backend(&self) -> &Arc<dyn Backend> {
+        &self.backend
+    }
+
+    /// Human-readable location of the repository
+    pub fn location(&self) -> String {
+        self.backend.location()
+    }
+
+    /// Lock the repository, failing with `RepoLocked` if a conflicting lock
+    /// is held. The lock is released when the returned guard is dropped.
+    pub fn lock(&self, mode: LockMode) -> Result<RepoLock> {
+        RepoLock::acquire(self.backend.clone(), mode)
+    }
+
+    /// List all lock files as (lock ID, contents), oldest first
+    pub fn list_locks(&self) -> Result<Vec<(String, Option<LockInfo>)>> {
+        let mut locks = lock::read_locks(self.backend.as_ref())?;
+        locks.sort_by(|a, b| {
+            let created = |info: &Option<LockInfo>| info.as_ref().map(|info| info.created_at.clone());
+            created(&a.1).cmp(&created(&b.1))
+        });
+        Ok(locks)
+    }
+
+    /// Remove stale locks, or every lock when `all` is set.
+    /// Returns the number of locks removed.
+    pub fn remove_locks(&self, all: bool) -> Result<usize> {
+        let mut removed = 0;
+        for (id, info) in self.list_locks()? {
+            match &info {
+                Some(info) if all || info.is_stale() => {
+                    info!("Removing lock {} (PID {} on {})", id, info.pid, info.hostname);
+                }
+                // Whoever wrote it cannot be told, so it may still be held
+                None if all => info!("Removing unreadable lock {}", id),
+                _ => continue,
+            }
+            self.backend.delete(ObjectKind::Lock, &id)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Open the chunk store, configured with this repository's compression,
+    /// pack size and encryption
+    pub fn chunk_store(&self) -> ChunkStore {
+        let store = ChunkStore::from_backend(self.backend.clone())
+            .with_compression(self.config.compression)
+            .with_pack_size(self.config.pack_size);
+        match &self.key {
+            Some(key) => store.with_encryption(key.clone()),
+            None => store,
+        }
     }
 
-    /// Get the snapshots directory path
-    pub fn snapshots_dir(&self) -> PathBuf {
-        self.root.join("snapshots")
+    /// Hasher used to compute chunk IDs in this repository
+    pub fn chunk_hasher(&self) -> ChunkHasher {
+        match &self.key {
+            Some(key) => ChunkHasher::Keyed(*key.id_key()),
+            None => ChunkHasher::Plain,
+        }
     }
 
-    /// Get the data directory path
-    pub fn data_dir(&self) -> PathBuf {
-        self.root.join("data")
+    /// Chunker configured with this repository's chunking parameters
+    pub fn chunker(&self) -> Chunker {
+        Chunker::from_config(&self.config.chunker).with_hasher(self.chunk_hasher())
     }
 
-    /// Get the chunks directory path (for content-addressed storage)
-    pub fn chunks_dir(&self) -> PathBuf {
-        self.data_dir().join("chunks")
+    /// Whether objects in this repository are encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
     }
 
-    /// Get the chunk index file path
-    pub fn index_path(&self) -> PathBuf {
-        self.root.join("index.json")
+    /// ID of the key slot used to unlock this repository
+    pub fn current_key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// List all key slots as (key ID, key file), oldest first
+    pub fn list_keys(&self) -> Result<Vec<(String, KeyFile)>> {
+        if !self.is_encrypted() {
+            return Err(SnapVaultError::NotEncrypted);
+        }
+        let mut keys = read_key_files(self.backend.as_ref())?;
+        keys.sort_by(|a, b| a.1.created_at.cmp(&b.1.created_at));
+        Ok(keys)
+    }
+
+    /// Add a key slot wrapping the master key with another passphrase.
+    /// Returns the new key ID.
+    pub fn add_key(&self, passphrase: &str, label: Option<String>) -> Result<String> {
+        let key = self.key.as_ref().ok_or(SnapVaultError::NotEncrypted)?;
+        let mut key_file = KeyFile::create(key, passphrase, KdfParams::default())?;
+        key_file.label = label;
+        let key_id = write_key_file(self.backend.as_ref(), &key_file)?;
+        info!("Added key {}", key_id);
+        Ok(key_id)
+    }
+
+    /// Remove a key slot. The key currently in use cannot be removed, which
+    /// also guarantees at least one key always remains.
+    pub fn remove_key(&self, key_id: &str) -> Result<()> {
+        let keys = self.list_keys()?;
+        if !keys.iter().any(|(id, _)| id == key_id) {
+            return Err(SnapVaultError::KeyNotFound(key_id.to_string()));
+        }
+        if self.current_key_id() == Some(key_id) {
+            return Err(SnapVaultError::KeyInUse(key_id.to_string()));
+        }
+        self.backend.delete(ObjectKind::Key, key_id)?;
+        info!("Removed key {}", key_id);
+        Ok(())
+    }
+
+    /// Replace the passphrase of the key slot currently in use.
+    /// Returns the ID of the replacement key.
+    pub fn change_passphrase(&mut self, passphrase: &str) -> Result<String> {
+        let old_id = self
+            .key_id
+            .clone()
+            .ok_or(SnapVaultError::NotEncrypted)?;
+        let label = self
+            .list_keys()?
+            .into_iter()
+            .find(|(id, _)| *id == old_id)
+            .and_then(|(_, key_file)| key_file.label);
+
+        // Write the new slot before removing the old one so a crash in
+        // between never leaves the repository without a usable key
+        let new_id = self.add_key(passphrase, label)?;
+        self.key_id = Some(new_id.clone());
+        self.remove_key(&old_id)?;
+        Ok(new_id)
+    }
+
+    /// Read a metadata object, decrypting it if the repository is encrypted
+    fn read_object(&self, kind: ObjectKind, name: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let raw = self.backend.get(kind, name)?;
+        match &self.key {
+            Some(key) => key.open(&raw, aad),
+            None => Ok(raw),
+        }
+    }
+
+    /// Write a metadata object, encrypting it if the repository is encrypted
+    fn write_object(&self, kind: ObjectKind, name: &str, aad: &[u8], data: Vec<u8>) -> Result<()> {
+        let data = match &self.key {
+            Some(key) => key.seal(&data, aad)?,
+            None => data,
+        };
+        self.backend.put(kind, name, &data)
+    }
+
+    /// List the IDs of all snapshots in the repository (unordered)
+    pub fn snapshot_ids(&self) -> Result<Vec<String>> {
+        self.backend.list(ObjectKind::Snapshot)
+    }
+
+    /// Whether a snapshot with the given ID exists
+    pub fn snapshot_exists(&self, snapshot_id: &str) -> Result<bool> {
+        validate_snapshot_id(snapshot_id)?;
+        self.backend.exists(ObjectKind::Snapshot, snapshot_id)
+    }
+
+    /// Load and validate a snapshot manifest, with its file records read
+    /// from its trees in `chunk_store`. Pass the same store when loading
+    /// several manifests, as opening one reads the whole pack index.
+    pub fn load_manifest(&self, snapshot_id: &str, chunk_store: &ChunkStore) -> Result<SnapshotManifest> {
+        let mut manifest = self.load_manifest_header(snapshot_id)?;
+        if let Some(root) = &manifest.tree {
+            let (files, trees) = tree::read_trees(chunk_store, root)?;
+            manifest.files = files;
+            manifest.trees = trees;
+        }
+        Ok(manifest)
+    }
+
+    /// Load and validate a snapshot manifest without reading its trees, for
+    /// when only its metadata is needed. Snapshots predating trees still
+    /// come with their file records.
+    pub fn load_manifest_header(&self, snapshot_id: &str) -> Result<SnapshotManifest> {
+        // Security: Validate snapshot ID
+        validate_snapshot_id(snapshot_id)?;
+
+        if !self.backend.exists(ObjectKind::Snapshot, snapshot_id)? {
+            return Err(SnapVaultError::SnapshotNotFound(snapshot_id.to_string()));
+        }
+
+        // Security: Check manifest size before reading
+        let size = self.backend.size(ObjectKind::Snapshot, snapshot_id)?;
+        if size > MAX_MANIFEST_SIZE {
+            return Err(SnapVaultError::FileTooLarge {
+                size,
+                max: MAX_MANIFEST_SIZE,
+            });
+        }
+
+        let raw = self.read_object(ObjectKind::Snapshot, snapshot_id, &manifest_aad(snapshot_id))?;
+        let manifest: SnapshotManifest = serde_json::from_slice(&raw)?;
+        if manifest.snapshot_id != snapshot_id {
+            return Err(SnapVaultError::Other(
+                "Manifest snapshot ID mismatch".to_string(),
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// Write a snapshot manifest. The file records of a snapshot with a
+    /// tree are left out, as its trees hold them.
+    pub fn save_manifest(&self, manifest: &SnapshotManifest) -> Result<()> {
+        validate_snapshot_id(&manifest.snapshot_id)?;
+        let data = match manifest.tree {
+            Some(_) => serde_json::to_vec_pretty(&manifest.header())?,
+            None => serde_json::to_vec_pretty(manifest)?,
+        };
+        self.write_object(
+            ObjectKind::Snapshot,
+            &manifest.snapshot_id,
+            &manifest_aad(&manifest.snapshot_id),
+            data,
+        )
+    }
+
+    /// Remove a snapshot manifest
+    pub fn delete_manifest(&self, snapshot_id: &str) -> Result<()> {
+        validate_snapshot_id(snapshot_id)?;
+        self.backend.delete(ObjectKind::Snapshot, snapshot_id)
+    }
+
+    /// Load the chunk index, or an empty index if none exists yet
+    pub fn load_index(&self) -> Result<ChunkIndex> {
+        if !self.backend.exists(ObjectKind::Index, "")? {
+            debug!("Index file not found, creating new index");
+            return Ok(ChunkIndex::new());
+        }
+        let raw = self.read_object(ObjectKind::Index, "", INDEX_AAD)?;
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    /// Save the chunk index
+    pub fn save_index(&self, index: &ChunkIndex) -> Result<()> {
+        self.write_object(ObjectKind::Index, "", INDEX_AAD, serde_json::to_vec_pretty(index)?)
+    }
+
+    /// Add the chunk references of a snapshot to the chunk index. The index
+    /// is loaded, updated and saved under an index lock, waiting for other
+    /// backups to finish theirs, so none of their updates are lost.
+    pub fn add_to_index(&self, manifest: &SnapshotManifest) -> Result<()> {
+        let _lock = RepoLock::acquire_waiting(self.backend.clone(), LockMode::Index, INDEX_LOCK_TIMEOUT)?;
+        let mut index = self.load_index()?;
+        index.add_snapshot(manifest);
+        self.save_index(&index)
     }
 
     /// Get repository configuration
@@ -129,9 +481,44 @@ impl Repository {
     }
 }
 
+/// Read all key files in the repository as (key ID, key file)
+fn read_key_files(backend: &dyn Backend) -> Result<Vec<(String, KeyFile)>> {
+    let mut keys = Vec::new();
+    for key_id in backend.list(ObjectKind::Key)? {
+        let key_file: KeyFile = serde_json::from_slice(&backend.get(ObjectKind::Key, &key_id)?)?;
+        keys.push((key_id, key_file));
+    }
+    Ok(keys)
+}
+
+/// Write a key file under a fresh random ID and return the ID
+fn write_key_file(backend: &dyn Backend, key_file: &KeyFile) -> Result<String> {
+    let key_id = uuid::Uuid::new_v4().simple().to_string()[..SNAPSHOT_UUID_LEN].to_string();
+    backend.put(
+        ObjectKind::Key,
+        &key_id,
+        serde_json::to_string_pretty(key_file)?.as_bytes(),
+    )?;
+    Ok(key_id)
+}
+
+/// Associated data for the chunk index
+const INDEX_AAD: &[u8] = b"snapvault index";
+
+/// Associated data for a manifest: binds it to its snapshot ID
+fn manifest_aad(snapshot_id: &str) -> Vec<u8> {
+    format!("snapvault snapshot {}", snapshot_id).into_bytes()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunking::ChunkerConfig;
+    use crate::compression::CompressionConfig;
+    use crate::backend::MemoryBackend;
+    use crate::crypto::EncryptionConfig;
+    use snapshot::FileRecord;
+    use std::fs;
     use tempfile::TempDir;
 
     #[test]
@@ -140,9 +527,9 @@ mod tests {
         let repo_path = temp.path().join("repo");
 
         let repo = Repository::init(&repo_path).unwrap();
-        assert_eq!(repo.root(), repo_path);
-        assert!(repo.snapshots_dir().exists());
-        assert!(repo.data_dir().exists());
+        assert_eq!(repo.location(), repo_path.display().to_string());
+        assert!(repo_path.join("snapshots").exists());
+        assert!(repo_path.join("data").exists());
         assert!(repo_path.join("config.json").exists());
     }
 
@@ -163,7 +550,314 @@ mod tests {
 
         Repository::init(&repo_path).unwrap();
         let repo = Repository::open(&repo_path).unwrap();
-        assert_eq!(repo.root(), repo_path);
+        assert_eq!(repo.location(), repo_path.display().to_string());
+    }
+
+    #[test]
+    fn test_init_records_chunker() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+
+        let config = RepoConfig {
+            chunker: ChunkerConfig::fastcdc(),
+            ..RepoConfig::new()
+        };
+        Repository::init_with_config(&repo_path, config, None).unwrap();
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(repo.config().chunker, ChunkerConfig::fastcdc());
+    }
+
+    #[test]
+    fn test_open_legacy_config_defaults_to_fixed() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+
+        Repository::init(&repo_path).unwrap();
+        fs::write(
+            repo_path.join("config.json"),
+            r#"{"version":1,"created_at":"2024-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(repo.config().chunker, ChunkerConfig::fixed());
+        assert_eq!(repo.config().compression, CompressionConfig::default());
+        assert_eq!(repo.config().pack_size, pack::DEFAULT_PACK_SIZE);
+    }
+
+    #[test]
+    fn test_init_invalid_pack_size_fails() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+
+        let config = RepoConfig {
+            pack_size: 1024,
+            ..RepoConfig::new()
+        };
+        let result = Repository::init_with_config(&repo_path, config, None);
+        assert!(matches!(result, Err(SnapVaultError::InvalidPackSize { .. })));
+        assert!(!repo_path.exists());
+    }
+
+    #[test]
+    fn test_chunk_store_reads_loose_chunks() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let repo = Repository::init(&repo_path).unwrap();
+
+        // Repositories created before pack files hold one file per chunk
+        let data = b"loose chunk";
+        let hash = crate::chunking::hash_bytes(data);
+        let path = repo_path
+            .join("data")
+            .join("chunks")
+            .join(hash.prefix())
+            .join(hash.to_hex());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, data).unwrap();
+
+        let store = repo.chunk_store();
+        assert_eq!(store.read(&hash).unwrap(), data);
+    }
+
+    #[test]
+    fn test_init_invalid_compression_fails() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+
+        let config = RepoConfig {
+            compression: CompressionConfig::Zstd { level: 99 },
+            ..RepoConfig::new()
+        };
+        let result = Repository::init_with_config(&repo_path, config, None);
+        assert!(matches!(result, Err(SnapVaultError::InvalidCompressionConfig(_))));
+        assert!(!repo_path.exists());
+    }
+
+    fn init_encrypted(repo_path: &Path) -> Repository {
+        let config = RepoConfig {
+            encryption: EncryptionConfig::XChaCha20Poly1305,
+            ..RepoConfig::new()
+        };
+        Repository::init_with_config(repo_path, config, Some("hunter2")).unwrap()
+    }
+
+    #[test]
+    fn test_init_encrypted_requires_passphrase() {
+        let temp = TempDir::new().unwrap();
+        let config = RepoConfig {
+            encryption: EncryptionConfig::XChaCha20Poly1305,
+            ..RepoConfig::new()
+        };
+        let result = Repository::init_with_config(&temp.path().join("repo"), config, None);
+        assert!(matches!(result, Err(SnapVaultError::PassphraseRequired)));
+    }
+
+    #[test]
+    fn test_open_encrypted_repository() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+
+        let repo = init_encrypted(&repo_path);
+        assert!(repo.is_encrypted());
+        assert_eq!(fs::read_dir(repo_path.join("keys")).unwrap().count(), 1);
+
+        let reopened = Repository::open_with_passphrase(&repo_path, "hunter2").unwrap();
+        assert!(reopened.is_encrypted());
+        assert!(matches!(
+            Repository::open_with_passphrase(&repo_path, "wrong"),
+            Err(SnapVaultError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_manifest_and_index() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let repo = init_encrypted(&repo_path);
+
+        let mut manifest = SnapshotManifest::new("snap-1".to_string(), "/secret/source".to_string());
+        manifest.total_files = 3;
+        repo.save_manifest(&manifest).unwrap();
+        let mut index = ChunkIndex::new();
+        index.add_snapshot(&manifest);
+        repo.save_index(&index).unwrap();
+
+        // Nothing readable on disk
+        let raw = fs::read(repo_path.join("snapshots").join("snap-1.json")).unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("/secret/source"));
+        assert!(serde_json::from_slice::<serde_json::Value>(&raw).is_err());
+        assert!(serde_json::from_slice::<serde_json::Value>(&fs::read(repo_path.join("index.json")).unwrap()).is_err());
+
+        let reopened = Repository::open_with_passphrase(&repo_path, "hunter2").unwrap();
+        assert_eq!(reopened.snapshot_ids().unwrap(), vec!["snap-1".to_string()]);
+        let manifest = reopened.load_manifest("snap-1", &reopened.chunk_store()).unwrap();
+        assert_eq!(manifest.total_files, 3);
+        reopened.load_index().unwrap();
+    }
+
+    #[test]
+    fn test_manifest_with_tree() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let repo = Repository::init(&repo_path).unwrap();
+        let mut manifest = SnapshotManifest::new("snap-1".to_string(), "/src".to_string());
+        manifest.add_file(FileRecord::new("dir/file".to_string(), 0, None, Vec::new(), None));
+
+        // Without a tree, as before trees, the records are in the manifest
+        repo.save_manifest(&manifest).unwrap();
+        let loaded = repo.load_manifest_header("snap-1").unwrap();
+        assert_eq!(loaded.files.len(), 1);
+        assert!(loaded.tree.is_none());
+
+        let chunk_store = repo.chunk_store();
+        let written = tree::write_trees(&chunk_store, &manifest.files).unwrap();
+        chunk_store.flush().unwrap();
+        manifest.tree = Some(written.root.clone());
+        manifest.trees = written.trees.clone();
+        repo.save_manifest(&manifest).unwrap();
+        let raw = fs::read_to_string(repo_path.join("snapshots").join("snap-1.json")).unwrap();
+        assert!(!raw.contains("dir/file"));
+
+        let header = repo.load_manifest_header("snap-1").unwrap();
+        assert!(header.files.is_empty());
+        assert_eq!(header.tree, Some(written.root));
+        let loaded = repo.load_manifest("snap-1", &repo.chunk_store()).unwrap();
+        assert_eq!(loaded.files.len(), 1);
+        assert_eq!(loaded.files[0].rel_path, "dir/file");
+        assert_eq!(loaded.trees.len(), written.trees.len());
+        assert_eq!(loaded.total_files, 1);
+    }
+
+    #[test]
+    fn test_encrypted_manifest_bound_to_id() {
+        let temp = TempDir::new().unwrap();
+        let snapshots_dir = temp.path().join("repo").join("snapshots");
+        let repo = init_encrypted(&temp.path().join("repo"));
+
+        let manifest = SnapshotManifest::new("snap-1".to_string(), "/src".to_string());
+        repo.save_manifest(&manifest).unwrap();
+        fs::rename(snapshots_dir.join("snap-1.json"), snapshots_dir.join("snap-2.json")).unwrap();
+
+        assert!(matches!(
+            repo.load_manifest("snap-2", &repo.chunk_store()),
+            Err(SnapVaultError::Crypto(_))
+        ));
+    }
+
+    #[test]
+    fn test_add_key_unlocks_same_master_key() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let repo = init_encrypted(&repo_path);
+
+        let manifest = SnapshotManifest::new("snap-1".to_string(), "/src".to_string());
+        repo.save_manifest(&manifest).unwrap();
+
+        let ci_id = repo.add_key("ci-secret", Some("ci".to_string())).unwrap();
+        let keys = repo.list_keys().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].1.label.as_deref(), Some("ci"));
+
+        let ci_repo = Repository::open_with_passphrase(&repo_path, "ci-secret").unwrap();
+        assert_eq!(ci_repo.current_key_id(), Some(ci_id.as_str()));
+        assert!(ci_repo.load_manifest("snap-1", &ci_repo.chunk_store()).is_ok());
+    }
+
+    #[test]
+    fn test_remove_key_revokes_passphrase() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let repo = init_encrypted(&repo_path);
+        let ci_id = repo.add_key("ci-secret", None).unwrap();
+
+        // The key in use can't be removed
+        let current = repo.current_key_id().unwrap().to_string();
+        assert!(matches!(
+            repo.remove_key(&current),
+            Err(SnapVaultError::KeyInUse(_))
+        ));
+        assert!(matches!(
+            repo.remove_key("missing"),
+            Err(SnapVaultError::KeyNotFound(_))
+        ));
+
+        repo.remove_key(&ci_id).unwrap();
+        assert_eq!(repo.list_keys().unwrap().len(), 1);
+        assert!(matches!(
+            Repository::open_with_passphrase(&repo_path, "ci-secret"),
+            Err(SnapVaultError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_change_passphrase() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let mut repo = init_encrypted(&repo_path);
+        let old_id = repo.current_key_id().unwrap().to_string();
+
+        let new_id = repo.change_passphrase("new-secret").unwrap();
+        assert_ne!(old_id, new_id);
+        assert_eq!(repo.list_keys().unwrap().len(), 1);
+
+        assert!(Repository::open_with_passphrase(&repo_path, "new-secret").is_ok());
+        assert!(Repository::open_with_passphrase(&repo_path, "hunter2").is_err());
+    }
+
+    #[test]
+    fn test_key_commands_require_encryption() {
+        let temp = TempDir::new().unwrap();
+        let repo = Repository::init(&temp.path().join("repo")).unwrap();
+        assert!(matches!(repo.list_keys(), Err(SnapVaultError::NotEncrypted)));
+        assert!(matches!(
+            repo.add_key("pw", None),
+            Err(SnapVaultError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn test_repository_on_memory_backend() {
+        let backend = MemoryBackend::new();
+        let config = RepoConfig {
+            encryption: EncryptionConfig::XChaCha20Poly1305,
+            ..RepoConfig::new()
+        };
+        let repo = Repository::init_backend(Arc::new(backend.clone()), config, Some("pw")).unwrap();
+        assert!(matches!(
+            Repository::init_backend(Arc::new(backend.clone()), RepoConfig::new(), None),
+            Err(SnapVaultError::RepoAlreadyExists(_))
+        ));
+
+        let manifest = SnapshotManifest::new("snap-1".to_string(), "/src".to_string());
+        repo.save_manifest(&manifest).unwrap();
+        let store = repo.chunk_store();
+        let hash = repo.chunk_hasher().hash(b"chunk data");
+        store.store(&hash, b"chunk data").unwrap();
+        store.flush().unwrap();
+
+        let reopened = Repository::open_backend(Arc::new(backend.clone()), Some("pw")).unwrap();
+        assert!(reopened.snapshot_exists("snap-1").unwrap());
+        let manifest = reopened.load_manifest("snap-1", &reopened.chunk_store()).unwrap();
+        assert_eq!(manifest.source_root, "/src");
+        assert_eq!(reopened.chunk_store().read(&hash).unwrap(), b"chunk data");
+
+        reopened.delete_manifest("snap-1").unwrap();
+        assert!(backend.list(ObjectKind::Snapshot).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_at_local_locator() {
+        let temp = TempDir::new().unwrap();
+        let repo_path = temp.path().join("repo");
+        let locator = RepoLocator::parse(&format!("local:{}", repo_path.display())).unwrap();
+
+        assert!(matches!(
+            Repository::open_at(&locator, None),
+            Err(SnapVaultError::RepoNotFound(_))
+        ));
+        Repository::init_at(&locator, RepoConfig::new(), None).unwrap();
+        let repo = Repository::open_at(&locator, None).unwrap();
+        assert_eq!(repo.location(), repo_path.display().to_string());
     }
 
     #[test]
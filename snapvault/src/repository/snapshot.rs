@@ -1,4 +1,7 @@
 use crate::chunking::ChunkHash;
+use crate::exclude::ExcludeRules;
+use crate::sparse::Hole;
+use crate::xattrs::ExtendedAttribute;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -6,12 +9,38 @@ pub struct SnapshotManifest {
     pub snapshot_id: String,
     pub created_at: String,
     pub source_root: String,
+    /// Host the snapshot was taken on (empty for snapshots predating it)
+    #[serde(default)]
+    pub hostname: String,
+    /// Labels given at backup time, sorted
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Snapshot whose file records unchanged files were taken from
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Rules that left paths out of the snapshot
+    #[serde(default)]
+    pub excludes: ExcludeRules,
     pub total_files: u64,
+    /// Number of directories below the source root
+    #[serde(default)]
+    pub total_dirs: u64,
     pub total_bytes: u64,
     /// Total number of unique chunks referenced
     pub total_chunks: u64,
     /// Total deduplicated size (sum of unique chunk sizes)
     pub deduplicated_bytes: u64,
+    /// Tree object of the source root, holding the file records (unset for
+    /// snapshots predating it, which list them in `files`)
+    #[serde(default)]
+    pub tree: Option<ChunkHash>,
+    /// Every tree object of the snapshot, known once its trees are written
+    /// or read
+    #[serde(skip)]
+    pub trees: Vec<ChunkHash>,
+    /// Records of every entry, in walk order. Saved in the manifest only
+    /// when the snapshot has no `tree`.
+    #[serde(default)]
     pub files: Vec<FileRecord>,
 }
 
@@ -19,15 +48,76 @@ pub struct SnapshotManifest {
 pub struct FileRecord {
     /// Relative path of the file
     pub rel_path: String,
+    /// What kind of entry this is (regular file for snapshots predating it)
+    #[serde(default)]
+    pub kind: EntryKind,
     /// Original file size in bytes
     pub size: u64,
     /// Modification time (RFC3339 format)
     pub modified: Option<String>,
-    /// List of chunk hashes that make up this file
+    /// List of chunk hashes that make up this file, leaving out its holes
     /// Empty for empty files or files that couldn't be chunked
     pub chunks: Vec<ChunkHash>,
-    /// Content hash of the entire file (for quick comparison)
+    /// Ranges of a sparse file that hold no data, in order
+    #[serde(default)]
+    pub holes: Vec<Hole>,
+    /// Content hash of the entire file (for quick comparison), or of its
+    /// data if it has holes
     pub content_hash: Option<ChunkHash>,
+    /// Inode number, to tell a replaced file from an unchanged one
+    #[serde(default)]
+    pub inode: Option<u64>,
+    /// Status change time (ctime, RFC3339 format)
+    #[serde(default)]
+    pub changed: Option<String>,
+    /// Last access time (RFC3339 format), as of the first backup that saw
+    /// the entry in its current state
+    #[serde(default)]
+    pub accessed: Option<String>,
+    /// Permission bits, including setuid, setgid and sticky
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Owner user ID
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// Owner group ID
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Owner user name, to find the same account on another host
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Owner group name
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Extended attributes, including ACLs and file capabilities, by name
+    #[serde(default)]
+    pub xattrs: Vec<ExtendedAttribute>,
+}
+
+/// Type of a file system entry. Only regular files have contents (chunks);
+/// the other kinds are recreated from their record alone.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryKind {
+    /// Regular file
+    #[default]
+    File,
+    /// Directory, recorded for its metadata and so that empty ones are kept
+    Directory,
+    /// Symbolic link to `target`, as stored in the link
+    Symlink { target: String },
+    /// Another name (hard link) for the regular file recorded earlier at
+    /// `target`, relative to the source
+    Hardlink { target: String },
+    /// Named pipe
+    Fifo,
+    /// Character device with device number `rdev`
+    CharDevice { rdev: u64 },
+    /// Block device with device number `rdev`
+    BlockDevice { rdev: u64 },
+    /// Unix domain socket, recorded as a placeholder: it cannot be restored,
+    /// only recreated by the program that listens on it
+    Socket,
 }
 
 impl SnapshotManifest {
@@ -36,14 +126,82 @@ impl SnapshotManifest {
             snapshot_id,
             created_at: chrono::Utc::now().to_rfc3339(),
             source_root,
+            hostname: String::new(),
+            tags: Vec::new(),
+            parent: None,
+            excludes: ExcludeRules::default(),
             total_files: 0,
+            total_dirs: 0,
             total_bytes: 0,
             total_chunks: 0,
             deduplicated_bytes: 0,
+            tree: None,
+            trees: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// The manifest without its file records and trees, as saved when the
+    /// records are in tree objects
+    pub fn header(&self) -> Self {
+        let Self {
+            snapshot_id,
+            created_at,
+            source_root,
+            hostname,
+            tags,
+            parent,
+            excludes,
+            total_files,
+            total_dirs,
+            total_bytes,
+            total_chunks,
+            deduplicated_bytes,
+            tree,
+            trees: _,
+            files: _,
+        } = self;
+        Self {
+            snapshot_id: snapshot_id.clone(),
+            created_at: created_at.clone(),
+            source_root: source_root.clone(),
+            hostname: hostname.clone(),
+            tags: tags.clone(),
+            parent: parent.clone(),
+            excludes: excludes.clone(),
+            total_files: *total_files,
+            total_dirs: *total_dirs,
+            total_bytes: *total_bytes,
+            total_chunks: *total_chunks,
+            deduplicated_bytes: *deduplicated_bytes,
+            tree: tree.clone(),
+            trees: Vec::new(),
             files: Vec::new(),
         }
     }
 
+    /// Every chunk the snapshot refers to: its tree objects, then the
+    /// contents of its files
+    pub fn referenced_chunks(&self) -> impl Iterator<Item = &ChunkHash> {
+        self.trees
+            .iter()
+            .chain(self.files.iter().flat_map(|file| &file.chunks))
+    }
+
+    /// Add a file record, counting it in the totals. Only the contents of
+    /// regular files count towards the total size, once per hard link group.
+    pub fn add_file(&mut self, file: FileRecord) {
+        match file.kind {
+            EntryKind::Directory => self.total_dirs += 1,
+            EntryKind::File => {
+                self.total_files += 1;
+                self.total_bytes += file.size;
+            }
+            _ => self.total_files += 1,
+        }
+        self.files.push(file);
+    }
+
     /// Calculate deduplication ratio as a percentage
     /// Returns None if no data has been processed
     pub fn dedup_ratio(&self) -> Option<f64> {
@@ -71,10 +229,36 @@ impl FileRecord {
     ) -> Self {
         Self {
             rel_path,
+            kind: EntryKind::File,
             size,
             modified,
             chunks,
+            holes: Vec::new(),
             content_hash,
+            inode: None,
+            changed: None,
+            accessed: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            user: None,
+            group: None,
+            xattrs: Vec::new(),
+        }
+    }
+
+    /// Whether the file this record was made from still looks the same:
+    /// kind, size and modification time match, as do the inode and change time
+    /// where both records have them. Unchanged files are not read again.
+    pub fn is_unchanged(&self, current: &FileRecord) -> bool {
+        fn agree<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+            a.is_none() || b.is_none() || a == b
         }
+        self.kind == current.kind
+            && self.size == current.size
+            && self.modified.is_some()
+            && self.modified == current.modified
+            && agree(&self.inode, &current.inode)
+            && agree(&self.changed, &current.changed)
     }
 }
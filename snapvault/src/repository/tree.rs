@@ -0,0 +1,285 @@
+//! Tree objects: the directory structure of a snapshot.
+//!
+//! The records of a snapshot are stored one directory at a time: a tree
+//! object lists the entries of a directory by name, and each directory entry
+//! points at the tree of its own entries. Trees are kept in the chunk store
+//! under the hash of their contents, so a directory that has not changed
+//! since an earlier snapshot, with everything below it, is the same object
+//! and is stored only once, and the manifest only names the tree of the
+//! source root, however many files the snapshot has.
+
+use crate::chunking::ChunkHash;
+use crate::error::Result;
+use crate::repository::snapshot::{EntryKind, FileRecord};
+use crate::storage::ChunkStore;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The entries of one directory, in the order they were backed up
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+/// An entry of a tree
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeEntry {
+    /// Record of the entry, whose `rel_path` is its name in the directory
+    #[serde(flatten)]
+    pub record: FileRecord,
+    /// Tree of the entries below a directory, unset if it has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subtree: Option<ChunkHash>,
+    /// Whether the entry only holds its subtree: the snapshot has records
+    /// below the directory but none of the directory itself
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub implicit: bool,
+}
+
+/// Trees written for a snapshot
+#[derive(Debug, Clone)]
+pub struct WrittenTrees {
+    /// Tree of the source root
+    pub root: ChunkHash,
+    /// Every tree of the snapshot, each once
+    pub trees: Vec<ChunkHash>,
+    /// Number of trees that were not in the chunk store yet
+    pub new_trees: usize,
+}
+
+/// A directory whose tree is being assembled
+#[derive(Default)]
+struct PendingTree {
+    tree: Tree,
+    /// Position of each entry by name
+    names: HashMap<String, usize>,
+}
+
+/// Store the records of `files`, given with paths relative to the source, as
+/// one tree per directory. Trees are stored before the directories holding
+/// them, so each points only at stored trees.
+pub fn write_trees(chunk_store: &ChunkStore, files: &[FileRecord]) -> Result<WrittenTrees> {
+    let mut pending: HashMap<String, PendingTree> = HashMap::new();
+    pending.insert(String::new(), PendingTree::default());
+    for file in files {
+        let (parent, name) = split_path(&file.rel_path);
+        add_directory(&mut pending, parent);
+        let dir = pending.get_mut(parent).expect("parent directory was just added");
+        let mut record = file.clone();
+        record.rel_path = name.to_string();
+        match dir.names.get(name) {
+            // Recorded after an entry below it
+            Some(&i) if dir.tree.entries[i].implicit => {
+                dir.tree.entries[i].record = record;
+                dir.tree.entries[i].implicit = false;
+            }
+            _ => {
+                dir.names.insert(name.to_string(), dir.tree.entries.len());
+                dir.tree.entries.push(TreeEntry {
+                    record,
+                    subtree: None,
+                    implicit: false,
+                });
+            }
+        }
+    }
+
+    // Deepest directories first
+    let mut paths: Vec<String> = pending.keys().cloned().collect();
+    paths.sort_by_key(|path| std::cmp::Reverse(depth(path)));
+
+    let mut trees = Vec::new();
+    let mut seen = HashSet::new();
+    let mut new_trees = 0;
+    for path in paths {
+        let dir = pending.remove(&path).expect("every directory is written once");
+        let data = serde_json::to_vec(&dir.tree)?;
+        let hash = chunk_store.hasher().hash(&data);
+        if chunk_store.store(&hash, &data)? {
+            new_trees += 1;
+        }
+        if seen.insert(hash.clone()) {
+            trees.push(hash.clone());
+        }
+        if path.is_empty() {
+            return Ok(WrittenTrees {
+                root: hash,
+                trees,
+                new_trees,
+            });
+        }
+        let (parent, name) = split_path(&path);
+        let parent = pending.get_mut(parent).expect("parents are written after their children");
+        let i = parent.names[name];
+        parent.tree.entries[i].subtree = Some(hash);
+    }
+    unreachable!("the root is the last directory written")
+}
+
+/// Read the tree `root` and every tree below it. Returns the records with
+/// paths relative to the source, each directory followed by its entries as
+/// in walk order, and the hashes of the trees read, each once.
+pub fn read_trees(chunk_store: &ChunkStore, root: &ChunkHash) -> Result<(Vec<FileRecord>, Vec<ChunkHash>)> {
+    let mut files = Vec::new();
+    let mut trees = vec![root.clone()];
+    let mut seen = HashSet::from([root.clone()]);
+    // Path of each directory being listed and its remaining entries
+    let mut stack = vec![(String::new(), read_tree(chunk_store, root)?.entries.into_iter())];
+    while let Some((dir, entries)) = stack.last_mut() {
+        let Some(entry) = entries.next() else {
+            stack.pop();
+            continue;
+        };
+        let path = if dir.is_empty() {
+            entry.record.rel_path.clone()
+        } else {
+            format!("{}/{}", dir, entry.record.rel_path)
+        };
+        if !entry.implicit {
+            let mut record = entry.record;
+            record.rel_path = path.clone();
+            files.push(record);
+        }
+        if let Some(subtree) = entry.subtree {
+            let tree = read_tree(chunk_store, &subtree)?;
+            if seen.insert(subtree.clone()) {
+                trees.push(subtree);
+            }
+            stack.push((path, tree.entries.into_iter()));
+        }
+    }
+    Ok((files, trees))
+}
+
+fn read_tree(chunk_store: &ChunkStore, hash: &ChunkHash) -> Result<Tree> {
+    Ok(serde_json::from_slice(&chunk_store.read(hash)?)?)
+}
+
+/// Make sure the directory at `path` and those above it have a tree, adding
+/// an implicit entry to its parent if the directory has no record yet
+fn add_directory(pending: &mut HashMap<String, PendingTree>, path: &str) {
+    if pending.contains_key(path) {
+        return;
+    }
+    let (parent, name) = split_path(path);
+    add_directory(pending, parent);
+    let parent = pending.get_mut(parent).expect("parent directory was just added");
+    if !parent.names.contains_key(name) {
+        let mut record = FileRecord::new(name.to_string(), 0, None, Vec::new(), None);
+        record.kind = EntryKind::Directory;
+        parent.names.insert(name.to_string(), parent.tree.entries.len());
+        parent.tree.entries.push(TreeEntry {
+            record,
+            subtree: None,
+            implicit: true,
+        });
+    }
+    pending.insert(path.to_string(), PendingTree::default());
+}
+
+/// Split a relative path into its directory and its name
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Number of directories between the source and `path`
+fn depth(path: &str) -> usize {
+    if path.is_empty() {
+        0
+    } else {
+        path.matches('/').count() + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::ChunkHasher;
+    use tempfile::TempDir;
+
+    fn record(rel_path: &str, kind: EntryKind) -> FileRecord {
+        let mut record = FileRecord::new(rel_path.to_string(), 0, None, Vec::new(), None);
+        record.kind = kind;
+        record
+    }
+
+    fn paths(files: &[FileRecord]) -> Vec<&str> {
+        files.iter().map(|file| file.rel_path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_trees_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let store = ChunkStore::new(temp.path());
+        store.init().unwrap();
+        let mut file = record("docs/a.txt", EntryKind::File);
+        file.size = 5;
+        file.chunks = vec![ChunkHasher::Plain.hash(b"hello")];
+        let files = vec![
+            record("docs", EntryKind::Directory),
+            file,
+            record("docs/empty", EntryKind::Directory),
+            record(
+                "docs/link",
+                EntryKind::Symlink {
+                    target: "a.txt".to_string(),
+                },
+            ),
+            record("z.txt", EntryKind::File),
+        ];
+
+        let written = write_trees(&store, &files).unwrap();
+        // The root and `docs`; the empty directory has no tree
+        assert_eq!(written.trees.len(), 2);
+        assert_eq!(written.new_trees, 2);
+        assert_eq!(written.trees.last(), Some(&written.root));
+
+        let (read, trees) = read_trees(&store, &written.root).unwrap();
+        assert_eq!(paths(&read), paths(&files));
+        assert_eq!(read[1].chunks, files[1].chunks);
+        assert_eq!(read[3].kind, files[3].kind);
+        assert_eq!(trees.len(), 2);
+
+        // The same records give the same trees, which are not stored again
+        let again = write_trees(&store, &files).unwrap();
+        assert_eq!(again.root, written.root);
+        assert_eq!(again.new_trees, 0);
+    }
+
+    #[test]
+    fn test_unchanged_directories_share_trees() {
+        let temp = TempDir::new().unwrap();
+        let store = ChunkStore::new(temp.path());
+        store.init().unwrap();
+        let mut files = vec![
+            record("a", EntryKind::Directory),
+            record("a/one", EntryKind::File),
+            record("b", EntryKind::Directory),
+            record("b/two", EntryKind::File),
+        ];
+        let first = write_trees(&store, &files).unwrap();
+
+        files[3].size = 3;
+        let second = write_trees(&store, &files).unwrap();
+        // Only `b` and the root changed
+        assert_eq!(second.new_trees, 2);
+        let shared: Vec<_> = second.trees.iter().filter(|hash| first.trees.contains(hash)).collect();
+        assert_eq!(shared.len(), 1);
+    }
+
+    #[test]
+    fn test_directories_without_records() {
+        let temp = TempDir::new().unwrap();
+        let store = ChunkStore::new(temp.path());
+        store.init().unwrap();
+        // `x` is only known from the entries below it, `y` after them
+        let files = vec![
+            record("x/deep/file", EntryKind::File),
+            record("y/file", EntryKind::File),
+            record("y", EntryKind::Directory),
+        ];
+        let written = write_trees(&store, &files).unwrap();
+        let (read, _) = read_trees(&store, &written.root).unwrap();
+        assert_eq!(paths(&read), vec!["x/deep/file", "y", "y/file"]);
+    }
+}
@@ -0,0 +1,350 @@
+//! Snapshot retention policies.
+//!
+//! A `RetentionPolicy` decides which snapshots of a group to keep. Snapshots
+//! are considered newest first: `last` keeps the newest N, and each of the
+//! hourly to yearly rules keeps the newest snapshot in each of the N most
+//! recent hours, days, ISO weeks, months or years that have one. `within`
+//! keeps every snapshot taken in a period before the newest snapshot, so a
+//! backup job that stopped running does not have its history expire. A
+//! snapshot is kept if any rule keeps it.
+
+use crate::error::{Result, SnapVaultError};
+use chrono::{DateTime, Datelike, Duration, Months, TimeZone, Timelike, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// Which snapshots to keep; a count of 0 disables a rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep the newest N snapshots
+    pub last: usize,
+    /// Keep the newest snapshot of each of the last N hours
+    pub hourly: usize,
+    /// Keep the newest snapshot of each of the last N days
+    pub daily: usize,
+    /// Keep the newest snapshot of each of the last N weeks
+    pub weekly: usize,
+    /// Keep the newest snapshot of each of the last N months
+    pub monthly: usize,
+    /// Keep the newest snapshot of each of the last N years
+    pub yearly: usize,
+    /// Keep every snapshot taken within this period before the newest one
+    pub within: Option<RetentionPeriod>,
+}
+
+/// Why a snapshot is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeepReason {
+    Last,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    Within,
+    /// The creation time cannot be parsed, so no rule can judge it
+    UnknownTime,
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeepReason::Last => "last snapshot",
+            KeepReason::Hourly => "hourly snapshot",
+            KeepReason::Daily => "daily snapshot",
+            KeepReason::Weekly => "weekly snapshot",
+            KeepReason::Monthly => "monthly snapshot",
+            KeepReason::Yearly => "yearly snapshot",
+            KeepReason::Within => "within period",
+            KeepReason::UnknownTime => "unknown creation time",
+        })
+    }
+}
+
+impl RetentionPolicy {
+    /// Whether no rule is set, in which case the policy would keep nothing
+    pub fn is_empty(&self) -> bool {
+        self.last == 0
+            && self.hourly == 0
+            && self.daily == 0
+            && self.weekly == 0
+            && self.monthly == 0
+            && self.yearly == 0
+            && self.within.is_none()
+    }
+
+    /// Decide which snapshots to keep, given their creation times in any
+    /// order. Hours, days and so on are counted in `tz`. Returns the reasons
+    /// to keep each snapshot, empty for those to remove.
+    pub fn apply<Tz: TimeZone>(
+        &self,
+        created: &[Option<DateTime<Utc>>],
+        tz: &Tz,
+    ) -> Vec<Vec<KeepReason>> {
+        let mut reasons = vec![Vec::new(); created.len()];
+        let mut dated = Vec::new();
+        for (i, time) in created.iter().enumerate() {
+            match time {
+                Some(time) => dated.push((i, *time)),
+                None => reasons[i].push(KeepReason::UnknownTime),
+            }
+        }
+        dated.sort_by_key(|&(_, time)| std::cmp::Reverse(time));
+
+        let cutoff = match (self.within, dated.first()) {
+            (Some(period), Some((_, newest))) => Some(period.before(*newest)),
+            _ => None,
+        };
+        let mut rules = [
+            (KeepReason::Hourly, self.hourly, None),
+            (KeepReason::Daily, self.daily, None),
+            (KeepReason::Weekly, self.weekly, None),
+            (KeepReason::Monthly, self.monthly, None),
+            (KeepReason::Yearly, self.yearly, None),
+        ];
+
+        for (rank, (i, time)) in dated.into_iter().enumerate() {
+            if rank < self.last {
+                reasons[i].push(KeepReason::Last);
+            }
+            let local = time.with_timezone(tz);
+            for (reason, remaining, last_bucket) in &mut rules {
+                if *remaining == 0 {
+                    continue;
+                }
+                // The first snapshot seen in a bucket is its newest
+                let bucket = bucket(*reason, &local);
+                if *last_bucket != Some(bucket) {
+                    *last_bucket = Some(bucket);
+                    *remaining -= 1;
+                    reasons[i].push(*reason);
+                }
+            }
+            if cutoff.is_some_and(|cutoff| time >= cutoff) {
+                reasons[i].push(KeepReason::Within);
+            }
+        }
+        reasons
+    }
+}
+
+/// Number identifying the hour, day, week, month or year `time` falls in
+fn bucket<Tz: TimeZone>(reason: KeepReason, time: &DateTime<Tz>) -> i64 {
+    let (year, month, day) = (time.year() as i64, time.month() as i64, time.day() as i64);
+    match reason {
+        KeepReason::Hourly => ((year * 100 + month) * 100 + day) * 100 + time.hour() as i64,
+        KeepReason::Daily => (year * 100 + month) * 100 + day,
+        KeepReason::Weekly => {
+            let week = time.iso_week();
+            week.year() as i64 * 100 + week.week() as i64
+        }
+        KeepReason::Monthly => year * 100 + month,
+        _ => year,
+    }
+}
+
+/// A period such as `7d`, `2w` or `1y6m`, made of numbers with the units
+/// y (years), m (months), w (weeks), d (days) and h (hours)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPeriod {
+    pub years: u32,
+    pub months: u32,
+    pub days: u32,
+    pub hours: u32,
+}
+
+impl RetentionPeriod {
+    /// The start of the period ending at `end`
+    pub fn before(&self, end: DateTime<Utc>) -> DateTime<Utc> {
+        let months = self.years.saturating_mul(12).saturating_add(self.months);
+        end.checked_sub_months(Months::new(months))
+            .and_then(|t| t.checked_sub_signed(Duration::days(self.days as i64)))
+            .and_then(|t| t.checked_sub_signed(Duration::hours(self.hours as i64)))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+}
+
+impl FromStr for RetentionPeriod {
+    type Err = SnapVaultError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            SnapVaultError::InvalidRetentionPolicy(format!(
+                "invalid period {:?}: expected e.g. 7d, 2w or 1y6m",
+                s
+            ))
+        };
+
+        let mut period = RetentionPeriod::default();
+        let mut number = String::new();
+        for c in s.trim().chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let n: u32 = number.parse().map_err(|_| invalid())?;
+            number.clear();
+            let field = match c {
+                'y' => &mut period.years,
+                'm' => &mut period.months,
+                'w' => {
+                    period.days = period.days.saturating_add(n.saturating_mul(7));
+                    continue;
+                }
+                'd' => &mut period.days,
+                'h' => &mut period.hours,
+                _ => return Err(invalid()),
+            };
+            *field = field.saturating_add(n);
+        }
+        if !number.is_empty() || period == RetentionPeriod::default() {
+            return Err(invalid());
+        }
+        Ok(period)
+    }
+}
+
+impl fmt::Display for RetentionPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (n, unit) in [
+            (self.years, 'y'),
+            (self.months, 'm'),
+            (self.days, 'd'),
+            (self.hours, 'h'),
+        ] {
+            if n > 0 {
+                write!(f, "{}{}", n, unit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc))
+    }
+
+    fn kept(reasons: &[Vec<KeepReason>]) -> Vec<usize> {
+        (0..reasons.len()).filter(|&i| !reasons[i].is_empty()).collect()
+    }
+
+    #[test]
+    fn test_keep_last() {
+        let created = [
+            at("2026-03-01T10:00:00Z"),
+            at("2026-03-03T10:00:00Z"),
+            at("2026-03-02T10:00:00Z"),
+        ];
+        let policy = RetentionPolicy {
+            last: 2,
+            ..RetentionPolicy::default()
+        };
+        let reasons = policy.apply(&created, &Utc);
+        assert_eq!(kept(&reasons), vec![1, 2]);
+        assert_eq!(reasons[1], vec![KeepReason::Last]);
+    }
+
+    #[test]
+    fn test_keep_daily_keeps_newest_of_each_day() {
+        let created = [
+            at("2026-03-03T23:00:00Z"),
+            at("2026-03-03T08:00:00Z"),
+            at("2026-03-02T12:00:00Z"),
+            at("2026-03-01T12:00:00Z"),
+        ];
+        let policy = RetentionPolicy {
+            daily: 2,
+            ..RetentionPolicy::default()
+        };
+        let reasons = policy.apply(&created, &Utc);
+        assert_eq!(kept(&reasons), vec![0, 2]);
+        assert_eq!(reasons[2], vec![KeepReason::Daily]);
+    }
+
+    #[test]
+    fn test_buckets_follow_time_zone() {
+        // 23:00 and 01:00 UTC are the same day two hours east of UTC
+        let created = [at("2026-03-03T01:00:00Z"), at("2026-03-02T23:00:00Z")];
+        let policy = RetentionPolicy {
+            daily: 5,
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(&policy.apply(&created, &Utc)), vec![0, 1]);
+
+        let east = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        assert_eq!(kept(&policy.apply(&created, &east)), vec![0]);
+    }
+
+    #[test]
+    fn test_rules_combine() {
+        let created = [
+            at("2026-03-10T12:00:00Z"),
+            at("2026-03-09T12:00:00Z"),
+            at("2026-02-20T12:00:00Z"),
+            at("2026-02-10T12:00:00Z"),
+            at("2025-06-01T12:00:00Z"),
+        ];
+        let policy = RetentionPolicy {
+            last: 1,
+            monthly: 2,
+            yearly: 2,
+            ..RetentionPolicy::default()
+        };
+        let reasons = policy.apply(&created, &Utc);
+        assert_eq!(kept(&reasons), vec![0, 2, 4]);
+        assert_eq!(
+            reasons[0],
+            vec![KeepReason::Last, KeepReason::Monthly, KeepReason::Yearly]
+        );
+        assert_eq!(reasons[4], vec![KeepReason::Yearly]);
+    }
+
+    #[test]
+    fn test_keep_within_is_relative_to_newest() {
+        let created = [
+            at("2025-01-10T00:00:00Z"),
+            at("2025-01-04T00:00:00Z"),
+            at("2025-01-02T00:00:00Z"),
+        ];
+        let policy = RetentionPolicy {
+            within: Some("1w".parse().unwrap()),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(&policy.apply(&created, &Utc)), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_unknown_time_is_kept() {
+        let policy = RetentionPolicy {
+            last: 1,
+            ..RetentionPolicy::default()
+        };
+        let reasons = policy.apply(&[None, at("2026-01-01T00:00:00Z"), at("2025-01-01T00:00:00Z")], &Utc);
+        assert_eq!(reasons[0], vec![KeepReason::UnknownTime]);
+        assert_eq!(kept(&reasons), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_parse_period() {
+        let period: RetentionPeriod = "1y6m2w3d12h".parse().unwrap();
+        assert_eq!(
+            period,
+            RetentionPeriod {
+                years: 1,
+                months: 6,
+                days: 17,
+                hours: 12,
+            }
+        );
+        assert_eq!(period.to_string(), "1y6m17d12h");
+        assert!("".parse::<RetentionPeriod>().is_err());
+        assert!("7".parse::<RetentionPeriod>().is_err());
+        assert!("d".parse::<RetentionPeriod>().is_err());
+        assert!("3x".parse::<RetentionPeriod>().is_err());
+        assert!("0d".parse::<RetentionPeriod>().is_err());
+    }
+}
@@ -0,0 +1,270 @@
+//! Sparse files: holes found at backup and left unwritten at restore.
+//!
+//! A hole is a range of a file with no storage allocated, which reads as
+//! zeros. A backup asks the file system where the holes of a file are
+//! (`SEEK_DATA`/`SEEK_HOLE`), records them and chunks only the data between
+//! them, so holes are neither read nor stored. A restore seeks over them
+//! instead of writing zeros, which leaves the same holes in the new file.
+//! Files whose allocated blocks cover their length have no holes and are not
+//! probed.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A range of a file that holds no data
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hole {
+    /// Offset of the first byte, from the start of the file
+    pub offset: u64,
+    /// Length in bytes
+    pub len: u64,
+}
+
+impl Hole {
+    fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// Find the holes of `file`, in order. Leaves the file positioned at its
+/// start.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn find_holes(file: &File) -> io::Result<Vec<Hole>> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let md = file.metadata()?;
+    let len = md.len();
+    // Without holes, the allocated 512-byte blocks cover the whole file
+    if md.blocks().saturating_mul(512) >= len {
+        return Ok(Vec::new());
+    }
+
+    let fd = file.as_raw_fd();
+    let mut holes = Vec::new();
+    let mut pos = 0;
+    while pos < len {
+        let data = match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(data) => (data as u64).min(len),
+            // No data after `pos`: the rest of the file is a hole
+            Err(Errno::ENXIO) => len,
+            Err(e) => return Err(e.into()),
+        };
+        if data > pos {
+            holes.push(Hole {
+                offset: pos,
+                len: data - pos,
+            });
+        }
+        if data >= len {
+            break;
+        }
+        pos = lseek(fd, data as i64, Whence::SeekHole)? as u64;
+    }
+    lseek(fd, 0, Whence::SeekSet)?;
+    Ok(holes)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+pub fn find_holes(_file: &File) -> io::Result<Vec<Hole>> {
+    Ok(Vec::new())
+}
+
+/// Reads the data of a file, skipping its holes
+pub struct DataReader<R> {
+    inner: R,
+    holes: Vec<Hole>,
+    /// Index of the first hole not skipped yet
+    next: usize,
+    /// Position in the file
+    pos: u64,
+}
+
+impl<R: Read + Seek> DataReader<R> {
+    /// Read `inner`, positioned at its start, leaving out `holes`
+    pub fn new(inner: R, holes: Vec<Hole>) -> Self {
+        Self {
+            inner,
+            holes,
+            next: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for DataReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(hole) = self.holes.get(self.next)
+            && hole.offset <= self.pos
+        {
+            if hole.end() > self.pos {
+                self.pos = hole.end();
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+            }
+            self.next += 1;
+        }
+        // Stop at the next hole
+        let limit = match self.holes.get(self.next) {
+            Some(hole) => buf.len().min((hole.offset - self.pos).try_into().unwrap_or(usize::MAX)),
+            None => buf.len(),
+        };
+        let n = self.inner.read(&mut buf[..limit])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes the data of a new file around its holes, which are left
+/// unwritten
+pub struct SparseWriter<'a> {
+    file: File,
+    holes: &'a [Hole],
+    /// Index of the first hole not passed yet
+    next: usize,
+    /// Position in the file of the next data byte
+    pos: u64,
+    /// Position of the file cursor
+    cursor: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    /// Write to the empty `file`, leaving `holes`
+    pub fn new(file: File, holes: &'a [Hole]) -> Self {
+        Self {
+            file,
+            holes,
+            next: 0,
+            pos: 0,
+            cursor: 0,
+        }
+    }
+
+    /// Write the next data of the file. Returns where it starts if it was
+    /// written in one piece, not split by a hole.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<Option<u64>> {
+        self.advance(data.len() as u64, Some(data))
+    }
+
+    /// Leave the next `len` bytes of data unwritten, making them a hole.
+    /// Returns where they start if they are in one piece.
+    pub fn skip(&mut self, len: u64) -> io::Result<Option<u64>> {
+        self.advance(len, None)
+    }
+
+    /// Extend the file over a final hole and flush it to disk
+    pub fn finish(mut self) -> io::Result<()> {
+        self.pass_holes();
+        if self.pos > self.cursor {
+            self.file.set_len(self.pos)?;
+        }
+        self.file.sync_all()
+    }
+
+    fn advance(&mut self, len: u64, data: Option<&[u8]>) -> io::Result<Option<u64>> {
+        self.pass_holes();
+        let start = self.pos;
+        let mut done = 0;
+        while done < len {
+            self.pass_holes();
+            let n = match self.holes.get(self.next) {
+                Some(hole) => (len - done).min(hole.offset - self.pos),
+                None => len - done,
+            };
+            if let Some(data) = data {
+                if self.cursor != self.pos {
+                    self.file.seek(SeekFrom::Start(self.pos))?;
+                }
+                self.file.write_all(&data[done as usize..(done + n) as usize])?;
+                self.cursor = self.pos + n;
+            }
+            self.pos += n;
+            done += n;
+        }
+        Ok((self.pos - start == len).then_some(start))
+    }
+
+    /// Move past the holes starting at the current position
+    fn pass_holes(&mut self) {
+        while let Some(hole) = self.holes.get(self.next)
+            && hole.offset <= self.pos
+        {
+            self.pos = self.pos.max(hole.end());
+            self.next += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn holes(ranges: &[(u64, u64)]) -> Vec<Hole> {
+        ranges
+            .iter()
+            .map(|&(offset, len)| Hole { offset, len })
+            .collect()
+    }
+
+    #[test]
+    fn test_data_reader_skips_holes() {
+        let content: Vec<u8> = (0..100u8).collect();
+        let mut reader = DataReader::new(Cursor::new(&content), holes(&[(0, 10), (40, 20), (90, 10)]));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [&content[10..40], &content[60..90]].concat());
+    }
+
+    #[test]
+    fn test_sparse_writer_round_trip() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("disk.img");
+        let holes = holes(&[(4, 4), (12, 8), (24, 6)]);
+
+        let mut writer = SparseWriter::new(File::create(&path).unwrap(), &holes);
+        // Split by the first hole, then contiguous, then skipped
+        assert_eq!(writer.write(b"abcdef").unwrap(), None);
+        assert_eq!(writer.write(b"gh").unwrap(), Some(10));
+        assert_eq!(writer.skip(2).unwrap(), Some(20));
+        assert_eq!(writer.write(b"ij").unwrap(), Some(22));
+        writer.finish().unwrap();
+
+        let mut expected = vec![0u8; 30];
+        expected[..4].copy_from_slice(b"abcd");
+        expected[8..12].copy_from_slice(b"efgh");
+        expected[22..24].copy_from_slice(b"ij");
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_find_holes() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("sparse");
+        let mut file = File::create(&path).unwrap();
+        let block = vec![1u8; 64 * 1024];
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        file.write_all(&block).unwrap();
+        file.set_len(4 << 20).unwrap();
+        file.sync_all().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let holes = find_holes(&file).unwrap();
+        if holes.is_empty() {
+            // The file system does not report holes
+            return;
+        }
+        assert_eq!(holes.first().map(|hole| hole.offset), Some(0));
+        assert_eq!(holes.last().map(Hole::end), Some(4 << 20));
+        let data: u64 = (4 << 20) - holes.iter().map(|hole| hole.len).sum::<u64>();
+        assert!(data >= block.len() as u64 && data < 1 << 20);
+
+        // The data the reader yields starts with the written block
+        let mut data = Vec::new();
+        DataReader::new(file, holes).read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..block.len()], &block[..]);
+    }
+}
@@ -1,67 +1,262 @@
 //! Chunk storage module for content-addressed storage (CAS).
 //!
-//! This module handles the physical storage of chunks on disk using content addressing.
-//! Chunks are stored in a two-level directory structure based on their hash prefix.
-
-use crate::chunking::ChunkHash;
+//! Chunks are addressed by hash and appended into pack files (see `pack`).
+//! The pack index, kept as one or more pack index objects, maps every chunk
+//! hash to its pack, offset and length. Each chunk carries a header naming its
+//! compression codec (see `compression`). In encrypted repositories the
+//! payload after the header is sealed with the master key, chunks are named by
+//! keyed hash, and pack trailers and index files are sealed as well.
+//!
+//! All objects are read and written through a storage `Backend`.
+//! Repositories created before pack files stored each chunk as its own
+//! object; such chunks remain readable and deletable.
+
+use crate::backend::{Backend, LocalBackend, ObjectKind};
+use crate::chunking::{ChunkHash, ChunkHasher};
+use crate::compression::{self, ChunkHeader, CompressionConfig, HEADER_SIZE};
+use crate::crypto::MasterKey;
 use crate::error::{Result, SnapVaultError};
+use crate::pack::{IndexFile, IndexedPack, PackBlob, PackLocation, PackWriter, DEFAULT_PACK_SIZE};
 use log::{debug, warn};
-use std::fs;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Associated data for a sealed pack index file
+const INDEX_AAD: &[u8] = b"snapvault pack index";
 
 /// Chunk storage manager for content-addressed storage
 pub struct ChunkStore {
-    /// Root directory for chunk storage (typically repo/data/chunks/)
-    root: PathBuf,
+    /// Storage holding packs and pack index files
+    backend: Arc<dyn Backend>,
+    /// Compression applied to newly stored chunks
+    compression: CompressionConfig,
+    /// Hasher used to name and verify chunks
+    hasher: ChunkHasher,
+    /// Master key for sealing chunk payloads (encrypted repositories only)
+    key: Option<MasterKey>,
+    /// Target size of newly written pack files
+    pack_size: u64,
+    /// Open pack and pack index
+    state: Mutex<PackState>,
+    /// Signalled whenever a pack upload ends
+    uploaded: Condvar,
+}
+
+/// Mutable state shared by all operations on a store
+#[derive(Default)]
+struct PackState {
+    /// Pack index, loaded on first use
+    index: Option<PackIndex>,
+    /// Pack currently being filled
+    writer: PackWriter,
+    /// Full packs being written to the backend by some thread, whose chunks
+    /// are not in the index yet
+    uploading: Vec<Arc<UploadingPack>>,
+    /// Packs written since the last flush that no index file lists yet
+    unindexed: Vec<IndexedPack>,
+    /// Packs left without chunks, removed once the index no longer lists them
+    obsolete: Vec<String>,
+    /// Packs that lost chunks but still hold others, repacked on flush
+    shrunk: HashSet<String>,
+    /// Set when chunks were deleted and the index files must be rewritten
+    rewrite: bool,
+}
+
+impl PackState {
+    /// Framed bytes of a chunk stored but not indexed yet: in the open pack
+    /// or in a pack being uploaded
+    fn pending(&self, hash: &ChunkHash) -> Option<&[u8]> {
+        self.writer
+            .get(hash)
+            .or_else(|| self.uploading.iter().find_map(|pack| pack.get(hash)))
+    }
+}
+
+/// A finished pack on its way to the backend
+struct UploadingPack {
+    id: String,
+    bytes: Vec<u8>,
+    blobs: Vec<PackBlob>,
+    /// Position of each chunk in `blobs`
+    lookup: HashMap<ChunkHash, usize>,
+}
+
+impl UploadingPack {
+    fn get(&self, hash: &ChunkHash) -> Option<&[u8]> {
+        let blob = &self.blobs[*self.lookup.get(hash)?];
+        Some(&self.bytes[blob.offset as usize..(blob.offset + blob.length) as usize])
+    }
+}
+
+/// Ends an upload that panicked, so flushes waiting for it do not hang
+struct UploadGuard<'a> {
+    store: &'a ChunkStore,
+    pack: Option<Arc<UploadingPack>>,
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pack) = self.pack.take() {
+            let mut state = self.store.state();
+            state.uploading.retain(|other| !Arc::ptr_eq(other, &pack));
+            self.store.uploaded.notify_all();
+        }
+    }
+}
+
+/// In-memory view of the pack index files
+#[derive(Default)]
+struct PackIndex {
+    chunks: HashMap<ChunkHash, PackLocation>,
+    /// Number of indexed chunks in each pack
+    pack_chunks: HashMap<String, usize>,
+    /// Names of the index files the entries came from
+    files: Vec<String>,
+    /// Chunks stored as their own objects (repositories created before packs)
+    loose: HashSet<ChunkHash>,
+}
+
+impl PackIndex {
+    fn insert(&mut self, pack: &IndexedPack) {
+        for blob in &pack.blobs {
+            // The same chunk may have been packed twice by concurrent writers;
+            // the first location wins and the duplicate is never read
+            if self.chunks.contains_key(&blob.hash) {
+                continue;
+            }
+            self.chunks.insert(
+                blob.hash.clone(),
+                PackLocation {
+                    pack: pack.id.clone(),
+                    offset: blob.offset,
+                    length: blob.length,
+                },
+            );
+            *self.pack_chunks.entry(pack.id.clone()).or_default() += 1;
+        }
+    }
+
+    /// Group all entries by pack into a single index file
+    fn to_file(&self) -> IndexFile {
+        let mut packs: HashMap<&str, Vec<PackBlob>> = HashMap::new();
+        for (hash, location) in &self.chunks {
+            packs
+                .entry(location.pack.as_str())
+                .or_default()
+                .push(PackBlob {
+                    hash: hash.clone(),
+                    offset: location.offset,
+                    length: location.length,
+                });
+        }
+        let mut packs: Vec<IndexedPack> = packs
+            .into_iter()
+            .map(|(id, mut blobs)| {
+                blobs.sort_by_key(|b| b.offset);
+                IndexedPack {
+                    id: id.to_string(),
+                    blobs,
+                }
+            })
+            .collect();
+        packs.sort_by(|a, b| a.id.cmp(&b.id));
+        IndexFile { packs }
+    }
+}
+
+/// Where a stored chunk lives
+enum Location {
+    /// Inside a pack file
+    Packed(PackLocation),
+    /// In its own object (repositories created before pack files)
+    Loose,
 }
 
 impl ChunkStore {
-    /// Create a new chunk store at the given root directory
+    /// Create a new chunk store for the local repository directory at `root`
     pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self::from_backend(Arc::new(LocalBackend::new(root)))
+    }
+
+    /// Create a new chunk store on top of a storage backend
+    pub fn from_backend(backend: Arc<dyn Backend>) -> Self {
         Self {
-            root: root.as_ref().to_path_buf(),
+            backend,
+            compression: CompressionConfig::None,
+            hasher: ChunkHasher::Plain,
+            key: None,
+            pack_size: DEFAULT_PACK_SIZE,
+            state: Mutex::new(PackState::default()),
+            uploaded: Condvar::new(),
         }
     }
 
-    /// Initialize the chunk storage directory structure
-    pub fn init(&self) -> Result<()> {
-        fs::create_dir_all(&self.root)?;
-        debug!("Initialized chunk storage at: {}", self.root.display());
-        Ok(())
+    /// Encrypt chunk payloads with `key` and address chunks by keyed hash
+    pub fn with_encryption(mut self, key: MasterKey) -> Self {
+        self.hasher = ChunkHasher::Keyed(*key.id_key());
+        self.key = Some(key);
+        self
+    }
+
+    /// Get the hasher used to compute chunk IDs for this store
+    pub fn hasher(&self) -> &ChunkHasher {
+        &self.hasher
+    }
+
+    /// Set the compression used for newly stored chunks.
+    /// Existing chunks are read with whatever codec their header names.
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Set the size at which a pack is closed and a new one started
+    pub fn with_pack_size(mut self, pack_size: u64) -> Self {
+        self.pack_size = pack_size;
+        self
     }
 
-    /// Get the path for a chunk based on its hash
-    /// Uses a two-level directory structure: chunks/<prefix>/<hash>
-    /// Example: chunks/ab/ab123456...
-    pub fn chunk_path(&self, hash: &ChunkHash) -> PathBuf {
-        let prefix = hash.prefix();
-        self.root.join(&prefix).join(hash.to_hex())
+    /// Initialize the chunk storage
+    pub fn init(&self) -> Result<()> {
+        self.backend.create()?;
+        debug!("Initialized chunk storage at: {}", self.backend.location());
+        Ok(())
     }
 
     /// Check if a chunk exists in storage
     pub fn contains(&self, hash: &ChunkHash) -> bool {
-        self.chunk_path(hash).exists()
+        let mut state = self.state();
+        if state.pending(hash).is_some() {
+            return true;
+        }
+        match self.locate(&mut state, hash) {
+            Ok(location) => location.is_some(),
+            Err(e) => {
+                warn!("Failed to load pack index: {}", e);
+                false
+            }
+        }
     }
 
     /// Store a chunk with the given data
-    /// Returns true if the chunk was newly stored, false if it already existed
+    /// Returns true if the chunk was newly stored, false if it already existed.
+    /// The chunk is appended to the open pack, which is written out once it
+    /// reaches the target pack size or on `flush`.
+    ///
+    /// Safe to call from many threads: chunks are compressed and encrypted
+    /// in parallel, and a full pack is uploaded by the thread that filled it
+    /// while the others keep storing. Of several threads storing the same
+    /// chunk at once, exactly one gets `true`.
     pub fn store(&self, hash: &ChunkHash, data: &[u8]) -> Result<bool> {
-        let path = self.chunk_path(hash);
-
         // If chunk already exists, skip writing (deduplication!)
-        if path.exists() {
+        if self.contains(hash) {
             debug!("Chunk already exists: {}", hash);
             return Ok(false);
         }
 
-        // Create parent directory if needed
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-
         // Verify the data matches the hash (security: prevent hash collision attacks)
-        let actual_hash = crate::chunking::hash_bytes(data);
+        let actual_hash = self.hasher.hash(data);
         if actual_hash != *hash {
             return Err(SnapVaultError::Other(format!(
                 "Hash mismatch: expected {}, got {}",
@@ -69,120 +264,253 @@ impl ChunkStore {
             )));
         }
 
-        // Write the chunk atomically
-        // TODO: Consider using tempfile + rename for atomic writes
-        let mut file = fs::File::create(&path)?;
-        file.write_all(data)?;
-        file.sync_all()?;
+        let (header, payload) = compression::compress(data, &self.compression)?;
+        let header = header.to_bytes();
+        let payload = match &self.key {
+            Some(key) => key.seal(&payload, &chunk_aad(&header, hash))?,
+            None => payload,
+        };
+        let mut encoded = Vec::with_capacity(HEADER_SIZE + payload.len());
+        encoded.extend_from_slice(&header);
+        encoded.extend_from_slice(&payload);
+
+        let mut state = self.state();
+        // Another caller may have stored the chunk while it was being encoded
+        if state.pending(hash).is_some() || self.locate(&mut state, hash)?.is_some() {
+            return Ok(false);
+        }
+        state.writer.add(hash, &encoded);
+        if state.writer.size() >= self.pack_size {
+            self.upload_pack(state)?;
+        }
 
-        debug!("Stored new chunk: {} ({} bytes)", hash, data.len());
+        debug!(
+            "Stored new chunk: {} ({} bytes, {} on disk)",
+            hash,
+            data.len(),
+            encoded.len()
+        );
         Ok(true)
     }
 
     /// Read a chunk from storage
     pub fn read(&self, hash: &ChunkHash) -> Result<Vec<u8>> {
-        let path = self.chunk_path(hash);
-
-        if !path.exists() {
-            return Err(SnapVaultError::Other(format!(
-                "Chunk not found: {}",
-                hash
-            )));
-        }
-
-        let mut file = fs::File::open(&path)?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)?;
+        let mut state = self.state();
+        let raw = match state.pending(hash) {
+            Some(framed) => framed.to_vec(),
+            None => match self.locate(&mut state, hash)? {
+                Some(Location::Packed(location)) => {
+                    drop(state);
+                    self.read_packed(&location, location.length)?
+                }
+                Some(Location::Loose) => {
+                    drop(state);
+                    self.backend.get(ObjectKind::Chunk, &hash.to_hex())?
+                }
+                None => {
+                    return Err(SnapVaultError::Other(format!(
+                        "Chunk not found: {}",
+                        hash
+                    )));
+                }
+            },
+        };
 
-        // Verify the hash (integrity check)
-        let actual_hash = crate::chunking::hash_bytes(&data);
-        if actual_hash != *hash {
+        // Decompress and verify the hash of the plaintext (integrity check)
+        let Some(data) = self.decode_verified(hash, raw) else {
             warn!(
-                "Chunk integrity check failed for {}: expected {}, got {}",
-                path.display(),
-                hash,
-                actual_hash
+                "Chunk integrity check failed: content does not match {}",
+                hash
             );
             return Err(SnapVaultError::Other(format!(
                 "Chunk corrupted: hash mismatch for {}",
                 hash
             )));
-        }
+        };
 
         debug!("Read chunk: {} ({} bytes)", hash, data.len());
         Ok(data)
     }
 
+    /// Decode a chunk file and return its plaintext if it matches the expected hash
+    fn decode_verified(&self, hash: &ChunkHash, raw: Vec<u8>) -> Option<Vec<u8>> {
+        if let Some(header) = ChunkHeader::parse(&raw) {
+            let payload = &raw[HEADER_SIZE..];
+            let decoded = match &self.key {
+                Some(key) => key
+                    .open(payload, &chunk_aad(&raw[..HEADER_SIZE], hash))
+                    .and_then(|payload| compression::decompress(&header, &payload)),
+                None => compression::decompress(&header, payload),
+            };
+            match decoded {
+                Ok(data) if self.hasher.hash(&data) == *hash => return Some(data),
+                Ok(_) => {}
+                Err(e) => debug!("Failed to decode chunk {}: {}", hash, e),
+            }
+        }
+
+        // Chunks written before compression support are stored raw without a
+        // header (and may happen to start with the header magic). Encrypted
+        // repositories never contain such chunks.
+        if self.key.is_some() {
+            return None;
+        }
+        (self.hasher.hash(&raw) == *hash).then_some(raw)
+    }
+
     /// Delete a chunk from storage
-    /// This should only be called after verifying the chunk is no longer referenced
+    /// This should only be called after verifying the chunk is no longer referenced.
+    /// The deletion is recorded in the pack index on `flush`, which also
+    /// copies the chunks left in the pack into a new one and removes the old
+    /// pack, so the space of the deleted chunk is reclaimed.
     pub fn delete(&self, hash: &ChunkHash) -> Result<()> {
-        let path = self.chunk_path(hash);
+        let mut state = self.wait_for_uploads();
 
-        if !path.exists() {
-            // Already deleted or never existed
+        // A chunk still in the open pack needs a location before it can go
+        if state.writer.get(hash).is_some() {
+            self.finish_pack(&mut state)?;
+        }
+
+        let index = self.index(&mut state)?;
+        if index.loose.remove(hash) {
+            drop(state);
+            self.backend.delete(ObjectKind::Chunk, &hash.to_hex())?;
+            debug!("Deleted chunk: {}", hash);
             return Ok(());
         }
 
-        fs::remove_file(&path)?;
-        debug!("Deleted chunk: {}", hash);
+        let Some(location) = index.chunks.remove(hash) else {
+            // Already deleted or never existed
+            return Ok(());
+        };
+        let emptied = match index.pack_chunks.get_mut(&location.pack) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                index.pack_chunks.remove(&location.pack);
+                true
+            }
+        };
+        if emptied {
+            state.shrunk.remove(&location.pack);
+            state.obsolete.push(location.pack.clone());
+        } else {
+            state.shrunk.insert(location.pack.clone());
+        }
+        state.rewrite = true;
+        debug!("Deleted chunk: {} (pack {})", hash, location.pack);
+        Ok(())
+    }
 
-        // Try to remove empty parent directory (cleanup)
-        if let Some(parent) = path.parent() {
-            if let Ok(mut entries) = fs::read_dir(parent) {
-                if entries.next().is_none() {
-                    // Directory is empty, remove it
-                    let _ = fs::remove_dir(parent);
+    /// Write out the open pack and persist pack index changes.
+    /// Stored and deleted chunks are only durable once this returns; it also
+    /// runs when the store is dropped.
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.wait_for_uploads();
+        self.finish_pack(&mut state)?;
+
+        if state.rewrite {
+            self.repack(&mut state)?;
+
+            // Replace every index file we know of with one listing the
+            // current entries, then drop packs nothing refers to anymore
+            let file = self.index(&mut state)?.to_file();
+            let name = self.write_index_file(&file)?;
+            let index = self.index(&mut state)?;
+            for old in std::mem::take(&mut index.files) {
+                if old != name {
+                    self.backend.delete(ObjectKind::PackIndex, &old)?;
                 }
             }
-        }
+            index.files.push(name);
+            state.unindexed.clear();
+            state.rewrite = false;
 
+            for pack_id in std::mem::take(&mut state.obsolete) {
+                self.backend.delete(ObjectKind::Pack, &pack_id)?;
+                debug!("Removed obsolete pack {}", pack_id);
+            }
+        } else if !state.unindexed.is_empty() {
+            let file = IndexFile {
+                packs: state.unindexed.clone(),
+            };
+            let name = self.write_index_file(&file)?;
+            state.unindexed.clear();
+            self.index(&mut state)?.files.push(name);
+        }
         Ok(())
     }
 
-    /// Get the size of a chunk in bytes
+    /// Get the logical (uncompressed) size of a chunk in bytes
     pub fn chunk_size(&self, hash: &ChunkHash) -> Result<u64> {
-        let path = self.chunk_path(hash);
-        let metadata = fs::metadata(&path)?;
-        Ok(metadata.len())
-    }
-
-    /// List all chunks in storage (for debugging/verification)
-    /// Returns a vector of (hash, size) tuples
-    pub fn list_chunks(&self) -> Result<Vec<(ChunkHash, u64)>> {
-        let mut chunks = Vec::new();
-
-        if !self.root.exists() {
-            return Ok(chunks);
+        let mut state = self.state();
+        if let Some(framed) = state.pending(hash) {
+            return Ok(framed_logical_size(framed, framed.len() as u64));
         }
-
-        // Iterate through prefix directories
-        for entry in fs::read_dir(&self.root)? {
-            let entry = entry?;
-            let path = entry.path();
-
-            if !path.is_dir() {
-                continue;
+        match self.locate(&mut state, hash)? {
+            Some(Location::Packed(location)) => {
+                drop(state);
+                let head = self.read_packed(&location, location.length.min(HEADER_SIZE as u64))?;
+                Ok(framed_logical_size(&head, location.length))
             }
+            Some(Location::Loose) => {
+                drop(state);
+                let name = hash.to_hex();
+                let stored = self.backend.size(ObjectKind::Chunk, &name)?;
+                let head = self.backend.get_range(
+                    ObjectKind::Chunk,
+                    &name,
+                    0,
+                    stored.min(HEADER_SIZE as u64),
+                )?;
+                Ok(framed_logical_size(&head, stored))
+            }
+            None => Err(SnapVaultError::Other(format!("Chunk not found: {}", hash))),
+        }
+    }
 
-            // Iterate through chunks in this prefix directory
-            for chunk_entry in fs::read_dir(&path)? {
-                let chunk_entry = chunk_entry?;
-                let chunk_path = chunk_entry.path();
-
-                if !chunk_path.is_file() {
-                    continue;
-                }
-
-                // Parse the hash from the filename
-                if let Some(filename) = chunk_path.file_name().and_then(|s| s.to_str()) {
-                    if let Ok(hash) = ChunkHash::from_hex(filename) {
-                        let size = chunk_entry.metadata()?.len();
-                        chunks.push((hash, size));
-                    }
-                }
+    /// Get the number of bytes a chunk occupies on disk
+    pub fn stored_size(&self, hash: &ChunkHash) -> Result<u64> {
+        let mut state = self.state();
+        if let Some(framed) = state.pending(hash) {
+            return Ok(framed.len() as u64);
+        }
+        match self.locate(&mut state, hash)? {
+            Some(Location::Packed(location)) => Ok(location.length),
+            Some(Location::Loose) => {
+                drop(state);
+                self.backend.size(ObjectKind::Chunk, &hash.to_hex())
             }
+            None => Err(SnapVaultError::Other(format!("Chunk not found: {}", hash))),
         }
+    }
 
+    /// List all chunks in storage (for debugging/verification)
+    /// Returns a vector of (hash, size on disk) tuples
+    pub fn list_chunks(&self) -> Result<Vec<(ChunkHash, u64)>> {
+        let mut state = self.wait_for_uploads();
+        let mut chunks: Vec<(ChunkHash, u64)> = state
+            .writer
+            .blobs()
+            .iter()
+            .map(|blob| (blob.hash.clone(), blob.length))
+            .collect();
+        let index = self.index(&mut state)?;
+        chunks.extend(
+            index
+                .chunks
+                .iter()
+                .map(|(hash, location)| (hash.clone(), location.length)),
+        );
+        let loose: Vec<ChunkHash> = index.loose.iter().cloned().collect();
+        drop(state);
+
+        for hash in loose {
+            let size = self.backend.size(ObjectKind::Chunk, &hash.to_hex())?;
+            chunks.push((hash, size));
+        }
         Ok(chunks)
     }
 
@@ -190,13 +518,238 @@ impl ChunkStore {
     pub fn stats(&self) -> Result<StorageStats> {
         let chunks = self.list_chunks()?;
         let total_chunks = chunks.len();
-        let total_size: u64 = chunks.iter().map(|(_, size)| size).sum();
+        let stored_size: u64 = chunks.iter().map(|(_, size)| size).sum();
+        let mut total_size = 0;
+        for (hash, _) in &chunks {
+            total_size += self.chunk_size(hash)?;
+        }
 
         Ok(StorageStats {
             total_chunks,
             total_size,
+            stored_size,
         })
     }
+
+    /// Lock the shared pack state
+    fn state(&self) -> MutexGuard<'_, PackState> {
+        // The state stays consistent even if a holder panicked
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock the shared pack state once no pack is being uploaded
+    fn wait_for_uploads(&self) -> MutexGuard<'_, PackState> {
+        let mut state = self.state();
+        while !state.uploading.is_empty() {
+            state = self.uploaded.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state
+    }
+
+    /// Get the pack index, loading it on first use
+    fn index<'a>(&self, state: &'a mut PackState) -> Result<&'a mut PackIndex> {
+        if state.index.is_none() {
+            state.index = Some(self.load_index()?);
+        }
+        Ok(state.index.get_or_insert_default())
+    }
+
+    /// Find where a chunk is stored, ignoring the open pack
+    fn locate(&self, state: &mut PackState, hash: &ChunkHash) -> Result<Option<Location>> {
+        let index = self.index(state)?;
+        if let Some(location) = index.chunks.get(hash) {
+            return Ok(Some(Location::Packed(location.clone())));
+        }
+        Ok(index.loose.contains(hash).then_some(Location::Loose))
+    }
+
+    /// Read and merge every pack index file, and note any loose chunks
+    fn load_index(&self) -> Result<PackIndex> {
+        let mut index = PackIndex::default();
+
+        for name in self.backend.list(ObjectKind::PackIndex)? {
+            let raw = self.backend.get(ObjectKind::PackIndex, &name)?;
+            let raw = match &self.key {
+                Some(key) => key.open(&raw, INDEX_AAD)?,
+                None => raw,
+            };
+            let file: IndexFile = serde_json::from_slice(&raw)?;
+            for pack in &file.packs {
+                index.insert(pack);
+            }
+            index.files.push(name);
+        }
+
+        index.loose = self
+            .backend
+            .list(ObjectKind::Chunk)?
+            .iter()
+            .filter_map(|name| ChunkHash::from_hex(name).ok())
+            .collect();
+
+        debug!(
+            "Loaded pack index: {} chunks in {} packs from {} files, {} loose chunks",
+            index.chunks.len(),
+            index.pack_chunks.len(),
+            index.files.len(),
+            index.loose.len()
+        );
+        Ok(index)
+    }
+
+    /// Write a pack index file named by the hash of its contents.
+    /// Returns the name of the file.
+    fn write_index_file(&self, file: &IndexFile) -> Result<String> {
+        let data = serde_json::to_vec(file)?;
+        let data = match &self.key {
+            Some(key) => key.seal(&data, INDEX_AAD)?,
+            None => data,
+        };
+        let name = blake3::hash(&data).to_hex().to_string();
+        self.backend.put(ObjectKind::PackIndex, &name, &data)?;
+        Ok(name)
+    }
+
+    /// Close the open pack and write it to storage without holding the lock,
+    /// so other threads can keep storing chunks meanwhile. Its chunks stay
+    /// readable from memory until the pack is in the index.
+    fn upload_pack(&self, mut state: MutexGuard<'_, PackState>) -> Result<()> {
+        let writer = std::mem::take(&mut state.writer);
+        let (id, bytes, blobs) = writer.finish(self.key.as_ref())?;
+        let lookup = blobs
+            .iter()
+            .enumerate()
+            .map(|(i, blob)| (blob.hash.clone(), i))
+            .collect();
+        let pack = Arc::new(UploadingPack {
+            id,
+            bytes,
+            blobs,
+            lookup,
+        });
+        state.uploading.push(pack.clone());
+        drop(state);
+
+        let mut guard = UploadGuard {
+            store: self,
+            pack: Some(pack.clone()),
+        };
+        let written = self.backend.put(ObjectKind::Pack, &pack.id, &pack.bytes);
+
+        // Index the pack in the same critical section that ends its upload,
+        // so waiting flushes see it
+        let mut state = self.state();
+        guard.pack = None;
+        state.uploading.retain(|other| !Arc::ptr_eq(other, &pack));
+        self.uploaded.notify_all();
+        written?;
+        debug!(
+            "Wrote pack {} ({} chunks, {} bytes)",
+            pack.id,
+            pack.blobs.len(),
+            pack.bytes.len()
+        );
+
+        let pack = IndexedPack {
+            id: pack.id.clone(),
+            blobs: pack.blobs.clone(),
+        };
+        self.index(&mut state)?.insert(&pack);
+        state.unindexed.push(pack);
+        Ok(())
+    }
+
+    /// Write the open pack to storage and add its chunks to the in-memory index
+    fn finish_pack(&self, state: &mut PackState) -> Result<()> {
+        if state.writer.is_empty() {
+            return Ok(());
+        }
+        let writer = std::mem::take(&mut state.writer);
+        let (id, bytes, blobs) = writer.finish(self.key.as_ref())?;
+
+        self.backend.put(ObjectKind::Pack, &id, &bytes)?;
+        debug!(
+            "Wrote pack {} ({} chunks, {} bytes)",
+            id,
+            blobs.len(),
+            bytes.len()
+        );
+
+        let pack = IndexedPack { id, blobs };
+        self.index(state)?.insert(&pack);
+        state.unindexed.push(pack);
+        Ok(())
+    }
+
+    /// Copy the chunks left in packs that lost some into new packs and mark
+    /// the old packs obsolete. The copies are written before the index files
+    /// are replaced, so the chunks stay readable if a flush is interrupted.
+    fn repack(&self, state: &mut PackState) -> Result<()> {
+        let shrunk = std::mem::take(&mut state.shrunk);
+        if shrunk.is_empty() {
+            return Ok(());
+        }
+        let mut live: HashMap<String, Vec<(ChunkHash, PackLocation)>> = HashMap::new();
+        for (hash, location) in &self.index(state)?.chunks {
+            if shrunk.contains(&location.pack) {
+                live.entry(location.pack.clone())
+                    .or_default()
+                    .push((hash.clone(), location.clone()));
+            }
+        }
+
+        for (pack_id, mut blobs) in live {
+            let bytes = self.backend.get(ObjectKind::Pack, &pack_id)?;
+            blobs.sort_by_key(|(_, location)| location.offset);
+            let index = self.index(state)?;
+            for (hash, _) in &blobs {
+                index.chunks.remove(hash);
+            }
+            index.pack_chunks.remove(&pack_id);
+
+            for (hash, location) in &blobs {
+                let framed = usize::try_from(location.offset)
+                    .ok()
+                    .zip(usize::try_from(location.offset + location.length).ok())
+                    .and_then(|(start, end)| bytes.get(start..end))
+                    .ok_or_else(|| SnapVaultError::Other(format!("Invalid pack file {}", pack_id)))?;
+                state.writer.add(hash, framed);
+                if state.writer.size() >= self.pack_size {
+                    self.finish_pack(state)?;
+                }
+            }
+            debug!("Repacking {} chunks of pack {}", blobs.len(), pack_id);
+            state.obsolete.push(pack_id);
+        }
+        self.finish_pack(state)
+    }
+
+    /// Read the first `len` bytes of a packed chunk
+    fn read_packed(&self, location: &PackLocation, len: u64) -> Result<Vec<u8>> {
+        self.backend
+            .get_range(ObjectKind::Pack, &location.pack, location.offset, len)
+    }
+}
+
+impl Drop for ChunkStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush chunk store: {}", e);
+        }
+    }
+}
+
+/// Associated data for a sealed chunk: binds the payload to its header and name
+fn chunk_aad(header: &[u8], hash: &ChunkHash) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(hash.as_bytes());
+    aad
+}
+
+/// Logical size named by the header at the start of a framed chunk, or
+/// `stored` for a legacy chunk without a header
+fn framed_logical_size(head: &[u8], stored: u64) -> u64 {
+    ChunkHeader::parse(head).map_or(stored, |header| header.logical_size)
 }
 
 /// Storage statistics
@@ -204,8 +757,10 @@ impl ChunkStore {
 pub struct StorageStats {
     /// Total number of unique chunks
     pub total_chunks: usize,
-    /// Total size in bytes
+    /// Total logical (uncompressed) size in bytes
     pub total_size: u64,
+    /// Total size on disk in bytes, after compression and including headers
+    pub stored_size: u64,
 }
 
 impl StorageStats {
@@ -213,10 +768,24 @@ impl StorageStats {
     pub fn format_size(&self) -> String {
         format_size(self.total_size)
     }
+
+    /// Format the on-disk size in human-readable form
+    pub fn format_stored_size(&self) -> String {
+        format_size(self.stored_size)
+    }
+
+    /// Ratio of on-disk bytes to logical bytes (1.0 = no savings)
+    /// Returns None if the store is empty
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.total_size == 0 {
+            return None;
+        }
+        Some(self.stored_size as f64 / self.total_size as f64)
+    }
 }
 
 /// Format a size in bytes to human-readable format
-fn format_size(bytes: u64) -> String {
+pub(crate) fn format_size(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
     let mut size = bytes as f64;
     let mut unit_idx = 0;
@@ -236,34 +805,43 @@ fn format_size(bytes: u64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::MemoryBackend;
     use crate::chunking::hash_bytes;
+    use crate::pack;
     use tempfile::TempDir;
 
+    /// Chunk store over a fresh in-memory backend, plus a handle to inspect it
+    fn memory_store() -> (ChunkStore, MemoryBackend) {
+        let backend = MemoryBackend::new();
+        let store = ChunkStore::from_backend(Arc::new(backend.clone()));
+        (store, backend)
+    }
+
+    /// Name of the single pack in `backend`
+    fn only_pack(backend: &MemoryBackend) -> String {
+        let packs = backend.list(ObjectKind::Pack).unwrap();
+        assert_eq!(packs.len(), 1);
+        packs[0].clone()
+    }
+
+    fn count(backend: &MemoryBackend, kind: ObjectKind) -> usize {
+        backend.list(kind).unwrap().len()
+    }
+
     #[test]
     fn test_chunk_store_init() -> Result<()> {
         let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let store = ChunkStore::new(temp_dir.path().join("repo"));
         store.init()?;
 
-        assert!(temp_dir.path().join("chunks").exists());
+        assert!(temp_dir.path().join("repo").join("data").join("packs").is_dir());
+        assert!(temp_dir.path().join("repo").join("data").join("index").is_dir());
         Ok(())
     }
 
-    #[test]
-    fn test_chunk_path() {
-        let store = ChunkStore::new("/tmp/chunks");
-        let hash = ChunkHash::from_hex("abcdef1234567890abcdef1234567890abcdef1234567890abcdef1234567890").unwrap();
-        let path = store.chunk_path(&hash);
-
-        assert!(path.to_string_lossy().contains("/ab/"));
-        assert!(path.to_string_lossy().ends_with("abcdef1234567890abcdef1234567890abcdef1234567890abcdef1234567890"));
-    }
-
     #[test]
     fn test_store_and_read_chunk() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let data = b"hello world";
         let hash = hash_bytes(data);
@@ -280,10 +858,24 @@ mod tests {
     }
 
     #[test]
-    fn test_store_duplicate_chunk() -> Result<()> {
+    fn test_local_store_and_read_chunk() -> Result<()> {
         let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let root = temp_dir.path().join("repo");
+        let data = b"hello world";
+        let hash = hash_bytes(data);
+
+        let store = ChunkStore::new(&root);
         store.init()?;
+        store.store(&hash, data)?;
+        store.flush()?;
+
+        assert_eq!(ChunkStore::new(&root).read(&hash)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_duplicate_chunk() -> Result<()> {
+        let (store, _) = memory_store();
 
         let data = b"hello world";
         let hash = hash_bytes(data);
@@ -300,9 +892,7 @@ mod tests {
 
     #[test]
     fn test_contains() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let data = b"hello world";
         let hash = hash_bytes(data);
@@ -316,9 +906,7 @@ mod tests {
 
     #[test]
     fn test_delete_chunk() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let data = b"hello world";
         let hash = hash_bytes(data);
@@ -334,9 +922,7 @@ mod tests {
 
     #[test]
     fn test_chunk_size() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let data = b"hello world";
         let hash = hash_bytes(data);
@@ -345,14 +931,15 @@ mod tests {
         let size = store.chunk_size(&hash)?;
         assert_eq!(size, data.len() as u64);
 
+        store.flush()?;
+        assert_eq!(store.chunk_size(&hash)?, data.len() as u64);
+
         Ok(())
     }
 
     #[test]
     fn test_list_chunks() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let data1 = b"hello";
         let data2 = b"world";
@@ -370,9 +957,7 @@ mod tests {
 
     #[test]
     fn test_storage_stats() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let data1 = b"hello";
         let data2 = b"world";
@@ -385,10 +970,309 @@ mod tests {
         let stats = store.stats()?;
         assert_eq!(stats.total_chunks, 2);
         assert_eq!(stats.total_size, 10); // "hello" (5) + "world" (5)
+        assert_eq!(stats.stored_size, 10 + 2 * HEADER_SIZE as u64); // stored raw
 
         Ok(())
     }
 
+    #[test]
+    fn test_compressed_store_and_read() -> Result<()> {
+        for compression in [CompressionConfig::Zstd { level: 3 }, CompressionConfig::Lz4] {
+            let (store, _) = memory_store();
+            let store = store.with_compression(compression);
+
+            let data = b"abcdefgh".repeat(4096);
+            let hash = hash_bytes(&data);
+            store.store(&hash, &data)?;
+
+            assert_eq!(store.read(&hash)?, data);
+            assert_eq!(store.chunk_size(&hash)?, data.len() as u64);
+            assert!(store.stored_size(&hash)? < data.len() as u64);
+
+            let stats = store.stats()?;
+            assert_eq!(stats.total_size, data.len() as u64);
+            assert!(stats.stored_size < stats.total_size);
+            assert!(stats.compression_ratio().unwrap() < 1.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_legacy_raw_chunk() -> Result<()> {
+        let (store, backend) = memory_store();
+
+        // Chunks written before compression support have no header
+        let data = b"legacy chunk";
+        let hash = hash_bytes(data);
+        backend.put(ObjectKind::Chunk, &hash.to_hex(), data)?;
+
+        assert_eq!(store.read(&hash)?, data);
+        assert_eq!(store.chunk_size(&hash)?, data.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_corrupted_compressed_chunk() -> Result<()> {
+        let (store, backend) = memory_store();
+        let store = store.with_compression(CompressionConfig::default());
+
+        let data = b"abcdefgh".repeat(4096);
+        let hash = hash_bytes(&data);
+        store.store(&hash, &data)?;
+        store.flush()?;
+
+        // Flip the last byte of the chunk inside its pack
+        let pack_id = only_pack(&backend);
+        let mut raw = backend.get(ObjectKind::Pack, &pack_id)?;
+        let last = pack::PACK_HEADER_SIZE + store.stored_size(&hash)? as usize - 1;
+        raw[last] ^= 0xff;
+        backend.put(ObjectKind::Pack, &pack_id, &raw)?;
+
+        assert!(store.read(&hash).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_store_and_read() -> Result<()> {
+        let key = MasterKey::generate();
+        let (store, backend) = memory_store();
+        let store = store
+            .with_compression(CompressionConfig::default())
+            .with_encryption(key.clone());
+
+        let data = b"top secret ".repeat(1000);
+        let hash = store.hasher().hash(&data);
+        assert_ne!(hash, hash_bytes(&data));
+
+        assert!(store.store(&hash, &data)?);
+        assert_eq!(store.read(&hash)?, data);
+        assert_eq!(store.chunk_size(&hash)?, data.len() as u64);
+
+        // Plaintext never hits the disk
+        store.flush()?;
+        let raw = backend.get(ObjectKind::Pack, &only_pack(&backend))?;
+        assert!(!raw.windows(11).any(|w| w == b"top secret "));
+
+        // A store with a different key can't read the chunk
+        let other = ChunkStore::from_backend(Arc::new(backend.clone()))
+            .with_encryption(MasterKey::generate());
+        assert!(other.read(&hash).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunks_share_pack_files() -> Result<()> {
+        let (store, backend) = memory_store();
+
+        let hashes: Vec<_> = (0..50)
+            .map(|i| {
+                let data = format!("chunk number {}", i).into_bytes();
+                let hash = hash_bytes(&data);
+                store.store(&hash, &data).map(|_| hash)
+            })
+            .collect::<Result<_>>()?;
+        store.flush()?;
+
+        assert_eq!(count(&backend, ObjectKind::Pack), 1);
+        assert_eq!(count(&backend, ObjectKind::PackIndex), 1);
+        assert_eq!(count(&backend, ObjectKind::Chunk), 0);
+        for (i, hash) in hashes.iter().enumerate() {
+            assert_eq!(store.read(hash)?, format!("chunk number {}", i).into_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_rolls_over_at_target_size() -> Result<()> {
+        let (store, backend) = memory_store();
+        let store = store.with_pack_size(1000);
+
+        for i in 0..10u8 {
+            let data = vec![i; 400];
+            store.store(&hash_bytes(&data), &data)?;
+        }
+        // Packs close after crossing 1000 bytes, every third chunk
+        assert_eq!(count(&backend, ObjectKind::Pack), 3);
+        store.flush()?;
+        assert_eq!(count(&backend, ObjectKind::Pack), 4);
+
+        for id in backend.list(ObjectKind::Pack)? {
+            let bytes = backend.get(ObjectKind::Pack, &id)?;
+            assert_eq!(id, blake3::hash(&bytes).to_hex().as_str());
+            assert!(!pack::read_trailer(&bytes, None)?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_persists_across_stores() -> Result<()> {
+        let backend = MemoryBackend::new();
+        let data = b"persisted chunk";
+        let hash = hash_bytes(data);
+
+        {
+            let store = ChunkStore::from_backend(Arc::new(backend.clone()));
+            store.store(&hash, data)?;
+            // Dropping the store flushes the open pack
+        }
+
+        let store = ChunkStore::from_backend(Arc::new(backend.clone()));
+        assert!(store.contains(&hash));
+        assert!(!store.store(&hash, data)?);
+        assert_eq!(store.read(&hash)?, data);
+
+        // A second writer adds its own index file rather than rewriting ours
+        let other = b"another chunk";
+        store.store(&hash_bytes(other), other)?;
+        store.flush()?;
+        assert_eq!(count(&backend, ObjectKind::PackIndex), 2);
+        let reopened = ChunkStore::from_backend(Arc::new(backend.clone()));
+        assert_eq!(reopened.list_chunks()?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_stores_deduplicate() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (store, backend) = memory_store();
+        let store = store.with_pack_size(4096);
+        let chunks: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!("chunk {} ", i).repeat(40).into_bytes())
+            .collect();
+
+        // Every thread stores every chunk, while full packs are uploaded
+        // alongside; each chunk is new to exactly one of them
+        let newly_stored = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for data in &chunks {
+                        if store.store(&hash_bytes(data), data).unwrap() {
+                            newly_stored.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                });
+            }
+        });
+        store.flush()?;
+
+        assert_eq!(newly_stored.into_inner(), chunks.len());
+        assert!(count(&backend, ObjectKind::Pack) > 1);
+        let reopened = ChunkStore::from_backend(Arc::new(backend.clone()));
+        assert_eq!(reopened.list_chunks()?.len(), chunks.len());
+        for data in &chunks {
+            assert_eq!(reopened.read(&hash_bytes(data))?, *data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_removes_empty_packs() -> Result<()> {
+        let (store, backend) = memory_store();
+
+        let (a, b) = (b"first chunk", b"second chunk");
+        let (hash_a, hash_b) = (hash_bytes(a), hash_bytes(b));
+        store.store(&hash_a, a)?;
+        store.store(&hash_b, b)?;
+        store.flush()?;
+
+        // The chunk left is copied into a smaller pack replacing the old one
+        let old_pack = only_pack(&backend);
+        let old_size = backend.size(ObjectKind::Pack, &old_pack)?;
+        store.delete(&hash_a)?;
+        store.flush()?;
+        assert!(!store.contains(&hash_a));
+        assert_eq!(store.read(&hash_b)?, b);
+        let new_pack = only_pack(&backend);
+        assert_ne!(new_pack, old_pack);
+        assert!(backend.size(ObjectKind::Pack, &new_pack)? < old_size);
+        assert_eq!(count(&backend, ObjectKind::PackIndex), 1);
+        let reopened = ChunkStore::from_backend(Arc::new(backend.clone()));
+        assert_eq!(reopened.read(&hash_b)?, b);
+
+        store.delete(&hash_b)?;
+        store.flush()?;
+        assert_eq!(count(&backend, ObjectKind::Pack), 0);
+
+        let reopened = ChunkStore::from_backend(Arc::new(backend.clone()));
+        assert!(!reopened.contains(&hash_a));
+        assert!(!reopened.contains(&hash_b));
+        assert!(reopened.list_chunks()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repacked_encrypted_chunks_stay_readable() -> Result<()> {
+        let key = MasterKey::generate();
+        let (store, backend) = memory_store();
+        let store = store.with_encryption(key.clone());
+        let (a, b) = (b"first secret", b"second secret");
+        let (hash_a, hash_b) = (store.hasher().hash(a), store.hasher().hash(b));
+        store.store(&hash_a, a)?;
+        store.store(&hash_b, b)?;
+        store.flush()?;
+        let old_pack = only_pack(&backend);
+
+        store.delete(&hash_a)?;
+        store.flush()?;
+        assert_ne!(only_pack(&backend), old_pack);
+
+        let reopened = ChunkStore::from_backend(Arc::new(backend.clone())).with_encryption(key);
+        assert_eq!(reopened.read(&hash_b)?, b);
+        assert!(!reopened.contains(&hash_a));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_index_is_sealed() -> Result<()> {
+        let key = MasterKey::generate();
+        let (store, backend) = memory_store();
+        let store = store.with_encryption(key.clone());
+
+        let data = b"secret";
+        let hash = store.hasher().hash(data);
+        store.store(&hash, data)?;
+        store.flush()?;
+
+        let index_name = backend.list(ObjectKind::PackIndex)?.remove(0);
+        let raw = backend.get(ObjectKind::PackIndex, &index_name)?;
+        assert!(!raw.windows(64).any(|w| w == hash.to_hex().as_bytes()));
+
+        let reopened = ChunkStore::from_backend(Arc::new(backend.clone())).with_encryption(key);
+        assert_eq!(reopened.read(&hash)?, data);
+        assert!(!ChunkStore::from_backend(Arc::new(backend.clone()))
+            .with_encryption(MasterKey::generate())
+            .contains(&hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_legacy_chunk() -> Result<()> {
+        let (store, backend) = memory_store();
+
+        let data = b"legacy chunk";
+        let hash = hash_bytes(data);
+        backend.put(ObjectKind::Chunk, &hash.to_hex(), data)?;
+
+        assert!(store.contains(&hash));
+        assert_eq!(store.list_chunks()?.len(), 1);
+        store.delete(&hash)?;
+        assert_eq!(count(&backend, ObjectKind::Chunk), 0);
+        assert!(!store.contains(&hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_store_rejects_plain_hash() -> Result<()> {
+        let (store, _) = memory_store();
+        let store = store.with_encryption(MasterKey::generate());
+
+        let data = b"hello world";
+        assert!(store.store(&hash_bytes(data), data).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_format_size() {
         assert_eq!(format_size(0), "0 B");
@@ -401,9 +1285,7 @@ mod tests {
 
     #[test]
     fn test_hash_mismatch_detection() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let data = b"hello world";
         let wrong_hash = hash_bytes(b"wrong data");
@@ -417,9 +1299,7 @@ mod tests {
 
     #[test]
     fn test_read_nonexistent_chunk() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let store = ChunkStore::new(temp_dir.path().join("chunks"));
-        store.init()?;
+        let (store, _) = memory_store();
 
         let hash = hash_bytes(b"nonexistent");
         let result = store.read(&hash);
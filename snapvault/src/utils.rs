@@ -1,4 +1,6 @@
 use crate::error::{Result, SnapVaultError};
+use std::fs;
+use std::io::Write;
 use std::path::Path;
 
 // Constants for security limits
@@ -54,10 +56,139 @@ pub fn is_safe_path(path_str: &str) -> bool {
     true
 }
 
+/// Whether a symlink at `link_path`, relative to the restore destination,
+/// may point to `target` without leading outside the destination. The
+/// target must be relative, its `..` components must not climb above the
+/// destination from the link's directory, and they must all come first: a
+/// `..` after a name would apply to wherever that name leads, which may be
+/// another symlink (`a -> .` makes `a/../outside` escape).
+pub fn is_safe_link_target(link_path: &str, target: &str) -> bool {
+    if target.is_empty() || target.contains('\0') || !is_safe_path(link_path) {
+        return false;
+    }
+    let target = Path::new(target);
+    if target.is_absolute() {
+        return false;
+    }
+
+    let mut depth = Path::new(link_path)
+        .parent()
+        .map_or(0, |dir| {
+            dir.components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .count()
+        });
+    let mut named = false;
+    for comp in target.components() {
+        match comp {
+            std::path::Component::Normal(_) => {
+                depth += 1;
+                named = true;
+            }
+            std::path::Component::ParentDir if named => return false,
+            std::path::Component::ParentDir => match depth.checked_sub(1) {
+                Some(up) => depth = up,
+                None => return false,
+            },
+            std::path::Component::CurDir => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Name of this host, as recorded in lock files and snapshot manifests
+pub fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Name of the temporary file an object is written to before being renamed
+/// into place. The leading dot keeps it out of object listings.
+pub fn temp_file_name(file_name: &str) -> String {
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    format!(".{}.tmp-{}", file_name, &suffix[..SNAPSHOT_UUID_LEN])
+}
+
+/// Write a file so that readers see either the old contents or all of the
+/// new ones: write a temporary file next to it, fsync it, rename it over
+/// `path` and fsync the directory so the rename survives a crash
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| SnapVaultError::UnsafePath(path.display().to_string()))?;
+    let temp_path = dir.join(temp_file_name(&file_name.to_string_lossy()));
+
+    let written = (|| {
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+    sync_dir(dir)
+}
+
+/// Flush a directory's entries to disk
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories cannot be opened for syncing here; renames are durable once
+/// the file system commits its metadata
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("object");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+
+        // No temporary files are left behind
+        let names: Vec<_> = fs::read_dir(temp.path()).unwrap().collect();
+        assert_eq!(names.len(), 1);
+    }
+
+    #[test]
+    fn test_write_atomic_failure_leaves_no_temp_file() {
+        let temp = tempfile::TempDir::new().unwrap();
+
+        // Renaming a file over a non-empty directory fails after the data
+        // was written
+        let dir = temp.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("child"), b"").unwrap();
+        assert!(write_atomic(&dir, b"replacement").is_err());
+
+        let names: Vec<_> = fs::read_dir(temp.path()).unwrap().collect();
+        assert_eq!(names.len(), 1);
+        assert!(dir.join("child").is_file());
+    }
+
     #[test]
     fn test_validate_snapshot_id_valid() {
         assert!(validate_snapshot_id("20240101T120000.000Z-abc123").is_ok());
@@ -109,4 +240,20 @@ mod tests {
         assert!(!is_safe_path("../etc/passwd"));
         assert!(!is_safe_path("dir/../../etc/passwd"));
     }
+
+    #[test]
+    fn test_is_safe_link_target() {
+        assert!(is_safe_link_target("link", "file.txt"));
+        assert!(is_safe_link_target("a/b/link", "../c/file.txt"));
+        assert!(is_safe_link_target("a/b/link", "../../file.txt"));
+        assert!(is_safe_link_target("a/link", "./../file.txt"));
+        assert!(!is_safe_link_target("a/link", "./b/../file.txt"));
+        assert!(!is_safe_link_target("b", "a/../outside"));
+        assert!(!is_safe_link_target("a/b/link", "../../../file.txt"));
+        assert!(!is_safe_link_target("link", "x/../../file.txt"));
+        assert!(!is_safe_link_target("link", "/etc/passwd"));
+        assert!(!is_safe_link_target("link", ""));
+        assert!(!is_safe_link_target("link", "file\0.txt"));
+        assert!(!is_safe_link_target("../link", "file.txt"));
+    }
 }
@@ -0,0 +1,232 @@
+//! Extended attributes, including POSIX ACLs and file capabilities.
+//!
+//! On Linux, ACLs are kept in the `system.posix_acl_access` and
+//! `system.posix_acl_default` attributes, file capabilities in
+//! `security.capability` and SELinux labels in `security.selinux`, so
+//! recording every attribute of a file preserves all of them. Filters pick
+//! attributes by name, where `*` matches any run of characters and `?` any
+//! single one, as in `user.*`. Restoring them is best effort: a destination
+//! file system without extended attributes, or an attribute only root may
+//! set, is reported with a warning.
+
+use crate::repository::snapshot::FileRecord;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+/// An extended attribute of a file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedAttribute {
+    /// Name, including its namespace (e.g. `user.comment`)
+    pub name: String,
+    /// Raw value, hex-encoded in manifests
+    #[serde(with = "hex")]
+    pub value: Vec<u8>,
+}
+
+/// Which extended attributes to back up or restore
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XattrFilter {
+    /// Only attributes matching one of these patterns (all if empty)
+    pub include: Vec<String>,
+    /// Never attributes matching one of these patterns
+    pub exclude: Vec<String>,
+}
+
+impl XattrFilter {
+    /// Whether the attribute `name` passes the filter
+    pub fn allows(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| wildcard_match(p, name)))
+            && !self.exclude.iter().any(|p| wildcard_match(p, name))
+    }
+}
+
+/// Match `name` against `pattern`, where `*` matches any run of characters
+/// and `?` any single one
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and of the name where it started matching
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` take one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Read the extended attributes of `path` that `filter` allows, sorted by
+/// name. A symlink's own attributes are read, not its target's. File systems
+/// without extended attributes have none.
+#[cfg(unix)]
+pub fn read_xattrs(path: &Path, filter: &XattrFilter) -> io::Result<Vec<ExtendedAttribute>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut attrs = Vec::new();
+    for name in names {
+        let Some(name) = name.to_str() else {
+            warn!(
+                "Skipping extended attribute {:?} of {}: name is not UTF-8",
+                name,
+                path.display()
+            );
+            continue;
+        };
+        if !filter.allows(name) {
+            continue;
+        }
+        // It may have been removed since it was listed
+        if let Some(value) = xattr::get(path, name)? {
+            attrs.push(ExtendedAttribute {
+                name: name.to_string(),
+                value,
+            });
+        }
+    }
+    attrs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(attrs)
+}
+
+#[cfg(not(unix))]
+pub fn read_xattrs(_path: &Path, _filter: &XattrFilter) -> io::Result<Vec<ExtendedAttribute>> {
+    Ok(Vec::new())
+}
+
+/// Sets recorded extended attributes on restored files
+#[derive(Debug, Default)]
+pub struct XattrWriter {
+    filter: XattrFilter,
+    /// Attributes that failed to be set, warned about once each
+    failed: HashSet<String>,
+}
+
+impl XattrWriter {
+    /// Restore the attributes `filter` allows
+    pub fn new(filter: XattrFilter) -> Self {
+        Self {
+            filter,
+            failed: HashSet::new(),
+        }
+    }
+
+    /// Set the recorded attributes of `record` on `path`. An attribute that
+    /// cannot be set is warned about the first time only, so that a
+    /// destination without support does not warn for every file.
+    pub fn apply(&mut self, record: &FileRecord, path: &Path) {
+        for attr in &record.xattrs {
+            if !self.filter.allows(&attr.name) {
+                continue;
+            }
+            if let Err(e) = set_xattr(path, attr) {
+                if self.failed.insert(attr.name.clone()) {
+                    warn!(
+                        "Failed to set extended attribute {} on {}: {} (further failures not shown)",
+                        attr.name,
+                        path.display(),
+                        e
+                    );
+                } else {
+                    debug!("Failed to set {} on {}: {}", attr.name, path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_xattr(path: &Path, attr: &ExtendedAttribute) -> io::Result<()> {
+    xattr::set(path, &attr.name, &attr.value)
+}
+
+#[cfg(not(unix))]
+fn set_xattr(_path: &Path, _attr: &ExtendedAttribute) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("user.*", "user.comment"));
+        assert!(wildcard_match("user.*", "user."));
+        assert!(!wildcard_match("user.*", "security.selinux"));
+        assert!(wildcard_match("*.posix_acl_*", "system.posix_acl_default"));
+        assert!(wildcard_match("security.capabilit?", "security.capability"));
+        assert!(!wildcard_match("security.capabilit?", "security.capabilities"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let all = XattrFilter::default();
+        assert!(all.allows("security.selinux"));
+
+        let filter = XattrFilter {
+            include: vec!["user.*".to_string(), "security.capability".to_string()],
+            exclude: vec!["user.tmp.*".to_string()],
+        };
+        assert!(filter.allows("user.comment"));
+        assert!(filter.allows("security.capability"));
+        assert!(!filter.allows("security.selinux"));
+        assert!(!filter.allows("user.tmp.cache"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_xattrs_round_trip() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let original = temp.path().join("original");
+        let restored = temp.path().join("restored");
+        std::fs::write(&original, "content").unwrap();
+        std::fs::write(&restored, "content").unwrap();
+        if xattr::set(&original, "user.comment", b"hello").is_err() {
+            // The file system has no user attributes
+            return;
+        }
+        xattr::set(&original, "user.tmp.cache", b"skip").unwrap();
+
+        let filter = XattrFilter {
+            exclude: vec!["user.tmp.*".to_string()],
+            ..XattrFilter::default()
+        };
+        let mut record = FileRecord::new("original".to_string(), 7, None, Vec::new(), None);
+        record.xattrs = read_xattrs(&original, &filter).unwrap();
+        assert!(record.xattrs.contains(&ExtendedAttribute {
+            name: "user.comment".to_string(),
+            value: b"hello".to_vec(),
+        }));
+        assert!(record.xattrs.iter().all(|attr| attr.name != "user.tmp.cache"));
+
+        XattrWriter::new(XattrFilter::default()).apply(&record, &restored);
+        assert_eq!(
+            xattr::get(&restored, "user.comment").unwrap().as_deref(),
+            Some(&b"hello"[..])
+        );
+        assert_eq!(xattr::get(&restored, "user.tmp.cache").unwrap(), None);
+    }
+}
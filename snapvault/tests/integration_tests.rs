@@ -1,7 +1,7 @@
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
 use predicates::prelude::*;
-use snapvault::{commands, Repository};
+use snapvault::{commands, ChunkerConfig, CompressionConfig, RepoLocator, Repository};
 use std::fs;
 
 /// Test complete workflow: init -> backup -> list -> restore
@@ -9,6 +9,7 @@ use std::fs;
 fn test_complete_workflow() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
     let dest = temp.child("restored");
 
@@ -17,23 +18,23 @@ fn test_complete_workflow() {
     source.child("dir/file2.txt").write_str("content2").unwrap();
 
     // Init repository
-    commands::init(repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
     repo_path.assert(predicate::path::is_dir());
     repo_path
         .child("config.json")
         .assert(predicate::path::is_file());
 
     // Backup
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     // List snapshots
-    commands::list(repo_path.path()).unwrap();
+    commands::list(&locator).unwrap();
 
     // Get snapshot ID
     let snapshot_id = get_first_snapshot_id(repo_path.path());
 
     // Restore
-    commands::restore(Some(&snapshot_id), dest.path(), repo_path.path()).unwrap();
+    commands::restore(Some(&snapshot_id), dest.path(), &locator).unwrap();
 
     // Verify restored files
     dest.child("file1.txt").assert("content1");
@@ -45,18 +46,19 @@ fn test_complete_workflow() {
 fn test_multiple_backups_ordering() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     source.child("file.txt").write_str("v1").unwrap();
 
-    commands::init(repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
 
     // Create multiple backups
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
     std::thread::sleep(std::time::Duration::from_millis(100));
 
     source.child("file.txt").write_str("v2").unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     // List should show 2 snapshots
     let count = fs::read_dir(repo_path.child("snapshots").path())
@@ -70,10 +72,11 @@ fn test_multiple_backups_ordering() {
 fn test_backup_empty_directory() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     // Should create snapshot even with no files
     let count = fs::read_dir(repo_path.child("snapshots").path())
@@ -87,16 +90,17 @@ fn test_backup_empty_directory() {
 fn test_restore_creates_destination() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
     let dest = temp.child("new_dir/subdir/restored");
 
     source.child("file.txt").write_str("content").unwrap();
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     let snapshot_id = get_first_snapshot_id(repo_path.path());
-    commands::restore(Some(&snapshot_id), dest.path(), repo_path.path()).unwrap();
+    commands::restore(Some(&snapshot_id), dest.path(), &locator).unwrap();
 
     dest.child("file.txt").assert("content");
 }
@@ -106,13 +110,14 @@ fn test_restore_creates_destination() {
 fn test_delete_all_workflow() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     source.child("file.txt").write_str("content").unwrap();
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     // Verify 2 snapshots exist
     let count_before = fs::read_dir(repo_path.child("snapshots").path())
@@ -121,7 +126,7 @@ fn test_delete_all_workflow() {
     assert_eq!(count_before, 2);
 
     // Delete all
-    commands::delete(repo_path.path(), None, true).unwrap();
+    commands::delete(&locator, None, true).unwrap();
 
     // Verify all deleted
     let count_after = fs::read_dir(repo_path.child("snapshots").path())
@@ -135,6 +140,7 @@ fn test_delete_all_workflow() {
 fn test_deeply_nested_directories() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     source
@@ -142,12 +148,12 @@ fn test_deeply_nested_directories() {
         .write_str("deep")
         .unwrap();
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     let dest = temp.child("restored");
     let snapshot_id = get_first_snapshot_id(repo_path.path());
-    commands::restore(Some(&snapshot_id), dest.path(), repo_path.path()).unwrap();
+    commands::restore(Some(&snapshot_id), dest.path(), &locator).unwrap();
 
     dest.child("a/b/c/d/e/f/file.txt").assert("deep");
 }
@@ -157,6 +163,7 @@ fn test_deeply_nested_directories() {
 fn test_special_characters_in_filenames() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     // Note: Some characters may not be valid on all filesystems
@@ -164,12 +171,12 @@ fn test_special_characters_in_filenames() {
     source.child("file-with-dashes.txt").write_str("dashes").unwrap();
     source.child("file_with_underscores.txt").write_str("underscores").unwrap();
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     let dest = temp.child("restored");
     let snapshot_id = get_first_snapshot_id(repo_path.path());
-    commands::restore(Some(&snapshot_id), dest.path(), repo_path.path()).unwrap();
+    commands::restore(Some(&snapshot_id), dest.path(), &locator).unwrap();
 
     dest.child("file with spaces.txt").assert("spaces");
     dest.child("file-with-dashes.txt").assert("dashes");
@@ -181,20 +188,21 @@ fn test_special_characters_in_filenames() {
 fn test_restore_latest_snapshot() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
     let dest = temp.child("restored");
 
     source.child("v1.txt").write_str("version1").unwrap();
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
     std::thread::sleep(std::time::Duration::from_millis(100));
 
     source.child("v2.txt").write_str("version2").unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     // Restore latest (should have v2.txt)
-    commands::restore(None, dest.path(), repo_path.path()).unwrap();
+    commands::restore(None, dest.path(), &locator).unwrap();
 
     dest.child("v1.txt").assert("version1");
     dest.child("v2.txt").assert("version2");
@@ -217,6 +225,7 @@ fn test_repository_validation() {
 fn test_backup_many_files() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     // Create 100 small files
@@ -227,12 +236,12 @@ fn test_backup_many_files() {
             .unwrap();
     }
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
     let dest = temp.child("restored");
     let snapshot_id = get_first_snapshot_id(repo_path.path());
-    commands::restore(Some(&snapshot_id), dest.path(), repo_path.path()).unwrap();
+    commands::restore(Some(&snapshot_id), dest.path(), &locator).unwrap();
 
     // Verify a few files
     dest.child("file_000.txt").assert("content_0");
@@ -240,25 +249,48 @@ fn test_backup_many_files() {
     dest.child("file_099.txt").assert("content_99");
 }
 
+/// Test a full cycle with the repository given as a `local:` location
+#[test]
+fn test_local_location_workflow() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let source = temp.child("source");
+    source.child("file.txt").write_str("located").unwrap();
+
+    let locator = RepoLocator::parse(&format!("local:{}", repo_path.path().display())).unwrap();
+    assert_eq!(locator, RepoLocator::from(repo_path.path()));
+
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
+
+    let dest = temp.child("restored");
+    commands::restore(None, dest.path(), &locator).unwrap();
+    dest.child("file.txt").assert("located");
+
+    // Unknown schemes are rejected rather than treated as relative paths
+    assert!(RepoLocator::parse("ftp://host/repo").is_err());
+}
+
 /// Test error handling for operations on non-existent repo
 #[test]
 fn test_operations_on_nonexistent_repo() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("nonexistent");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     source.child("file.txt").write_str("content").unwrap();
 
     // Backup should fail
-    let result = commands::backup(source.path(), repo_path.path());
+    let result = commands::backup(source.path(), &locator);
     assert!(result.is_err());
 
     // List should fail
-    let result = commands::list(repo_path.path());
+    let result = commands::list(&locator);
     assert!(result.is_err());
 
     // Delete should fail
-    let result = commands::delete(repo_path.path(), Some("snap"), false);
+    let result = commands::delete(&locator, Some("snap"), false);
     assert!(result.is_err());
 }
 
@@ -267,15 +299,16 @@ fn test_operations_on_nonexistent_repo() {
 fn test_concurrent_backups_different_ids() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     source.child("file.txt").write_str("content").unwrap();
 
-    commands::init(repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
 
     // Create multiple backups rapidly
     for _ in 0..5 {
-        commands::backup(source.path(), repo_path.path()).unwrap();
+        commands::backup(source.path(), &locator).unwrap();
         // Small delay to ensure different millisecond timestamps
         std::thread::sleep(std::time::Duration::from_millis(5));
     }
@@ -292,22 +325,446 @@ fn test_concurrent_backups_different_ids() {
 fn test_metadata_preservation() {
     let temp = TempDir::new().unwrap();
     let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
     let source = TempDir::new().unwrap();
 
     source.child("file.txt").write_str("content").unwrap();
 
-    commands::init(repo_path.path()).unwrap();
-    commands::backup(source.path(), repo_path.path()).unwrap();
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
 
-    // Read manifest and verify metadata exists
+    // The manifest points at the root tree, which holds the file records
     let snapshot_id = get_first_snapshot_id(repo_path.path());
     let manifest_path = repo_path
         .child("snapshots")
         .child(format!("{}.json", snapshot_id));
     let manifest_content = fs::read_to_string(manifest_path.path()).unwrap();
+    assert!(manifest_content.contains("\"tree\""));
+    assert!(!manifest_content.contains("file.txt"));
+
+    let repo = Repository::open(repo_path.path()).unwrap();
+    let manifest = repo.load_manifest(&snapshot_id, &repo.chunk_store()).unwrap();
+    let file = manifest.files.iter().find(|f| f.rel_path == "file.txt").unwrap();
+    assert!(file.modified.is_some());
+    assert_eq!(file.size, 7);
+}
+
+/// Test that compressed repositories round-trip and store fewer bytes
+#[test]
+fn test_compressed_workflow() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
+    let source = TempDir::new().unwrap();
+    let dest = temp.child("restored");
+
+    let content = "compressible line of text\n".repeat(100_000);
+    source.child("big.txt").write_str(&content).unwrap();
+
+    let options = commands::InitOptions {
+        chunker: ChunkerConfig::fastcdc(),
+        compression: CompressionConfig::Lz4,
+        ..Default::default()
+    };
+    commands::init_with_options(&locator, &options).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
+
+    let repo = Repository::open(repo_path.path()).unwrap();
+    let stats = repo.chunk_store().stats().unwrap();
+    assert!(stats.stored_size < stats.total_size);
+
+    let snapshot_id = get_first_snapshot_id(repo_path.path());
+    commands::restore(Some(&snapshot_id), dest.path(), &locator).unwrap();
+    dest.child("big.txt").assert(content.as_str());
+}
 
-    assert!(manifest_content.contains("modified"));
-    assert!(manifest_content.contains("size"));
+/// Test that many small files land in a handful of pack files, and that
+/// deleting every snapshot removes the packs again
+#[test]
+fn test_pack_files_workflow() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let locator = RepoLocator::from(repo_path.path());
+    let source = TempDir::new().unwrap();
+    let dest = temp.child("restored");
+
+    for i in 0..200 {
+        source
+            .child(format!("dir{}/file{}.txt", i % 10, i))
+            .write_str(&format!("small file {}", i))
+            .unwrap();
+    }
+
+    commands::init(&locator).unwrap();
+    commands::backup(source.path(), &locator).unwrap();
+
+    let count_files = |dir: &std::path::Path| {
+        walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .count()
+    };
+    let packs_dir = repo_path.path().join("data").join("packs");
+    assert_eq!(count_files(&packs_dir), 1);
+    assert!(!repo_path.path().join("data").join("chunks").exists());
+
+    let snapshot_id = get_first_snapshot_id(repo_path.path());
+    commands::restore(Some(&snapshot_id), dest.path(), &locator).unwrap();
+    dest.child("dir3/file123.txt").assert("small file 123");
+
+    commands::delete(&locator, None, true).unwrap();
+    assert_eq!(count_files(&packs_dir), 0);
+    let repo = Repository::open(repo_path.path()).unwrap();
+    assert_eq!(repo.chunk_store().stats().unwrap().total_chunks, 0);
+}
+
+/// Test an encrypted repository end to end through the CLI
+#[test]
+fn test_encrypted_workflow_cli() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let source = TempDir::new().unwrap();
+    let dest = temp.child("restored");
+
+    source.child("secret.txt").write_str("plaintext secret").unwrap();
+
+    snapvault_cmd("hunter2")
+        .args(["init", "--encrypt", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("hunter2")
+        .args(["backup", "--source"])
+        .arg(source.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+
+    // No plaintext manifest or index on disk
+    let snapshot_id = get_first_snapshot_id(repo_path.path());
+    let manifest_path = repo_path
+        .child("snapshots")
+        .child(format!("{}.json", snapshot_id));
+    let manifest = fs::read(manifest_path.path()).unwrap();
+    assert!(!String::from_utf8_lossy(&manifest).contains("secret.txt"));
+
+    // Wrong passphrase is rejected
+    let output = snapvault_cmd("wrong")
+        .args(["list", "--repo"])
+        .arg(repo_path.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    snapvault_cmd("hunter2")
+        .args(["restore", "--threads", "2", "--cache-size", "0", "--dest"])
+        .arg(dest.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+    dest.child("secret.txt").assert("plaintext secret");
+}
+
+/// Test adding, rotating and revoking key slots through the CLI
+#[test]
+fn test_key_management_cli() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+
+    snapvault_cmd("admin")
+        .args(["init", "--encrypt", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("admin")
+        .env("SNAPVAULT_NEW_PASSWORD", "ci")
+        .args(["key", "add", "--label", "ci", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("ci")
+        .args(["list", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+
+    // Rotate the CI passphrase; the old one stops working
+    snapvault_cmd("ci")
+        .env("SNAPVAULT_NEW_PASSWORD", "ci-rotated")
+        .args(["key", "passwd", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    assert!(!snapvault_cmd("ci")
+        .args(["list", "--repo"])
+        .arg(repo_path.path())
+        .status()
+        .unwrap()
+        .success());
+
+    // Revoke the CI key using the admin passphrase
+    let ci_key = fs::read_dir(repo_path.child("keys").path())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| fs::read_to_string(p).unwrap().contains("\"ci\""))
+        .unwrap();
+    let ci_key_id = ci_key.file_stem().unwrap().to_string_lossy().to_string();
+    snapvault_cmd("admin")
+        .args(["key", "remove", "--key", &ci_key_id, "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    assert!(!snapvault_cmd("ci-rotated")
+        .args(["list", "--repo"])
+        .arg(repo_path.path())
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(fs::read_dir(repo_path.child("keys").path()).unwrap().count(), 1);
+}
+
+/// Test tagging backups and forgetting snapshots through the CLI
+#[test]
+fn test_forget_cli() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let source = TempDir::new().unwrap();
+
+    snapvault_cmd("")
+        .args(["init", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    for (content, tag) in [("one", "nightly"), ("two", "nightly"), ("three", "manual")] {
+        source.child("file.txt").write_str(content).unwrap();
+        snapvault_cmd("")
+            .args(["backup", "--tag", tag, "--source"])
+            .arg(source.path())
+            .arg("--repo")
+            .arg(repo_path.path())
+            .assert_success();
+    }
+
+    // Without a keep rule nothing is removed
+    assert!(!snapvault_cmd("")
+        .args(["forget", "--repo"])
+        .arg(repo_path.path())
+        .status()
+        .unwrap()
+        .success());
+
+    // The newest snapshot of each tag is kept
+    let output = snapvault_cmd("")
+        .args(["forget", "--keep-last", "1", "--prune", "--repo"])
+        .arg(repo_path.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("tags [nightly]"), "{}", stdout);
+    assert!(stdout.contains("Kept 2 and removed 1 snapshots"), "{}", stdout);
+    assert_eq!(fs::read_dir(repo_path.child("snapshots").path()).unwrap().count(), 2);
+}
+
+/// Test exclude options and ignore files through the CLI
+#[test]
+fn test_backup_excludes_cli() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let source = TempDir::new().unwrap();
+    let dest = temp.child("restored");
+    let exclude_file = temp.child("excludes.txt");
+
+    source.child("keep.txt").write_str("kept").unwrap();
+    source.child("build/out.o").write_str("object").unwrap();
+    source.child("notes.BAK").write_str("backup copy").unwrap();
+    source.child("video.mp4").write_str(&"v".repeat(4096)).unwrap();
+    source.child("docs/.snapvaultignore").write_str("draft*\n").unwrap();
+    source.child("docs/draft1.md").write_str("draft").unwrap();
+    source.child("docs/final.md").write_str("final").unwrap();
+    exclude_file.write_str("# build output\nbuild/\n").unwrap();
+
+    snapvault_cmd("")
+        .args(["init", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    let output = snapvault_cmd("")
+        .args(["backup", "--iexclude", "*.bak", "--exclude-larger-than", "2k"])
+        .arg("--exclude-file")
+        .arg(exclude_file.path())
+        .arg("--source")
+        .arg(source.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Excluded:         3 files, 1 directories"), "{}", stdout);
+
+    snapvault_cmd("")
+        .args(["restore", "--dest"])
+        .arg(dest.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+    dest.child("keep.txt").assert("kept");
+    dest.child("docs/final.md").assert("final");
+    dest.child("build").assert(predicate::path::missing());
+    dest.child("notes.BAK").assert(predicate::path::missing());
+    dest.child("video.mp4").assert(predicate::path::missing());
+    dest.child("docs/draft1.md").assert(predicate::path::missing());
+}
+
+/// Test that permissions, owners and times survive a CLI backup and restore
+#[cfg(unix)]
+#[test]
+fn test_restore_metadata_cli() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let source = TempDir::new().unwrap();
+    let dest = temp.child("restored");
+
+    let file = source.child("private/key.pem");
+    file.write_str("key").unwrap();
+    fs::set_permissions(file.path(), fs::Permissions::from_mode(0o600)).unwrap();
+    let original = fs::metadata(file.path()).unwrap();
+    source.child("empty").create_dir_all().unwrap();
+    fs::set_permissions(source.child("empty").path(), fs::Permissions::from_mode(0o700)).unwrap();
+
+    snapvault_cmd("")
+        .args(["init", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("")
+        .args(["backup", "--source"])
+        .arg(source.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("")
+        .args(["restore", "--numeric-owner", "--dest"])
+        .arg(dest.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+
+    let restored = fs::metadata(dest.child("private/key.pem").path()).unwrap();
+    assert_eq!(restored.mode() & 0o7777, 0o600);
+    assert_eq!((restored.uid(), restored.gid()), (original.uid(), original.gid()));
+    assert_eq!(
+        (restored.mtime(), restored.mtime_nsec()),
+        (original.mtime(), original.mtime_nsec())
+    );
+    let empty = fs::metadata(dest.child("empty").path()).unwrap();
+    assert!(empty.is_dir());
+    assert_eq!(empty.mode() & 0o7777, 0o700);
+}
+
+/// Test that symlinks and hard links survive a CLI backup and restore
+#[cfg(unix)]
+#[test]
+fn test_links_cli() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let source = TempDir::new().unwrap();
+    let dest = temp.child("restored");
+
+    source.child("config/app.toml").write_str("debug = true").unwrap();
+    fs::hard_link(
+        source.child("config/app.toml").path(),
+        source.child("app.toml").path(),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink("config/app.toml", source.child("current").path()).unwrap();
+
+    snapvault_cmd("")
+        .args(["init", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("")
+        .args(["backup", "--source"])
+        .arg(source.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("")
+        .args(["restore", "--dest"])
+        .arg(dest.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+
+    let original = fs::metadata(dest.child("config/app.toml").path()).unwrap();
+    let linked = fs::metadata(dest.child("app.toml").path()).unwrap();
+    assert_eq!(original.ino(), linked.ino());
+    assert_eq!(
+        fs::read_link(dest.child("current").path()).unwrap(),
+        std::path::Path::new("config/app.toml")
+    );
+    dest.child("current").assert("debug = true");
+}
+
+/// Test that --xattr-include picks the extended attributes a backup records
+#[cfg(unix)]
+#[test]
+fn test_xattrs_cli() {
+    let temp = TempDir::new().unwrap();
+    let repo_path = temp.child("repo");
+    let source = TempDir::new().unwrap();
+    let dest = temp.child("restored");
+
+    let file = source.child("notes.txt");
+    file.write_str("notes").unwrap();
+    if xattr::set(file.path(), "user.author", b"ops").is_err() {
+        // The file system has no extended attributes
+        return;
+    }
+    xattr::set(file.path(), "trusted.cache", b"local").ok();
+
+    snapvault_cmd("")
+        .args(["init", "--repo"])
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("")
+        .args(["backup", "--xattr-include", "user.*", "--source"])
+        .arg(source.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+    snapvault_cmd("")
+        .args(["restore", "--dest"])
+        .arg(dest.path())
+        .arg("--repo")
+        .arg(repo_path.path())
+        .assert_success();
+
+    let restored = dest.child("notes.txt");
+    assert_eq!(
+        xattr::get(restored.path(), "user.author").unwrap().as_deref(),
+        Some(&b"ops"[..])
+    );
+    assert_eq!(xattr::get(restored.path(), "trusted.cache").unwrap(), None);
+}
+
+/// Build a command running the snapvault binary with the given passphrase
+fn snapvault_cmd(passphrase: &str) -> std::process::Command {
+    let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_snapvault"));
+    cmd.env("SNAPVAULT_PASSWORD", passphrase);
+    cmd
+}
+
+trait AssertSuccess {
+    fn assert_success(&mut self);
+}
+
+impl AssertSuccess for std::process::Command {
+    fn assert_success(&mut self) {
+        let output = self.output().unwrap();
+        assert!(
+            output.status.success(),
+            "command failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
 }
 
 // Helper function